@@ -7,7 +7,8 @@
 
 set -euo pipefail
 
-# ** Don't forget to `$qemu-img create fs.img 64k` (or whatever size you want).
+# ** Don't forget to `$qemu-img create fs.img 64k` (or whatever size you want),
+# ** or make a FAT32 image as described in the README.
 FLAGS=(-machine virt -smp 2 -m 128M -bios none -nographic \
     -global virtio-mmio.force-legacy=false \
     -drive file=fs.img,if=none,format=raw,id=x0,read-only=off \
@@ -15,6 +16,13 @@ FLAGS=(-machine virt -smp 2 -m 128M -bios none -nographic \
 
 print_help() { echo "$(tput setaf 2)$(tput bold)(info)$(tput sgr0) $1"; }
 
+if [ -n "${APPEND+x}" ] ; then
+    FLAGS+=(-append "$APPEND")
+fi
+if [ -f initramfs.cpio ] ; then
+    FLAGS+=(-initrd initramfs.cpio)
+fi
+
 print_help "Type CTRL-A, X to exit QEMU"
 if [ -n "${DEBUG+x}" ] ; then
     FLAGS+=(-s -S)
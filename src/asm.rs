@@ -5,3 +5,4 @@ global_asm!(include_str!("asm/macro.s"));
 global_asm!(include_str!("asm/entry.s"));
 global_asm!(include_str!("asm/trap.s"));
 global_asm!(include_str!("asm/trampoline.s"));
+global_asm!(include_str!("asm/uaccess.s"));
@@ -27,6 +27,10 @@ _entry:
         .option pop
         ## Set up stack per of hart ids according to linker script
 
+        ## The bootloader passes the device tree address in a1, which
+        ## we are about to clobber. Keep it for _start.
+        mv t1, a1
+
         ## Add 4k guard page per hart
         csrr a1, mhartid
         li a0, 0x3000           #2 page stack + guard page
@@ -55,6 +59,7 @@ _entry:
 
                                 # Jump to _start in src/main.rs
         .extern _start
+        mv a0, t1               # device tree address
         call _start
 spin:
         wfi
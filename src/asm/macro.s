@@ -35,6 +35,7 @@
     sd x28, 224(sp)
     sd x29, 232(sp)
     sd x30, 240(sp)
+    sd x31, 248(sp)
 .endm
 
 .macro load_gp_regs
@@ -68,6 +69,7 @@
     ld x28, 224(sp)
     ld x29, 232(sp)
     ld x30, 240(sp)
+    ld x31, 248(sp)
 
     addi sp, sp, 256
 .endm
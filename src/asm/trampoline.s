@@ -42,7 +42,8 @@ process_start_asm:
 ### ------------------------------------------------------------------
 
         ## jump into a process that has been run before
-        ## takes pc in a0, new base pt in a1, and new sp in a2
+        ## takes pc in a0, new base pt in a1, and new sp in a2, with the
+        ## registers already under the sscratch stack
         .global process_resume_asm
 process_resume_asm:
         csrw sepc, a0
@@ -68,11 +69,33 @@ process_resume_asm:
         sfence.vma x0, x0
         ## swap tables
 
-        mv sp, a2
+        ## resume copied the saved registers from the process stack
+        ## to just under the sscratch stack, a kernel page, with the
+        ## slot for sp pointing at them. The process sp waits in
+        ## sscratch to be swapped in last.
+        csrr t0, sscratch
+        csrw sscratch, a2
+        addi sp, t0, -256
+        ## load_gp_regs leaves tp alone, as the kernel keeps the hart
+        ## id there, but the process wants its own back
+        ld tp, 32(sp)
         load_gp_regs
+        csrrw sp, sscratch, sp
         sret
         ## jump there and enter U mode
         ## TODO worry about prior priv != U mode?
 
+### ------------------------------------------------------------------
+
+        ## Signal handlers return here, see process/signal.rs. This
+        ## page is mapped into every process at SIGRETURN_VA, so it
+        ## gets a page to itself.
+        .balign 4096
+        .global sigreturn_trampoline
+sigreturn_trampoline:
+        li a7, 139              # rt_sigreturn
+        ecall
+        .balign 4096
+
 ### ------------------------------------------------------------------
         ## this is the end of the file
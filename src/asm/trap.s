@@ -52,6 +52,12 @@ regular_strap:
         ld t0, -8(sp)
         save_gp_regs
 
+        ## this can land in the middle of copy_user_asm, so put SUM
+        ## aside for s_handler and give it back on the way out
+        li t0, 0x40000
+        csrrc s2, sstatus, t0
+        and s2, s2, t0
+
         ## load kernel page table
         ld t1, 264(sp)          #256 + 8
 
@@ -65,23 +71,55 @@ regular_strap:
         sfence.vma x0, x0
         csrrw s1, satp, t1
         sfence.vma x0, x0
-        ## now in kernel space, note that s1 should not be distrubed
+        ## now in kernel space, note that s1 and s2 should not be distrubed
         ## by rust
 
         ## get gp back to restore more info from later
         ld gp, 256(sp)
-
+        ## out of a process, tp is still the process's. The hart id is
+        ## at the start of the GPInfo gp points to (see hartlocal.rs)
+        csrr t0, sstatus
+        andi t0, t0, 0x100      # SPP
+        bnez t0, 1f
+        ld tp, (gp)
+1:
+
+        ## s_handler gets the saved registers, for user_fault_asm
+        mv a0, sp
         .extern s_handler
         call s_handler
 
         sfence.vma x0, x0
         csrw satp, s1
         sfence.vma x0, x0
+        csrs sstatus, s2
 
+        ## load_gp_regs leaves tp alone, put back whatever we came
+        ## from had
+        ld tp, 32(sp)
         load_gp_regs
         csrrw sp, sscratch, sp
         sret
 
+        ## A process faulted, and s_handler is turning that into a
+        ## signal rather than going back. a0 is the frame
+        ## regular_strap saved on the interrupt stack, which
+        ## user_fault_rust copies off before anything can trap
+        ## again. We leave the interrupt stack the way we found it and
+        ## get on the kernel stack, like scall_asm does.
+        .global user_fault_asm
+user_fault_asm:
+        ## the process sp, from the swap on the way in
+        csrr a1, sscratch
+        addi t0, a0, 256
+        csrw sscratch, t0
+        ld sp, 16(t0)
+        csrr a2, scause
+        csrr a3, stval
+        csrr a4, sepc
+        .extern user_fault_rust
+        j user_fault_rust
+
 
         ## The ecall / syscall handler is here.
         ##
@@ -97,47 +135,36 @@ regular_strap:
         ## The convention is that the caller saved registers are free
         ## to clobber as with a regular call
         ##
-        ## The convention leaves a6 unused but safe to clobber, so we
-        ## will use it for other communication purposes, specifically
-        ## directing traffic
+        ## Every syscall is handled by scall_rust on the kernel stack
+        ## with the kernel page table, so all of them go through the
+        ## context switch below.
         ##
 scall_asm:
-        ## handle a yield specifically
-
-        ## make quick space by using the sscratch stack without
-        ## changing its value
+        ## The registers go on the sscratch stack, a kernel page, not
+        ## the process stack, as the process can point sp anywhere.
+        ## scall_rust copies them out to the process stack with
+        ## copy_to_user, so a bad sp is a fault for the process rather
+        ## than for us.
         csrrw sp, sscratch, sp
-        addi sp, sp, -8
-        sd a0, (sp)
-        ## we are on the sscratch stack and can clobber a0 freely. All
-        ## others must be preserved
-        jal scall_direct
-
-        ## returns zero in a0 if we want to stay on the program
-        ## stack/page table, and non-zero for the kernel stack/ page
-        ## table
+        save_gp_regs
 
-        beqz a0, dont_change_stack
 ### -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 ### This is the context switch
 
         ## change stacks/page table here
-        ld a0, (sp)
-        addi sp, sp, 8
-        csrrw sp, sscratch, sp
-        ## now program register state is as it was when scall was
-        ## executed, and we are back on the program stack
-        save_gp_regs
-        ## onto PROCESS stack
 
         ## hold onto what we need to save
         csrr s2, sepc
-        mv s3, sp
+        ## the process sp, from the swap on the way in, less the room
+        ## the registers take on the process stack
+        csrr s3, sscratch
+        addi s3, s3, -256
         ## These two must be preserved across several calls until they
         ## might be used in scall_rust
 
-        ## sscratch holds the interrupt stack
-        csrr sp, sscratch
+        ## leave the sscratch stack the way we found it
+        addi sp, sp, 256
+        csrw sscratch, sp
 
         ## sscratch stack holds, from low addr to high:
         ##
@@ -167,14 +194,8 @@ scall_asm:
 ### -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 ### This is the end of the context switch
 ### We are fully in kernel space now.
-### The program pc is in a0 and the program sp is in a1
-
-dont_change_stack:
+### The program pc is in s2 and the program sp is in s3
 
-        addi sp, sp, -8
-        ld ra, (sp)
-        ## call the main handler
+        ## call the main handler, which leaves through the process
+        ## code rather than coming back here
         jal scall_rust
-
-        ## TODO do we need to manually increment sepc? unclear
-        sret
@@ -0,0 +1,43 @@
+### Copying to and from process memory. See vm/uaccess.rs, which
+### checks the range before it gets here.
+
+        .section .text
+        ## copy_user_asm(dst, src, len, satp) -> bytes not copied
+        ##
+        ## Copies len bytes from src to dst with the page table in satp
+        ## (the process's) and SUM set, so one side can be a user
+        ## address. The kernel is mapped into every process page table,
+        ## so the other side can be anything in kernel memory.
+        ##
+        ## If a load or store faults, s_handler sends us to
+        ## copy_user_fixup, and we return how much was left.
+        .global copy_user_asm
+        .global copy_user_load
+        .global copy_user_store
+        .global copy_user_fixup
+copy_user_asm:
+        csrrw a3, satp, a3
+        sfence.vma x0, x0
+        ## a3 now holds the kernel page table
+        li t0, 0x40000
+        csrs sstatus, t0
+        ## SUM is set, user pages are readable and writable
+
+copy_user_loop:
+        beqz a2, copy_user_fixup
+copy_user_load:
+        lb t1, (a1)
+copy_user_store:
+        sb t1, (a0)
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+        j copy_user_loop
+
+copy_user_fixup:
+        li t0, 0x40000
+        csrc sstatus, t0
+        csrw satp, a3
+        sfence.vma x0, x0
+        mv a0, a2
+        ret
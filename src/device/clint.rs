@@ -1,4 +1,4 @@
-//! Core local interruptor (timer interrupts).
+//! Core local interruptor (timer and software interrupts).
 use crate::hw::param;
 use crate::hw::riscv;
 
@@ -12,6 +12,15 @@ pub fn read_mtime() -> u64 {
     mtime
 }
 
+/// How many times a second mtime ticks, from the device tree. QEMU's
+/// virt machine uses 10MHz.
+pub fn mtime_frequency() -> u64 {
+    crate::hw::fdt::get()
+        .and_then(|fdt| fdt.property("/cpus", "timebase-frequency"))
+        .and_then(crate::hw::fdt::be_int)
+        .unwrap_or(10_000_000)
+}
+
 /// Set the CLINT MTIMECMP register.
 /// When CLINT MTIME >= CLINT MTIMECMP it triggers
 /// a *machine*-mode interrupt.
@@ -28,3 +37,22 @@ pub fn set_mtimecmp(interval: u64) {
             .write_volatile(mtime + interval as usize);
     }
 }
+
+/// Raise a machine software interrupt on `hart`. `m_handler` passes it
+/// on to supervisor mode there.
+// msip reg is at base + 4 * hart
+pub fn send_ipi(hart: usize) {
+    let base = param::CLINT_BASE as *mut u32;
+    unsafe {
+        base.byte_add(4 * hart).write_volatile(1);
+    }
+}
+
+/// Lower this hart's machine software interrupt. Machine mode only.
+pub fn clear_ipi() {
+    let hartid = riscv::read_mhartid() as usize;
+    let base = param::CLINT_BASE as *mut u32;
+    unsafe {
+        base.byte_add(4 * hartid).write_volatile(0);
+    }
+}
@@ -18,8 +18,9 @@
 
 
 use core::cell::OnceCell; // for PLIC, write once read many times
+use core::sync::atomic::{AtomicU64, Ordering};
 use crate::hw::riscv;
-use crate::hw::param::{PLIC_BASE, UART_IRQ, VIRTIO_IRQ};
+use crate::hw::param::{NHART, PLIC_BASE, UART_IRQ, VIRTIO_IRQ};
 
 // ^ constants for PLIC_BASE & device interrupt (IRQ) priority locations.
 
@@ -29,6 +30,19 @@ pub struct Plic {
     base: usize,
 }
 
+/// Interrupt sources we keep count of, more than QEMU's virt machine
+/// has.
+pub const NSOURCES: usize = 64;
+
+// How many times each source was claimed, on each hart.
+static DISPATCHED: [[AtomicU64; NHART]; NSOURCES] =
+    [const { [const { AtomicU64::new(0) }; NHART] }; NSOURCES];
+
+/// How many times source `irq` has been claimed so far, by hart.
+pub fn dispatched(irq: usize) -> [u64; NHART] {
+    core::array::from_fn(|hart| DISPATCHED[irq][hart].load(Ordering::Relaxed))
+}
+
 /// Single-time global initialization for Plic.
 /// Sets magic number device IRQ priorities, then initializes.
 pub fn global_init() {
@@ -116,7 +130,8 @@ impl Plic {
         }
     }
 
-    /// Claim an interupt that you were alerted to.
+    /// Claim an interupt that you were alerted to, and count it for
+    /// `dispatched`.
     pub fn claim(&self) -> u32 {
         let addr = self.base as *mut u32;
         let hart = riscv::read_tp() as usize;
@@ -125,11 +140,15 @@ impl Plic {
         const RAW_STEP: usize = 0x2000;
 
         let final_offset = (RAW_OFFSET + (hart * RAW_STEP))/4; //ASK / 4?
-        unsafe {
+        let irq = unsafe {
             // returns highest-priority pending interrupt
             addr.add(final_offset).read_volatile()
             // ^ reading mmapped register
+        };
+        if irq != 0 && (irq as usize) < NSOURCES {
+            DISPATCHED[irq as usize][hart].fetch_add(1, Ordering::Relaxed);
         }
+        irq
     }
 
     /// Alert the PLIC that we have completed the interupt we claimed
@@ -4,6 +4,7 @@
 // from https://github.com/sgmarz/osblog/tree/master/risc_v/src
 use core::fmt::Error;
 use core::fmt::Write;
+use core::ptr::addr_of;
 
 use crate::hw::param::UART_BASE;
 use crate::lock::mutex::*;
@@ -15,6 +16,66 @@ const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
 
 pub static mut WRITER: Mutex<Uart> = Uart::new(UART_BASE);
 
+// Input the interrupt handler has taken off the device but nobody has
+// read yet. Oldest first, and new input is dropped when it is full.
+static INPUT: Mutex<InputBuffer> = Mutex::new(InputBuffer { buf: [0; 256], head: 0, len: 0 });
+
+struct InputBuffer {
+    buf: [u8; 256],
+    head: usize,
+    len: usize,
+}
+
+/// Keep a character of input for a later `read_input`.
+pub fn push_input(c: u8) {
+    let mut input = INPUT.lock();
+    if input.len < input.buf.len() {
+        let tail = (input.head + input.len) % input.buf.len();
+        input.buf[tail] = c;
+        input.len += 1;
+    }
+}
+
+/// Put `data`, which `read_input` gave us, back in front of whatever
+/// input is left, for a read that couldn't take it after all. If that
+/// is more than fits, the newest input goes.
+pub fn unread_input(data: &[u8]) {
+    let mut input = INPUT.lock();
+    let size = input.buf.len();
+    for &c in data.iter().rev() {
+        input.head = (input.head + size - 1) % size;
+        let head = input.head;
+        input.buf[head] = c;
+        input.len = (input.len + 1).min(size);
+    }
+}
+
+/// What a console read with nothing to read sleeps on, see
+/// `futex::wait_object`.
+pub fn input_key() -> usize {
+    &INPUT as *const _ as usize
+}
+
+/// Whether the interrupt handler has kept any input.
+pub fn has_input() -> bool {
+    INPUT.lock().len > 0
+}
+
+/// The next character of input, from what the interrupt handler kept
+/// or else straight from the device.
+pub fn read_input() -> Option<u8> {
+    {
+        let mut input = INPUT.lock();
+        if input.len > 0 {
+            let c = input.buf[input.head];
+            input.head = (input.head + 1) % input.buf.len();
+            input.len -= 1;
+            return Some(c);
+        }
+    }
+    unsafe { (*addr_of!(WRITER)).lock().get() }
+}
+
 pub struct Uart {
     base_address: usize,
 }
@@ -30,7 +91,7 @@ impl Write for Uart {
 
 pub fn init() {
     unsafe {
-        WRITER.lock().init();
+        (*addr_of!(WRITER)).lock().init();
     }
 }
 
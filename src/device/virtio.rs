@@ -10,6 +10,7 @@ use crate::lock::mutex::Mutex;
 use crate::alloc::{vec::Vec, boxed::Box};
 use core::cell::OnceCell;
 use core::mem::size_of;
+use core::ptr::addr_of;
 
 static mut BLK_DEV: OnceCell<Mutex<SplitVirtQueue>> = OnceCell::new();
 
@@ -112,14 +113,16 @@ impl SplitVirtQueue {
         self.reqs[idx] = VirtBlkReq::default();
         let next_flag = VirtQueueDescFeat::Next as u16;
         loop {
-            if self.desc[idx].flags & next_flag != 0 {
-                self.free[idx] = 1;
-                let next = (*self.desc)[idx].next as usize;
-                self.desc[idx] = VirtQueueDesc::default();
-                idx = next;
-            } else {
+            // The tail of the chain (status desc) has no NEXT flag,
+            // but still needs to go back on the free list.
+            let flags = self.desc[idx].flags;
+            let next = (*self.desc)[idx].next as usize;
+            self.free[idx] = 1;
+            self.desc[idx] = VirtQueueDesc::default();
+            if flags & next_flag == 0 {
                 break;
             }
+            idx = next;
         }
     }
 }
@@ -268,25 +271,34 @@ impl Block {
 
 impl Block {
     /// Blocking write to device. Spins on `status` until device sets it.
-    pub fn write(&mut self) {
+    pub fn write(&mut self) -> Result<(), &'static str> {
         let mut status = 0xff_u8;
-        match blk_dev_ops(true, &mut status as *mut u8, self) {
-            Ok(_) => {
-                while status == 0xff {}
-                println!("Finished blk write.");
-            },
-            Err(_) => {log!(Error, "Failed to write block. Err code: {}", status); },
-        };
+        blk_dev_ops(true, &mut status as *mut u8, self)?;
+        wait_status(&status)
     }
     /// Blocking read from device. Spins on `status` until device sets it.
-    pub fn read(&mut self) {
+    pub fn read(&mut self) -> Result<(), &'static str> {
         let mut status = 0xff_u8;
-        match blk_dev_ops(false, &mut status as *mut u8, self) {
-            Ok(_) => {
-                while status == 0xff {}
-            },
-            Err(_) => {log!(Error, "Failed to read block. Err code: {}", status); },
-        };
+        blk_dev_ops(false, &mut status as *mut u8, self)?;
+        wait_status(&status)
+    }
+}
+
+/// Spin until the device fills in the status byte of a request.
+///
+/// We poll the used ring ourselves rather than waiting for the
+/// interrupt, as S mode interrupts are not enabled while we are in
+/// the kernel, and the other hart may not be in a position to take
+/// it for us.
+fn wait_status(status: &u8) -> Result<(), &'static str> {
+    let status = status as *const u8;
+    loop {
+        match unsafe { status.read_volatile() } {
+            0xff => virtio_blk_poll(),
+            VIRTIO_BLK_S_OK => return Ok(()),
+            VIRTIO_BLK_S_UNSUPP => return Err("Block request unsupported."),
+            _ => return Err("Block request IO error."),
+        }
     }
 }
 
@@ -357,7 +369,7 @@ pub fn virtio_block_init() -> Result<(), &'static str> {
     // iv. Allocate and zero queue. Must by physically contiguous.
     let sq = SplitVirtQueue::new();
     let (desc_ptr, avail_ptr, used_ptr) = sq.get_ring_ptrs();
-    match unsafe { BLK_DEV.set(Mutex::new(sq)) } {
+    match unsafe { (*addr_of!(BLK_DEV)).set(Mutex::new(sq)) } {
         Ok(_) => (),
         Err(_) => { return Err("Unable to init memory for ring queues."); },
     }
@@ -386,7 +398,7 @@ pub fn virtio_block_init() -> Result<(), &'static str> {
 // Section 2.6.13
 fn blk_dev_ops(write: bool, status: *mut u8, buf: &mut Block) -> Result<(), &'static str>{
     if buf.len % 512 != 0 { return Err("Data must be multiple of 512 bytes."); }
-    let mut sq = match unsafe { BLK_DEV.get() } {
+    let mut sq = match unsafe { (*addr_of!(BLK_DEV)).get() } {
         Some(sq) => sq.lock(),
         None => { return Err("Uninitialized blk device."); },
     };
@@ -448,7 +460,7 @@ fn blk_dev_ops(write: bool, status: *mut u8, buf: &mut Block) -> Result<(), &'st
     io_barrier();
 
     // Incr avail ring index. Section 2.6.13.3
-    sq.avail.idx += 1; // Or += num desc heads if we are batching.
+    sq.avail.idx = sq.avail.idx.wrapping_add(1); // Or += num desc heads if we are batching.
 
     io_barrier();
 
@@ -462,7 +474,7 @@ fn blk_dev_ops(write: bool, status: *mut u8, buf: &mut Block) -> Result<(), &'st
 }
 
 pub fn virtio_blk_intr() {
-    let mut sq = match unsafe { BLK_DEV.get() } {
+    let mut sq = match unsafe { (*addr_of!(BLK_DEV)).get() } {
         Some(sq) => sq.lock(),
         None => { return; },
     };
@@ -473,7 +485,38 @@ pub fn virtio_blk_intr() {
     write_virtio_32(VIRTIO_INTERRUPT_ACK, int_status & 0x3); // match xv6
     //println!("Virtio BLK dev intr status: {:#02x}", int_status);
 
-    while sq.last_seen_used != sq.used.idx {
+    process_used(&mut sq);
+}
+
+/// How many 512 byte sectors the device has, from its config space.
+/// Section 5.2.4
+pub fn virtio_blk_capacity() -> u64 {
+    // the config generation changes if the device updates it while
+    // we read it, Section 4.2.2.2
+    loop {
+        let generation = read_virtio_32(VIRTIO_CONFIG_GENERATION);
+        let low = read_virtio_32(VIRTIO_CONFIG) as u64;
+        let high = read_virtio_32(VIRTIO_CONFIG + 4) as u64;
+        if read_virtio_32(VIRTIO_CONFIG_GENERATION) == generation {
+            return high << 32 | low;
+        }
+    }
+}
+
+/// Reap any finished requests without waiting for an interrupt. See
+/// `wait_status`.
+pub fn virtio_blk_poll() {
+    let mut sq = match unsafe { (*addr_of!(BLK_DEV)).get() } {
+        Some(sq) => sq.lock(),
+        None => { return; },
+    };
+    process_used(&mut sq);
+}
+
+fn process_used(sq: &mut SplitVirtQueue) {
+    // The device updates this behind our back.
+    let used_idx_ptr = core::ptr::addr_of!(sq.used.idx);
+    while sq.last_seen_used != unsafe { used_idx_ptr.read_volatile() } {
         io_barrier();
         let used_idx = sq.last_seen_used % (RING_SIZE as u16);
         let used_id = sq.used.ring[used_idx as usize].id as usize;
@@ -483,7 +526,7 @@ pub fn virtio_blk_intr() {
             log!(Error, "Block IO status: {}", iostat);
             //panic!("virtio blk req status");
         }
-        sq.last_seen_used += 1;
+        sq.last_seen_used = sq.last_seen_used.wrapping_add(1);
         sq.free_descs(used_id);
     }
 }
@@ -1 +1,58 @@
+//! Files, filesystems, and executable formats.
 pub mod elf64;
+pub mod block;
+pub mod vfs;
+pub mod fat32;
+pub mod rfs;
+pub mod ext2;
+pub mod tmpfs;
+pub mod initramfs;
+
+use alloc::sync::Arc;
+
+use block::{BlockDevice, VirtioBlockDevice};
+use vfs::{FileSystem, FsError};
+
+/// Look for a filesystem we understand on the virtio block device and
+/// mount it at `path`.
+pub fn mount_block(path: &str) -> Result<(), FsError> {
+    let dev: Arc<dyn BlockDevice> = Arc::new(VirtioBlockDevice);
+    vfs::mount(path, probe(dev)?)
+}
+
+/// If we booted with an initramfs, mount a tmpfs at `/` and unpack it
+/// there. Returns whether there was one.
+pub fn mount_initramfs() -> Result<bool, FsError> {
+    let archive = match initramfs::find() {
+        Some(a) => a,
+        None => return Ok(false),
+    };
+    vfs::mount("/", Arc::new(tmpfs::TmpFs::new()))?;
+    let count = initramfs::unpack(archive)?;
+    log!(Info, "Unpacked {} initramfs entries ({} bytes)", count, archive.len());
+    Ok(true)
+}
+
+/// Mount a fresh in memory filesystem at `/tmp`.
+pub fn mount_tmp() -> Result<(), FsError> {
+    vfs::mount("/tmp", Arc::new(tmpfs::TmpFs::new()))
+}
+
+/// Mount processes and kernel state at `/proc`.
+pub fn mount_proc() -> Result<(), FsError> {
+    vfs::mount("/proc", Arc::new(crate::process::procfs::ProcFs::new()))
+}
+
+/// Try each disk filesystem driver on `dev` in turn. Drivers say
+/// `Unsupported` when they don't recognize the volume at all.
+fn probe(dev: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError> {
+    match rfs::Rfs::new(dev.clone()) {
+        Err(FsError::Unsupported) => {},
+        res => return res.map(|fs| Arc::new(fs) as Arc<dyn FileSystem>),
+    }
+    match ext2::Ext2::new(dev.clone()) {
+        Err(FsError::Unsupported) => {},
+        res => return res.map(|fs| Arc::new(fs) as Arc<dyn FileSystem>),
+    }
+    Ok(Arc::new(fat32::Fat32::new(dev)?))
+}
@@ -0,0 +1,72 @@
+//! Sector granularity access to block devices for filesystems.
+//!
+//! Filesystems are written against the `BlockDevice` trait rather
+//! than the virtio driver directly, so that they don't care where
+//! their sectors come from.
+
+use crate::device::virtio::{self, Block};
+use crate::file::vfs::FsError;
+
+/// Size of a device sector in bytes. Everything below the filesystem
+/// layer works in these units.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Something that can read and write fixed size sectors.
+///
+/// `buf` must be exactly `SECTOR_SIZE` bytes long.
+pub trait BlockDevice: Send + Sync {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError>;
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), FsError>;
+
+    /// How many sectors there are, if the device says.
+    fn sectors(&self) -> Option<u64> {
+        None
+    }
+
+    /// Read `buf.len() / SECTOR_SIZE` consecutive sectors starting at
+    /// `sector`.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            self.read_sector(sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf.len() / SECTOR_SIZE` consecutive sectors starting at
+    /// `sector`.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), FsError> {
+        for (i, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+            self.write_sector(sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// The (single) virtio block device attached by QEMU.
+pub struct VirtioBlockDevice;
+
+impl BlockDevice for VirtioBlockDevice {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        assert!(buf.len() == SECTOR_SIZE, "Partial sector read.");
+        let mut blk = Block::new(buf.as_mut_ptr(), sector);
+        blk.read().map_err(|e| {
+            log!(Error, "Failed to read sector {}: {}", sector, e);
+            FsError::Io
+        })
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), FsError> {
+        assert!(buf.len() == SECTOR_SIZE, "Partial sector write.");
+        // The device only ever reads from this buffer on a write, but
+        // Block wants a mutable pointer.
+        let mut blk = Block::new(buf.as_ptr() as *mut u8, sector);
+        blk.write().map_err(|e| {
+            log!(Error, "Failed to write sector {}: {}", sector, e);
+            FsError::Io
+        })
+    }
+
+    fn sectors(&self) -> Option<u64> {
+        Some(virtio::virtio_blk_capacity())
+    }
+}
@@ -1,21 +1,29 @@
 //! This module is for the interpretation of 64 bit ELF executable files.
+//!
+//! 32 bit files get the same header and program header checks, and
+//! their program headers are widened to the 64 bit layout, but we
+//! only look at sections and relocations in 64 bit files.
+
+use alloc::vec::Vec;
+use core::cmp::max;
+use core::ops::Range;
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Endianness {
     Little = 1,
     Big = 2,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum AddrWidth {
     Word = 1,
     DoubleWord = 2,
 }
 
 #[repr(u16)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ELFType {
     Relocatable = 1,
     Executable = 2,
@@ -24,7 +32,7 @@ pub enum ELFType {
 }
 
 #[repr(u16)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Architecture {
     Unspecified = 0,
     Sparc = 2,
@@ -39,11 +47,41 @@ pub enum Architecture {
     RISCV = 0xF3,
 }
 
+impl TryFrom<u16> for Architecture {
+    type Error = ELFError;
+
+    fn try_from(val: u16) -> Result<Self, ELFError> {
+        Ok(match val {
+            0 => Self::Unspecified,
+            2 => Self::Sparc,
+            3 => Self::X86,
+            8 => Self::Mips,
+            0x14 => Self::PowerPC,
+            0x28 => Self::Arm,
+            0x2A => Self::SuperH,
+            0x32 => Self::IA64,
+            0x3E => Self::X86_64,
+            0xB7 => Self::Aarch64,
+            0xF3 => Self::RISCV,
+            _ => return Err(ELFError::WrongArchitecture),
+        })
+    }
+}
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 
-/// Corresponds to the literal bits of the header. Not all values are
-/// meaningful, and `usize` is used when 32 and 64 bit ELF files
-/// differ.
-#[repr(C)]
+/// Size of the 64 bit ELF header in the file.
+pub const HEADER_SIZE64: usize = 64;
+/// Size of one 64 bit program header in the file.
+pub const PROGRAM_HEADER_SIZE64: usize = 56;
+/// Size of the 32 bit ELF header in the file.
+pub const HEADER_SIZE32: usize = 52;
+/// Size of one 32 bit program header in the file.
+pub const PROGRAM_HEADER_SIZE32: usize = 32;
+
+/// The fields of the header. Only ever built by `ELFProgram::new64`
+/// or `ELFProgram::new32`, so every value here has been checked against the file. `usize` is
+/// used when 32 and 64 bit ELF files differ.
 #[derive(Copy, Clone)]
 pub struct ELFHeader {
     pub magic: [u8; 4],
@@ -69,31 +107,45 @@ pub struct ELFHeader {
     // index of section string table of names of sections
 }
 
-#[repr(u32)]
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum ProgramSegmentType {
-    Ignore = 0,
-    Load = 1,
-    Dynamic = 2,
-    Interpreter = 3,
-    Notes = 4,
-    Reserved = 5,
-    ProgramTable = 6,
+    Ignore,
+    Load,
+    Dynamic,
+    Interpreter,
+    Notes,
+    Reserved,
+    ProgramTable,
     // if there is a segment that points to the table itself
+    Other(u32),
+    // OS or processor specific, like PT_GNU_STACK
+}
+
+impl From<u32> for ProgramSegmentType {
+    fn from(val: u32) -> Self {
+        match val {
+            0 => Self::Ignore,
+            1 => Self::Load,
+            2 => Self::Dynamic,
+            3 => Self::Interpreter,
+            4 => Self::Notes,
+            5 => Self::Reserved,
+            6 => Self::ProgramTable,
+            other => Self::Other(other),
+        }
+    }
 }
 
 pub const PROG_SEG_EXEC: u16 = 1;
 pub const PROG_SEG_WRITE: u16 = 2;
 pub const PROG_SEG_READ: u16 = 4;
 
-/// A sub header of an ELF file describing the file itself. Also
-/// corresponds to the literal bits from the file. Not all values are
-/// valid.
+/// A sub header of an ELF file describing the file itself. Like
+/// `ELFHeader`, only ever built from a checked file.
 ///
 /// This is a seperate struct from the 32 bit version as their length
 /// and arrangment are different enough to warrant it.
-#[repr(C)]
 #[derive(Copy, Clone)]
 pub struct ProgramHeaderSegment64 {
     pub seg_type: ProgramSegmentType,
@@ -106,8 +158,197 @@ pub struct ProgramHeaderSegment64 {
     pub alignment: u64,              // is a power of two
 }
 
-// TODO sections are not implemented currently, as we are interested
-// in execution and not linking / relocation
+/// The 32 bit program header. Note `flags` moves to the end.
+#[derive(Copy, Clone)]
+pub struct ProgramHeaderSegment32 {
+    pub seg_type: ProgramSegmentType,
+    pub file_offset: u32,
+    pub vmem_addr: u32,
+    pub unused: u32,
+    pub size_in_file: u32,
+    pub size_in_memory: u32,
+    pub flags: u32,
+    pub alignment: u32,
+}
+
+impl From<ProgramHeaderSegment32> for ProgramHeaderSegment64 {
+    fn from(seg: ProgramHeaderSegment32) -> Self {
+        Self {
+            seg_type: seg.seg_type,
+            flags: seg.flags,
+            file_offset: seg.file_offset as u64,
+            vmem_addr: seg.vmem_addr as u64,
+            unused: seg.unused as u64,
+            size_in_file: seg.size_in_file as u64,
+            size_in_memory: seg.size_in_memory as u64,
+            alignment: seg.alignment as u64,
+        }
+    }
+}
+
+// Dynamic section tags we care about.
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_REL: i64 = 17;
+const DT_RELR: i64 = 36;
+const DYN_SIZE64: usize = 16;
+const RELA_SIZE64: usize = 24;
+
+// Auxiliary vector entry types, passed to a new program on its stack.
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+pub const R_RISCV_NONE: u32 = 0;
+pub const R_RISCV_RELATIVE: u32 = 3;
+
+/// One entry of a RELA relocation table.
+#[derive(Copy, Clone, Debug)]
+pub struct Rela64 {
+    pub offset: u64,                // where, as linked
+    pub kind: u32,                  // R_RISCV_*
+    pub symbol: u32,                // index into the dynamic symbols
+    pub addend: i64,
+}
+
+/// Size of one 64 bit section header in the file.
+pub const SECTION_HEADER_SIZE64: usize = 64;
+/// Size of one 64 bit symbol table entry in the file.
+pub const SYMBOL_SIZE64: usize = 24;
+
+// Special section indices.
+const SHN_UNDEF: u16 = 0;
+const SHN_XINDEX: u16 = 0xffff;     // real index is elsewhere
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SectionType {
+    Null,
+    ProgBits,
+    SymTab,
+    StrTab,
+    Rela,
+    Hash,
+    Dynamic,
+    Note,
+    NoBits,                     // takes no space in the file, like .bss
+    Rel,
+    DynSym,
+    Other(u32),
+}
+
+impl From<u32> for SectionType {
+    fn from(val: u32) -> Self {
+        match val {
+            0 => Self::Null,
+            1 => Self::ProgBits,
+            2 => Self::SymTab,
+            3 => Self::StrTab,
+            4 => Self::Rela,
+            5 => Self::Hash,
+            6 => Self::Dynamic,
+            7 => Self::Note,
+            8 => Self::NoBits,
+            9 => Self::Rel,
+            11 => Self::DynSym,
+            other => Self::Other(other),
+        }
+    }
+}
+
+pub const SEC_WRITE: u64 = 1;
+pub const SEC_ALLOC: u64 = 2;
+pub const SEC_EXEC: u64 = 4;
+
+/// A section header. Sections are for linkers and debuggers, and we
+/// never need them to run a program, so unlike the program headers
+/// these are only checked as they are used.
+#[derive(Copy, Clone, Debug)]
+pub struct SectionHeader64 {
+    pub name: u32,              // offset into the section name table
+    pub sec_type: SectionType,
+    pub flags: u64,             // OR of SEC_*
+    pub vmem_addr: u64,
+    pub file_offset: u64,
+    pub size: u64,
+    pub link: u32,              // meaning depends on the type
+    pub info: u32,
+    pub alignment: u64,
+    pub entry_size: u64,        // for tables
+}
+
+pub const SYM_LOCAL: u8 = 0;
+pub const SYM_GLOBAL: u8 = 1;
+pub const SYM_WEAK: u8 = 2;
+
+pub const SYM_NOTYPE: u8 = 0;
+pub const SYM_OBJECT: u8 = 1;
+pub const SYM_FUNC: u8 = 2;
+pub const SYM_SECTION: u8 = 3;
+pub const SYM_FILE: u8 = 4;
+
+/// One symbol from `.symtab` or `.dynsym`, with its name looked up.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol64<'a> {
+    pub name: &'a str,
+    pub info: u8,               // binding and type, see bind/kind
+    pub other: u8,              // visibility
+    pub section_index: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+impl Symbol64<'_> {
+    /// One of SYM_LOCAL, SYM_GLOBAL, SYM_WEAK.
+    pub fn bind(&self) -> u8 {
+        self.info >> 4
+    }
+
+    /// One of SYM_NOTYPE, SYM_OBJECT, SYM_FUNC, ...
+    pub fn kind(&self) -> u8 {
+        self.info & 0xf
+    }
+}
+
+/// Walks a symbol table. Entries whose names can't be read are
+/// given an empty one rather than ending the walk.
+pub struct Symbols<'a> {
+    table: &'a [u8],
+    strtab: &'a [u8],
+    next: usize,
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = Symbol64<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ent = self.table.get(self.next * SYMBOL_SIZE64..(self.next + 1) * SYMBOL_SIZE64)?;
+        self.next += 1;
+        let name = read_u32(ent, 0, ELFError::BadSymbolTable).unwrap_or(0);
+        Some(Symbol64 {
+            name: table_str(self.strtab, name as usize).unwrap_or(""),
+            info: ent[4],
+            other: ent[5],
+            section_index: read_u16(ent, 6, ELFError::BadSymbolTable).unwrap_or(0),
+            value: read_u64(ent, 8, ELFError::BadSymbolTable).unwrap_or(0),
+            size: read_u64(ent, 16, ELFError::BadSymbolTable).unwrap_or(0),
+        })
+    }
+}
+
+// The NUL terminated string at `off` in a string table.
+fn table_str(table: &[u8], off: usize) -> Option<&str> {
+    let rest = table.get(off..)?;
+    let len = rest.iter().position(|&c| c == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
 
 // todo think about if there is a nice streaming solution that avoids
 // the intermediate copy
@@ -140,29 +381,16 @@ pub struct ProgramHeaderSegment64 {
 //     }
 // }
 
-/// We expect that the data at source continues to be valid for the entire lifetime of ELFProgram.
+/// A checked, in memory ELF file. Everything reachable through this
+/// has been bounds checked against `source`, so a malformed file
+/// can't make us read outside of it.
 ///
-/// TODO how to do wthat with rust lifetime stuff? Restart my attempt for a source trait?
-///
-/// TODO consider interactions with lifetimes and if it makes more
-/// sense to have a in-memory ELF struct rather than moving only one
-/// part into memory at a time. Requires more copying, but allows
-/// closing the file / (currently) would avoid having to map the entire
-/// file for the full duration of process starting
-pub struct ELFProgram {
+/// TODO consider if it makes sense to stream from the file rather
+/// than read the whole thing into memory first. Requires less
+/// memory, but more care about the file changing under us.
+pub struct ELFProgram<'a> {
     pub header: ELFHeader,
-    pub source: *const u8,
-}
-
-macro_rules! ill_formed {
-    () => {
-        panic!("ELF is ill-formed.")
-    }
-}
-macro_rules! unsupported {
-    () => {
-        panic!("ELF is unsupported.")
-    }
+    pub source: &'a [u8],
 }
 
 // TODO consider rolling together a bunch of these overlapping error
@@ -171,53 +399,669 @@ macro_rules! unsupported {
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum ELFError {
+    Truncated,                  // file ends inside the ELF header
+    BadMagic,
+    BadWidth,                   // EI_CLASS is neither 32 nor 64 bit
+    UnsupportedWidth,           // 32 bit where we wanted 64, or the other way
+    BadEndianness,
+    UnsupportedEndianness,      // big endian
+    BadVersion,
+    BadType,
+    UnsupportedType,            // not an executable
+    DynamicallyLinked,          // wants an interpreter (ld.so)
+    WrongArchitecture,          // not RISC-V
+    BadHeaderSize,
+    BadProgramHeaderSize,
+    ProgramHeadersOutOfBounds,
+    SegmentOutOfBounds,         // file data past the end of the file
+    BadAlignment,               // not a power of two
+    BadDynamic,                 // dynamic section or relocation table is malformed
+    UnsupportedRelocation,
+    BadRelocation,              // target outside the loaded image
+    BadSectionHeaderSize,
+    SectionHeadersOutOfBounds,
+    SectionOutOfBounds,         // section data past the end of the file
+    BadStringTable,
+    BadSymbolTable,
+    BadAddress,                 // segment runs off the top of user memory
     MappedZeroPage,
     MappedKernelText,
     FailedAlloc,
     FailedMap,
-    InequalSizes,               // in_file and in_memory don't match
-    ExcessiveAlignment,
-}
-
-impl ELFProgram {
-    /// Create a new object to represent an ELF program and check that
-    /// we can actually run it. We need that the data source backing
-    /// this object (probably a file), must exist at least until all
-    /// the load segments have been copied into the process address
-    /// space.
-    pub fn new64(src: *const u8) -> Self {
-        let out = Self {
-            header: unsafe { *(src as *const ELFHeader) },
-            source: src,
+    InequalSizes,               // more in_file than in_memory
+    ArgumentsTooLong,           // argv and envp don't fit on the new stack
+}
+
+// Little endian reads at a byte offset. Anything past the end of the
+// buffer is `err`.
+fn read_u8(buf: &[u8], off: usize, err: ELFError) -> Result<u8, ELFError> {
+    buf.get(off).copied().ok_or(err)
+}
+
+fn read_u16(buf: &[u8], off: usize, err: ELFError) -> Result<u16, ELFError> {
+    match buf.get(off..off.wrapping_add(2)) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(err),
+    }
+}
+
+fn read_u32(buf: &[u8], off: usize, err: ELFError) -> Result<u32, ELFError> {
+    match buf.get(off..off.wrapping_add(4)) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(err),
+    }
+}
+
+fn read_u64(buf: &[u8], off: usize, err: ELFError) -> Result<u64, ELFError> {
+    match buf.get(off..off.wrapping_add(8)) {
+        Some(b) => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(b);
+            Ok(u64::from_le_bytes(bytes))
+        },
+        None => Err(err),
+    }
+}
+
+// Byte range `off..off + len` of a file of `size` bytes, if it fits.
+fn file_range(off: u64, len: u64, size: usize) -> Option<Range<usize>> {
+    let end = off.checked_add(len)?;
+    if end > size as u64 {
+        return None;
+    }
+    Some(off as usize..end as usize)
+}
+
+impl<'a> ELFProgram<'a> {
+    /// Parse and check the ELF file in `src`, making sure it is
+    /// something we can actually run: a little endian, 64 bit, RISC-V,
+    /// statically linked executable (position independent or not)
+    /// whose program headers and segments all lie within the file.
+    pub fn new64(src: &'a [u8]) -> Result<Self, ELFError> {
+        Self::parse(src, AddrWidth::DoubleWord)
+    }
+
+    /// As `new64`, but for a 32 bit file.
+    pub fn new32(src: &'a [u8]) -> Result<Self, ELFError> {
+        Self::parse(src, AddrWidth::Word)
+    }
+
+    /// As `new64` or `new32`, whichever the file says it is.
+    pub fn new(src: &'a [u8]) -> Result<Self, ELFError> {
+        match src.get(4) {
+            Some(1) => Self::new32(src),
+            _ => Self::new64(src),
+        }
+    }
+
+    fn parse(src: &'a [u8], width: AddrWidth) -> Result<Self, ELFError> {
+        use ELFError::*;
+        let (header_size, program_entry_size) = match width {
+            AddrWidth::Word => (HEADER_SIZE32, PROGRAM_HEADER_SIZE32),
+            AddrWidth::DoubleWord => (HEADER_SIZE64, PROGRAM_HEADER_SIZE64),
         };
-        if out.header.magic != [0x7f, 'E' as u8, 'L' as u8, 'F' as u8] {
-            ill_formed!();
-        }
-        // filter out illformed or unsupported ELFs
-        match out.header.endian as u8 { // little only
-            1 => {},
-            2 => {unsupported!()}
-            _ => {ill_formed!()},
+        if src.len() < header_size {
+            return Err(Truncated);
+        }
+        if src[..4] != ELF_MAGIC {
+            return Err(BadMagic);
+        }
+        match src[4] {
+            1 | 2 if src[4] == width as u8 => {},
+            1 | 2 => return Err(UnsupportedWidth),
+            _ => return Err(BadWidth),
+        }
+        let endian = match src[5] {
+            1 => Endianness::Little,
+            2 => return Err(UnsupportedEndianness),
+            _ => return Err(BadEndianness),
         };
-        match out.header.width as u8 { // 64 bit only
-            1 => {unsupported!()},
-            2 => {},
-            _ => {ill_formed!()},
+        if src[6] != 1 {
+            return Err(BadVersion);
+        }
+        let elf_type = match read_u16(src, 16, Truncated)? {
+            2 => ELFType::Executable,
+            3 => ELFType::Shared,       // position independent executables
+            1 | 4 => return Err(UnsupportedType),
+            _ => return Err(BadType),
         };
-        match out.header.elf_type as u8 { // executable only
-            2 => {},
-            1 | 3..=4 => {unsupported!()},
-            _ => {ill_formed!()},
+        let instruction_set = Architecture::try_from(read_u16(src, 18, Truncated)?)?;
+        if instruction_set != Architecture::RISCV {
+            return Err(WrongArchitecture);
+        }
+        let version = read_u32(src, 20, Truncated)?;
+        if version != 1 {
+            return Err(BadVersion);
+        }
+        let mut padding = [0; 8];
+        padding.copy_from_slice(&src[7..15]);
+
+        // After e_version the two layouts only differ in how wide the
+        // addresses and offsets are.
+        let word = match width {
+            AddrWidth::Word => 4,
+            AddrWidth::DoubleWord => 8,
+        };
+        let read_word = |off: usize| match width {
+            AddrWidth::Word => read_u32(src, off, Truncated).map(|v| v as usize),
+            AddrWidth::DoubleWord => read_u64(src, off, Truncated).map(|v| v as usize),
+        };
+
+        let header = ELFHeader {
+            magic: ELF_MAGIC,
+            width,
+            endian,
+            header_version: src[6],
+            padding,
+            ident_size: read_u8(src, 15, Truncated)?,
+            elf_type,
+            instruction_set,
+            version,
+            entry: read_word(24)?,
+            program_header_pos: read_word(24 + word)?,
+            section_header_pos: read_word(24 + 2 * word)?,
+            flags: read_u32(src, 24 + 3 * word, Truncated)?,
+            header_size: read_u16(src, header_size - 12, Truncated)?,
+            program_entry_size: read_u16(src, header_size - 10, Truncated)?,
+            num_program_entries: read_u16(src, header_size - 8, Truncated)?,
+            section_entry_size: read_u16(src, header_size - 6, Truncated)?,
+            num_section_entries: read_u16(src, header_size - 4, Truncated)?,
+            section_name_index: read_u16(src, header_size - 2, Truncated)?,
+        };
+        if header.header_size as usize != header_size {
+            return Err(BadHeaderSize);
+        }
+        if header.num_program_entries != 0
+            && header.program_entry_size as usize != program_entry_size {
+            return Err(BadProgramHeaderSize);
+        }
+        let table_size = header.num_program_entries as u64 * program_entry_size as u64;
+        if file_range(header.program_header_pos as u64, table_size, src.len()).is_none() {
+            return Err(ProgramHeadersOutOfBounds);
+        }
+
+        let out = Self { header, source: src };
+        for segment in out.program_headers() {
+            if segment.seg_type == ProgramSegmentType::Interpreter {
+                return Err(DynamicallyLinked);
+            }
+            if segment.seg_type != ProgramSegmentType::Load {
+                continue;
+            }
+            if file_range(segment.file_offset, segment.size_in_file, src.len()).is_none() {
+                return Err(SegmentOutOfBounds);
+            }
+            if segment.alignment > 1 && !segment.alignment.is_power_of_two() {
+                return Err(BadAlignment);
+            }
+        }
+        Ok(out)
+    }
+
+    /// The program headers, in file order.
+    /// 32 bit headers are widened to the 64 bit layout.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeaderSegment64> + '_ {
+        (0..self.header.num_program_entries as usize).map(move |i| {
+            if let Some(seg) = self.program_header32(i) {
+                return seg.into();
+            }
+            let off = self.header.program_header_pos + i * PROGRAM_HEADER_SIZE64;
+            // parse checked the whole table is in the file
+            let ent = &self.source[off..off + PROGRAM_HEADER_SIZE64];
+            let u32_at = |o: usize| u32::from_le_bytes([ent[o], ent[o + 1], ent[o + 2], ent[o + 3]]);
+            let u64_at = |o: usize| u32_at(o) as u64 | (u32_at(o + 4) as u64) << 32;
+            ProgramHeaderSegment64 {
+                seg_type: ProgramSegmentType::from(u32_at(0)),
+                flags: u32_at(4),
+                file_offset: u64_at(8),
+                vmem_addr: u64_at(16),
+                unused: u64_at(24),
+                size_in_file: u64_at(32),
+                size_in_memory: u64_at(40),
+                alignment: u64_at(48),
+            }
+        })
+    }
+
+    /// Program header `i` of a 32 bit file, as it is laid out there.
+    /// `None` for a 64 bit file.
+    pub fn program_header32(&self, i: usize) -> Option<ProgramHeaderSegment32> {
+        if self.header.width != AddrWidth::Word || i >= self.header.num_program_entries as usize {
+            return None;
+        }
+        let off = self.header.program_header_pos + i * PROGRAM_HEADER_SIZE32;
+        let ent = &self.source[off..off + PROGRAM_HEADER_SIZE32];
+        let u32_at = |o: usize| u32::from_le_bytes([ent[o], ent[o + 1], ent[o + 2], ent[o + 3]]);
+        Some(ProgramHeaderSegment32 {
+            seg_type: ProgramSegmentType::from(u32_at(0)),
+            file_offset: u32_at(4),
+            vmem_addr: u32_at(8),
+            unused: u32_at(12),
+            size_in_file: u32_at(16),
+            size_in_memory: u32_at(20),
+            flags: u32_at(24),
+            alignment: u32_at(28),
+        })
+    }
+
+    /// Where the program headers are once the program is loaded, as
+    /// linked. From the PT_PHDR segment if there is one, otherwise
+    /// from the load segment that covers the table, if any.
+    pub fn program_headers_vaddr(&self) -> Option<u64> {
+        if let Some(seg) = self.program_headers()
+            .find(|seg| seg.seg_type == ProgramSegmentType::ProgramTable) {
+            return Some(seg.vmem_addr);
+        }
+        let pos = self.header.program_header_pos as u64;
+        let size = self.header.num_program_entries as u64 * self.header.program_entry_size as u64;
+        self.program_headers()
+            .filter(|seg| seg.seg_type == ProgramSegmentType::Load)
+            .find(|seg| pos >= seg.file_offset
+                  && pos + size <= seg.file_offset + seg.size_in_file)
+            .map(|seg| seg.vmem_addr + (pos - seg.file_offset))
+    }
+
+    // File range of the `len` bytes the program is linked to load at
+    // `va`, if they come from the file.
+    fn vaddr_range(&self, va: u64, len: u64) -> Option<Range<usize>> {
+        self.program_headers()
+            .filter(|seg| seg.seg_type == ProgramSegmentType::Load)
+            .find(|seg| va >= seg.vmem_addr
+                  && va - seg.vmem_addr <= seg.size_in_file
+                  && len <= seg.size_in_file - (va - seg.vmem_addr))
+            .and_then(|seg| file_range(seg.file_offset + (va - seg.vmem_addr), len,
+                                       self.source.len()))
+    }
+
+    /// The RELA relocations the dynamic section asks for, in order.
+    /// Empty for a program without one. Static position independent
+    /// executables are left with only relative relocations here.
+    pub fn relocations(&self) -> Result<Vec<Rela64>, ELFError> {
+        use ELFError::*;
+        if self.header.width != AddrWidth::DoubleWord {
+            return Err(UnsupportedWidth);
+        }
+        let dynamic = match self.program_headers()
+            .find(|seg| seg.seg_type == ProgramSegmentType::Dynamic) {
+                Some(seg) => seg,
+                None => return Ok(Vec::new()),
+            };
+        let table = file_range(dynamic.file_offset, dynamic.size_in_file, self.source.len())
+            .ok_or(BadDynamic)?;
+        let table = &self.source[table];
+
+        let (mut rela, mut relasz, mut relaent) = (None, 0, RELA_SIZE64 as u64);
+        for i in 0..table.len() / DYN_SIZE64 {
+            let tag = read_u64(table, i * DYN_SIZE64, BadDynamic)? as i64;
+            let val = read_u64(table, i * DYN_SIZE64 + 8, BadDynamic)?;
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(val),
+                DT_RELASZ => relasz = val,
+                DT_RELAENT => relaent = val,
+                DT_REL | DT_RELR => return Err(UnsupportedRelocation),
+                _ => {},
+            }
+        }
+        let rela = match rela {
+            Some(va) => va,
+            None => return Ok(Vec::new()),
+        };
+        if relaent != RELA_SIZE64 as u64 || relasz % relaent != 0 {
+            return Err(BadDynamic);
+        }
+        let range = self.vaddr_range(rela, relasz).ok_or(BadDynamic)?;
+        self.source[range].chunks_exact(RELA_SIZE64)
+            .map(|ent| {
+                let info = read_u64(ent, 8, BadDynamic)?;
+                Ok(Rela64 {
+                    offset: read_u64(ent, 0, BadDynamic)?,
+                    kind: info as u32,
+                    symbol: (info >> 32) as u32,
+                    addend: read_u64(ent, 16, BadDynamic)? as i64,
+                })
+            })
+            .collect()
+    }
+
+    // Offset and number of section headers, after checking the table
+    // is in the file. Huge tables keep their count in section 0.
+    fn section_table(&self) -> Result<(usize, usize), ELFError> {
+        use ELFError::*;
+        let pos = self.header.section_header_pos;
+        if pos == 0 {
+            return Ok((0, 0));
+        }
+        if self.header.width != AddrWidth::DoubleWord {
+            return Err(UnsupportedWidth);
+        }
+        if self.header.section_entry_size as usize != SECTION_HEADER_SIZE64 {
+            return Err(BadSectionHeaderSize);
+        }
+        let mut count = self.header.num_section_entries as u64;
+        if count == 0 {
+            count = read_u64(self.source, pos.wrapping_add(32), SectionHeadersOutOfBounds)?;
+        }
+        let size = count.checked_mul(SECTION_HEADER_SIZE64 as u64)
+            .ok_or(SectionHeadersOutOfBounds)?;
+        file_range(pos as u64, size, self.source.len()).ok_or(SectionHeadersOutOfBounds)?;
+        Ok((pos, count as usize))
+    }
+
+    /// The section headers, in file order. The first is always the
+    /// null section.
+    pub fn section_headers(&self) -> Result<impl Iterator<Item = SectionHeader64> + 'a, ELFError> {
+        let (pos, count) = self.section_table()?;
+        let source = self.source;
+        Ok((0..count).map(move |i| {
+            let off = pos + i * SECTION_HEADER_SIZE64;
+            // section_table checked the whole table is in the file
+            let ent = &source[off..off + SECTION_HEADER_SIZE64];
+            let u32_at = |o: usize| u32::from_le_bytes([ent[o], ent[o + 1], ent[o + 2], ent[o + 3]]);
+            let u64_at = |o: usize| u32_at(o) as u64 | (u32_at(o + 4) as u64) << 32;
+            SectionHeader64 {
+                name: u32_at(0),
+                sec_type: SectionType::from(u32_at(4)),
+                flags: u64_at(8),
+                vmem_addr: u64_at(16),
+                file_offset: u64_at(24),
+                size: u64_at(32),
+                link: u32_at(40),
+                info: u32_at(44),
+                alignment: u64_at(48),
+                entry_size: u64_at(56),
+            }
+        }))
+    }
+
+    /// The section at `index`.
+    pub fn section(&self, index: usize) -> Result<SectionHeader64, ELFError> {
+        self.section_headers()?.nth(index).ok_or(ELFError::SectionOutOfBounds)
+    }
+
+    /// What a section holds in the file. Empty for sections that
+    /// take no space there, like .bss.
+    pub fn section_data(&self, section: &SectionHeader64) -> Result<&'a [u8], ELFError> {
+        if section.sec_type == SectionType::NoBits {
+            return Ok(&[]);
+        }
+        match file_range(section.file_offset, section.size, self.source.len()) {
+            Some(range) => Ok(&self.source[range]),
+            None => Err(ELFError::SectionOutOfBounds),
+        }
+    }
+
+    /// The section name string table, if there is one.
+    pub fn section_names(&self) -> Result<Option<&'a [u8]>, ELFError> {
+        let index = match self.header.section_name_index {
+            // too big for the header, so it's in the first section's link
+            SHN_XINDEX => self.section(0)?.link as usize,
+            index => index as usize,
         };
-        match out.header.instruction_set as u8 { // riscv only
-            0 | 2 | 3 | 8 |
-            0x14 | 0x28 | 0x2A | 0x32 |
-            0x3E | 0xB7 => {unsupported!()},
-            0xF3 => {},
-            _ => {ill_formed!()},
+        if index == SHN_UNDEF as usize {
+            return Ok(None);
+        }
+        let (_, count) = self.section_table()?;
+        if index >= count {
+            return Err(ELFError::SectionOutOfBounds);
+        }
+        let names = self.section(index)?;
+        if names.sec_type != SectionType::StrTab {
+            return Err(ELFError::BadStringTable);
+        }
+        self.section_data(&names).map(Some)
+    }
+
+    /// The name of a section, like ".text".
+    pub fn section_name(&self, section: &SectionHeader64) -> Result<&'a str, ELFError> {
+        let names = self.section_names()?.ok_or(ELFError::BadStringTable)?;
+        table_str(names, section.name as usize).ok_or(ELFError::BadStringTable)
+    }
+
+    /// The first section called `name`, if any.
+    pub fn section_by_name(&self, name: &str) -> Result<Option<SectionHeader64>, ELFError> {
+        let names = match self.section_names()? {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+        Ok(self.section_headers()?
+           .find(|sec| table_str(names, sec.name as usize) == Some(name)))
+    }
+
+    // The first symbol table section of `kind`, and the string table
+    // it links to.
+    fn symbol_table(&self, kind: SectionType) -> Result<Option<Symbols<'a>>, ELFError> {
+        use ELFError::*;
+        let table = match self.section_headers()?.find(|sec| sec.sec_type == kind) {
+            Some(table) => table,
+            None => return Ok(None),
         };
-        // todo? Test header_version and version. header flags maybe?
-        out
+        if table.entry_size != SYMBOL_SIZE64 as u64 || table.size % table.entry_size != 0 {
+            return Err(BadSymbolTable);
+        }
+        let strtab = self.section(table.link as usize).map_err(|_| BadStringTable)?;
+        if strtab.sec_type != SectionType::StrTab {
+            return Err(BadStringTable);
+        }
+        Ok(Some(Symbols {
+            table: self.section_data(&table)?,
+            strtab: self.section_data(&strtab)?,
+            next: 0,
+        }))
+    }
+
+    /// The symbols in `.symtab` (names from `.strtab`), if the program
+    /// hasn't been stripped.
+    pub fn symbols(&self) -> Result<Option<Symbols<'a>>, ELFError> {
+        self.symbol_table(SectionType::SymTab)
+    }
+
+    /// The symbols in `.dynsym`, if any.
+    pub fn dynamic_symbols(&self) -> Result<Option<Symbols<'a>>, ELFError> {
+        self.symbol_table(SectionType::DynSym)
+    }
+
+    /// The function or object containing `addr`, and how far into it
+    /// `addr` is, like `("main", 0x10)`. For backtraces.
+    pub fn symbolize(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.symbols().ok()??
+            .filter(|sym| matches!(sym.kind(), SYM_FUNC | SYM_OBJECT)
+                    && sym.section_index != SHN_UNDEF
+                    && addr >= sym.value
+                    && addr - sym.value < max(sym.size, 1))
+            .map(|sym| (sym.name, addr - sym.value))
+            .next()
+    }
+
+    /// The file contents of a load segment from `program_headers`.
+    pub fn segment_data(&self, segment: &ProgramHeaderSegment64) -> &'a [u8] {
+        match file_range(segment.file_offset, segment.size_in_file, self.source.len()) {
+            Some(range) => &self.source[range],
+            None => &[],
+        }
+    }
+}
+
+// -------------------------------------------------------------------
+
+// Little endian writes for building test files.
+fn put(buf: &mut [u8], off: usize, bytes: &[u8]) {
+    buf[off..off + bytes.len()].copy_from_slice(bytes);
+}
+
+// A small 64 bit executable: one load segment covering the start of
+// the file, and sections .shstrtab, .symtab and .strtab with a single
+// function `main` at 0x10040.
+fn test_file64() -> Vec<u8> {
+    let mut f = alloc::vec![0; 464];
+    put(&mut f, 0, &ELF_MAGIC);
+    put(&mut f, 4, &[2, 1, 1]);
+    put(&mut f, 16, &2u16.to_le_bytes());
+    put(&mut f, 18, &0xf3u16.to_le_bytes());
+    put(&mut f, 20, &1u32.to_le_bytes());
+    put(&mut f, 24, &0x10040u64.to_le_bytes());
+    put(&mut f, 32, &64u64.to_le_bytes());      // phoff
+    put(&mut f, 40, &208u64.to_le_bytes());     // shoff
+    for (off, val) in [(52, 64u16), (54, 56), (56, 1), (58, 64), (60, 4), (62, 1)] {
+        put(&mut f, off, &val.to_le_bytes());
+    }
+
+    put(&mut f, 64, &1u32.to_le_bytes());
+    put(&mut f, 68, &5u32.to_le_bytes());
+    for (off, val) in [(80, 0x10000u64), (88, 0x10000), (96, 0x100), (104, 0x100), (112, 0x1000)] {
+        put(&mut f, off, &val.to_le_bytes());
+    }
+
+    put(&mut f, 120, b"\0.shstrtab\0.symtab\0.strtab\0");
+    put(&mut f, 148, b"\0main\0");
+    put(&mut f, 184, &1u32.to_le_bytes());
+    put(&mut f, 188, &[(SYM_GLOBAL << 4) | SYM_FUNC, 0]);
+    put(&mut f, 190, &1u16.to_le_bytes());
+    put(&mut f, 192, &0x10040u64.to_le_bytes());
+    put(&mut f, 200, &0x20u64.to_le_bytes());
+
+    // name, type, offset, size, link, entry size
+    let sections = [(1, 3, 120u64, 27u64, 0, 0u64), (11, 2, 160, 48, 3, 24), (19, 3, 148, 6, 0, 0)];
+    for (i, (name, kind, off, size, link, entsize)) in sections.into_iter().enumerate() {
+        let sh = 208 + (i + 1) * SECTION_HEADER_SIZE64;
+        put(&mut f, sh, &(name as u32).to_le_bytes());
+        put(&mut f, sh + 4, &(kind as u32).to_le_bytes());
+        put(&mut f, sh + 24, &off.to_le_bytes());
+        put(&mut f, sh + 32, &size.to_le_bytes());
+        put(&mut f, sh + 40, &(link as u32).to_le_bytes());
+        put(&mut f, sh + 56, &entsize.to_le_bytes());
+    }
+    f
+}
+
+// The same program as a 32 bit file, without sections.
+fn test_file32() -> Vec<u8> {
+    let mut f = alloc::vec![0; 128];
+    put(&mut f, 0, &ELF_MAGIC);
+    put(&mut f, 4, &[1, 1, 1]);
+    put(&mut f, 16, &2u16.to_le_bytes());
+    put(&mut f, 18, &0xf3u16.to_le_bytes());
+    put(&mut f, 20, &1u32.to_le_bytes());
+    put(&mut f, 24, &0x10040u32.to_le_bytes());
+    put(&mut f, 28, &52u32.to_le_bytes());      // phoff
+    for (off, val) in [(40, 52u16), (42, 32), (44, 1)] {
+        put(&mut f, off, &val.to_le_bytes());
+    }
+    for (off, val) in [(52, 1u32), (60, 0x10000), (64, 0x10000), (68, 0x80), (72, 0x80), (76, 5), (80, 0x1000)] {
+        put(&mut f, off, &val.to_le_bytes());
+    }
+    f
+}
+
+/// Checks the parser takes a good file apart, and turns away broken
+/// ones before anything reads outside of them.
+pub fn test_elf() {
+    use ELFError::*;
+    let good = test_file64();
+    let prog = ELFProgram::new64(&good).unwrap();
+    let segments: Vec<_> = prog.program_headers().collect();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].seg_type, ProgramSegmentType::Load);
+    assert_eq!(prog.program_headers_vaddr(), Some(0x10040));
+    assert_eq!(prog.section_headers().unwrap().count(), 4);
+    let symtab = prog.section_by_name(".symtab").unwrap().unwrap();
+    assert_eq!(symtab.sec_type, SectionType::SymTab);
+    assert_eq!(prog.section_name(&prog.section(3).unwrap()).unwrap(), ".strtab");
+    let syms: Vec<_> = prog.symbols().unwrap().unwrap().collect();
+    assert_eq!(syms.len(), 2);
+    assert!(syms[1].name == "main" && syms[1].bind() == SYM_GLOBAL && syms[1].kind() == SYM_FUNC);
+    assert_eq!(prog.symbolize(0x10050), Some(("main", 0x10)));
+    assert_eq!(prog.symbolize(0x10060), None);
+    assert!(prog.dynamic_symbols().unwrap().is_none());
+
+    // truncated anywhere in the header, or in the program headers
+    for len in 0..HEADER_SIZE64 {
+        assert!(matches!(ELFProgram::new64(&good[..len]), Err(Truncated)));
+    }
+    assert!(matches!(ELFProgram::new64(&good[..100]), Err(ProgramHeadersOutOfBounds)));
+
+    let with = |off: usize, bytes: &[u8]| {
+        let mut f = good.clone();
+        put(&mut f, off, bytes);
+        f
+    };
+    // phoff and shoff so large that adding the table size overflows
+    let f = with(32, &(u64::MAX - 8).to_le_bytes());
+    assert!(matches!(ELFProgram::new64(&f), Err(ProgramHeadersOutOfBounds)));
+    let f = with(56, &u16::MAX.to_le_bytes());
+    assert!(matches!(ELFProgram::new64(&f), Err(ProgramHeadersOutOfBounds)));
+    let f = with(40, &(u64::MAX - 8).to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(matches!(prog.section_headers(), Err(SectionHeadersOutOfBounds)));
+    assert!(matches!(prog.symbols(), Err(SectionHeadersOutOfBounds)));
+    assert_eq!(prog.symbolize(0x10050), None);
+    // the count comes from section 0 when e_shnum is 0
+    let mut f = with(60, &0u16.to_le_bytes());
+    put(&mut f, 40, &(u64::MAX - 8).to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(matches!(prog.section_headers(), Err(SectionHeadersOutOfBounds)));
+    let f = with(60, &0u16.to_le_bytes());
+    assert!(ELFProgram::new64(&f).unwrap().section_headers().unwrap().next().is_none());
+
+    // shstrndx past the table, at something that isn't a string
+    // table, or not there at all
+    let f = with(62, &9u16.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(matches!(prog.section_names(), Err(SectionOutOfBounds)));
+    assert!(matches!(prog.section_by_name(".symtab"), Err(SectionOutOfBounds)));
+    assert_eq!(prog.symbolize(0x10050), Some(("main", 0x10)));
+    let f = with(62, &2u16.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(matches!(prog.section_name(&prog.section(1).unwrap()), Err(BadStringTable)));
+    let f = with(62, &0u16.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(prog.section_names().unwrap().is_none());
+    assert!(prog.section_by_name(".symtab").unwrap().is_none());
+    // SHN_XINDEX has the index in section 0's link, all 32 bits of it
+    let mut f = with(62, &SHN_XINDEX.to_le_bytes());
+    put(&mut f, 208 + 40, &1u32.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert_eq!(prog.section_name(&prog.section(3).unwrap()).unwrap(), ".strtab");
+    put(&mut f, 208 + 40, &0x10001u32.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert!(matches!(prog.section_names(), Err(SectionOutOfBounds)));
+
+    // a symbol table with the wrong entry size, linked to something
+    // that isn't a string table, or naming past the end of one
+    let symtab = 208 + 2 * SECTION_HEADER_SIZE64;
+    let f = with(symtab + 56, &16u64.to_le_bytes());
+    assert!(matches!(ELFProgram::new64(&f).unwrap().symbols(), Err(BadSymbolTable)));
+    let f = with(symtab + 40, &0u32.to_le_bytes());
+    assert!(matches!(ELFProgram::new64(&f).unwrap().symbols(), Err(BadStringTable)));
+    let f = with(184, &100u32.to_le_bytes());
+    let prog = ELFProgram::new64(&f).unwrap();
+    assert_eq!(prog.symbols().unwrap().unwrap().nth(1).unwrap().name, "");
+    let f = with(symtab + 32, &0x1008u64.to_le_bytes());
+    assert!(matches!(ELFProgram::new64(&f).unwrap().symbols(), Err(SectionOutOfBounds)));
+
+    let good = test_file32();
+    let prog = ELFProgram::new(&good).unwrap();
+    assert_eq!(prog.header.width, AddrWidth::Word);
+    assert_eq!(prog.header.entry, 0x10040);
+    let seg = prog.program_headers().next().unwrap();
+    assert!(seg.seg_type == ProgramSegmentType::Load && seg.size_in_file == 0x80 && seg.flags == 5);
+    assert!(prog.program_header32(1).is_none());
+    assert_eq!(prog.section_headers().unwrap().count(), 0);
+    assert!(matches!(ELFProgram::new64(&good), Err(UnsupportedWidth)));
+    assert!(matches!(ELFProgram::new32(&test_file64()), Err(UnsupportedWidth)));
+    for len in 0..HEADER_SIZE32 {
+        assert!(matches!(ELFProgram::new32(&good[..len]), Err(Truncated)));
     }
+    assert!(matches!(ELFProgram::new32(&good[..60]), Err(ProgramHeadersOutOfBounds)));
+    let mut f = good.clone();
+    put(&mut f, 28, &(u32::MAX - 8).to_le_bytes());
+    assert!(matches!(ELFProgram::new32(&f), Err(ProgramHeadersOutOfBounds)));
+    let mut f = good.clone();
+    put(&mut f, 68, &0x1000u32.to_le_bytes());
+    assert!(matches!(ELFProgram::new32(&f), Err(SegmentOutOfBounds)));
+    let mut f = good.clone();
+    put(&mut f, 32, &64u32.to_le_bytes());
+    assert!(matches!(ELFProgram::new32(&f).unwrap().section_headers(), Err(UnsupportedWidth)));
 
+    log!(Debug, "Successful test of ELF parsing...");
 }
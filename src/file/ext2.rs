@@ -0,0 +1,399 @@
+//! Read only ext2 filesystem driver.
+//!
+//! Enough to read images made on the host with `mke2fs -t ext2 -d`:
+//! the superblock, block group descriptors, inodes with direct and
+//! (single, double, triple) indirect blocks, and linear directories.
+//! Hashed directories are still valid linear directories, so those
+//! work too. Extents and the other ext4 incompatible features don't.
+//!
+//! Only regular files and directories are visible; symlinks, device
+//! nodes and the like are skipped.
+//!
+//! Reference: <https://www.nongnu.org/ext2-doc/ext2.html>
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::file::block::{BlockDevice, SECTOR_SIZE};
+use crate::file::vfs::*;
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const SUPERBLOCK_OFFSET: usize = 1024;
+const ROOT_INO: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+// Anything else incompatible is beyond us.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+
+const GROUP_DESC_SIZE: usize = 32;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+
+const EXT4_EXTENTS_FL: u32 = 0x0008_0000;
+
+const N_DIRECT: usize = 12;
+const IND_BLOCK: usize = 12;
+const DIND_BLOCK: usize = 13;
+const TIND_BLOCK: usize = 14;
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+/// The parts of an on disk inode we use.
+struct RawInode {
+    mode: u16,
+    size: u64,
+    links: u16,
+    flags: u32,
+    block: [u32; 15],
+}
+
+impl RawInode {
+    fn ftype(&self) -> Option<FileType> {
+        match self.mode & S_IFMT {
+            S_IFREG => Some(FileType::Regular),
+            S_IFDIR => Some(FileType::Directory),
+            _ => None,
+        }
+    }
+}
+
+struct Volume {
+    dev: Arc<dyn BlockDevice>,
+    block_size: usize,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    inode_tables: Vec<u32>,     // first block of each group's inode table
+    filetype: bool,             // directory entries carry a type byte
+}
+
+impl Volume {
+    fn read_block(&self, bno: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let per = (self.block_size / SECTOR_SIZE) as u64;
+        self.dev.read_sectors(bno as u64 * per, &mut buf[..self.block_size])
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<RawInode, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupt);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as usize;
+        let byte = index * self.inode_size;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt)?;
+
+        let mut buf = alloc::vec![0_u8; self.block_size];
+        self.read_block(table + (byte / self.block_size) as u32, &mut buf)?;
+        let raw = &buf[byte % self.block_size..];
+        let mut block = [0; 15];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = le32(raw, 40 + 4 * i);
+        }
+        let mode = le16(raw, 0);
+        // i_dir_acl doubles as the high half of the size for files
+        let high = if mode & S_IFMT == S_IFREG { le32(raw, 108) as u64 } else { 0 };
+        Ok(RawInode {
+            mode,
+            size: le32(raw, 4) as u64 | high << 32,
+            links: le16(raw, 26),
+            flags: le32(raw, 32),
+            block,
+        })
+    }
+}
+
+/// Maps file blocks to disk blocks, remembering the last indirect
+/// block read at each level so sequential reads don't re-read them.
+struct BlockMap<'a> {
+    vol: &'a Volume,
+    inode: &'a RawInode,
+    cache: [(u32, Vec<u8>); 3],
+}
+
+impl<'a> BlockMap<'a> {
+    fn new(vol: &'a Volume, inode: &'a RawInode) -> Self {
+        Self {
+            vol,
+            inode,
+            cache: [(0, Vec::new()), (0, Vec::new()), (0, Vec::new())],
+        }
+    }
+
+    /// Entry `idx` of indirect block `table`, at indirection `level`.
+    fn entry(&mut self, level: usize, table: u32, idx: usize) -> Result<u32, FsError> {
+        if table == 0 {
+            return Ok(0);
+        }
+        let (cached, buf) = &mut self.cache[level];
+        if *cached != table || buf.is_empty() {
+            buf.resize(self.vol.block_size, 0);
+            self.vol.read_block(table, buf)?;
+            *cached = table;
+        }
+        Ok(le32(buf, 4 * idx))
+    }
+
+    /// Disk block for file block `bn`, zero for a hole.
+    fn map(&mut self, bn: u64) -> Result<u32, FsError> {
+        let per = (self.vol.block_size / 4) as u64;
+        let blocks = self.inode.block;
+        if bn < N_DIRECT as u64 {
+            return Ok(blocks[bn as usize]);
+        }
+        let bn = bn - N_DIRECT as u64;
+        if bn < per {
+            return self.entry(0, blocks[IND_BLOCK], bn as usize);
+        }
+        let bn = bn - per;
+        if bn < per * per {
+            let ind = self.entry(1, blocks[DIND_BLOCK], (bn / per) as usize)?;
+            return self.entry(0, ind, (bn % per) as usize);
+        }
+        let bn = bn - per * per;
+        if bn < per * per * per {
+            let dind = self.entry(2, blocks[TIND_BLOCK], (bn / (per * per)) as usize)?;
+            let ind = self.entry(1, dind, (bn / per % per) as usize)?;
+            return self.entry(0, ind, (bn % per) as usize);
+        }
+        Err(FsError::Corrupt)
+    }
+}
+
+struct Ext2Node {
+    vol: Arc<Volume>,
+    ino: u32,
+}
+
+impl Ext2Node {
+    fn inode(&self) -> Result<RawInode, FsError> {
+        let inode = self.vol.read_inode(self.ino)?;
+        if inode.flags & EXT4_EXTENTS_FL != 0 {
+            return Err(FsError::Unsupported);
+        }
+        Ok(inode)
+    }
+
+    fn read(&self, inode: &RawInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let n = min(buf.len() as u64, inode.size - offset) as usize;
+        let bs = self.vol.block_size;
+        let mut map = BlockMap::new(&self.vol, inode);
+        let mut block = alloc::vec![0_u8; bs];
+        let mut done = 0;
+        while done < n {
+            let pos = offset + done as u64;
+            let start = (pos % bs as u64) as usize;
+            let len = min(n - done, bs - start);
+            let dst = &mut buf[done..done + len];
+            match map.map(pos / bs as u64)? {
+                0 => dst.fill(0),
+                b => {
+                    self.vol.read_block(b, &mut block)?;
+                    dst.copy_from_slice(&block[start..start + len]);
+                },
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    /// Every live entry in this directory, as (name, inode, type byte).
+    /// Entries never cross a block, so this reads a block at a time,
+    /// however big the inode says the directory is.
+    fn entries(&self) -> Result<Vec<(String, u32, u8)>, FsError> {
+        let inode = self.inode()?;
+        if inode.ftype() != Some(FileType::Directory) {
+            return Err(FsError::NotDirectory);
+        }
+        let bs = self.vol.block_size;
+        let mut raw = alloc::vec![0_u8; bs];
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < inode.size {
+            let len = self.read(&inode, pos, &mut raw)?;
+            let mut off = 0;
+            while off + 8 <= len {
+                let ino = le32(&raw, off);
+                let rec_len = le16(&raw, off + 4) as usize;
+                let name_len = if self.vol.filetype {
+                    raw[off + 6] as usize
+                } else {
+                    le16(&raw, off + 6) as usize
+                };
+                if rec_len < 8 || off + rec_len > len || 8 + name_len > rec_len {
+                    return Err(FsError::Corrupt);
+                }
+                if ino != 0 {
+                    let name = &raw[off + 8..off + 8 + name_len];
+                    let kind = if self.vol.filetype { raw[off + 7] } else { 0 };
+                    out.push((String::from_utf8_lossy(name).into_owned(), ino, kind));
+                }
+                off += rec_len;
+            }
+            pos += bs as u64;
+        }
+        Ok(out)
+    }
+}
+
+impl Inode for Ext2Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let inode = self.inode()?;
+        Ok(Stat {
+            ino: self.ino as u64,
+            ftype: inode.ftype().ok_or(FsError::Unsupported)?,
+            size: inode.size,
+            nlink: inode.links as u32,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode()?;
+        match inode.ftype() {
+            Some(FileType::Regular) => self.read(&inode, offset, buf),
+            Some(FileType::Directory) => Err(FsError::IsDirectory),
+            None => Err(FsError::Unsupported),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let (_, ino, _) = self.entries()?
+            .into_iter()
+            .find(|(n, _, _)| n == name)
+            .ok_or(FsError::NotFound)?;
+        let node = Ext2Node {
+            vol: self.vol.clone(),
+            ino,
+        };
+        if node.inode()?.ftype().is_none() {
+            return Err(FsError::Unsupported);
+        }
+        Ok(Arc::new(node))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut out = Vec::new();
+        for (name, ino, kind) in self.entries()? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            // Without the filetype feature we have to ask the inode.
+            let ftype = match kind {
+                1 => Some(FileType::Regular),
+                2 => Some(FileType::Directory),
+                0 => self.vol.read_inode(ino)?.ftype(),
+                _ => None,
+            };
+            if let Some(ftype) = ftype {
+                out.push(DirEntry { name, ino: ino as u64, ftype });
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// A mounted (read only) ext2 volume.
+pub struct Ext2 {
+    vol: Arc<Volume>,
+}
+
+impl Ext2 {
+    /// Check for an ext2 superblock on `dev` and read the group
+    /// descriptors.
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut sb = [0_u8; 1024];
+        dev.read_sectors((SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64, &mut sb)?;
+        if le16(&sb, 56) != EXT2_MAGIC {
+            return Err(FsError::Unsupported);
+        }
+        let inodes_count = le32(&sb, 0);
+        let blocks_count = le32(&sb, 4);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let rev_level = le32(&sb, 76);
+        let (inode_size, incompat) = if rev_level == 0 {
+            (128, 0)
+        } else {
+            (le16(&sb, 88) as usize, le32(&sb, 96))
+        };
+
+        if incompat & INCOMPAT_RECOVER != 0 {
+            log!(Warning, "ext2: volume needs journal recovery");
+            return Err(FsError::Unsupported);
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            log!(Warning, "ext2: unsupported incompatible features {:#x}", incompat);
+            return Err(FsError::Unsupported);
+        }
+        if log_block_size > 6 || blocks_count <= first_data_block || blocks_per_group == 0 || inodes_per_group == 0
+            || inode_size < 128 || !inode_size.is_power_of_two() {
+            return Err(FsError::Corrupt);
+        }
+        let block_size = 1024 << log_block_size;
+        if inode_size > block_size {
+            return Err(FsError::Corrupt);
+        }
+        // a volume bigger than its device would have us read past the
+        // end, and allocate a descriptor table to match
+        let per = (block_size / SECTOR_SIZE) as u64;
+        if dev.sectors().is_some_and(|sectors| blocks_count as u64 * per > sectors) {
+            return Err(FsError::Corrupt);
+        }
+
+        // Group descriptors start in the block after the superblock.
+        let groups = blocks_count.checked_sub(first_data_block)
+            .ok_or(FsError::Corrupt)?
+            .div_ceil(blocks_per_group) as usize;
+        let gdt_bytes = groups * GROUP_DESC_SIZE;
+        let gdt_blocks = gdt_bytes.div_ceil(block_size);
+        let mut gdt = alloc::vec![0_u8; gdt_blocks * block_size];
+        dev.read_sectors((first_data_block as u64 + 1) * per, &mut gdt)?;
+        let inode_tables = (0..groups)
+            .map(|g| le32(&gdt, g * GROUP_DESC_SIZE + 8))
+            .collect();
+
+        log!(Debug, "ext2 volume: {} blocks of {} bytes, {} groups",
+             blocks_count, block_size, groups);
+        let vol = Volume {
+            dev,
+            block_size,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        };
+        if vol.read_inode(ROOT_INO)?.ftype() != Some(FileType::Directory) {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Self { vol: Arc::new(vol) })
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2Node {
+            vol: self.vol.clone(),
+            ino: ROOT_INO,
+        })
+    }
+}
@@ -0,0 +1,1107 @@
+//! FAT32 filesystem driver.
+//!
+//! Supports reading and writing files and directories, including VFAT
+//! long file names. Images made with `mkfs.fat -F 32 -S 512` on the
+//! host work out of the box.
+//!
+//! Everything is write-through and serialized by a single per volume
+//! lock, so the on-disk image is always as current as the last call
+//! that returned. We do not keep FSInfo's free cluster count up to
+//! date, we mark it unknown the first time we allocate or free.
+//!
+//! References:
+//! <https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf>
+//! (Microsoft's fatgen103) and <https://wiki.osdev.org/FAT>
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::char::decode_utf16;
+use core::cmp::min;
+
+use crate::file::block::{BlockDevice, SECTOR_SIZE};
+use crate::file::vfs::*;
+use crate::lock::mutex::Mutex;
+
+const FAT_MASK: u32 = 0x0FFF_FFFF; // top 4 bits are reserved
+const FAT_EOC: u32 = 0x0FFF_FFF8; // anything >= is end of chain
+const FAT_EOC_MARK: u32 = 0x0FFF_FFFF;
+
+const DIRENT_SIZE: usize = 32;
+const DIRENTS_PER_SECTOR: usize = SECTOR_SIZE / DIRENT_SIZE;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const DIRENT_END: u8 = 0x00; // this and everything after is free
+const DIRENT_FREE: u8 = 0xE5;
+const DIRENT_KANJI: u8 = 0x05; // stands in for a leading 0xE5
+
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+// Offsets of the three name pieces inside a long name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// NTRes bits used by Windows and Linux for all lower case 8.3 names.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+// We don't have a clock, so everything was made 1980-01-01 00:00.
+const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn put16(b: &mut [u8], off: usize, val: u16) {
+    b[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put32(b: &mut [u8], off: usize, val: u32) {
+    b[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+/// The parts of the BIOS parameter block we care about, plus some
+/// values derived from it.
+struct Bpb {
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    num_fats: u32,
+    fat_size: u32,              // in sectors
+    root_cluster: u32,
+    fsinfo_sector: u32,
+    first_data_sector: u32,
+    num_clusters: u32,
+    active_fat: Option<u32>,    // None if the FATs are mirrored
+}
+
+impl Bpb {
+    fn parse(boot: &[u8]) -> Result<Self, FsError> {
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(FsError::Unsupported);
+        }
+        let bytes_per_sector = le16(boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = le16(boot, 14) as u32;
+        let num_fats = boot[16] as u32;
+        let root_entries = le16(boot, 17);
+        let total_16 = le16(boot, 19) as u32;
+        let fat_size_16 = le16(boot, 22);
+        let total_32 = le32(boot, 32);
+        let fat_size = le32(boot, 36);
+        let ext_flags = le16(boot, 40);
+        let root_cluster = le32(boot, 44);
+        let fsinfo_sector = le16(boot, 48) as u32;
+
+        // FAT12/16 have a fixed root directory and a 16 bit FAT size
+        if root_entries != 0 || fat_size_16 != 0 || fat_size == 0 {
+            return Err(FsError::Unsupported);
+        }
+        if bytes_per_sector != SECTOR_SIZE {
+            log!(Warning, "FAT32 with {} byte sectors is unsupported", bytes_per_sector);
+            return Err(FsError::Unsupported);
+        }
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0 || reserved_sectors == 0 {
+            return Err(FsError::Corrupt);
+        }
+
+        let total = if total_16 != 0 { total_16 } else { total_32 };
+        let first_data_sector = reserved_sectors + num_fats * fat_size;
+        if total <= first_data_sector {
+            return Err(FsError::Corrupt);
+        }
+        // The FAT itself may be too small to describe every cluster
+        let num_clusters = min(
+            (total - first_data_sector) / sectors_per_cluster,
+            fat_size * (SECTOR_SIZE as u32 / 4) - 2,
+        );
+        if root_cluster < 2 || root_cluster >= num_clusters + 2 {
+            return Err(FsError::Corrupt);
+        }
+        let active_fat = if ext_flags & 0x80 != 0 {
+            Some((ext_flags & 0xF) as u32)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_size,
+            root_cluster,
+            fsinfo_sector,
+            first_data_sector,
+            num_clusters,
+            active_fat,
+        })
+    }
+}
+
+/// Physical location of a 32 byte directory entry. Entries never move
+/// once written, so this identifies a file for as long as it exists.
+/// After that the slot can be reused, see `Volume::slots`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryLoc {
+    sector: u64,
+    index: usize,
+}
+
+impl EntryLoc {
+    fn ino(&self) -> u64 {
+        self.sector * DIRENTS_PER_SECTOR as u64 + self.index as u64
+    }
+}
+
+/// A parsed directory entry along with everything needed to remove it.
+struct Found {
+    name: String,
+    short: [u8; 11],
+    attr: u8,
+    loc: EntryLoc,
+    lfn_locs: Vec<EntryLoc>,
+}
+
+impl Found {
+    fn ftype(&self) -> FileType {
+        if self.attr & ATTR_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+/// Mutable state of a volume. Holding the lock on this is what gives
+/// us permission to touch the disk.
+struct FatState {
+    // single sector cache of the (first or active) FAT
+    fat_sector: u64,
+    fat_buf: [u8; SECTOR_SIZE],
+    next_free: u32,             // allocation hint
+    fsinfo_stale: bool,
+}
+
+/// The directory slots some `FatNode` refers to.
+struct Slot {
+    generation: u64, // times its file has been unlinked since
+    nodes: usize,    // how many refer to it
+}
+
+struct Volume {
+    dev: Arc<dyn BlockDevice>,
+    bpb: Bpb,
+    state: Mutex<FatState>,
+    // so nodes for a file that's gone don't see the next one put in
+    // its place. Slots nobody refers to aren't kept.
+    slots: Mutex<BTreeMap<EntryLoc, Slot>>,
+}
+
+impl Volume {
+    fn generation(&self, loc: EntryLoc) -> u64 {
+        self.slots.lock().get(&loc).map_or(0, |slot| slot.generation)
+    }
+
+    /// A node for the file whose short entry is at `loc`, or the root.
+    fn node(self: &Arc<Self>, loc: Option<EntryLoc>) -> FatNode {
+        let generation = loc.map_or(0, |loc| {
+            let mut slots = self.slots.lock();
+            let slot = slots.entry(loc).or_insert(Slot { generation: 0, nodes: 0 });
+            slot.nodes += 1;
+            slot.generation
+        });
+        FatNode {
+            vol: self.clone(),
+            loc,
+            generation,
+        }
+    }
+
+    fn read(&self, sector: u64) -> Result<[u8; SECTOR_SIZE], FsError> {
+        let mut buf = [0_u8; SECTOR_SIZE];
+        self.dev.read_sector(sector, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        (self.bpb.sectors_per_cluster as usize * SECTOR_SIZE) as u64
+    }
+
+    fn valid_cluster(&self, c: u32) -> bool {
+        c >= 2 && c < self.bpb.num_clusters + 2
+    }
+
+    fn cluster_sector(&self, c: u32) -> u64 {
+        self.bpb.first_data_sector as u64
+            + (c as u64 - 2) * self.bpb.sectors_per_cluster as u64
+    }
+
+    fn fat_start(&self, copy: u32) -> u64 {
+        (self.bpb.reserved_sectors + copy * self.bpb.fat_size) as u64
+    }
+
+    fn load_fat_sector(&self, st: &mut FatState, sector: u64) -> Result<(), FsError> {
+        if st.fat_sector != sector {
+            self.dev.read_sector(sector, &mut st.fat_buf)?;
+            st.fat_sector = sector;
+        }
+        Ok(())
+    }
+
+    fn fat_get(&self, st: &mut FatState, c: u32) -> Result<u32, FsError> {
+        let byte = c as u64 * 4;
+        let copy = self.bpb.active_fat.unwrap_or(0);
+        let sector = self.fat_start(copy) + byte / SECTOR_SIZE as u64;
+        self.load_fat_sector(st, sector)?;
+        Ok(le32(&st.fat_buf, (byte % SECTOR_SIZE as u64) as usize) & FAT_MASK)
+    }
+
+    fn fat_set(&self, st: &mut FatState, c: u32, val: u32) -> Result<(), FsError> {
+        let byte = c as u64 * 4;
+        let offset = (byte % SECTOR_SIZE as u64) as usize;
+        let copy = self.bpb.active_fat.unwrap_or(0);
+        let sector = self.fat_start(copy) + byte / SECTOR_SIZE as u64;
+        self.load_fat_sector(st, sector)?;
+        let old = le32(&st.fat_buf, offset);
+        put32(&mut st.fat_buf, offset, (old & !FAT_MASK) | (val & FAT_MASK));
+
+        match self.bpb.active_fat {
+            Some(_) => self.dev.write_sector(sector, &st.fat_buf)?,
+            None => {
+                for i in 0..self.bpb.num_fats {
+                    let mirror = self.fat_start(i) + byte / SECTOR_SIZE as u64;
+                    self.dev.write_sector(mirror, &st.fat_buf)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Mark the FSInfo free count as unknown so that nobody trusts it
+    /// after we start changing the FAT.
+    fn invalidate_fsinfo(&self, st: &mut FatState) -> Result<(), FsError> {
+        if st.fsinfo_stale {
+            return Ok(());
+        }
+        st.fsinfo_stale = true;
+        let sector = self.bpb.fsinfo_sector as u64;
+        if sector == 0 || sector == 0xFFFF {
+            return Ok(());
+        }
+        let mut buf = self.read(sector)?;
+        if le32(&buf, 0) != 0x4161_5252 || le32(&buf, 484) != 0x6141_7272 {
+            return Ok(());
+        }
+        put32(&mut buf, 488, 0xFFFF_FFFF);
+        self.dev.write_sector(sector, &buf)
+    }
+
+    /// Every cluster in the chain starting at `first`.
+    fn chain(&self, st: &mut FatState, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut out = Vec::new();
+        let mut c = first;
+        if c == 0 {
+            return Ok(out);
+        }
+        loop {
+            if !self.valid_cluster(c) || out.len() > self.bpb.num_clusters as usize {
+                return Err(FsError::Corrupt);
+            }
+            out.push(c);
+            c = self.fat_get(st, c)?;
+            if c >= FAT_EOC {
+                return Ok(out);
+            }
+        }
+    }
+
+    /// Find a free cluster, zero it, and mark it as the end of a
+    /// chain. Linking it to anything is up to the caller.
+    fn alloc_cluster(&self, st: &mut FatState) -> Result<u32, FsError> {
+        self.invalidate_fsinfo(st)?;
+        let n = self.bpb.num_clusters;
+        for i in 0..n {
+            let c = 2 + (st.next_free - 2 + i) % n;
+            if self.fat_get(st, c)? == 0 {
+                self.fat_set(st, c, FAT_EOC_MARK)?;
+                st.next_free = c;
+                let zero = [0_u8; SECTOR_SIZE];
+                let base = self.cluster_sector(c);
+                for s in 0..self.bpb.sectors_per_cluster as u64 {
+                    self.dev.write_sector(base + s, &zero)?;
+                }
+                return Ok(c);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, st: &mut FatState, first: u32) -> Result<(), FsError> {
+        self.invalidate_fsinfo(st)?;
+        for c in self.chain(st, first)? {
+            self.fat_set(st, c, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Grow `chain` to `len` clusters, returning the (possibly new)
+    /// first cluster.
+    fn extend_chain(&self, st: &mut FatState, chain: &mut Vec<u32>, len: usize)
+                    -> Result<u32, FsError> {
+        while chain.len() < len {
+            let c = self.alloc_cluster(st)?;
+            if let Some(&last) = chain.last() {
+                self.fat_set(st, last, c)?;
+            }
+            chain.push(c);
+        }
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    fn chain_bytes_read(&self, chain: &[u32], pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let cb = self.cluster_bytes();
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let c = *chain.get((at / cb) as usize).ok_or(FsError::Corrupt)?;
+            let sector = self.cluster_sector(c) + (at % cb) / SECTOR_SIZE as u64;
+            let off = (at % SECTOR_SIZE as u64) as usize;
+            let len = min(SECTOR_SIZE - off, buf.len() - done);
+            let data = self.read(sector)?;
+            buf[done..done + len].copy_from_slice(&data[off..off + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `pos` in the chain. `data` of `None` writes
+    /// `len` zeros instead.
+    fn chain_bytes_write(&self, chain: &[u32], pos: u64, data: Option<&[u8]>, len: usize)
+                         -> Result<(), FsError> {
+        let cb = self.cluster_bytes();
+        let mut done = 0;
+        while done < len {
+            let at = pos + done as u64;
+            let c = *chain.get((at / cb) as usize).ok_or(FsError::Corrupt)?;
+            let sector = self.cluster_sector(c) + (at % cb) / SECTOR_SIZE as u64;
+            let off = (at % SECTOR_SIZE as u64) as usize;
+            let n = min(SECTOR_SIZE - off, len - done);
+            let mut buf = if n == SECTOR_SIZE {
+                [0_u8; SECTOR_SIZE]
+            } else {
+                self.read(sector)?
+            };
+            match data {
+                Some(d) => buf[off..off + n].copy_from_slice(&d[done..done + n]),
+                None => buf[off..off + n].fill(0),
+            }
+            self.dev.write_sector(sector, &buf)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, loc: EntryLoc) -> Result<[u8; DIRENT_SIZE], FsError> {
+        let buf = self.read(loc.sector)?;
+        let mut out = [0_u8; DIRENT_SIZE];
+        out.copy_from_slice(&buf[loc.index * DIRENT_SIZE..(loc.index + 1) * DIRENT_SIZE]);
+        Ok(out)
+    }
+
+    fn write_entry(&self, loc: EntryLoc, ent: &[u8; DIRENT_SIZE]) -> Result<(), FsError> {
+        let mut buf = self.read(loc.sector)?;
+        buf[loc.index * DIRENT_SIZE..(loc.index + 1) * DIRENT_SIZE].copy_from_slice(ent);
+        self.dev.write_sector(loc.sector, &buf)
+    }
+
+    /// Every raw 32 byte slot of a directory, in order, along with its
+    /// location. Stops at the end of the cluster chain, not at the end
+    /// marker.
+    fn raw_entries(&self, st: &mut FatState, dir: u32)
+                   -> Result<Vec<(EntryLoc, [u8; DIRENT_SIZE])>, FsError> {
+        let mut out = Vec::new();
+        for c in self.chain(st, dir)? {
+            let base = self.cluster_sector(c);
+            for s in 0..self.bpb.sectors_per_cluster as u64 {
+                let buf = self.read(base + s)?;
+                for index in 0..DIRENTS_PER_SECTOR {
+                    let mut ent = [0_u8; DIRENT_SIZE];
+                    ent.copy_from_slice(&buf[index * DIRENT_SIZE..(index + 1) * DIRENT_SIZE]);
+                    out.push((EntryLoc { sector: base + s, index }, ent));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Parse a directory into its live entries, putting long names
+    /// back together where they are present and intact.
+    fn list(&self, st: &mut FatState, dir: u32) -> Result<Vec<Found>, FsError> {
+        let mut out = Vec::new();
+        let mut lfn: Vec<u16> = Vec::new();
+        let mut lfn_locs: Vec<EntryLoc> = Vec::new();
+        let mut lfn_sum: Option<u8> = None;
+        let mut lfn_next = 0_u8;
+
+        for (loc, ent) in self.raw_entries(st, dir)? {
+            match ent[0] {
+                DIRENT_END => break,
+                DIRENT_FREE => {
+                    lfn_sum = None;
+                    continue;
+                },
+                _ => {},
+            }
+            if ent[11] & 0x3F == ATTR_LFN {
+                let ord = ent[0] & !LFN_LAST;
+                if ent[0] & LFN_LAST != 0 {
+                    // start of a new name, pieces come last to first
+                    lfn = alloc::vec![0xFFFF; ord as usize * LFN_CHARS];
+                    lfn_locs.clear();
+                    lfn_sum = Some(ent[13]);
+                } else if lfn_sum != Some(ent[13]) || ord != lfn_next {
+                    lfn_sum = None;
+                }
+                if lfn_sum.is_some() && ord >= 1 {
+                    let base = (ord as usize - 1) * LFN_CHARS;
+                    for (i, off) in LFN_OFFSETS.iter().enumerate() {
+                        lfn[base + i] = le16(&ent, *off);
+                    }
+                    lfn_locs.push(loc);
+                    lfn_next = ord - 1;
+                } else {
+                    lfn_sum = None;
+                }
+                continue;
+            }
+            if ent[11] & ATTR_VOLUME_ID != 0 {
+                lfn_sum = None;
+                continue;
+            }
+
+            let mut short = [0_u8; 11];
+            short.copy_from_slice(&ent[0..11]);
+            let long = match lfn_sum {
+                Some(sum) if lfn_next == 0 && sum == short_checksum(&short) => {
+                    let end = lfn.iter().position(|&u| u == 0 || u == 0xFFFF)
+                        .unwrap_or(lfn.len());
+                    Some(decode_utf16(lfn[..end].iter().copied())
+                         .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
+                         .collect::<String>())
+                },
+                _ => None,
+            };
+            let locs = if long.is_some() {
+                core::mem::take(&mut lfn_locs)
+            } else {
+                Vec::new()
+            };
+            lfn_sum = None;
+
+            out.push(Found {
+                name: long.unwrap_or_else(|| short_display(&short, ent[12])),
+                short,
+                attr: ent[11],
+                loc,
+                lfn_locs: locs,
+            });
+        }
+        Ok(out)
+    }
+
+    fn find(&self, st: &mut FatState, dir: u32, name: &str) -> Result<Found, FsError> {
+        self.list(st, dir)?
+            .into_iter()
+            .find(|f| !f.is_dot() && names_match(&f.name, name))
+            .ok_or(FsError::NotFound)
+    }
+}
+
+fn names_match(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn short_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0_u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// Turn a space padded 8.3 name back into something readable.
+fn short_display(short: &[u8; 11], ntres: u8) -> String {
+    let mut out = String::new();
+    for (i, &b) in short[0..8].iter().enumerate() {
+        let b = if i == 0 && b == DIRENT_KANJI { DIRENT_FREE } else { b };
+        if b == b' ' {
+            break;
+        }
+        out.push(if ntres & NTRES_LOWER_BASE != 0 {
+            b.to_ascii_lowercase() as char
+        } else {
+            b as char
+        });
+    }
+    if short[8] != b' ' {
+        out.push('.');
+        for &b in short[8..11].iter().take_while(|&&b| b != b' ') {
+            out.push(if ntres & NTRES_LOWER_EXT != 0 {
+                b.to_ascii_lowercase() as char
+            } else {
+                b as char
+            });
+        }
+    }
+    out
+}
+
+fn valid_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_lowercase() || c.is_ascii_digit()
+        || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// All upper case -> Some(0), all lower case -> Some(flag), mixed -> None
+fn case_flag(part: &str, flag: u8) -> Option<u8> {
+    let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+    let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+    match (upper, lower) {
+        (true, true) => None,
+        (false, true) => Some(flag),
+        _ => Some(0),
+    }
+}
+
+fn pad_short(base: &[u8], ext: &[u8]) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base);
+    short[8..8 + ext.len()].copy_from_slice(ext);
+    short
+}
+
+/// Pick the 8.3 name for `name`. Returns the short name, its NTRes
+/// case bits, and whether long name entries are needed as well.
+fn make_short_name(name: &str, taken: &[[u8; 11]]) -> ([u8; 11], u8, bool) {
+    // Does it fit as is?
+    let (base, ext) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(i) => (&name[..i], &name[i + 1..]),
+    };
+    if (1..=8).contains(&base.len()) && ext.len() <= 3
+        && base.bytes().chain(ext.bytes()).all(valid_short_char) {
+        if let (Some(bf), Some(ef)) = (case_flag(base, NTRES_LOWER_BASE),
+                                       case_flag(ext, NTRES_LOWER_EXT)) {
+            let short = pad_short(&base.to_ascii_uppercase().into_bytes(),
+                                  &ext.to_ascii_uppercase().into_bytes());
+            if !taken.contains(&short) {
+                return (short, bf | ef, false);
+            }
+        }
+    }
+
+    // Otherwise, a numeric tail basis name
+    let clean = |s: &str, n: usize| -> Vec<u8> {
+        s.bytes()
+            .filter(|&c| c != b' ' && c != b'.')
+            .map(|c| if valid_short_char(c) { c.to_ascii_uppercase() } else { b'_' })
+            .take(n)
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (clean(&trimmed[..i], 8), clean(&trimmed[i + 1..], 3)),
+        None => (clean(trimmed, 8), Vec::new()),
+    };
+    let base = if base.is_empty() { Vec::from(&b"_"[..]) } else { base };
+    let mut n = 1_u32;
+    loop {
+        let tail = alloc::format!("~{}", n);
+        let keep = min(base.len(), 8 - tail.len());
+        let mut b = Vec::from(&base[..keep]);
+        b.extend_from_slice(tail.as_bytes());
+        let short = pad_short(&b, &ext);
+        if !taken.contains(&short) {
+            return (short, 0, true);
+        }
+        n += 1;
+    }
+}
+
+fn valid_long_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn new_short_entry(short: &[u8; 11], attr: u8, ntres: u8, cluster: u32) -> [u8; DIRENT_SIZE] {
+    let mut ent = [0_u8; DIRENT_SIZE];
+    ent[0..11].copy_from_slice(short);
+    ent[11] = attr;
+    ent[12] = ntres;
+    put16(&mut ent, 16, FAT_EPOCH_DATE); // created
+    put16(&mut ent, 18, FAT_EPOCH_DATE); // accessed
+    put16(&mut ent, 24, FAT_EPOCH_DATE); // written
+    put16(&mut ent, 20, (cluster >> 16) as u16);
+    put16(&mut ent, 26, cluster as u16);
+    ent
+}
+
+fn entry_cluster(ent: &[u8]) -> u32 {
+    ((le16(ent, 20) as u32) << 16) | le16(ent, 26) as u32
+}
+
+/// A file or directory on a FAT32 volume. The root directory has no
+/// directory entry of its own.
+struct FatNode {
+    vol: Arc<Volume>,
+    loc: Option<EntryLoc>,
+    generation: u64,            // of `loc` when we found the file there
+}
+
+impl Drop for FatNode {
+    fn drop(&mut self) {
+        let Some(loc) = self.loc else {
+            return;
+        };
+        if let Entry::Occupied(mut slot) = self.vol.slots.lock().entry(loc) {
+            slot.get_mut().nodes -= 1;
+            if slot.get().nodes == 0 {
+                slot.remove();
+            }
+        }
+    }
+}
+
+impl FatNode {
+    /// (first cluster, size, attributes) as currently on disk. `_st`
+    /// is the volume lock, so a rename or unlink can't be halfway done.
+    fn info(&self, _st: &FatState) -> Result<(u32, u32, u8), FsError> {
+        match self.loc {
+            None => Ok((self.vol.bpb.root_cluster, 0, ATTR_DIRECTORY)),
+            Some(loc) => {
+                let ent = self.vol.read_entry(loc)?;
+                if ent[0] == DIRENT_FREE || ent[0] == DIRENT_END
+                    || self.vol.generation(loc) != self.generation {
+                    // removed out from under us
+                    return Err(FsError::NotFound);
+                }
+                Ok((entry_cluster(&ent), le32(&ent, 28), ent[11]))
+            },
+        }
+    }
+
+    fn set_info(&self, cluster: u32, size: u32) -> Result<(), FsError> {
+        let loc = self.loc.expect("Tried to resize the FAT root directory");
+        let mut ent = self.vol.read_entry(loc)?;
+        put16(&mut ent, 20, (cluster >> 16) as u16);
+        put16(&mut ent, 26, cluster as u16);
+        put32(&mut ent, 28, size);
+        self.vol.write_entry(loc, &ent)
+    }
+
+    fn dir_cluster(&self, st: &FatState) -> Result<u32, FsError> {
+        let (cluster, _, attr) = self.info(st)?;
+        if attr & ATTR_DIRECTORY == 0 {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(cluster)
+    }
+
+    fn file_info(&self, st: &FatState) -> Result<(u32, u32), FsError> {
+        let (cluster, size, attr) = self.info(st)?;
+        if attr & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        Ok((cluster, size))
+    }
+
+    /// Resize to `new_size`, making sure that everything between the
+    /// old end of file and `zero_to` reads back as zeros. Caller holds
+    /// the volume lock. Returns the new cluster chain.
+    fn resize(&self, st: &mut FatState, new_size: u64, zero_to: u64)
+              -> Result<Vec<u32>, FsError> {
+        if new_size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let (first, size) = self.file_info(st)?;
+        let vol = &self.vol;
+        let cb = vol.cluster_bytes();
+        let needed = new_size.div_ceil(cb) as usize;
+        let mut chain = vol.chain(st, first)?;
+        let allocated = chain.len() as u64 * cb;
+
+        if needed < chain.len() {
+            if needed == 0 {
+                vol.free_chain(st, first)?;
+                chain.clear();
+            } else {
+                vol.fat_set(st, chain[needed - 1], FAT_EOC_MARK)?;
+                vol.free_chain(st, chain[needed])?;
+                chain.truncate(needed);
+            }
+        } else {
+            vol.extend_chain(st, &mut chain, needed)?;
+        }
+        // Fresh clusters are zeroed on allocation, but the tail of the
+        // old last cluster may be stale.
+        let zero_end = min(min(zero_to, new_size), allocated);
+        if zero_end > size as u64 {
+            vol.chain_bytes_write(&chain, size as u64, None, (zero_end - size as u64) as usize)?;
+        }
+        self.set_info(chain.first().copied().unwrap_or(0), new_size as u32)?;
+        Ok(chain)
+    }
+}
+
+impl Inode for FatNode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let (_, size, attr) = self.info(&self.vol.state.lock())?;
+        let dir = attr & ATTR_DIRECTORY != 0;
+        Ok(Stat {
+            ino: self.loc.map(|l| l.ino()).unwrap_or(1),
+            ftype: if dir { FileType::Directory } else { FileType::Regular },
+            size: size as u64,
+            nlink: 1,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut st = self.vol.state.lock();
+        let (first, size) = self.file_info(&st)?;
+        if offset >= size as u64 {
+            return Ok(0);
+        }
+        let n = min(buf.len() as u64, size as u64 - offset) as usize;
+        let chain = self.vol.chain(&mut st, first)?;
+        self.vol.chain_bytes_read(&chain, offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut st = self.vol.state.lock();
+        let (first, size) = self.file_info(&st)?;
+        let end = offset + buf.len() as u64;
+        let chain = if end > size as u64 {
+            self.resize(&mut st, end, offset)?
+        } else {
+            self.vol.chain(&mut st, first)?
+        };
+        self.vol.chain_bytes_write(&chain, offset, Some(buf), buf.len())?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut st = self.vol.state.lock();
+        self.resize(&mut st, size, size).map(|_| ())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut st = self.vol.state.lock();
+        let dir = self.dir_cluster(&st)?;
+        let found = self.vol.find(&mut st, dir, name)?;
+        Ok(Arc::new(self.vol.node(Some(found.loc))))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut st = self.vol.state.lock();
+        let dir = self.dir_cluster(&st)?;
+        Ok(self.vol.list(&mut st, dir)?
+           .into_iter()
+           .filter(|f| !f.is_dot())
+           .map(|f| DirEntry {
+               ino: f.loc.ino(),
+               ftype: f.ftype(),
+               name: f.name,
+           })
+           .collect())
+    }
+
+    fn create(&self, name: &str, ftype: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        valid_long_name(name)?;
+        let vol = &self.vol;
+        let mut st = vol.state.lock();
+        let dir = self.dir_cluster(&st)?;
+        let existing = vol.list(&mut st, dir)?;
+        if existing.iter().any(|f| !f.is_dot() && names_match(&f.name, name)) {
+            return Err(FsError::Exists);
+        }
+        let taken: Vec<[u8; 11]> = existing.iter().map(|f| f.short).collect();
+        let (short, ntres, needs_lfn) = make_short_name(name, &taken);
+        let utf16: Vec<u16> = name.encode_utf16().collect();
+        let num_lfn = if needs_lfn { utf16.len().div_ceil(LFN_CHARS) } else { 0 };
+        let wanted = num_lfn + 1;
+
+        // find (or make) a run of free slots
+        let slots = loop {
+            let raw = vol.raw_entries(&mut st, dir)?;
+            let mut run = 0;
+            let mut found = None;
+            for (i, (_, ent)) in raw.iter().enumerate() {
+                if ent[0] == DIRENT_FREE || ent[0] == DIRENT_END {
+                    run += 1;
+                    if run == wanted {
+                        found = Some(i + 1 - wanted);
+                        break;
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+            match found {
+                Some(start) => {
+                    break raw[start..start + wanted].iter().map(|(l, _)| *l).collect::<Vec<_>>();
+                },
+                None => {
+                    let mut chain = vol.chain(&mut st, dir)?;
+                    let len = chain.len() + 1;
+                    vol.extend_chain(&mut st, &mut chain, len)?;
+                },
+            }
+        };
+
+        let (attr, cluster) = match ftype {
+            FileType::Regular => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let c = vol.alloc_cluster(&mut st)?;
+                let parent = if dir == vol.bpb.root_cluster { 0 } else { dir };
+                let base = vol.cluster_sector(c);
+                let dot = new_short_entry(b".          ", ATTR_DIRECTORY, 0, c);
+                let dotdot = new_short_entry(b"..         ", ATTR_DIRECTORY, 0, parent);
+                vol.write_entry(EntryLoc { sector: base, index: 0 }, &dot)?;
+                vol.write_entry(EntryLoc { sector: base, index: 1 }, &dotdot)?;
+                (ATTR_DIRECTORY, c)
+            },
+        };
+
+        // long name pieces go in last to first, then the short entry
+        let sum = short_checksum(&short);
+        for (i, loc) in slots[..num_lfn].iter().enumerate() {
+            let ord = num_lfn - i;
+            let mut ent = [0_u8; DIRENT_SIZE];
+            ent[0] = ord as u8 | if i == 0 { LFN_LAST } else { 0 };
+            ent[11] = ATTR_LFN;
+            ent[13] = sum;
+            for (j, off) in LFN_OFFSETS.iter().enumerate() {
+                let idx = (ord - 1) * LFN_CHARS + j;
+                let unit = match idx.cmp(&utf16.len()) {
+                    core::cmp::Ordering::Less => utf16[idx],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                put16(&mut ent, *off, unit);
+            }
+            vol.write_entry(*loc, &ent)?;
+        }
+        let loc = slots[num_lfn];
+        vol.write_entry(loc, &new_short_entry(&short, attr, ntres, cluster))?;
+
+        Ok(Arc::new(vol.node(Some(loc))))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let vol = &self.vol;
+        let mut st = vol.state.lock();
+        let dir = self.dir_cluster(&st)?;
+        let found = vol.find(&mut st, dir, name)?;
+        let ent = vol.read_entry(found.loc)?;
+        let cluster = entry_cluster(&ent);
+        if found.ftype() == FileType::Directory
+            && vol.list(&mut st, cluster)?.iter().any(|f| !f.is_dot()) {
+            return Err(FsError::NotEmpty);
+        }
+        for loc in found.lfn_locs.iter().chain(core::iter::once(&found.loc)) {
+            let mut ent = vol.read_entry(*loc)?;
+            ent[0] = DIRENT_FREE;
+            vol.write_entry(*loc, &ent)?;
+        }
+        if let Some(slot) = vol.slots.lock().get_mut(&found.loc) {
+            slot.generation += 1;
+        }
+        if cluster != 0 {
+            vol.free_chain(&mut st, cluster)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mounted FAT32 volume.
+pub struct Fat32 {
+    vol: Arc<Volume>,
+}
+
+impl Fat32 {
+    /// Check for a FAT32 boot sector on `dev` and set up the volume.
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut boot = [0_u8; SECTOR_SIZE];
+        dev.read_sector(0, &mut boot)?;
+        let bpb = Bpb::parse(&boot)?;
+        log!(Debug, "FAT32 volume: {} clusters of {} bytes",
+             bpb.num_clusters, bpb.sectors_per_cluster as usize * SECTOR_SIZE);
+        let vol = Volume {
+            dev,
+            bpb,
+            state: Mutex::new(FatState {
+                fat_sector: u64::MAX,
+                fat_buf: [0; SECTOR_SIZE],
+                next_free: 2,
+                fsinfo_stale: false,
+            }),
+            slots: Mutex::new(BTreeMap::new()),
+        };
+        Ok(Self { vol: Arc::new(vol) })
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(self.vol.node(None))
+    }
+}
+
+// -------------------------------------------------------------------
+
+// Sectors held in memory, for testing without a disk.
+struct RamDisk(Mutex<Vec<u8>>);
+
+impl BlockDevice for RamDisk {
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let off = sector as usize * SECTOR_SIZE;
+        buf.copy_from_slice(self.0.lock().get(off..off + SECTOR_SIZE).ok_or(FsError::Io)?);
+        Ok(())
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), FsError> {
+        let off = sector as usize * SECTOR_SIZE;
+        self.0.lock().get_mut(off..off + SECTOR_SIZE).ok_or(FsError::Io)?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sectors(&self) -> Option<u64> {
+        Some((self.0.lock().len() / SECTOR_SIZE) as u64)
+    }
+}
+
+// A blank volume of `sectors` sectors, with one sector clusters and
+// an empty root directory in cluster 2.
+fn test_volume(sectors: u32) -> Fat32 {
+    let (reserved, fat_size) = (32, 8);
+    let mut img = alloc::vec![0_u8; sectors as usize * SECTOR_SIZE];
+    put16(&mut img, 11, SECTOR_SIZE as u16);
+    img[13] = 1;
+    put16(&mut img, 14, reserved as u16);
+    img[16] = 2;
+    put32(&mut img, 32, sectors);
+    put32(&mut img, 36, fat_size);
+    put32(&mut img, 44, 2);
+    img[510] = 0x55;
+    img[511] = 0xAA;
+    for copy in 0..2 {
+        let fat = (reserved + copy * fat_size) as usize * SECTOR_SIZE;
+        put32(&mut img, fat, 0x0FFF_FFF8);
+        put32(&mut img, fat + 4, FAT_EOC_MARK);
+        put32(&mut img, fat + 8, FAT_EOC_MARK);
+    }
+    Fat32::new(Arc::new(RamDisk(Mutex::new(img)))).unwrap()
+}
+
+/// Checks the 8.3 names picked for new files, and that long names
+/// come back the same after going through a directory.
+pub fn test_names() {
+    let none: &[[u8; 11]] = &[];
+    assert_eq!(make_short_name("README.TXT", none), (*b"README  TXT", 0, false));
+    assert_eq!(make_short_name("readme.txt", none),
+               (*b"README  TXT", NTRES_LOWER_BASE | NTRES_LOWER_EXT, false));
+    assert_eq!(make_short_name("Makefile", none), (*b"MAKEFI~1   ", 0, true));
+    assert_eq!(make_short_name("file.html", none), (*b"FILE~1  HTM", 0, true));
+    assert_eq!(make_short_name("A long file name.html", none), (*b"ALONGF~1HTM", 0, true));
+    assert_eq!(make_short_name(".bashrc", none), (*b"BASHRC~1   ", 0, true));
+    assert_eq!(make_short_name("a+b.c", none), (*b"A_B~1   C  ", 0, true));
+    assert_eq!(make_short_name("é.txt", none), (*b"__~1    TXT", 0, true));
+    assert_eq!(make_short_name("...", none), (*b"_~1        ", 0, true));
+    for name in ["README.TXT", "readme.txt", "README.txt", "x", "NOEXT"] {
+        let (short, ntres, lfn) = make_short_name(name, none);
+        assert!(!lfn && short_display(&short, ntres) == name);
+    }
+    // the tail grows into the base once it is two digits
+    let mut taken = alloc::vec![*b"README  TXT"];
+    assert_eq!(make_short_name("readme.txt", &taken), (*b"README~1TXT", 0, true));
+    for n in 1..10 {
+        taken.push(pad_short(alloc::format!("ALONGF~{}", n).as_bytes(), b"HTM"));
+    }
+    assert_eq!(make_short_name("A long file name.html", &taken), (*b"ALONG~10HTM", 0, true));
+    assert_eq!(short_checksum(b"README  TXT"), 0x73);
+
+    let fs = test_volume(1024);
+    let root = fs.root();
+    let alphabet = "abcdefghijklmnopqrstuvwxyz";   // two whole long name entries
+    let longest = "n".repeat(NAME_MAX);             // more than a cluster of them
+    let names = ["README.TXT", "readme2.txt", "Makefile", "A long file name.html",
+                 "A long file name.htm", "héllo wörld.txt", alphabet, &longest];
+    for name in names {
+        root.create(name, FileType::Regular).unwrap();
+    }
+    assert_eq!(root.create("readme.txt", FileType::Regular).err(), Some(FsError::Exists));
+    assert_eq!(root.create(&"n".repeat(NAME_MAX + 1), FileType::Regular).err(),
+               Some(FsError::NameTooLong));
+    assert!(root.lookup("a LONG file NAME.HTML").is_ok());
+    let listed: Vec<String> = root.readdir().unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(listed, names);
+
+    let vol = &fs.vol;
+    let found = vol.list(&mut vol.state.lock(), vol.bpb.root_cluster).unwrap();
+    let shorts: Vec<[u8; 11]> = found.iter().map(|f| f.short).collect();
+    assert_eq!(shorts[..5], [*b"README  TXT", *b"README2 TXT", *b"MAKEFI~1   ",
+                             *b"ALONGF~1HTM", *b"ALONGF~2HTM"]);
+    assert_eq!(found.iter().map(|f| f.lfn_locs.len()).collect::<Vec<_>>(),
+               [0, 0, 1, 2, 2, 2, 2, 20]);
+
+    // a long name whose checksum no longer matches is left out
+    let mut ent = vol.read_entry(found[2].lfn_locs[0]).unwrap();
+    ent[13] ^= 1;
+    vol.write_entry(found[2].lfn_locs[0], &ent).unwrap();
+    assert_eq!(root.readdir().unwrap()[2].name, "MAKEFI~1");
+
+    // unlinking frees the long name entries too, and the name can
+    // be had again
+    root.unlink("A long file name.html").unwrap();
+    assert!(root.readdir().unwrap().iter().all(|e| e.name != "A long file name.html"));
+    root.create("A long file name.html", FileType::Regular).unwrap();
+    let found = vol.list(&mut vol.state.lock(), vol.bpb.root_cluster).unwrap();
+    assert!(found[3].name == "A long file name.html" && found[3].short == *b"ALONGF~1HTM");
+
+    // a node for a file that's gone doesn't see the one in its place,
+    // and slots are only kept while nodes refer to them
+    let old = root.create("x.txt", FileType::Regular).unwrap();
+    root.unlink("x.txt").unwrap();
+    let new = root.create("x.txt", FileType::Regular).unwrap();
+    assert_eq!(old.stat().err(), Some(FsError::NotFound));
+    assert!(new.stat().is_ok());
+    drop((old, new));
+    assert!(vol.slots.lock().is_empty());
+
+    log!(Debug, "Successful test of FAT names...");
+}
@@ -0,0 +1,248 @@
+//! Initial ram filesystem.
+//!
+//! An initramfs is a cpio archive in the "newc" format, the same thing
+//! `find . | cpio -o -H newc` writes. We unpack it into a fresh tmpfs
+//! mounted at `/` before anything else, so the first process can come
+//! from there instead of being linked into the kernel.
+//!
+//! The archive comes from one of two places: QEMU's `-initrd`, which
+//! we find through the `/chosen` node of the device tree, or the
+//! `initramfs.cpio` at the top of the repo, linked into the kernel
+//! when it is built with the `initramfs` feature.
+//!
+//! Only directories and regular files are unpacked. Everything else
+//! (symlinks, device nodes) is skipped with a warning.
+
+use alloc::string::String;
+use core::str;
+
+use crate::file::vfs::{self, FileType, FsError};
+use crate::hw::fdt;
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[cfg(feature = "initramfs")]
+static LINKED: &[u8] = include_bytes!("../../initramfs.cpio");
+#[cfg(not(feature = "initramfs"))]
+static LINKED: &[u8] = &[];
+
+/// One member of the archive.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// Walks the members of a newc archive, stopping at the trailer.
+pub struct Archive<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+// Header fields are eight ASCII hex digits each, after the magic.
+fn field(header: &[u8], index: usize) -> Result<u32, FsError> {
+    let start = MAGIC.len() + index * 8;
+    let digits = str::from_utf8(&header[start..start + 8])
+        .map_err(|_| FsError::Corrupt)?;
+    u32::from_str_radix(digits, 16).map_err(|_| FsError::Corrupt)
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0, done: false }
+    }
+
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, FsError> {
+        let header = self.buf.get(self.pos..self.pos + HEADER_SIZE)
+            .ok_or(FsError::Corrupt)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(FsError::Corrupt);
+        }
+        let mode = field(header, 1)?;
+        let filesize = field(header, 6)? as usize;
+        let namesize = field(header, 11)? as usize;
+
+        // The name includes its NUL, and the header plus name is
+        // padded to four bytes. So is the data.
+        let name_start = self.pos + HEADER_SIZE;
+        let name = self.buf.get(name_start..name_start + namesize)
+            .ok_or(FsError::Corrupt)?;
+        let name = name.strip_suffix(&[0]).ok_or(FsError::Corrupt)?;
+        let name = str::from_utf8(name).map_err(|_| FsError::Corrupt)?;
+        let data_start = align4(name_start + namesize);
+        let data = self.buf.get(data_start..data_start + filesize)
+            .ok_or(FsError::Corrupt)?;
+        self.pos = align4(data_start + filesize);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// The archive we booted with, if any. One from the bootloader wins
+/// over one linked into the kernel.
+pub fn find() -> Option<&'static [u8]> {
+    if let Some((start, end)) = fdt::get().and_then(|f| f.initrd()) {
+        // Safety: the bootloader put it there, and vm keeps the
+        // memory out of the page pool until we are done with it.
+        return Some(unsafe { core::slice::from_raw_parts(start as *const u8, end - start) });
+    }
+    if LINKED.is_empty() { None } else { Some(LINKED) }
+}
+
+// Create `path` as a directory unless it already is one.
+fn mkdir(path: &str) -> Result<(), FsError> {
+    match vfs::create(path, FileType::Directory) {
+        Err(FsError::Exists) => match vfs::lookup(path)?.stat()?.ftype {
+            FileType::Directory => Ok(()),
+            FileType::Regular => Err(FsError::NotDirectory),
+        },
+        res => res.map(|_| ()),
+    }
+}
+
+/// Unpack `archive` into the filesystem under `/`. Parent directories
+/// are created as needed, whatever order the archive lists them in.
+pub fn unpack(archive: &[u8]) -> Result<usize, FsError> {
+    let mut count = 0;
+    for entry in Archive::new(archive) {
+        let entry = entry?;
+        let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        let mut path = String::new();
+        let mut parts = name.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(part) = parts.next() {
+            path.push('/');
+            path.push_str(part);
+            if parts.peek().is_some() {
+                mkdir(&path)?;
+            }
+        }
+        match entry.mode & S_IFMT {
+            S_IFDIR => mkdir(&path)?,
+            S_IFREG => {
+                let node = match vfs::create(&path, FileType::Regular) {
+                    Err(FsError::Exists) => {
+                        let node = vfs::lookup(&path)?;
+                        node.truncate(0)?;
+                        node
+                    },
+                    res => res?,
+                };
+                let mut done = 0;
+                while done < entry.data.len() {
+                    done += node.write_at(done as u64, &entry.data[done..])?;
+                }
+            },
+            other => {
+                log!(Warning, "initramfs: skipping {} (mode {:o})", path, other);
+                continue;
+            },
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+// -------------------------------------------------------------------
+
+// Append a member to a newc archive, padded the way cpio does it.
+fn test_member(out: &mut alloc::vec::Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    out.extend_from_slice(MAGIC);
+    for field in fields {
+        out.extend_from_slice(alloc::format!("{:08x}", field).as_bytes());
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.resize(align4(out.len()), 0);
+    out.extend_from_slice(data);
+    out.resize(align4(out.len()), 0);
+}
+
+/// Checks a good archive is walked member by member, and that a
+/// broken one ends the walk with `Corrupt` instead of reading past it.
+pub fn test_cpio() {
+    let mut good = alloc::vec::Vec::new();
+    test_member(&mut good, ".", S_IFDIR | 0o755, &[]);
+    test_member(&mut good, "bin", S_IFDIR | 0o755, &[]);
+    test_member(&mut good, "bin/init", S_IFREG | 0o755, b"hello");
+    test_member(&mut good, "empty", S_IFREG | 0o644, &[]);
+    let trailer = good.len();
+    test_member(&mut good, TRAILER, 0, &[]);
+    let end = trailer + HEADER_SIZE + TRAILER.len() + 1;
+
+    let mut members = Archive::new(&good);
+    for (name, mode, data) in [(".", S_IFDIR, &b""[..]), ("bin", S_IFDIR, b""),
+                               ("bin/init", S_IFREG, b"hello"), ("empty", S_IFREG, b"")] {
+        let entry = members.next().unwrap().unwrap();
+        assert!(entry.name == name && entry.mode & S_IFMT == mode && entry.data == data);
+    }
+    assert!(members.next().is_none());
+    assert!(members.next().is_none());
+    // cpio pads the archive out to whole blocks after the trailer
+    let mut padded = good.clone();
+    padded.resize((good.len() + 511) & !511, 0);
+    assert_eq!(Archive::new(&padded).count(), 4);
+
+    // cut short anywhere before the end of the trailer, or missing it
+    for len in 0..end {
+        let last = Archive::new(&good[..len]).last().unwrap();
+        assert_eq!(last.err(), Some(FsError::Corrupt));
+    }
+    let mut members = Archive::new(&good[..trailer]);
+    assert_eq!(members.nth(4).unwrap().err(), Some(FsError::Corrupt));
+    assert!(members.next().is_none());
+
+    let broken = |off: usize, bytes: &[u8]| {
+        let mut archive = good.clone();
+        archive[off..off + bytes.len()].copy_from_slice(bytes);
+        Archive::new(&archive).last().unwrap().err()
+    };
+    // the crc format, which we don't read
+    assert_eq!(broken(0, b"070702"), Some(FsError::Corrupt));
+    // not hex
+    assert_eq!(broken(MAGIC.len() + 8, b"0000zz00"), Some(FsError::Corrupt));
+    // a name without its NUL
+    assert_eq!(broken(MAGIC.len() + 11 * 8, b"00000001"), Some(FsError::Corrupt));
+    // file data past the end of the archive
+    assert_eq!(broken(MAGIC.len() + 6 * 8, b"ffffffff"), Some(FsError::Corrupt));
+
+    log!(Debug, "Successful test of cpio parsing...");
+}
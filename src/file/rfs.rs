@@ -0,0 +1,616 @@
+//! reedos native filesystem.
+//!
+//! A small xv6 style filesystem: fixed size inodes with direct, single
+//! and double indirect block pointers, a free block bitmap, flat
+//! directories of fixed size entries, and a write-ahead log (see
+//! `log`) that makes every operation atomic across crashes.
+//!
+//! Images are made on the host with `tools/rfs`, which shares the
+//! on-disk definitions in `layout` with this driver.
+//!
+//! All operations on a filesystem are serialized by one lock, and each
+//! one is its own transaction, committed before it returns. Writes
+//! larger than a transaction can hold are split into several. Files
+//! that are unlinked while still open are only freed when the last
+//! `Inode` handle to them is dropped, or by the next operation if the
+//! lock was held then; if we crash before that the inode leaks until
+//! the image is checked on the host.
+
+pub mod layout;
+pub mod log;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::file::block::BlockDevice;
+use crate::file::vfs::*;
+use crate::lock::mutex::Mutex;
+use layout::*;
+use self::log::{zeroed, Log};
+
+/// Most bytes one transaction may write to a file: leaves room for
+/// the inode, two bitmap blocks, and three levels of indirect block
+/// on top of the data, and slop for unaligned ends.
+const WRITE_CHUNK: usize = (MAXOPBLOCKS - 6) / 2 * BSIZE;
+
+struct State {
+    sb: SuperBlock,
+    log: Log,
+    open: BTreeMap<u32, usize>, // live Inode handles per inum
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // inums of handles dropped since, see `release_dropped`
+    dropped: Mutex<Vec<u32>>,
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > DIRSIZ {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl State {
+    fn iget(&self, inum: u32) -> Result<DiskInode, FsError> {
+        if inum == 0 || inum >= self.sb.ninodes {
+            return Err(FsError::Corrupt);
+        }
+        let buf = self.log.read(self.sb.iblock(inum))?;
+        Ok(DiskInode::decode(&buf[..], inum))
+    }
+
+    fn iput(&mut self, inum: u32, ip: &DiskInode) -> Result<(), FsError> {
+        let bno = self.sb.iblock(inum);
+        let mut buf = self.log.read(bno)?;
+        ip.encode(&mut buf[..], inum);
+        self.log.write(bno, &buf)?;
+        Ok(())
+    }
+
+    /// Find a free inode and mark it in use.
+    fn ialloc(&mut self, ftype: u16) -> Result<u32, FsError> {
+        let mut inum = 1;
+        while inum < self.sb.ninodes {
+            let bno = self.sb.iblock(inum);
+            let mut buf = self.log.read(bno)?;
+            let last = min((inum / IPB as u32 + 1) * IPB as u32, self.sb.ninodes);
+            for i in inum..last {
+                if DiskInode::decode(&buf[..], i).ftype == T_FREE {
+                    let mut ip = DiskInode::empty(ftype);
+                    ip.nlink = 1;
+                    ip.encode(&mut buf[..], i);
+                    self.log.write(bno, &buf)?;
+                    return Ok(i);
+                }
+            }
+            inum = last;
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn check_block(&self, b: u32) -> Result<u32, FsError> {
+        if b != 0 && (b < self.sb.datastart() || b >= self.sb.size) {
+            log!(Error, "rfs: block {} out of range", b);
+            return Err(FsError::Corrupt);
+        }
+        Ok(b)
+    }
+
+    /// Allocate a zeroed data block.
+    fn balloc(&mut self) -> Result<u32, FsError> {
+        let mut b = self.sb.datastart();
+        while b < self.sb.size {
+            let bno = self.sb.bblock(b);
+            let mut buf = self.log.read(bno)?;
+            let last = min((b / BPB as u32 + 1) * BPB as u32, self.sb.size);
+            for i in b..last {
+                let bit = i as usize % BPB;
+                if buf[bit / 8] & (1 << (bit % 8)) == 0 {
+                    buf[bit / 8] |= 1 << (bit % 8);
+                    self.log.write(bno, &buf)?;
+                    self.log.write(i, &zeroed())?;
+                    return Ok(i);
+                }
+            }
+            b = last;
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn bfree(&mut self, b: u32) -> Result<(), FsError> {
+        if b == 0 || self.check_block(b).is_err() {
+            return Err(FsError::Corrupt);
+        }
+        let bno = self.sb.bblock(b);
+        let mut buf = self.log.read(bno)?;
+        let bit = b as usize % BPB;
+        if buf[bit / 8] & (1 << (bit % 8)) == 0 {
+            log!(Error, "rfs: freeing free block {}", b);
+            return Err(FsError::Corrupt);
+        }
+        buf[bit / 8] &= !(1 << (bit % 8));
+        self.log.write(bno, &buf)?;
+        Ok(())
+    }
+
+    fn direct(&mut self, slot: &mut u32, alloc: bool) -> Result<u32, FsError> {
+        if *slot == 0 && alloc {
+            *slot = self.balloc()?;
+        }
+        self.check_block(*slot)
+    }
+
+    /// Entry `idx` of indirect block `table`, allocating it if needed.
+    fn indirect(&mut self, table: u32, idx: usize, alloc: bool) -> Result<u32, FsError> {
+        if table == 0 {
+            return Ok(0);
+        }
+        let mut buf = self.log.read(table)?;
+        let mut addr = indirect_get(&buf[..], idx);
+        if addr == 0 && alloc {
+            addr = self.balloc()?;
+            indirect_set(&mut buf[..], idx, addr);
+            self.log.write(table, &buf)?;
+        }
+        self.check_block(addr)
+    }
+
+    /// Disk block holding block `bn` of a file, or zero for a hole
+    /// when not allocating.
+    fn bmap(&mut self, ip: &mut DiskInode, bn: usize, alloc: bool) -> Result<u32, FsError> {
+        if bn < NDIRECT {
+            return self.direct(&mut ip.addrs[bn], alloc);
+        }
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT {
+            let ind = self.direct(&mut ip.addrs[NDIRECT], alloc)?;
+            return self.indirect(ind, bn, alloc);
+        }
+        let bn = bn - NINDIRECT;
+        if bn < NINDIRECT * NINDIRECT {
+            let dind = self.direct(&mut ip.addrs[NDIRECT + 1], alloc)?;
+            let ind = self.indirect(dind, bn / NINDIRECT, alloc)?;
+            return self.indirect(ind, bn % NINDIRECT, alloc);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Free every block at or after file block `first` underneath the
+    /// indirect block `table`, which maps `NINDIRECT^depth` blocks.
+    /// The table itself goes too if `first` is zero.
+    fn free_from(&mut self, table: u32, depth: u32, first: usize) -> Result<(), FsError> {
+        if table == 0 {
+            return Ok(());
+        }
+        let span = NINDIRECT.pow(depth - 1);
+        let mut buf = self.log.read(table)?;
+        let mut changed = false;
+        for i in 0..NINDIRECT {
+            let addr = indirect_get(&buf[..], i);
+            let start = i * span;
+            if addr == 0 || start + span <= first {
+                continue;
+            }
+            if depth > 1 {
+                self.free_from(addr, depth - 1, first.saturating_sub(start))?;
+            } else if start >= first {
+                self.bfree(addr)?;
+            }
+            if start >= first {
+                indirect_set(&mut buf[..], i, 0);
+                changed = true;
+            }
+        }
+        if first == 0 {
+            self.bfree(table)
+        } else {
+            if changed {
+                self.log.write(table, &buf)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Set the size of a file, freeing blocks past the new end.
+    fn itrunc(&mut self, ip: &mut DiskInode, size: u64) -> Result<(), FsError> {
+        if size > (MAXFILE * BSIZE) as u64 {
+            return Err(FsError::NoSpace);
+        }
+        if size < ip.size as u64 {
+            let keep = (size as usize).div_ceil(BSIZE);
+            for i in keep..NDIRECT {
+                if ip.addrs[i] != 0 {
+                    self.bfree(ip.addrs[i])?;
+                    ip.addrs[i] = 0;
+                }
+            }
+            let first = keep.saturating_sub(NDIRECT);
+            self.free_from(ip.addrs[NDIRECT], 1, first)?;
+            if first == 0 {
+                ip.addrs[NDIRECT] = 0;
+            }
+            let first = keep.saturating_sub(NDIRECT + NINDIRECT);
+            self.free_from(ip.addrs[NDIRECT + 1], 2, first)?;
+            if first == 0 {
+                ip.addrs[NDIRECT + 1] = 0;
+            }
+            // Bytes past the end must read back as zero if the file
+            // grows again.
+            let tail = size as usize % BSIZE;
+            if tail != 0 {
+                let b = self.bmap(ip, size as usize / BSIZE, false)?;
+                if b != 0 {
+                    let mut buf = self.log.read(b)?;
+                    buf[tail..].fill(0);
+                    self.log.write(b, &buf)?;
+                }
+            }
+        }
+        ip.size = size as u32;
+        Ok(())
+    }
+
+    fn readi(&mut self, ip: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= ip.size as u64 {
+            return Ok(0);
+        }
+        let n = min(buf.len() as u64, ip.size as u64 - offset) as usize;
+        let mut ip = *ip;
+        let mut done = 0;
+        while done < n {
+            let off = offset as usize + done;
+            let len = min(n - done, BSIZE - off % BSIZE);
+            let b = self.bmap(&mut ip, off / BSIZE, false)?;
+            let dst = &mut buf[done..done + len];
+            if b == 0 {
+                dst.fill(0);
+            } else {
+                let blk = self.log.read(b)?;
+                dst.copy_from_slice(&blk[off % BSIZE..off % BSIZE + len]);
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    /// Write into a file, growing it as needed. The caller writes the
+    /// inode back.
+    fn writei(&mut self, ip: &mut DiskInode, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let end = offset + buf.len() as u64;
+        if end > (MAXFILE * BSIZE) as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let off = offset as usize + done;
+            let len = min(buf.len() - done, BSIZE - off % BSIZE);
+            let b = self.bmap(ip, off / BSIZE, true)?;
+            let mut blk = self.log.read(b)?;
+            blk[off % BSIZE..off % BSIZE + len].copy_from_slice(&buf[done..done + len]);
+            self.log.write(b, &blk)?;
+            done += len;
+        }
+        if end > ip.size as u64 {
+            ip.size = end as u32;
+        }
+        Ok(())
+    }
+
+    /// Every slot of a directory, free or not, with its byte offset.
+    fn dirents(&mut self, dp: &DiskInode) -> Result<Vec<(u64, DiskDirent)>, FsError> {
+        if dp.ftype != T_DIR {
+            return Err(FsError::NotDirectory);
+        }
+        let mut raw = alloc::vec![0_u8; dp.size as usize];
+        self.readi(dp, 0, &mut raw)?;
+        Ok(raw.chunks_exact(DIRENT_SIZE)
+           .enumerate()
+           .map(|(i, ent)| ((i * DIRENT_SIZE) as u64, DiskDirent::decode(ent)))
+           .collect())
+    }
+
+    fn dirlookup(&mut self, dp: &DiskInode, name: &str) -> Result<(u64, u32), FsError> {
+        self.dirents(dp)?
+            .into_iter()
+            .find(|(_, d)| d.inum != 0 && d.name() == name.as_bytes())
+            .map(|(off, d)| (off, d.inum))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Add `name` -> `inum` to a directory, reusing a free slot if
+    /// there is one. The caller writes the directory inode back.
+    fn dirlink(&mut self, dp: &mut DiskInode, name: &str, inum: u32) -> Result<(), FsError> {
+        let off = self.dirents(dp)?
+            .into_iter()
+            .find(|(_, d)| d.inum == 0)
+            .map(|(off, _)| off)
+            .unwrap_or(dp.size as u64);
+        let mut ent = [0_u8; DIRENT_SIZE];
+        DiskDirent::new(inum, name.as_bytes()).encode(&mut ent);
+        self.writei(dp, off, &ent)
+    }
+
+    /// Drop a link to `inum`, freeing it if that was the last link and
+    /// nobody has it open.
+    fn drop_link(&mut self, inum: u32, ip: &mut DiskInode) -> Result<(), FsError> {
+        ip.nlink = ip.nlink.saturating_sub(1);
+        if ip.nlink == 0 && !self.open.contains_key(&inum) {
+            self.itrunc(ip, 0)?;
+            *ip = DiskInode::empty(T_FREE);
+        }
+        self.iput(inum, ip)
+    }
+}
+
+/// Run `f` as a single transaction on the locked state, committing it
+/// on success and discarding it on failure.
+fn transaction<T>(st: &mut State, f: impl FnOnce(&mut State) -> Result<T, FsError>) -> Result<T, FsError> {
+    let out = f(st);
+    let out = match out {
+        Ok(v) => st.log.commit().map(|_| v),
+        Err(e) => Err(e),
+    };
+    if out.is_err() {
+        st.log.abort();
+    }
+    out
+}
+
+impl Shared {
+    /// Lock the state and run `f` as a `transaction`, once the handles
+    /// dropped in the meantime are let go of.
+    fn op<T>(&self, f: impl FnOnce(&mut State) -> Result<T, FsError>) -> Result<T, FsError> {
+        let mut st = self.state.lock();
+        self.release_dropped(&mut st);
+        transaction(&mut st, f)
+    }
+
+    /// Let go of the inodes of handles dropped since last time, freeing
+    /// the ones that were unlinked while open. A handle can be dropped
+    /// with the state locked, by whoever has it locked, so `Drop` only
+    /// does this itself if the lock is free and otherwise leaves it to
+    /// the next `op`.
+    fn release_dropped(&self, st: &mut State) {
+        let dropped = core::mem::take(&mut *self.dropped.lock());
+        for inum in dropped {
+            let res = transaction(st, |st| {
+                let count = st.open.get_mut(&inum).expect("rfs: untracked inode handle");
+                *count -= 1;
+                if *count > 0 {
+                    return Ok(());
+                }
+                st.open.remove(&inum);
+                let mut ip = st.iget(inum)?;
+                if ip.nlink == 0 && ip.ftype != T_FREE {
+                    st.itrunc(&mut ip, 0)?;
+                    st.iput(inum, &DiskInode::empty(T_FREE))?;
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                log!(Error, "rfs: failed to release inode {}: {:?}", inum, e);
+            }
+        }
+    }
+
+    fn node(self: &Arc<Self>, st: &mut State, inum: u32) -> Arc<dyn Inode> {
+        *st.open.entry(inum).or_insert(0) += 1;
+        Arc::new(RfsNode {
+            fs: self.clone(),
+            inum,
+        })
+    }
+}
+
+struct RfsNode {
+    fs: Arc<Shared>,
+    inum: u32,
+}
+
+impl Drop for RfsNode {
+    fn drop(&mut self) {
+        self.fs.dropped.lock().push(self.inum);
+        if let Some(mut st) = self.fs.state.try_lock() {
+            self.fs.release_dropped(&mut st);
+        }
+    }
+}
+
+impl Inode for RfsNode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let st = self.fs.state.lock();
+        let ip = st.iget(self.inum)?;
+        let ftype = match ip.ftype {
+            T_DIR => FileType::Directory,
+            T_FILE => FileType::Regular,
+            _ => return Err(FsError::Corrupt),
+        };
+        Ok(Stat {
+            ino: self.inum as u64,
+            ftype,
+            size: ip.size as u64,
+            nlink: ip.nlink as u32,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut st = self.fs.state.lock();
+        let ip = st.iget(self.inum)?;
+        if ip.ftype == T_DIR {
+            return Err(FsError::IsDirectory);
+        }
+        st.readi(&ip, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let n = min(buf.len() - done, WRITE_CHUNK);
+            let res = self.fs.op(|st| {
+                let mut ip = st.iget(self.inum)?;
+                if ip.ftype == T_DIR {
+                    return Err(FsError::IsDirectory);
+                }
+                st.writei(&mut ip, offset + done as u64, &buf[done..done + n])?;
+                st.iput(self.inum, &ip)
+            });
+            match res {
+                Ok(()) => done += n,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.op(|st| {
+            let mut ip = st.iget(self.inum)?;
+            if ip.ftype == T_DIR {
+                return Err(FsError::IsDirectory);
+            }
+            st.itrunc(&mut ip, size)?;
+            st.iput(self.inum, &ip)
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut st = self.fs.state.lock();
+        let dp = st.iget(self.inum)?;
+        let (_, inum) = st.dirlookup(&dp, name)?;
+        Ok(self.fs.node(&mut st, inum))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut st = self.fs.state.lock();
+        let dp = st.iget(self.inum)?;
+        let mut out = Vec::new();
+        for (_, d) in st.dirents(&dp)? {
+            if d.inum == 0 || d.name() == b"." || d.name() == b".." {
+                continue;
+            }
+            let ftype = match st.iget(d.inum)?.ftype {
+                T_DIR => FileType::Directory,
+                _ => FileType::Regular,
+            };
+            out.push(DirEntry {
+                name: String::from_utf8_lossy(d.name()).into_owned(),
+                ino: d.inum as u64,
+                ftype,
+            });
+        }
+        Ok(out)
+    }
+
+    fn create(&self, name: &str, ftype: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        check_name(name)?;
+        let mut st = self.fs.state.lock();
+        let parent = self.inum;
+        let res = (|| {
+            let mut dp = st.iget(parent)?;
+            match st.dirlookup(&dp, name) {
+                Ok(_) => return Err(FsError::Exists),
+                Err(FsError::NotFound) => {},
+                Err(e) => return Err(e),
+            }
+            let inum = match ftype {
+                FileType::Regular => st.ialloc(T_FILE)?,
+                FileType::Directory => {
+                    let inum = st.ialloc(T_DIR)?;
+                    let mut ip = st.iget(inum)?;
+                    st.dirlink(&mut ip, ".", inum)?;
+                    st.dirlink(&mut ip, "..", parent)?;
+                    st.iput(inum, &ip)?;
+                    dp.nlink += 1;
+                    inum
+                },
+            };
+            st.dirlink(&mut dp, name, inum)?;
+            st.iput(parent, &dp)?;
+            st.log.commit()?;
+            Ok(inum)
+        })();
+        match res {
+            Ok(inum) => Ok(self.fs.node(&mut st, inum)),
+            Err(e) => {
+                st.log.abort();
+                Err(e)
+            },
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        check_name(name)?;
+        let parent = self.inum;
+        self.fs.op(|st| {
+            let mut dp = st.iget(parent)?;
+            let (off, inum) = st.dirlookup(&dp, name)?;
+            let mut ip = st.iget(inum)?;
+            if ip.ftype == T_DIR {
+                let busy = st.dirents(&ip)?.iter()
+                    .any(|(_, d)| d.inum != 0 && d.name() != b"." && d.name() != b"..");
+                if busy {
+                    return Err(FsError::NotEmpty);
+                }
+                // the child's ".." goes away with it
+                dp.nlink = dp.nlink.saturating_sub(1);
+            }
+            st.writei(&mut dp, off, &[0; DIRENT_SIZE])?;
+            st.iput(parent, &dp)?;
+            st.drop_link(inum, &mut ip)
+        })
+    }
+}
+
+/// A mounted native filesystem.
+pub struct Rfs {
+    fs: Arc<Shared>,
+}
+
+impl Rfs {
+    /// Check for a native superblock on `dev`, replay the log if a
+    /// crash left anything in it, and set up the filesystem.
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut buf = zeroed();
+        log::read_block(&*dev, SUPER_BLOCK, &mut buf)?;
+        let sb = SuperBlock::decode(&buf[..]);
+        if sb.magic != FS_MAGIC {
+            return Err(FsError::Unsupported);
+        }
+        if !sb.valid() || sb.ninodes <= ROOTINO {
+            return Err(FsError::Corrupt);
+        }
+        let mut log = Log::new(dev, &sb);
+        log.recover()?;
+        log!(Debug, "rfs: {} blocks, {} data, {} inodes", sb.size, sb.nblocks, sb.ninodes);
+        let st = State {
+            sb,
+            log,
+            open: BTreeMap::new(),
+        };
+        if st.iget(ROOTINO)?.ftype != T_DIR {
+            return Err(FsError::Corrupt);
+        }
+        let fs = Shared { state: Mutex::new(st), dropped: Mutex::new(Vec::new()) };
+        Ok(Self { fs: Arc::new(fs) })
+    }
+}
+
+impl FileSystem for Rfs {
+    fn name(&self) -> &'static str {
+        "rfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut st = self.fs.state.lock();
+        self.fs.node(&mut st, ROOTINO)
+    }
+}
@@ -0,0 +1,259 @@
+//! On-disk format of the reedos native filesystem.
+//!
+//! This module is shared verbatim with the host side tools in
+//! `tools/rfs`, so it must only depend on `core`.
+//!
+//! Disk layout, in `BSIZE` blocks:
+//!
+//! ```text
+//! [ boot | super | log header | log data ... | inodes ... | bitmap ... | data ... ]
+//! ```
+//!
+//! All multi-byte values are little endian.
+
+pub const BSIZE: usize = 1024;
+pub const SECTORS_PER_BLOCK: u64 = (BSIZE / 512) as u64;
+
+pub const FS_MAGIC: u32 = 0x5346_4552; // "REFS"
+pub const SUPER_BLOCK: u32 = 1;
+pub const ROOTINO: u32 = 1;
+
+/// Most distinct blocks any single transaction may write.
+pub const MAXOPBLOCKS: usize = 32;
+/// Data blocks in the on disk log. The header must fit in a single
+/// sector so that writing it is atomic.
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
+
+pub const NDIRECT: usize = 11;
+pub const NINDIRECT: usize = BSIZE / 4;
+/// Largest file, in blocks: direct, single indirect, double indirect.
+pub const MAXFILE: usize = NDIRECT + NINDIRECT + NINDIRECT * NINDIRECT;
+
+pub const DINODE_SIZE: usize = 64;
+/// Inodes per block.
+pub const IPB: usize = BSIZE / DINODE_SIZE;
+/// Bitmap bits per block.
+pub const BPB: usize = BSIZE * 8;
+
+/// Longest directory entry name in bytes.
+pub const DIRSIZ: usize = 60;
+pub const DIRENT_SIZE: usize = 64;
+
+// Inode types
+pub const T_FREE: u16 = 0;
+pub const T_DIR: u16 = 1;
+pub const T_FILE: u16 = 2;
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn put32(b: &mut [u8], off: usize, val: u32) {
+    b[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn put16(b: &mut [u8], off: usize, val: u16) {
+    b[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Describes the rest of the disk. Lives in block 1.
+#[derive(Copy, Clone, Debug)]
+pub struct SuperBlock {
+    pub magic: u32,
+    pub size: u32,              // total blocks in the image
+    pub nblocks: u32,           // data blocks
+    pub ninodes: u32,
+    pub nlog: u32,              // log blocks, including the header
+    pub logstart: u32,
+    pub inodestart: u32,
+    pub bmapstart: u32,
+}
+
+impl SuperBlock {
+    /// Lay out a filesystem of `size` blocks with `ninodes` inodes.
+    pub fn new(size: u32, ninodes: u32) -> Self {
+        let nlog = LOGSIZE as u32 + 1;
+        let ninodeblocks = ninodes / IPB as u32 + 1;
+        let nbitmap = size / BPB as u32 + 1;
+        let logstart = SUPER_BLOCK + 1;
+        let inodestart = logstart + nlog;
+        let bmapstart = inodestart + ninodeblocks;
+        let nmeta = bmapstart + nbitmap;
+        Self {
+            magic: FS_MAGIC,
+            size,
+            nblocks: size.saturating_sub(nmeta),
+            ninodes,
+            nlog,
+            logstart,
+            inodestart,
+            bmapstart,
+        }
+    }
+
+    /// First block number that holds file data.
+    pub fn datastart(&self) -> u32 {
+        self.size - self.nblocks
+    }
+
+    pub fn decode(b: &[u8]) -> Self {
+        Self {
+            magic: le32(b, 0),
+            size: le32(b, 4),
+            nblocks: le32(b, 8),
+            ninodes: le32(b, 12),
+            nlog: le32(b, 16),
+            logstart: le32(b, 20),
+            inodestart: le32(b, 24),
+            bmapstart: le32(b, 28),
+        }
+    }
+
+    pub fn encode(&self, b: &mut [u8]) {
+        put32(b, 0, self.magic);
+        put32(b, 4, self.size);
+        put32(b, 8, self.nblocks);
+        put32(b, 12, self.ninodes);
+        put32(b, 16, self.nlog);
+        put32(b, 20, self.logstart);
+        put32(b, 24, self.inodestart);
+        put32(b, 28, self.bmapstart);
+    }
+
+    /// Sanity check values read off of a disk.
+    pub fn valid(&self) -> bool {
+        self.magic == FS_MAGIC
+            && self.nlog == LOGSIZE as u32 + 1
+            && self.logstart == SUPER_BLOCK + 1
+            && self.inodestart == self.logstart + self.nlog
+            && self.bmapstart == self.inodestart + self.ninodes / IPB as u32 + 1
+            && self.bmapstart + self.size / BPB as u32 + 1 == self.datastart()
+            && self.nblocks < self.size
+    }
+
+    /// Block holding inode `inum`.
+    pub fn iblock(&self, inum: u32) -> u32 {
+        self.inodestart + inum / IPB as u32
+    }
+
+    /// Bitmap block holding the bit for block `b`.
+    pub fn bblock(&self, b: u32) -> u32 {
+        self.bmapstart + b / BPB as u32
+    }
+}
+
+/// The log header. `n` is the commit record: non-zero means the
+/// first `n` log data blocks belong at `blocks[..n]`.
+pub struct LogHeader {
+    pub n: u32,
+    pub blocks: [u32; LOGSIZE],
+}
+
+impl LogHeader {
+    pub fn decode(b: &[u8]) -> Self {
+        let mut blocks = [0; LOGSIZE];
+        for (i, blk) in blocks.iter_mut().enumerate() {
+            *blk = le32(b, 4 + 4 * i);
+        }
+        Self { n: le32(b, 0), blocks }
+    }
+
+    pub fn encode(&self, b: &mut [u8]) {
+        put32(b, 0, self.n);
+        for (i, blk) in self.blocks.iter().enumerate() {
+            put32(b, 4 + 4 * i, *blk);
+        }
+    }
+}
+
+/// An inode as stored on disk.
+#[derive(Copy, Clone, Debug)]
+pub struct DiskInode {
+    pub ftype: u16,
+    pub nlink: u16,
+    pub size: u32,
+    /// `NDIRECT` direct blocks, then single and double indirect.
+    pub addrs: [u32; NDIRECT + 2],
+}
+
+impl DiskInode {
+    pub fn empty(ftype: u16) -> Self {
+        Self {
+            ftype,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT + 2],
+        }
+    }
+
+    /// Read inode `inum` out of its inode block.
+    pub fn decode(block: &[u8], inum: u32) -> Self {
+        let off = (inum as usize % IPB) * DINODE_SIZE;
+        let mut addrs = [0; NDIRECT + 2];
+        for (i, a) in addrs.iter_mut().enumerate() {
+            *a = le32(block, off + 8 + 4 * i);
+        }
+        Self {
+            ftype: le16(block, off),
+            nlink: le16(block, off + 2),
+            size: le32(block, off + 4),
+            addrs,
+        }
+    }
+
+    /// Write inode `inum` into its inode block.
+    pub fn encode(&self, block: &mut [u8], inum: u32) {
+        let off = (inum as usize % IPB) * DINODE_SIZE;
+        block[off..off + DINODE_SIZE].fill(0);
+        put16(block, off, self.ftype);
+        put16(block, off + 2, self.nlink);
+        put32(block, off + 4, self.size);
+        for (i, a) in self.addrs.iter().enumerate() {
+            put32(block, off + 8 + 4 * i, *a);
+        }
+    }
+}
+
+/// Directory entries are packed `DIRENT_SIZE` to a slot. An `inum` of
+/// zero marks a free slot. Names are NUL padded, not terminated.
+pub struct DiskDirent {
+    pub inum: u32,
+    pub name: [u8; DIRSIZ],
+}
+
+impl DiskDirent {
+    pub fn new(inum: u32, name: &[u8]) -> Self {
+        let mut out = Self { inum, name: [0; DIRSIZ] };
+        out.name[..name.len()].copy_from_slice(name);
+        out
+    }
+
+    pub fn decode(b: &[u8]) -> Self {
+        let mut name = [0; DIRSIZ];
+        name.copy_from_slice(&b[4..4 + DIRSIZ]);
+        Self { inum: le32(b, 0), name }
+    }
+
+    pub fn encode(&self, b: &mut [u8]) {
+        put32(b, 0, self.inum);
+        b[4..4 + DIRSIZ].copy_from_slice(&self.name);
+    }
+
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+}
+
+/// Read entry `i` of an indirect block.
+pub fn indirect_get(block: &[u8], i: usize) -> u32 {
+    le32(block, 4 * i)
+}
+
+pub fn indirect_set(block: &mut [u8], i: usize, val: u32) {
+    put32(block, 4 * i, val)
+}
@@ -0,0 +1,137 @@
+//! Write-ahead log for the native filesystem.
+//!
+//! Same scheme as xv6: every change a filesystem operation makes is
+//! buffered in memory as a whole block. On commit the blocks are first
+//! written to the log area, then the header naming their home
+//! locations is written (the commit point), then the blocks are
+//! installed at home and the header is cleared. Mounting replays any
+//! committed but uninstalled log, so after a crash the disk reflects
+//! either all of an operation or none of it.
+//!
+//! The header fits in one sector, and sector writes are assumed to be
+//! atomic, so a crash can't leave a half written commit record.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use super::layout::*;
+use crate::file::block::{BlockDevice, SECTOR_SIZE};
+use crate::file::vfs::FsError;
+
+pub type Buf = Box<[u8; BSIZE]>;
+
+pub fn zeroed() -> Buf {
+    Box::new([0; BSIZE])
+}
+
+pub fn read_block(dev: &dyn BlockDevice, bno: u32, buf: &mut [u8; BSIZE]) -> Result<(), FsError> {
+    dev.read_sectors(bno as u64 * SECTORS_PER_BLOCK, buf)
+}
+
+pub fn write_block(dev: &dyn BlockDevice, bno: u32, buf: &[u8; BSIZE]) -> Result<(), FsError> {
+    dev.write_sectors(bno as u64 * SECTORS_PER_BLOCK, buf)
+}
+
+/// The in memory half of the log: blocks changed by the current
+/// transaction, keyed by their home block number.
+pub struct Log {
+    dev: Arc<dyn BlockDevice>,
+    start: u32,
+    size: u32,
+    dirty: BTreeMap<u32, Buf>,
+}
+
+impl Log {
+    pub fn new(dev: Arc<dyn BlockDevice>, sb: &SuperBlock) -> Self {
+        Self {
+            dev,
+            start: sb.logstart,
+            size: sb.size,
+            dirty: BTreeMap::new(),
+        }
+    }
+
+    /// Read a block as the current transaction sees it.
+    pub fn read(&self, bno: u32) -> Result<Buf, FsError> {
+        if let Some(buf) = self.dirty.get(&bno) {
+            return Ok(buf.clone());
+        }
+        let mut buf = zeroed();
+        read_block(&*self.dev, bno, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Stage a whole block as part of the current transaction. A
+    /// transaction touching more blocks than the log holds fails with
+    /// `NoSpace`, and the operation should abort.
+    pub fn write(&mut self, bno: u32, buf: &[u8; BSIZE]) -> Result<(), FsError> {
+        match self.dirty.get_mut(&bno) {
+            Some(old) => old.copy_from_slice(buf),
+            None => {
+                if self.dirty.len() >= LOGSIZE {
+                    log!(Warning, "rfs: transaction too big for the log");
+                    return Err(FsError::NoSpace);
+                }
+                self.dirty.insert(bno, Box::new(*buf));
+            },
+        }
+        Ok(())
+    }
+
+    /// Throw away the current transaction. Only safe if nothing has
+    /// been committed from it, which is always the case since
+    /// commits are all or nothing.
+    pub fn abort(&mut self) {
+        self.dirty.clear();
+    }
+
+    fn write_header(&self, hdr: &LogHeader) -> Result<(), FsError> {
+        let mut buf = zeroed();
+        hdr.encode(&mut buf[..]);
+        self.dev.write_sector(self.start as u64 * SECTORS_PER_BLOCK, &buf[..SECTOR_SIZE])
+    }
+
+    /// Make the current transaction durable and install it.
+    pub fn commit(&mut self) -> Result<(), FsError> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let mut hdr = LogHeader { n: 0, blocks: [0; LOGSIZE] };
+        for (i, (bno, buf)) in self.dirty.iter().enumerate() {
+            write_block(&*self.dev, self.start + 1 + i as u32, buf)?;
+            hdr.blocks[i] = *bno;
+        }
+        hdr.n = self.dirty.len() as u32;
+        self.write_header(&hdr)?;
+        for (bno, buf) in self.dirty.iter() {
+            write_block(&*self.dev, *bno, buf)?;
+        }
+        hdr.n = 0;
+        self.write_header(&hdr)?;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Install anything left committed in the on disk log.
+    pub fn recover(&mut self) -> Result<(), FsError> {
+        let mut buf = zeroed();
+        read_block(&*self.dev, self.start, &mut buf)?;
+        let mut hdr = LogHeader::decode(&buf[..]);
+        let first_home = self.start + 1 + LOGSIZE as u32;
+        if hdr.n as usize > LOGSIZE
+            || hdr.blocks[..hdr.n as usize].iter().any(|&b| b < first_home || b >= self.size) {
+            return Err(FsError::Corrupt);
+        }
+        if hdr.n == 0 {
+            return Ok(());
+        }
+        log!(Info, "Replaying {} logged blocks", hdr.n);
+        for i in 0..hdr.n as usize {
+            read_block(&*self.dev, self.start + 1 + i as u32, &mut buf)?;
+            write_block(&*self.dev, hdr.blocks[i], &buf)?;
+        }
+        hdr.n = 0;
+        self.write_header(&hdr)
+    }
+}
@@ -0,0 +1,257 @@
+//! In memory filesystem.
+//!
+//! File contents live in whole physical pages from
+//! `request_phys_page`, allocated as they are first written, so files
+//! can be sparse. Everything goes away when the last reference to a
+//! node does, including the pages.
+//!
+//! Each node has its own lock. Only `unlink` holds two at once, and it
+//! always takes the parent before the child.
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::file::vfs::*;
+use crate::hw::param::PAGE_SIZE;
+use crate::lock::mutex::Mutex;
+use crate::vm::{request_phys_page, PhysPageExtent};
+
+enum Contents {
+    File {
+        size: u64,
+        pages: BTreeMap<u64, PhysPageExtent>, // by page index in the file
+    },
+    Dir(BTreeMap<String, Arc<TmpNode>>),
+}
+
+struct NodeData {
+    nlink: u32,
+    contents: Contents,
+}
+
+struct TmpNode {
+    ino: u64,
+    next_ino: Arc<AtomicU64>,
+    data: Mutex<NodeData>,
+}
+
+fn page_bytes(page: &PhysPageExtent) -> &[u8] {
+    // Safety: the extent owns the page for as long as it lives.
+    unsafe { core::slice::from_raw_parts(page.start() as *const u8, PAGE_SIZE) }
+}
+
+fn page_bytes_mut(page: &mut PhysPageExtent) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(page.start() as *mut u8, PAGE_SIZE) }
+}
+
+impl TmpNode {
+    fn new(ftype: FileType, next_ino: Arc<AtomicU64>) -> Self {
+        let (nlink, contents) = match ftype {
+            FileType::Regular => (1, Contents::File { size: 0, pages: BTreeMap::new() }),
+            FileType::Directory => (2, Contents::Dir(BTreeMap::new())),
+        };
+        Self {
+            ino: next_ino.fetch_add(1, Ordering::Relaxed),
+            next_ino,
+            data: Mutex::new(NodeData { nlink, contents }),
+        }
+    }
+}
+
+impl Inode for TmpNode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let data = self.data.lock();
+        let (ftype, size) = match &data.contents {
+            Contents::File { size, .. } => (FileType::Regular, *size),
+            Contents::Dir(entries) => (FileType::Directory, entries.len() as u64),
+        };
+        Ok(Stat {
+            ino: self.ino,
+            ftype,
+            size,
+            nlink: data.nlink,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data.lock();
+        let (size, pages) = match &data.contents {
+            Contents::File { size, pages } => (*size, pages),
+            Contents::Dir(_) => return Err(FsError::IsDirectory),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let n = min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        while done < n {
+            let pos = offset + done as u64;
+            let start = (pos % PAGE_SIZE as u64) as usize;
+            let len = min(n - done, PAGE_SIZE - start);
+            let dst = &mut buf[done..done + len];
+            match pages.get(&(pos / PAGE_SIZE as u64)) {
+                Some(page) => dst.copy_from_slice(&page_bytes(page)[start..start + len]),
+                None => dst.fill(0),
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let (size, pages) = match &mut data.contents {
+            Contents::File { size, pages } => (size, pages),
+            Contents::Dir(_) => return Err(FsError::IsDirectory),
+        };
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % PAGE_SIZE as u64) as usize;
+            let len = min(buf.len() - done, PAGE_SIZE - start);
+            let idx = pos / PAGE_SIZE as u64;
+            let page = match pages.entry(idx) {
+                Entry::Occupied(page) => page.into_mut(),
+                Entry::Vacant(slot) => match request_phys_page(1) {
+                    Ok(page) => slot.insert(page),
+                    Err(_) => break,
+                },
+            };
+            page_bytes_mut(page)[start..start + len].copy_from_slice(&buf[done..done + len]);
+            done += len;
+        }
+        if done == 0 && !buf.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        *size = (*size).max(offset + done as u64);
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let (size, pages) = match &mut data.contents {
+            Contents::File { size, pages } => (size, pages),
+            Contents::Dir(_) => return Err(FsError::IsDirectory),
+        };
+        if new_size < *size {
+            let keep = new_size.div_ceil(PAGE_SIZE as u64);
+            // dropping the extents frees the pages
+            drop(pages.split_off(&keep));
+            // the rest of the last page has to read back as zeros
+            let tail = (new_size % PAGE_SIZE as u64) as usize;
+            if tail != 0 {
+                if let Some(page) = pages.get_mut(&(new_size / PAGE_SIZE as u64)) {
+                    page_bytes_mut(page)[tail..].fill(0);
+                }
+            }
+        }
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &self.data.lock().contents {
+            Contents::Dir(entries) => entries.get(name)
+                .map(|n| n.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            Contents::File { .. } => Err(FsError::NotDirectory),
+        }
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let entries = match &self.data.lock().contents {
+            Contents::Dir(entries) => entries.clone(),
+            Contents::File { .. } => return Err(FsError::NotDirectory),
+        };
+        // Children are locked one at a time, after we let go of this one.
+        entries.into_iter()
+            .map(|(name, node)| Ok(DirEntry {
+                name,
+                ino: node.ino,
+                ftype: node.stat()?.ftype,
+            }))
+            .collect()
+    }
+
+    fn create(&self, name: &str, ftype: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        let mut data = self.data.lock();
+        let entries = match &mut data.contents {
+            Contents::Dir(entries) => entries,
+            Contents::File { .. } => return Err(FsError::NotDirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+        let node = Arc::new(TmpNode::new(ftype, self.next_ino.clone()));
+        entries.insert(String::from(name), node.clone());
+        if ftype == FileType::Directory {
+            data.nlink += 1;
+        }
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let entries = match &mut data.contents {
+            Contents::Dir(entries) => entries,
+            Contents::File { .. } => return Err(FsError::NotDirectory),
+        };
+        let node = entries.get(name).ok_or(FsError::NotFound)?.clone();
+        let is_dir = {
+            let mut child = node.data.lock();
+            let is_dir = match &child.contents {
+                Contents::Dir(e) if !e.is_empty() => return Err(FsError::NotEmpty),
+                Contents::Dir(_) => true,
+                Contents::File { .. } => false,
+            };
+            child.nlink = 0;
+            is_dir
+        };
+        entries.remove(name);
+        if is_dir {
+            data.nlink -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// An in memory filesystem instance.
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        // ino 1 is the root
+        let next_ino = Arc::new(AtomicU64::new(1));
+        Self {
+            root: Arc::new(TmpNode::new(FileType::Directory, next_ino)),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,241 @@
+//! Virtual filesystem layer.
+//!
+//! Every concrete filesystem exposes its files and directories as
+//! `Inode` trait objects and is attached somewhere in the single
+//! global namespace with `mount`. Path lookup finds the mount with the
+//! longest matching prefix and walks the rest of the path from the
+//! root of that filesystem. Mount points do not need to exist as
+//! directories in the parent filesystem.
+//!
+//! Paths are always absolute, and `.`/`..` are resolved lexically
+//! before any filesystem sees them.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::lock::rw::RwLock;
+
+/// Longest single path component we accept.
+pub const NAME_MAX: usize = 255;
+
+/// (Still growing) list of filesystem error cases.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    Exists,
+    NotEmpty,
+    NoSpace,
+    ReadOnly,
+    InvalidPath,
+    NameTooLong,
+    Corrupt,                    // on disk structures don't make sense
+    Io,
+    Busy,
+    Unsupported,                // valid, but not something we handle
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+}
+
+/// Filesystem independent file metadata.
+#[derive(Debug, Copy, Clone)]
+pub struct Stat {
+    pub ino: u64,
+    pub ftype: FileType,
+    pub size: u64,
+    pub nlink: u32,
+}
+
+/// One entry of a directory listing. Never includes `.` or `..`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub ftype: FileType,
+}
+
+/// A file or directory in some filesystem.
+///
+/// Inodes are handed out as `Arc<dyn Inode>`, and may outlive the
+/// directory entry that named them. Read only filesystems can leave
+/// the mutating methods as their defaults.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Result<Stat, FsError>;
+
+    /// Read starting at byte `offset`, returning the number of bytes
+    /// read. Zero means end of file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Write starting at byte `offset`, growing the file as needed.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Set the file size, zero filling on growth.
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Find a single path component in this directory.
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Make a new empty file or directory in this directory.
+    fn create(&self, _name: &str, _ftype: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Remove a file or empty directory from this directory.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// A mountable filesystem instance.
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Push any cached state out to the backing device.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNT_TABLE: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Split an absolute path into normalized components.
+fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut out: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                out.pop();
+            },
+            _ => {
+                if part.len() > NAME_MAX {
+                    return Err(FsError::NameTooLong);
+                }
+                out.push(part);
+            },
+        }
+    }
+    Ok(out)
+}
+
+/// Attach `fs` at `path`, shadowing anything that was there.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path: Vec<String> = components(path)?.into_iter().map(String::from).collect();
+    let mut table = MOUNT_TABLE.write();
+    if table.iter().any(|m| m.path == path) {
+        return Err(FsError::Busy);
+    }
+    log!(Info, "Mounted {} filesystem at /{}", fs.name(), path.join("/"));
+    table.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Detach whatever is mounted exactly at `path`, syncing it first.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let path = components(path)?;
+    let mut table = MOUNT_TABLE.write();
+    let idx = table.iter()
+        .position(|m| m.path.iter().map(String::as_str).eq(path.iter().copied()))
+        .ok_or(FsError::NotFound)?;
+    table[idx].fs.sync()?;
+    Ok(table.remove(idx).fs)
+}
+
+/// Walk `path` and return the inode it names.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let parts = components(path)?;
+    let (mut node, rest) = {
+        let table = MOUNT_TABLE.read();
+        let mount = table.iter()
+            .filter(|m| m.path.len() <= parts.len() &&
+                    m.path.iter().zip(parts.iter()).all(|(a, b)| a == b))
+            .max_by_key(|m| m.path.len())
+            .ok_or(FsError::NotFound)?;
+        (mount.fs.root(), &parts[mount.path.len()..])
+    };
+    for part in rest {
+        if node.stat()?.ftype != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        node = node.lookup(part)?;
+    }
+    Ok(node)
+}
+
+/// Split off the last component of `path` and look up the directory
+/// that contains it.
+fn lookup_parent(path: &str) -> Result<(Arc<dyn Inode>, String), FsError> {
+    let mut parts = components(path)?;
+    let name = match parts.pop() {
+        Some(n) => String::from(n),
+        None => return Err(FsError::InvalidPath),
+    };
+    let mut parent = String::from("/");
+    parent.push_str(&parts.join("/"));
+    let dir = lookup(&parent)?;
+    if dir.stat()?.ftype != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((dir, name))
+}
+
+/// Make a new file or directory at `path`.
+pub fn create(path: &str, ftype: FileType) -> Result<Arc<dyn Inode>, FsError> {
+    let (dir, name) = lookup_parent(path)?;
+    dir.create(&name, ftype)
+}
+
+/// Remove the file or empty directory at `path`.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (dir, name) = lookup_parent(path)?;
+    dir.unlink(&name)
+}
+
+/// Read an entire file into memory.
+pub fn read_all(node: &dyn Inode) -> Result<Vec<u8>, FsError> {
+    let stat = node.stat()?;
+    if stat.ftype != FileType::Regular {
+        return Err(FsError::IsDirectory);
+    }
+    let mut out = alloc::vec![0_u8; stat.size as usize];
+    let mut done = 0;
+    while done < out.len() {
+        match node.read_at(done as u64, &mut out[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    out.truncate(done);
+    Ok(out)
+}
+
+/// Sync every mounted filesystem.
+pub fn sync_all() -> Result<(), FsError> {
+    let table = MOUNT_TABLE.read();
+    for m in table.iter() {
+        m.fs.sync()?;
+    }
+    Ok(())
+}
@@ -2,6 +2,8 @@
 pub mod param;
 pub mod riscv;
 pub mod hartlocal;
+pub mod fdt;
+pub mod random;
 
 use crate::device::clint;
 use crate::trap;
@@ -36,7 +38,8 @@ pub fn timerinit() {
     mstatus |= MSTATUS_MIE;
     write_mstatus(mstatus);
 
-    // Enable machine-mode timer interrupts.
-    let mie = read_mie() | MIE_MTIE;
+    // Enable machine-mode timer interrupts, and software interrupts
+    // for other harts to poke us with (see clint::send_ipi).
+    let mie = read_mie() | MIE_MTIE | MIE_MSIE;
     write_mie(mie);
 }
@@ -0,0 +1,207 @@
+//! Flattened device tree (FDT) reading.
+//!
+//! QEMU hands every hart the physical address of a device tree blob in
+//! `a1` at boot. We only read it: find a node by path and pull out its
+//! properties. The blob lives in memory the page allocator would
+//! otherwise hand out, so early in boot we copy it onto the heap with
+//! `preserve`, after which the original memory can be released.
+//!
+//! Reference: <https://devicetree-specification.readthedocs.io/> (chapter 5)
+
+use alloc::vec::Vec;
+use core::cell::OnceCell;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Where the bootloader left the blob. Zero if we don't know.
+static BOOT_ADDR: AtomicUsize = AtomicUsize::new(0);
+static mut COPY: OnceCell<Vec<u8>> = OnceCell::new();
+
+fn be32(b: &[u8], off: usize) -> Option<u32> {
+    let bytes = b.get(off..off + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Read a big endian 32 or 64 bit cell, like `linux,initrd-start`.
+pub fn be_int(val: &[u8]) -> Option<u64> {
+    match val.len() {
+        4 => be32(val, 0).map(|v| v as u64),
+        8 => Some((be32(val, 0)? as u64) << 32 | be32(val, 4)? as u64),
+        _ => None,
+    }
+}
+
+/// A validated device tree blob.
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: usize,
+    strings: usize,
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header of the blob at the start of `blob`.
+    pub fn new(blob: &'a [u8]) -> Option<Self> {
+        if be32(blob, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(blob, 4)? as usize;
+        if total < HEADER_SIZE || total > blob.len() {
+            return None;
+        }
+        let blob = &blob[..total];
+        let structs = be32(blob, 8)? as usize;
+        let strings = be32(blob, 12)? as usize;
+        if structs >= total || strings > total {
+            return None;
+        }
+        Some(Self { blob, structs, strings })
+    }
+
+    /// The whole blob.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.blob
+    }
+
+    fn string(&self, off: usize) -> Option<&'a [u8]> {
+        let rest = self.blob.get(self.strings + off..)?;
+        let len = rest.iter().position(|&c| c == 0)?;
+        Some(&rest[..len])
+    }
+
+    /// Find property `name` on the node at `path`, like
+    /// `property("/chosen", "bootargs")`. Node names must match
+    /// exactly, including any `@unit` part.
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let want = path.split('/').filter(|c| !c.is_empty());
+        let ncomps = want.clone().count();
+        let mut depth = 0; // 1 is the root node
+        let mut matched = 0; // leading components of `path` we are inside
+        let mut off = self.structs;
+        loop {
+            let token = be32(self.blob, off)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let rest = self.blob.get(off..)?;
+                    let len = rest.iter().position(|&c| c == 0)?;
+                    depth += 1;
+                    if depth >= 2 && matched == depth - 2
+                        && want.clone().nth(depth - 2).map(str::as_bytes) == Some(&rest[..len]) {
+                        matched = depth - 1;
+                    }
+                    off = align4(off + len + 1);
+                },
+                FDT_END_NODE => {
+                    if depth >= 2 && matched == depth - 1 {
+                        matched -= 1;
+                    }
+                    depth = depth.checked_sub(1)?;
+                },
+                FDT_PROP => {
+                    let len = be32(self.blob, off)? as usize;
+                    let nameoff = be32(self.blob, off + 4)? as usize;
+                    let val = self.blob.get(off + 8..off + 8 + len)?;
+                    if depth == ncomps + 1 && matched == ncomps
+                        && self.string(nameoff)? == name.as_bytes() {
+                        return Some(val);
+                    }
+                    off = align4(off + 8 + len);
+                },
+                FDT_NOP => {},
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+
+    /// A string property, without its terminating NUL.
+    pub fn property_str(&self, path: &str, name: &str) -> Option<&'a str> {
+        let val = self.property(path, name)?;
+        let val = val.strip_suffix(&[0]).unwrap_or(val);
+        core::str::from_utf8(val).ok()
+    }
+
+    /// Physical address range of an initial ramdisk the bootloader
+    /// loaded for us (QEMU's `-initrd`).
+    pub fn initrd(&self) -> Option<(usize, usize)> {
+        let start = be_int(self.property("/chosen", "linux,initrd-start")?)? as usize;
+        let end = be_int(self.property("/chosen", "linux,initrd-end")?)? as usize;
+        if end > start { Some((start, end)) } else { None }
+    }
+
+    /// The kernel command line (QEMU's `-append`).
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.property_str("/chosen", "bootargs")
+    }
+}
+
+/// Remember where the bootloader put the device tree. Called from
+/// `_start` in machine mode, before anything else can look.
+pub fn set_boot_addr(addr: usize) {
+    BOOT_ADDR.store(addr, Ordering::Relaxed);
+}
+
+/// The blob where the bootloader left it. Only valid until
+/// `vm::release_boot_memory`.
+fn boot_blob() -> Option<Fdt<'static>> {
+    let addr = BOOT_ADDR.load(Ordering::Relaxed);
+    if addr == 0 {
+        return None;
+    }
+    // Safety: the bootloader promised us a blob here, and the header
+    // tells us how long it is.
+    let header = unsafe { core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+    let total = be32(header, 4)? as usize;
+    Fdt::new(unsafe { core::slice::from_raw_parts(addr as *const u8, total) })
+}
+
+/// Physical address range of the original blob, which must be kept
+/// away from the page allocator until `preserve` has run.
+pub fn boot_range() -> Option<(usize, usize)> {
+    let fdt = boot_blob()?;
+    let start = fdt.bytes().as_ptr() as usize;
+    Some((start, start + fdt.bytes().len()))
+}
+
+/// Copy the blob onto the heap so it outlives the boot memory.
+pub fn preserve() {
+    if let Some(fdt) = boot_blob() {
+        unsafe {
+            if (*addr_of!(COPY)).set(fdt.bytes().to_vec()).is_err() {
+                panic!("Device tree preserved twice!");
+            }
+        }
+    }
+}
+
+/// The device tree we booted with, if there was one.
+pub fn get() -> Option<Fdt<'static>> {
+    match unsafe { (*addr_of!(COPY)).get() } {
+        Some(copy) => Fdt::new(copy),
+        None => boot_blob(),
+    }
+}
+
+/// Look up `key` on the kernel command line. `key=value` gives
+/// `value`, and a bare `key` gives an empty string.
+pub fn bootarg(key: &str) -> Option<&'static str> {
+    get()?.bootargs()?.split_whitespace().find_map(|arg| match arg.split_once('=') {
+        Some((k, v)) if k == key => Some(v),
+        None if arg == key => Some(""),
+        _ => None,
+    })
+}
@@ -5,10 +5,14 @@ use core::arch::asm;
 use alloc::boxed::Box;
 
 use crate::process::Process;
-use crate::hw::riscv::{write_gp, read_gp};
+use crate::hw::riscv::{write_gp, read_gp, write_tp, read_tp};
 
 /// What do we need to restore when returning from a process
+#[repr(C)]
 pub struct GPInfo {
+    // The process owns tp while it runs, so we keep the hart id
+    // here. First, as regular_strap loads it from (gp)
+    pub hartid: u64,
     pub current_process: Process,
     // TODO consider moving the page table and the sp from the
     // sscratch stack to here
@@ -22,6 +26,7 @@ impl GPInfo {
     pub fn new(current_process: Process) -> Self {
         Self {
             current_process,
+            hartid: read_tp(),
         }
     }
 }
@@ -41,17 +46,18 @@ pub fn save_gp_info64(gpi: GPInfo) {
 
 pub fn restore_gp_info64() -> GPInfo {
     let ptr = read_gp() as *mut GPInfo;
-    unsafe {
+    let gpi = unsafe {
         let b_ptr = Box::from_raw(ptr);
 
         Box::into_inner(b_ptr)
-    }
+    };
+    // tp may still be whatever the process left in it
+    write_tp(gpi.hartid);
+    gpi
 }
 
 pub fn hartlocal_info_interrupt_stack_init() {
-    let gpi = GPInfo {
-        current_process: Process::new_uninit(),
-    };
+    let gpi = GPInfo::new(Process::new_uninit());
     save_gp_info64(gpi);
     unsafe {
         asm!(
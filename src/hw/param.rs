@@ -30,6 +30,9 @@ use core::ptr::addr_of_mut;
 /// CLINT base address.
 pub const CLINT_BASE: usize = 0x2000000;
 
+/// CLINT size in memory
+pub const CLINT_SIZE: usize = 0x10000;
+
 /// PLIC base address.
 pub const PLIC_BASE: usize = 0xc000000;
 
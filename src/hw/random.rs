@@ -0,0 +1,61 @@
+//! Kernel random numbers.
+//!
+//! Good enough to randomize where user programs are loaded, NOT for
+//! cryptography. We seed from the `rng-seed` QEMU puts in the device
+//! tree when there is one, and from the timer, and stir the timer in
+//! again on every draw so harts racing for numbers don't predict each
+//! other.
+//!
+//! The generator is splitmix64: <https://prng.di.unimi.it/splitmix64.c>
+
+use crate::device::clint::read_mtime;
+use crate::hw::fdt;
+use crate::lock::mutex::Mutex;
+
+static STATE: Mutex<u64> = Mutex::new(0);
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Seed the generator. Call once, after the device tree is preserved.
+pub fn init() {
+    let mut state = STATE.lock();
+    *state ^= read_mtime();
+    match fdt::get().and_then(|f| f.property("/chosen", "rng-seed")) {
+        Some(seed) => {
+            for chunk in seed.chunks(8) {
+                let mut bytes = [0; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                *state ^= u64::from_le_bytes(bytes);
+                splitmix64(&mut state);
+            }
+        },
+        None => log!(Warning, "No rng-seed in the device tree, seeding from the timer alone"),
+    }
+}
+
+/// A random 64 bit number.
+pub fn next_u64() -> u64 {
+    let mut state = STATE.lock();
+    *state ^= read_mtime();
+    splitmix64(&mut state)
+}
+
+/// A random number below `bound`, which must not be zero.
+pub fn below(bound: u64) -> u64 {
+    // the bias is at most bound / 2^64, which we can live with
+    next_u64() % bound
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
@@ -8,8 +8,9 @@ pub const MSTATUS_MPP_S: u64 = 1 << 11; // Supervisor
 pub const MSTATUS_MPP_U: u64 = 0 << 11; // User
 pub const MSTATUS_MIE: u64 = 1 << 3; // machine-mode interrupt enable.
 pub const MSTATUS_TIMER: u64 = (1 << 63) | (7); // mcause for machine mode timer.
+pub const MSTATUS_SOFTWARE: u64 = (1 << 63) | (3); // mcause for machine mode software.
                                                 // sstatus := Supervisor status reg.
-pub const SSTATUS_SUM: u64 = 1 << 18; // Previous mode, 1=Supervisor, 0=User
+pub const SSTATUS_SUM: u64 = 1 << 18; // Supervisor may access User pages
 pub const SSTATUS_SPP: u64 = 1 << 8; // Previous mode, 1=Supervisor, 0=User
 pub const SSTATUS_SPIE: u64 = 1 << 5; // Supervisor Previous Interrupt Enable
 pub const SSTATUS_UPIE: u64 = 1 << 4; // User Previous Interrupt Enable
@@ -26,6 +27,9 @@ pub const SIE_SEIE: u64 = 1 << 9; // external
 pub const SIE_STIE: u64 = 1 << 5; // timer
 pub const SIE_SSIE: u64 = 1 << 1; // software
 
+/// Supervisor Interrupt Pending
+pub const SIP_SSIP: u64 = 1 << 1; // software
+
 /// Return id of current hart while in machine mode.
 pub fn read_mhartid() -> u64 {
     let id: u64;
@@ -144,6 +148,20 @@ pub fn write_mie(x: u64) {
     }
 }
 
+pub fn read_mip() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, mip", out(reg) x);
+    }
+    x
+}
+
+pub fn write_mip(x: u64) {
+    unsafe {
+        asm!("csrw mip, {}", in(reg) x);
+    }
+}
+
 /// SATP Sv39 mode: (8L << 60)
 // From addr to satp reg: (pagetable) (SATP_SV39 | (((uint64)pagetable) >> 12))
 pub fn read_satp() -> usize {
@@ -269,6 +287,15 @@ pub fn write_mscratch(scratch: usize) {
     }
 }
 
+/// The top of this hart's interrupt stack, see hartlocal.rs.
+pub fn read_sscratch() -> usize {
+    let x: usize;
+    unsafe {
+        asm!("csrr {}, sscratch", out(reg) x);
+    }
+    x
+}
+
 pub fn write_mtvec(addr: usize) {
     unsafe {
         asm!(r#"
@@ -295,6 +322,21 @@ pub fn write_stvec(addr: usize) {
     }
 }
 
+/// Where a supervisor mode trap will return to.
+pub fn read_sepc() -> usize {
+    let pc: usize;
+    unsafe {
+        asm!("csrr {}, sepc", out(reg) pc);
+    }
+    pc
+}
+
+pub fn write_sepc(pc: usize) {
+    unsafe {
+        asm!("csrw sepc, {}", in(reg) pc);
+    }
+}
+
 pub fn read_stvec() -> usize {
     let addr: usize;
     unsafe {
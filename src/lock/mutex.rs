@@ -64,4 +64,12 @@ impl<T> Mutex<T> {
         }
         MutexGuard { mutex: self }
     }
+
+    /// `lock`, unless someone has it already.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        match self.lock_state.swap(1, Ordering::Acquire) {
+            1 => None,
+            _ => Some(MutexGuard { mutex: self }),
+        }
+    }
 }
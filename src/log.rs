@@ -7,7 +7,7 @@ macro_rules! print
         use crate::uart;
         // LSP is confused by macros, this unsafe is required
         #[allow(unused_unsafe)]
-        let mut dev = unsafe {uart::WRITER.lock()};
+        let mut dev = unsafe {(*core::ptr::addr_of!(uart::WRITER)).lock()};
         let _ = write!(dev, $($args)+);
         // let _ = write!(uart::Uart::new().lock(), $($args)+);
     });
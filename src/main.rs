@@ -3,15 +3,14 @@
 #![no_main]
 #![feature(pointer_byte_offsets)]
 #![feature(error_in_core)]
-#![feature(sync_unsafe_cell)]
 #![feature(panic_info_message)]
 #![feature(strict_provenance)]
 #![feature(unsized_fn_params)]
 #![feature(box_into_inner)]
-#![feature(never_type)]
 #![allow(dead_code)]
 use core::cell::OnceCell;
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::panic::PanicInfo;
 extern crate alloc;
 
@@ -40,6 +39,10 @@ static mut GLOBAL_INIT_FLAG: MaybeUninit<ConditionVar> = MaybeUninit::uninit();
 // pass the initial kernel page table to non-zero id harts. This is
 // not how it is accessed after inialization
 static mut KERNEL_PAGE_TABLE: OnceCell<PageTable> = OnceCell::new();
+// whether hart0 found and queued the root process
+static INIT_SPAWNED: AtomicBool = AtomicBool::new(false);
+// how often the sync kthread flushes the buffer cache
+const SYNC_PERIOD_MS: u64 = 5000;
 
 // The never type "!" means diverging function (never returns).
 #[panic_handler]
@@ -62,9 +65,10 @@ fn panic(info: &PanicInfo) -> ! {
 
 /// This gets called from entry.S and runs on each hart.
 /// Run configuration steps that will allow us to run the
-/// kernel in supervisor mode.
+/// kernel in supervisor mode. `fdt` is the device tree address the
+/// bootloader gave us.
 #[no_mangle]
-pub extern "C" fn _start() {
+pub extern "C" fn _start(fdt: usize) {
     // xv6-riscv/kernel/start.c
     let fn_main = main as *const ();
 
@@ -74,7 +78,8 @@ pub extern "C" fn _start() {
     let mut ms = read_mstatus();
     ms &= !MSTATUS_MPP_MASK;
     ms |= MSTATUS_MPP_S;
-    ms |= SSTATUS_SUM;          // allow sup access to user pages
+    // SUM stays clear. We only touch user pages through vm::uaccess.
+    ms &= !SSTATUS_SUM;
     write_mstatus(ms);
 
     // Set machine exception prog counter to
@@ -101,6 +106,9 @@ pub extern "C" fn _start() {
     // Store each hart's hartid in its tp reg for identification.
     let hartid = read_mhartid();
     write_tp(hartid);
+    if hartid == 0 {
+        hw::fdt::set_boot_addr(fdt);
+    }
 
     // Get interrupts from clock and set mtev handler fn.
     hw::timerinit();
@@ -142,6 +150,8 @@ fn main() -> ! {
             }
         }
         log!(Info, "Initialized the kernel page table...");
+        hw::fdt::preserve();
+        hw::random::init();
         plic::global_init();
         log!(Info, "Finished plic globl init...");
         unsafe {
@@ -153,15 +163,53 @@ fn main() -> ! {
         log!(Debug, "Testing phys page extent allocation and freeing...");
         vm::test_phys_page();
         log!(Debug, "Successful phys page extent allocation and freeing...");
+        log!(Debug, "Testing ELF parsing...");
+        file::elf64::test_elf();
+        log!(Debug, "Testing cpio parsing...");
+        file::initramfs::test_cpio();
+        log!(Debug, "Testing FAT names...");
+        file::fat32::test_names();
         
+        let initramfs = match file::mount_initramfs() {
+            Ok(found) => found,
+            Err(e) => {
+                log!(Warning, "Could not unpack the initramfs: {:?}", e);
+                false
+            },
+        };
+        vm::release_boot_memory();
+
         log!(Debug, "Initializing VIRTIO blk device...");
+        // With an initramfs as root the disk goes under /mnt.
+        let disk = if initramfs { "/mnt" } else { "/" };
         if let Err(e) = device::virtio::virtio_block_init() {
             println!("{:?}", e);
+        } else if let Err(e) = file::mount_block(disk) {
+            log!(Warning, "Could not mount the block device: {:?}", e);
+        }
+        if let Err(e) = file::mount_tmp() {
+            log!(Warning, "Could not mount /tmp: {:?}", e);
+        }
+        if let Err(e) = file::mount_proc() {
+            log!(Warning, "Could not mount /proc: {:?}", e);
         }
 
         process::init_process_structure();
         hartlocal::hartlocal_info_interrupt_stack_init();
+        // write dirty blocks back now and then, not just on sync
+        process::kthread::spawn("sync", SYNC_PERIOD_MS, || {
+            if let Err(e) = file::vfs::sync_all() {
+                log!(Warning, "Periodic sync failed: {:?}", e);
+            }
+        });
         log!(Debug, "Successfuly initialized the process system...");
+        // The root process is /init unless the command line says
+        // otherwise with init=<path>.
+        let init = hw::fdt::bootarg("init").unwrap_or("/init");
+        match process::spawn(init) {
+            Ok(()) => INIT_SPAWNED.store(true, Ordering::Release),
+            Err(e) => log!(Warning, "No {} to run ({:?}), running the built in tests", init, e),
+        }
         plic::local_init();
         log!(Info, "Finished plic local init hart0...");
         log!(Info, "Completed all hart0 initialization and testing...");
@@ -185,6 +233,9 @@ fn main() -> ! {
 
     }
     
+    if INIT_SPAWNED.load(Ordering::Acquire) {
+        process::schedule();
+    }
     // we want to test multiple processes with multiple harts
     process::test_multiprocess_syscall();
     //loop {}
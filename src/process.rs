@@ -4,21 +4,28 @@
 // extern crate alloc;
 
 // use alloc::boxed::Box;
-use alloc::collections::vec_deque::*;
-use core::assert;
-use core::mem::{size_of, MaybeUninit};
-use core::ptr::{copy_nonoverlapping, null_mut};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use core::cmp::{max, min};
+use core::mem::size_of;
+use core::ptr::{addr_of, copy_nonoverlapping, null_mut};
 use core::cell::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 // use crate::hw::HartContext;
 // use crate::trap::TrapFrame;
 use crate::vm::ptable::*;
 use crate::vm::VmError;
-use crate::hw::riscv::read_tp;
+use crate::hw::riscv::{read_gp, read_sscratch, read_tp};
 use crate::hw::param::*;
 use crate::vm::{request_phys_page, PhysPageExtent};
 use crate::file::elf64::*;
+use crate::file::vfs::{self, FsError};
 use crate::hw::hartlocal::*;
+use crate::hw::random;
+use crate::device::{clint, uart};
 use crate::lock::mutex::Mutex;
 
 
@@ -28,8 +35,25 @@ use crate::process::pid::*;
 
 mod scheduler;
 use crate::process::scheduler::ProcessQueue;
-
-
+use crate::process::syscall::seccomp::Seccomp;
+use crate::process::signal::{SigInfo, Signals, SEGV_MAPERR, SIGSEGV, SI_KERNEL};
+use crate::process::address_space::AddressSpace;
+use crate::process::fd::FdTable;
+use crate::process::rlimit::Limits;
+use crate::vm::tlb;
+use crate::vm::uaccess::copy_to_user;
+use alloc::sync::Arc;
+
+
+mod address_space;
+mod channel;
+mod fd;
+pub mod kthread;
+mod memfd;
+mod pipe;
+pub mod procfs;
+mod rlimit;
+mod signal;
 #[allow(unused_variables)]
 mod syscall;
 // This should not be exposed to anything, and we don't need to call
@@ -43,8 +67,9 @@ static mut QUEUE: OnceCell<Mutex<ProcessQueue>> = OnceCell::new();
 /// need hartlocal_info_interrupt_stack_init
 pub fn init_process_structure() {
     init_pid_subsystem();
+    syscall::trace::init();
     unsafe {
-        match QUEUE.set(Mutex::new(ProcessQueue::new())) {
+        match (*addr_of!(QUEUE)).set(Mutex::new(ProcessQueue::new())) {
             Ok(()) => {},
             Err(_) => {
                 panic!("Process structure double init!");
@@ -57,7 +82,25 @@ pub fn init_process_structure() {
 //
 // this is a *MOVE* of the process. Handle elsewhere
 fn get_running_process() -> Process {
-    restore_gp_info64().current_process
+    let mut proc = restore_gp_info64().current_process;
+    // it has been in user mode since we last let it go
+    proc.account(true);
+    proc
+}
+
+/// An interrupt taken in user mode is handled without leaving the
+/// process, so `s_handler` marks the switch each way: `true` on the way
+/// in, which ends a stretch of user time, and `false` on the way out.
+pub fn interrupted(entering: bool) {
+    let gpi = read_gp() as *mut GPInfo;
+    let proc = unsafe { &mut (*gpi).current_process };
+    proc.account(entering);
+}
+
+/// The UART interrupt handler kept some input for the console, so
+/// whatever waits to read it can have another go.
+pub fn console_input() {
+    syscall::futex::wake_object(uart::input_key());
 }
 
 #[derive(Debug)]
@@ -69,27 +112,39 @@ pub enum ProcessState {
     // ^ is because ownership alone is risky to ensure safety accross
     // context switches
     Wait,                       // blocked on on something
+    Stopped,                    // by a signal, parked until SIGCONT
     Sleep,                      // out of the running for a bit
     Dead,                       // do not run (needed?)
 }
 
-/// A process. The there is a real possiblity of this being largly
-/// uninitialized, so check the state always
+/// A process, or one thread of one: what the scheduler runs. The
+/// there is a real possiblity of this being largly uninitialized, so
+/// check the state always
+///
+/// Threads made by clone share `address_space` and have the same
+/// `tgid`, the pid of the first of them. `id` is the thread id.
 pub struct Process {
     saved_pc: usize,            // uninit with 0
     saved_sp: usize,            // uninit with 0
     id: usize,                  // uninit with 0
+    tgid: usize,                // uninit with 0
+    parent: usize,              // tgid of whoever forked it, 0 for none
     state: ProcessState,        // use uninit state
-    pgtbl: PageTable,                     // uninizalied with null
-    phys_pages: MaybeUninit<VecDeque<PhysPageExtent>>, // vec to avoid Ord requirement
-    // ^ hopefully it's clear how this is uninit
-    // TODO consider this as a OnceCell
+    pgtbl: PageTable,           // address_space's, kept handy, uninizalied with null
+    address_space: Arc<Mutex<AddressSpace>>, // uninit with an empty one
+    clear_child_tid: usize,     // zeroed when the thread exits, uninit with 0
+    files: Arc<Mutex<FdTable>>, // open files, uninit with just the console
+    name: String,               // its program's file name, uninit with none
+    traced: bool,               // log its syscalls, see syscall::trace
+    seccomp: Seccomp,           // syscall filters, uninit with none
+    signals: Signals,           // handlers and mask, uninit with defaults
+    limits: Arc<Mutex<Limits>>, // resource limits and CPU time, uninit with defaults
+    user_time: u64,             // mtime ticks this thread spent in user mode
+    system_time: u64,           // and in the kernel, both uninit with 0
+    since: u64,                 // mtime of its last switch between user and
+                                // kernel, 0 when it's not on a hart
 
     // sleep_time: usize           // uninit with 0, only valid with sleep state
-
-    // currently unused, but needed in the future
-    // address_space: BTreeSet<Box<dyn Resource>>, // todo: Balanced BST of Resources
-
 }
 
 impl Process {
@@ -98,28 +153,43 @@ impl Process {
     pub fn new_uninit() -> Self {
         let out = Self {
             id: 0,
+            tgid: 0,
+            parent: 0,
             state: ProcessState::Uninitialized,
             pgtbl: PageTable::new(null_mut()),
-            phys_pages: MaybeUninit::uninit(),
+            address_space: Arc::new(Mutex::new(AddressSpace::empty())),
+            clear_child_tid: 0,
+            files: Arc::new(Mutex::new(FdTable::new())),
             saved_pc: 0,
             saved_sp: 0,
+            name: String::new(),
+            traced: false,
+            seccomp: Seccomp::default(),
+            signals: Signals::new(),
+            limits: Arc::new(Mutex::new(Limits::new())),
+            user_time: 0,
+            system_time: 0,
+            since: 0,
         };
         out
     }
 
-    pub fn initialize64(&mut self, elf: &ELFProgram) -> Result<(), ELFError> {
+    /// Load `elf` into this process, with `argv` and `envp` on its
+    /// initial stack.
+    pub fn initialize64(&mut self, elf: &ELFProgram, argv: &[&str], envp: &[&str]) -> Result<(), ELFError> {
         // Doesn't assert uninitialized state so you can do a write over of an existing process
+        if elf.header.width != AddrWidth::DoubleWord {
+            return Err(ELFError::UnsupportedWidth);
+        }
 
         match self.state {
             ProcessState::Uninitialized => {
                 self.id = generate_new_pid();
-                let pt = request_phys_page(1)
-                    .expect("Could not allocate a page table for a new process.");
-                self.pgtbl = PageTable::new(pt.start());
-                self.phys_pages.write(VecDeque::new());
-                unsafe {
-                    self.phys_pages.assume_init_mut().push_back(pt);
-                }
+                self.tgid = self.id;
+                signal::register(self.id, self.tgid, self.parent);
+                let space = AddressSpace::new().map_err(|_| ELFError::FailedAlloc)?;
+                self.pgtbl = space.pgtbl;
+                self.address_space = Arc::new(Mutex::new(space));
             },
             ProcessState::Running => {
                 panic!("Tried to re-initialize a running process!");
@@ -127,18 +197,144 @@ impl Process {
             _ => {},
         }
 
-        self.populate_pagetable64(elf)?;
-        match self.map_kernel_text() {
-            Ok(_) => {},
-            Err(_) => {
-                panic!("Failed to map kernel text into process space!");
-            }
-        }
-        self.saved_pc = elf.header.entry;
+        let bias = self.populate_pagetable64(elf)?;
+        self.setup_stack(elf, bias, argv, envp)?;
+        self.map_kernel_text().map_err(|_| ELFError::FailedMap)?;
+        self.saved_pc = elf.header.entry.wrapping_add(bias);
         self.state = ProcessState::Unstarted;
         Ok(())
     }
 
+    /// Read the executable at `path` through the VFS and build a
+    /// process from it, ready to start.
+    pub fn load(path: &str, argv: &[&str], envp: &[&str]) -> Result<Self, ExecError> {
+        let node = vfs::lookup(path).map_err(ExecError::File)?;
+        let bytes = vfs::read_all(&*node).map_err(ExecError::File)?;
+        let program = ELFProgram::new64(&bytes).map_err(ExecError::Elf)?;
+        let mut proc = Process::new_uninit();
+        proc.name = String::from(path.rsplit('/').next().unwrap_or(path));
+        proc.initialize64(&program, argv, envp).map_err(ExecError::Elf)?;
+        Ok(proc)
+    }
+
+    /// Throw away this process's program and memory and take on
+    /// `image`'s instead, keeping our pid. This is the back half of
+    /// `execve`.
+    fn replace_image(&mut self, mut image: Process) {
+        core::mem::swap(&mut self.pgtbl, &mut image.pgtbl);
+        core::mem::swap(&mut self.address_space, &mut image.address_space);
+        self.saved_pc = image.saved_pc;
+        self.saved_sp = image.saved_sp;
+        self.clear_child_tid = 0;
+        self.files.lock().exec();
+        self.signals.exec();
+        self.name = image.name.clone();
+        self.state = ProcessState::Unstarted;
+        procfs::register(self);
+        // image now holds our old address space, which goes when it
+        // does, unless other threads still have it
+    }
+
+    /// A new thread of this process, sharing its memory, open files and
+    /// signal handlers, not yet ready to run.
+    fn new_thread(&self) -> Process {
+        let mut thread = Process::new_uninit();
+        thread.id = generate_new_pid();
+        thread.tgid = self.tgid;
+        thread.parent = self.parent;
+        signal::register(thread.id, thread.tgid, thread.parent);
+        thread.pgtbl = self.pgtbl;
+        thread.address_space = self.address_space.clone();
+        thread.files = self.files.clone();
+        thread.name = self.name.clone();
+        thread.traced = self.traced;
+        thread.seccomp = self.seccomp.clone();
+        thread.signals = self.signals.clone();
+        thread.signals.restarting = false;
+        thread.limits = self.limits.clone();
+        thread
+    }
+
+    /// A new process forked from this thread, with a copy of its memory
+    /// and limits, sharing its open files and seccomp filters, and with
+    /// the same signal handlers and mask. Not yet ready to run.
+    fn fork(&self) -> Result<Process, VmError> {
+        let mut child = Process::new_uninit();
+        let space = self.address_space.lock().fork()?;
+        child.pgtbl = space.pgtbl;
+        child.address_space = Arc::new(Mutex::new(space));
+        child.map_kernel_text()?;
+        child.id = generate_new_pid();
+        child.tgid = child.id;
+        child.parent = self.tgid;
+        signal::register(child.id, child.tgid, child.parent);
+        child.files = self.files.clone();
+        child.name = self.name.clone();
+        child.traced = self.traced;
+        child.seccomp = self.seccomp.clone();
+        child.signals = self.signals.clone();
+        child.signals.restarting = false;
+        child.limits = Arc::new(Mutex::new(self.limits.lock().fork()));
+        Ok(child)
+    }
+
+    /// Charge the time since its last switch between user and kernel
+    /// mode to this thread and its process, as user time if `user`,
+    /// and send whatever RLIMIT_CPU says is due for it.
+    fn account(&mut self, user: bool) {
+        self.tally(user);
+        procfs::update(self);
+        let sig = self.limits.lock().cpu_signal();
+        if let Some(sig) = sig {
+            let info = SigInfo { code: SI_KERNEL, pid: 0, addr: 0 };
+            let _ = signal::send(self.id, sig, info);
+        }
+    }
+
+    // The charging half of `account`, which can be done with the
+    // scheduler's locks held. Nothing, if it wasn't on a hart.
+    fn tally(&mut self, user: bool) {
+        let now = clint::read_mtime();
+        let last = core::mem::replace(&mut self.since, now);
+        if last == 0 {
+            return;
+        }
+        let ticks = now.saturating_sub(last);
+        match user {
+            true => self.user_time += ticks,
+            false => self.system_time += ticks,
+        }
+        self.limits.lock().charge(ticks, user);
+    }
+
+    /// As it leaves the hart, to wait or be queued, its time in the
+    /// kernel stops counting: waiting to run is nobody's CPU time. A
+    /// signal for going over RLIMIT_CPU waits for it to run again.
+    fn off_cpu(&mut self) {
+        self.tally(false);
+        self.since = 0;
+        procfs::update(self);
+    }
+
+    /// Overwrite a register in the frame saved on the process stack at
+    /// `sp`, like a0 to hand back a return value. The
+    /// process picks `sp`, so unless that is its own writable memory it
+    /// gets SIGSEGV instead.
+    fn write_saved_reg(&mut self, sp: usize, reg: usize, val: usize) {
+        let va = sp.wrapping_add(reg * size_of::<usize>()) as *mut usize;
+        let pa = match sp % size_of::<usize>() {
+            0 => unsafe { translate(self.pgtbl, va, user_process_flags(true, true, false)) },
+            _ => None,
+        };
+        match pa {
+            Some(pa) => unsafe { pa.write(val) },
+            None => {
+                let info = SigInfo { code: SEGV_MAPERR, pid: 0, addr: va as usize };
+                signal::force(self, SIGSEGV, info);
+            },
+        }
+    }
+
     // TODO is this the right error type?
     fn map_kernel_text(&mut self) -> Result<(), VmError> {
         // This is currently a large copy of kpage_init with a few tweaks
@@ -244,6 +440,17 @@ impl Process {
         )?;
         // log!(Debug, "Succesfully mapped kernel heap into process...");
 
+        // and the one page of it the process itself runs, which signal
+        // handlers return through
+        extern "C" {fn sigreturn_trampoline();}
+        page_map(
+            self.pgtbl,
+            VirtAddress::from(signal::SIGRETURN_VA as *mut usize),
+            PhysAddress::from(sigreturn_trampoline as *mut usize),
+            PAGE_SIZE,
+            user_process_flags(true, false, true),
+        )?;
+
         Ok(())
     }
 
@@ -252,89 +459,198 @@ impl Process {
     /// Copies the LOAD segment memory layout from the elf to the
     /// program. This is not the only initialization step.
     ///
-    /// This also setups up the program stack and sets saved_sp
-    fn populate_pagetable64(&mut self, elf: &ELFProgram) -> Result<(), ELFError>{
-        assert!(elf.header.program_entry_size as usize == size_of::<ProgramHeaderSegment64>(),
-                "Varying ELF entry size expectations.");
-
-        let num = elf.header.num_program_entries;
-        let ptr = unsafe {
-            elf.source.add(elf.header.program_header_pos)
-                as *const ProgramHeaderSegment64
+    /// Segments need not start on a page boundary, and neighbouring
+    /// segments may share a page, which then gets the permissions of
+    /// both. Memory past the end of a segment's file data (.bss) is
+    /// zero.
+    ///
+    /// The heap (brk) starts on the page after the end of the image.
+    fn populate_pagetable64(&mut self, elf: &ELFProgram) -> Result<usize, ELFError>{
+        // every page of the image by user address, with its PTE flags
+        let mut image: BTreeMap<usize, (PhysPageExtent, usize)> = BTreeMap::new();
+
+        // how far from its linked addresses we load the program
+        let bias = match elf.header.elf_type {
+            ELFType::Shared => pie_load_bias(elf)?,
+            _ => 0,
         };
-        for i in 0..num {
-            let segment = unsafe { *ptr.add(i as usize) };
+
+        for segment in elf.program_headers() {
             if segment.seg_type != ProgramSegmentType::Load { continue; }
-            else if segment.vmem_addr < 0x1000  { return Err(ELFError::MappedZeroPage) }
-            else if segment.vmem_addr >= text_start().addr() as u64 &&
-                segment.vmem_addr <= text_end().addr() as u64 {
-                    return Err(ELFError::MappedKernelText)
-                }
-            else if segment.size_in_file != segment.size_in_memory {return Err(ELFError::InequalSizes)}
-            else if segment.alignment > 0x1000 {return Err(ELFError::ExcessiveAlignment)}
+            else if segment.size_in_file > segment.size_in_memory {return Err(ELFError::InequalSizes)}
+            else if segment.size_in_memory == 0 { continue; }
 
-            let n_pages = (segment.size_in_memory + (0x1000 - 1)) / 0x1000;
-            let pages = match request_phys_page(n_pages as usize) {
-                Ok(p) => {p},
-                Err(_) => {return Err(ELFError::FailedAlloc)}
+            let start = match (segment.vmem_addr as usize).checked_add(bias) {
+                Some(start) => start,
+                None => return Err(ELFError::BadAddress),
             };
-            unsafe {
-                copy_nonoverlapping(elf.source.add(segment.file_offset as usize),
-                                    pages.start() as *mut u8,
-                                    segment.size_in_file as usize);
+            let end = match start.checked_add(segment.size_in_memory as usize) {
+                Some(end) if end <= USER_VA_TOP => end,
+                _ => return Err(ELFError::BadAddress),
+            };
+            if start < PAGE_SIZE { return Err(ELFError::MappedZeroPage) }
+            if start < memory_end().addr() && end > text_start().addr() {
+                return Err(ELFError::MappedKernelText)
             }
+
             let flags = user_process_flags(
                 (segment.flags as u16) & PROG_SEG_READ != 0,
                 (segment.flags as u16) & PROG_SEG_WRITE != 0,
                 (segment.flags as u16) & PROG_SEG_EXEC != 0
             );
+            let data = elf.segment_data(&segment);
+            let data_end = start + data.len();
+
+            let mut va = start & !(PAGE_SIZE - 1);
+            while va < end {
+                let (page, page_flags) = match image.entry(va) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => match request_phys_page(1) {
+                        // comes to us zeroed
+                        Ok(p) => e.insert((p, 0)),
+                        Err(_) => {return Err(ELFError::FailedAlloc)}
+                    },
+                };
+                *page_flags |= flags;
+                // the part of the file data that lands in this page
+                let lo = max(va, start);
+                let hi = min(va + PAGE_SIZE, data_end);
+                if lo < hi {
+                    unsafe {
+                        copy_nonoverlapping(data[lo - start..].as_ptr(),
+                                            (page.start() as *mut u8).add(lo - va),
+                                            hi - lo);
+                    }
+                }
+                va += PAGE_SIZE;
+            }
+        }
+
+        for rela in elf.relocations()? {
+            match rela.kind {
+                R_RISCV_NONE => {},
+                R_RISCV_RELATIVE => {
+                    let target = (rela.offset as usize).wrapping_add(bias);
+                    let val = (bias as i64).wrapping_add(rela.addend) as u64;
+                    image_write(&mut image, target, &val.to_le_bytes())?;
+                },
+                _ => return Err(ELFError::UnsupportedRelocation),
+            }
+        }
 
+        // everything in the image is below the last page
+        let image_end = image.keys().next_back().map_or(PAGE_SIZE, |va| va + PAGE_SIZE);
+        let mut space = self.address_space.lock();
+        space.heap_start = image_end;
+        space.brk = image_end;
+        space.heap_end = image_end;
+        space.mmap_base = MMAP_TOP;
+
+        for (va, (page, flags)) in image {
             match page_map(
                 self.pgtbl,
-                VirtAddress::from(segment.vmem_addr as *mut usize),
-                PhysAddress::from(pages.start() as *mut usize),
-                n_pages as usize,
+                VirtAddress::from(va as *mut usize),
+                PhysAddress::from(page.start()),
+                PAGE_SIZE,
                 flags
             ) {
                 Ok(_) => {},
                 Err(_) => {return Err(ELFError::FailedMap)}
             }
-            unsafe {
-                self.phys_pages.assume_init_mut().push_back(pages);
-            }
+            space.keep(page);
         }
 
+        Ok(bias)
+    }
+
+    /// Map the process stack and lay out what the RISC-V Linux ABI
+    /// promises a new program finds there, then set saved_sp to it:
+    ///
+    /// ```text
+    ///   sp -> argc
+    ///         argv[0], ..., NULL
+    ///         envp[0], ..., NULL
+    ///         auxv (type, value) pairs, ending with AT_NULL
+    ///         padding to keep sp 16 byte aligned
+    ///         16 random bytes for AT_RANDOM
+    ///         the argument and environment strings
+    ///   top
+    /// ```
+    fn setup_stack(&mut self, elf: &ELFProgram, bias: usize,
+                   argv: &[&str], envp: &[&str]) -> Result<(), ELFError> {
         // TODO what does process heap look like? depends on our syscalls I guess?
         // We would map it here if we had any
 
-        // map the process stack. They will get 2 pages for now
-        const STACK_PAGES: usize = 2;
+        // map the process stack, ending at a random page under STACK_TOP
+        let stack_size = STACK_PAGES * PAGE_SIZE;
+
+        let mut blob = vec![0; 16];
+        random::fill(&mut blob);
+        let mut strings = Vec::with_capacity(argv.len() + envp.len());
+        for arg in argv.iter().chain(envp) {
+            strings.push(blob.len());
+            blob.extend_from_slice(arg.as_bytes());
+            blob.push(0);
+        }
+        if blob.len() > stack_size {
+            return Err(ELFError::ArgumentsTooLong);
+        }
+
+        // TODO guard page? you'll get a page fault anyway?
+        let stack_top = STACK_TOP - random::below(STACK_SLOTS) as usize * PAGE_SIZE;
+        let stack_base = stack_top - stack_size;
+        let blob_start = stack_top - blob.len();
+
+        let mut words = Vec::new();
+        words.push(argv.len());
+        words.extend(strings[..argv.len()].iter().map(|off| blob_start + off));
+        words.push(0);
+        words.extend(strings[argv.len()..].iter().map(|off| blob_start + off));
+        words.push(0);
+        if let Some(phdr) = elf.program_headers_vaddr() {
+            words.extend([AT_PHDR, (phdr as usize).wrapping_add(bias)]);
+            words.extend([AT_PHENT, elf.header.program_entry_size as usize]);
+            words.extend([AT_PHNUM, elf.header.num_program_entries as usize]);
+        }
+        words.extend([AT_PAGESZ, PAGE_SIZE]);
+        words.extend([AT_ENTRY, elf.header.entry.wrapping_add(bias)]);
+        words.extend([AT_RANDOM, blob_start]);
+        words.extend([AT_NULL, 0]);
+
+        let sp = match (blob_start - stack_base).checked_sub(words.len() * size_of::<usize>()) {
+            Some(off) => (stack_base + off) & !0xf,
+            None => 0,
+        };
+        if sp < stack_base {
+            return Err(ELFError::ArgumentsTooLong);
+        }
+
         let stack_pages = match request_phys_page(STACK_PAGES) {
             Ok(p) => {p},
             Err(_) => {
                 return Err(ELFError::FailedAlloc);
             }
         };
-        // TODO guard page? you'll get a page fault anyway?
-        let process_stack_location = unsafe {
-            text_start().sub(0x1000 * STACK_PAGES)
-        };
-        // under the kernel text
+        // The stack comes to us zeroed, and is one physically
+        // contiguous piece, so we can fill it in before it is mapped.
+        let mem = stack_pages.start() as *mut u8;
+        unsafe {
+            copy_nonoverlapping(blob.as_ptr(), mem.add(blob_start - stack_base), blob.len());
+            copy_nonoverlapping(words.as_ptr(), mem.add(sp - stack_base) as *mut usize, words.len());
+        }
+
         match page_map(
             self.pgtbl,
-            VirtAddress::from(process_stack_location),
+            VirtAddress::from(stack_base as *mut usize),
             PhysAddress::from(stack_pages.start()),
-            STACK_PAGES,
+            stack_size,
             user_process_flags(true, true, false)
         ) {
             Ok(_) =>{},
             Err(_) => {return Err(ELFError::FailedMap)}
         }
-        self.saved_sp = stack_pages.end() as usize;
-        unsafe {
-            self.phys_pages.assume_init_mut().push_back(stack_pages);
-        }
-
+        self.saved_sp = sp;
+        self.address_space.lock().keep(stack_pages);
         Ok(())
     }
 
@@ -365,6 +681,7 @@ impl Process {
         let saved_pc = self.saved_pc;
         let pgtbl_base = self.pgtbl.base as usize;
         let saved_sp = self.saved_sp;
+        self.account(false);
         let gpi = GPInfo::new(self);
         save_gp_info64(gpi);
 
@@ -374,6 +691,7 @@ impl Process {
             // be. We want to do that later in the asm.
             //
             // relies on args in a0, a1, a2 in order
+            tlb::enter_user();
             process_start_asm(saved_pc, pgtbl_base, saved_sp);
         }
     }
@@ -389,22 +707,46 @@ impl Process {
                 panic!("Attempted to resume a process that was not marked as Ready.")
             },
         }
-        self.state = ProcessState::Running;
+        // the kernel's done with it, and any signals that brings on go
+        // in now, as the last stop before user mode
+        self.account(false);
+        let mut proc = signal::deliver(self);
+        // the registers come off the process stack here, where a bad
+        // sp is the process's fault, not in process_resume_asm
+        let mut regs = match signal::read_regs(proc.pgtbl, proc.saved_sp) {
+            Ok(regs) => regs,
+            Err(_) => signal::process_kill(proc, SIGSEGV),
+        };
+        proc.state = ProcessState::Running;
 
         extern "C" {pub fn process_resume_asm(pc: usize, pgtbl: usize, sp: usize) -> !;}
 
-        let saved_pc = self.saved_pc;
-        let pgtbl_base = self.pgtbl.base as usize;
-        let saved_sp = self.saved_sp;
-        let gpi = GPInfo::new(self);
+        let saved_pc = proc.saved_pc;
+        let pgtbl_base = proc.pgtbl.base as usize;
+        let user_sp = proc.saved_sp.wrapping_add(signal::REG_FRAME);
+        let gpi = GPInfo::new(proc);
         save_gp_info64(gpi);
 
+        let frame = trap_frame();
+        // load_gp_regs loads sp from the frame before the rest
+        regs[2] = frame as usize;
         unsafe {
-            process_resume_asm(saved_pc, pgtbl_base, saved_sp);
+            frame.write(regs);
+            tlb::enter_user();
+            process_resume_asm(saved_pc, pgtbl_base, user_sp);
         }
     }
 }
 
+/// Where the trap code keeps a process's registers while they move
+/// between it and the process stack: just under the top of this
+/// hart's sscratch stack, which is kernel memory mapped into every
+/// process. `scall_asm` leaves them here on the way in, and
+/// `process_resume_asm` loads them from here on the way out.
+fn trap_frame() -> *mut [usize; 32] {
+    (read_sscratch() - signal::REG_FRAME) as *mut [usize; 32]
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
         match self.state {
@@ -413,16 +755,68 @@ impl Drop for Process {
             }
             _ => {}
         }
+        signal::unregister(self.id);
+        procfs::unregister(self.id);
         return_used_pid(self.id);
-        // dropping the phys pages vector will automatically clean
-        // those up
+        // the address space goes with the last thread holding it
+    }
+}
+
+// Position independent programs load at a random spot in
+// [PIE_BASE, PIE_BASE + PIE_SPAN), well clear of the kernel's mappings.
+const PIE_BASE: usize = 0x10_0000_0000;
+const PIE_SPAN: usize = 0x10_0000_0000;
+// User stacks end at one of the STACK_SLOTS pages under STACK_TOP,
+// and are STACK_PAGES long, 128 KiB, as they don't grow yet. That is
+// enough for what libc and a program's arguments want, if well short
+// of RLIMIT_STACK, which we only check this against.
+const STACK_TOP: usize = 0x30_0000_0000;
+const STACK_SLOTS: u64 = 1 << 16;
+const STACK_PAGES: usize = 32;
+// mmap hands out memory downwards from here, below the stacks. The
+// heap grows up to meet it.
+const MMAP_TOP: usize = 0x2f_0000_0000;
+
+// Pick a random load bias for a position independent program that
+// keeps every segment at its alignment.
+fn pie_load_bias(elf: &ELFProgram) -> Result<usize, ELFError> {
+    let mut align = PAGE_SIZE as u64;
+    let mut hi = 0;
+    for segment in elf.program_headers() {
+        if segment.seg_type != ProgramSegmentType::Load { continue; }
+        align = max(align, segment.alignment);
+        hi = max(hi, segment.vmem_addr.checked_add(segment.size_in_memory)
+                 .ok_or(ELFError::BadAddress)?);
+    }
+    if align > PIE_SPAN as u64 {
+        return Err(ELFError::BadAlignment);
+    }
+    if hi > PIE_SPAN as u64 {
+        return Err(ELFError::BadAddress);
+    }
+    let slots = (PIE_SPAN as u64 - hi) / align + 1;
+    Ok(PIE_BASE + (random::below(slots) * align) as usize)
+}
+
+// Write `bytes` at user address `va` of a program image that isn't
+// mapped yet.
+fn image_write(image: &mut BTreeMap<usize, (PhysPageExtent, usize)>,
+               va: usize, bytes: &[u8]) -> Result<(), ELFError> {
+    for (i, byte) in bytes.iter().enumerate() {
+        let addr = va.wrapping_add(i);
+        match image.get_mut(&(addr & !(PAGE_SIZE - 1))) {
+            Some((page, _)) => unsafe {
+                *(page.start() as *mut u8).add(addr % PAGE_SIZE) = *byte;
+            },
+            None => return Err(ELFError::BadRelocation),
+        }
     }
+    Ok(())
 }
 
 /// Suspend process so that it can be restored/restarted later. Called
 /// from syscalls currently
-fn process_pause(pc: usize, sp: usize, cause: usize) -> ! {
-    let mut proc = get_running_process();
+fn process_pause(mut proc: Process, pc: usize, sp: usize, cause: usize) -> ! {
     proc.saved_pc = pc + 4;
     // ^ ecall doesn't automatically increment pc
     proc.saved_sp = sp;
@@ -439,51 +833,116 @@ fn process_pause(pc: usize, sp: usize, cause: usize) -> ! {
     // log!(Debug, "Hart {}: Process {} yielded.", read_tp(), proc.id);
 
 
-    // This is careful code to avoid holding the lock when we enter
-    // the process, as that would lead to an infinite lock
-    let next;
     unsafe {
-        let mut locked = QUEUE.get().unwrap().lock();
-        locked.insert(proc);
-        next = locked.get_ready_process();
+        (*addr_of!(QUEUE)).get().unwrap().lock().insert(proc);
     }
-    match next.state {
-        ProcessState::Ready => {next.resume()},
-        ProcessState::Unstarted => {next.start()},
-        _ => {panic!("Bad process state from scheduler!")}
+    schedule();
+}
+
+/// Put the running process back in the queue to make the same
+/// syscall again later, because it can't finish yet.
+fn process_retry(mut proc: Process, pc: usize, sp: usize) -> ! {
+    proc.signals.restarting = true;
+    proc.saved_pc = pc;
+    proc.saved_sp = sp;
+    proc.state = ProcessState::Ready;
+    unsafe {
+        (*addr_of!(QUEUE)).get().unwrap().lock().insert(proc);
     }
+    schedule();
 }
 
 #[no_mangle]
 pub extern "C" fn process_exit_rust(exit_code: isize) -> ! {
-    let proc = get_running_process();
+    process_exit(get_running_process(), exit_code);
+}
+
+/// End `proc`, which was running, and move on to something else.
+fn process_exit(mut proc: Process, exit_code: isize) -> ! {
     log!(Debug, "Process {} exited with code {}.", proc.id, exit_code);
+    if proc.clear_child_tid != 0 {
+        // tell whoever joins the thread. Nothing to be done if it's bad
+        if copy_to_user(proc.pgtbl, proc.clear_child_tid, &0u32.to_le_bytes()).is_ok() {
+            syscall::futex::wake_one(&proc, proc.clear_child_tid);
+        }
+    }
+    proc.off_cpu();
+    proc.state = ProcessState::Dead;
     drop(proc);
     // ^ ensure that the never returning scheduler call doesn't extend
     // the life of the process
 
 
-    // This is careful code to avoid holding the lock when we enter
-    // the process, as that would lead to an infinite lock
-    let next;
-    unsafe {
-        let mut locked = QUEUE.get().unwrap().lock();
-        next = locked.get_ready_process();
+    schedule();
+}
+
+/// Run the next ready process on this hart, waiting for one to show
+/// up if there is nothing to do right now. Kernel threads that are due
+/// get their turn first, and futex waits that have timed out go back in
+/// the queue.
+pub fn schedule() -> ! {
+    let mut idle_since = 0;
+    loop {
+        kthread::run_due();
+        syscall::futex::expire();
+        // This is careful code to avoid holding the lock when we enter
+        // the process, as that would lead to an infinite lock
+        let next;
+        unsafe {
+            next = (*addr_of!(QUEUE)).get().unwrap().lock().try_get_ready_process();
+        }
+        match next {
+            Some(next) => match next.state {
+                ProcessState::Ready => {next.resume()},
+                ProcessState::Unstarted => {next.start()},
+                _ => {panic!("Bad process state from scheduler!")}
+            },
+            None => {
+                // time between two goes round with nothing to run
+                let now = clint::read_mtime();
+                if idle_since != 0 {
+                    IDLE.fetch_add(now - idle_since, Ordering::Relaxed);
+                }
+                idle_since = now;
+                core::hint::spin_loop()
+            },
+        }
     }
-    match next.state {
-        ProcessState::Ready => {next.resume()},
-        ProcessState::Unstarted => {next.start()},
-        _ => {panic!("Bad process state from scheduler!")}
+}
+
+// mtime ticks harts have spent in `schedule` with nothing to run.
+static IDLE: AtomicU64 = AtomicU64::new(0);
+
+/// How long the harts have spent idle, in mtime ticks, between them.
+pub fn idle_ticks() -> u64 {
+    IDLE.load(Ordering::Relaxed)
+}
+
+/// Ways loading a program from a file can fail.
+#[derive(Debug)]
+pub enum ExecError {
+    File(FsError),
+    Elf(ELFError),
+}
+
+/// Load the executable at `path` into a new process and queue it to
+/// run.
+pub fn spawn(path: &str) -> Result<(), ExecError> {
+    let proc = Process::load(path, &[path], &[])?;
+    log!(Info, "Spawned process {} from {}", proc.id, path);
+    unsafe {
+        (*addr_of!(QUEUE)).get().unwrap().lock().insert(proc);
     }
+    Ok(())
 }
 
 
 pub fn _test_process_spin() {
     let bytes = include_bytes!("programs/spin/spin.elf");
-    let program = ELFProgram::new64(&bytes[0] as *const u8);
+    let program = ELFProgram::new64(bytes).expect("Built in test program is not a valid ELF.");
     let mut proc = Process::new_uninit();
 
-    match proc.initialize64(&program) {
+    match proc.initialize64(&program, &[], &[]) {
         Ok(_) => {},
         Err(e) => {panic!("Couldn't start process: {:?}", e)}
     }
@@ -492,10 +951,10 @@ pub fn _test_process_spin() {
 
 pub fn _test_process_syscall_basic() {
     let bytes = include_bytes!("programs/syscall-basic/syscall-basic.elf");
-    let program = ELFProgram::new64(&bytes[0] as *const u8);
+    let program = ELFProgram::new64(bytes).expect("Built in test program is not a valid ELF.");
     let mut proc = Process::new_uninit();
 
-    match proc.initialize64(&program) {
+    match proc.initialize64(&program, &[], &[]) {
         Ok(_) => {},
         Err(e) => {panic!("Couldn't start process: {:?}", e)}
     }
@@ -504,10 +963,10 @@ pub fn _test_process_syscall_basic() {
 
 pub fn test_multiprocess_syscall() {
     let bytes = include_bytes!("programs/syscall-basic/syscall-basic.elf");
-    let program = ELFProgram::new64(&bytes[0] as *const u8);
+    let program = ELFProgram::new64(bytes).expect("Built in test program is not a valid ELF.");
     let mut proc = Process::new_uninit();
 
-    match proc.initialize64(&program) {
+    match proc.initialize64(&program, &[], &[]) {
         Ok(_) => {},
         Err(e) => {panic!("Couldn't start process: {:?}", e)}
     }
@@ -515,19 +974,19 @@ pub fn test_multiprocess_syscall() {
     for _ in 0..4 {
         let mut proc = Process::new_uninit();
 
-        match proc.initialize64(&program) {
+        match proc.initialize64(&program, &[], &[]) {
             Ok(_) => {},
             Err(e) => {panic!("Couldn't start process: {:?}", e)}
         }
 
         unsafe {
-            QUEUE.get().unwrap().lock().insert(proc)
+            (*addr_of!(QUEUE)).get().unwrap().lock().insert(proc)
         }
     }
 
     let enter;
     unsafe {
-        enter = QUEUE.get().unwrap().lock().get_ready_process();
+        enter = (*addr_of!(QUEUE)).get().unwrap().lock().get_ready_process();
     }
     match enter.state {
         ProcessState::Unstarted => enter.start(),
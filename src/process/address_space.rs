@@ -0,0 +1,209 @@
+//! The memory a process runs in: its page table, the physical pages
+//! mapped into it, and where its heap and mmap areas are.
+//!
+//! Threads of one process share a single `AddressSpace` through an
+//! `Arc`, and the pages go back to the pool when the last of them lets
+//! go of it. Pages shared with other address spaces, like those of a
+//! memfd, are refcounted and only go back once nobody has them.
+
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use crate::hw::param::PAGE_SIZE;
+use crate::vm::ptable::*;
+use crate::vm::tlb;
+use crate::vm::{request_phys_page, PhysPageExtent, VmError};
+
+pub struct AddressSpace {
+    pub pgtbl: PageTable,
+    pages: VecDeque<PhysPageExtent>, // vec to avoid Ord requirement
+    shared: Vec<Arc<PhysPageExtent>>, // mapped here and somewhere else too
+    pub heap_start: usize,          // first page after the program image
+    pub brk: usize,                 // current program break
+    pub heap_end: usize,            // end of the pages mapped for the heap
+    pub mmap_base: usize,           // lowest address handed out by mmap
+    pub mapped: usize,              // bytes of user memory, for RLIMIT_AS
+}
+
+// The page table is ours, and only changes with the address space
+// locked. The copies threads keep in `Process::pgtbl` only read it,
+// which is what the hardware does anyway, from whichever hart.
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// Nothing at all, for a process that isn't initialized.
+    pub fn empty() -> Self {
+        Self {
+            pgtbl: PageTable::new(null_mut()),
+            pages: VecDeque::new(),
+            shared: Vec::new(),
+            heap_start: 0,
+            brk: 0,
+            heap_end: 0,
+            mmap_base: 0,
+            mapped: 0,
+        }
+    }
+
+    /// A fresh page table with nothing mapped yet.
+    pub fn new() -> Result<Self, VmError> {
+        let pt = request_phys_page(1)?;
+        let mut out = Self::empty();
+        out.pgtbl = PageTable::new(pt.start());
+        out.pages.push_back(pt);
+        Ok(out)
+    }
+
+    /// Hold on to `page`, which has been mapped in, for as long as
+    /// the address space lives.
+    pub fn keep(&mut self, page: PhysPageExtent) {
+        self.mapped += page.end() as usize - page.start() as usize;
+        self.pages.push_back(page);
+    }
+
+    /// A copy for a forked process: the same layout, with copies of our
+    /// own pages and the very same shared ones. Whatever else is mapped
+    /// for user mode, like the signal trampoline, is the kernel's, for
+    /// the caller to map again.
+    pub fn fork(&self) -> Result<Self, VmError> {
+        let mut out = Self::new()?;
+        out.heap_start = self.heap_start;
+        out.brk = self.brk;
+        out.heap_end = self.heap_end;
+        out.mmap_base = self.mmap_base;
+        for (va, pa, flags) in user_pages(self.pgtbl) {
+            if let Some(page) = self.shared.iter().find(|p| p.start() == pa) {
+                out.map_shared(va, core::slice::from_ref(page), flags)?;
+            } else if self.pages.iter().any(|p| p.start() <= pa && pa < p.end()) {
+                let page = request_phys_page(1)?;
+                unsafe {
+                    copy_nonoverlapping(pa as *const u8, page.start() as *mut u8, PAGE_SIZE);
+                }
+                page_map(
+                    out.pgtbl,
+                    VirtAddress::from(va as *mut usize),
+                    PhysAddress::from(page.start()),
+                    PAGE_SIZE,
+                    flags
+                )?;
+                out.keep(page);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Take `pages` pages worth of addresses for mmap, below what it has
+    /// handed out so far and leaving a page above the heap.
+    pub fn reserve(&mut self, pages: usize) -> Option<usize> {
+        let len = pages * PAGE_SIZE;
+        len.checked_add(self.heap_end + PAGE_SIZE).filter(|&low| low <= self.mmap_base)?;
+        self.mmap_base -= len;
+        Some(self.mmap_base)
+    }
+
+    /// Map `page` somewhere mmap would put it, and say where. If that
+    /// fails the page comes back.
+    pub fn map_page(&mut self, page: PhysPageExtent, flags: usize)
+                    -> Result<usize, (VmError, PhysPageExtent)> {
+        let Some(va) = self.reserve(1) else {
+            return Err((VmError::OutOfPages, page));
+        };
+        if let Err(e) = page_map(
+            self.pgtbl,
+            VirtAddress::from(va as *mut usize),
+            PhysAddress::from(page.start()),
+            PAGE_SIZE,
+            flags
+        ) {
+            return Err((e, page));
+        }
+        self.keep(page);
+        Ok(va)
+    }
+
+    /// Unmap the page at user address `va` and hand it over with the
+    /// flags it had, if it is mapped with at least `flags` and is ours
+    /// alone, a page of its own rather than part of a bigger piece like
+    /// the stack. Threads on other harts are out of it by the time this
+    /// returns.
+    pub fn take_page(&mut self, va: usize, flags: usize) -> Option<(PhysPageExtent, usize)> {
+        let va = VirtAddress::from(va as *mut usize);
+        let pa = unsafe { translate(self.pgtbl, va, flags) }?;
+        let i = self.pages.iter().position(|p| {
+            p.start() == pa && p.end() as usize - p.start() as usize == PAGE_SIZE
+        })?;
+        let (_, had) = unsafe { page_unmap(self.pgtbl, va) }?;
+        tlb::shootdown();
+        self.mapped -= PAGE_SIZE;
+        Some((self.pages.remove(i)?, had))
+    }
+
+    /// Map `page` back at user address `va` with `flags`, after
+    /// `take_page` took it and nothing came of it.
+    pub fn put_page(&mut self, va: usize, page: PhysPageExtent, flags: usize) -> Result<(), VmError> {
+        page_map(
+            self.pgtbl,
+            VirtAddress::from(va as *mut usize),
+            PhysAddress::from(page.start()),
+            PAGE_SIZE,
+            flags
+        )?;
+        self.keep(page);
+        Ok(())
+    }
+
+    /// Map `pages` fresh zeroed pages at user address `va`.
+    pub fn map_anonymous(&mut self, va: usize, pages: usize, flags: usize) -> Result<(), VmError> {
+        for i in 0..pages {
+            let page = request_phys_page(1)?;
+            page_map(
+                self.pgtbl,
+                VirtAddress::from((va + i * PAGE_SIZE) as *mut usize),
+                PhysAddress::from(page.start()),
+                PAGE_SIZE,
+                flags
+            )?;
+            self.keep(page);
+        }
+        Ok(())
+    }
+
+    /// Map `pages` at user address `va`, one after the other, as the
+    /// same memory whoever else has them.
+    pub fn map_shared(&mut self, va: usize, pages: &[Arc<PhysPageExtent>], flags: usize) -> Result<(), VmError> {
+        for (i, page) in pages.iter().enumerate() {
+            page_map(
+                self.pgtbl,
+                VirtAddress::from((va + i * PAGE_SIZE) as *mut usize),
+                PhysAddress::from(page.start()),
+                PAGE_SIZE,
+                flags
+            )?;
+            self.shared.push(page.clone());
+            self.mapped += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Map copies of `pages` at user address `va`, for a private
+    /// mapping of something shared.
+    pub fn map_copy(&mut self, va: usize, pages: &[Arc<PhysPageExtent>], flags: usize) -> Result<(), VmError> {
+        for (i, from) in pages.iter().enumerate() {
+            let page = request_phys_page(1)?;
+            unsafe {
+                copy_nonoverlapping(from.start() as *const u8, page.start() as *mut u8, PAGE_SIZE);
+            }
+            page_map(
+                self.pgtbl,
+                VirtAddress::from((va + i * PAGE_SIZE) as *mut usize),
+                PhysAddress::from(page.start()),
+                PAGE_SIZE,
+                flags
+            )?;
+            self.keep(page);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Channels, our own message passing, a little like a microkernel's.
+//!
+//! A channel is a pair of endpoints, each an fd. What one endpoint
+//! sends the other receives, as fixed size messages in order, each of
+//! which can bring a page of memory along. A process hands an endpoint
+//! to another by publishing it under a name for the other to connect
+//! to, so there is no need for a filesystem to find each other in.
+//!
+//! Sending never waits: a full queue is EAGAIN. Receiving can wait,
+//! sleeping on the endpoint with the futex wait queues.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use crate::lock::mutex::Mutex;
+use crate::process::fd::File;
+use crate::process::syscall::futex;
+use crate::vm::PhysPageExtent;
+
+/// Bytes in a message.
+pub const MSG_SIZE: usize = 64;
+/// Messages an endpoint holds before sends to it fail.
+pub const CHANNEL_DEPTH: usize = 64;
+
+/// What a channel carries.
+pub struct Message {
+    pub data: [u8; MSG_SIZE],
+    pub page: Option<PhysPageExtent>, // handed over to the receiver
+}
+
+/// Why a channel couldn't be used right now.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    Full,   // send with the peer's queue full
+    Empty,  // receive with nothing there yet
+    Closed, // the other endpoint is gone
+}
+
+struct Side {
+    queue: VecDeque<Message>, // received, waiting to be taken
+    open: bool,
+}
+
+struct Channel {
+    sides: [Mutex<Side>; 2],
+}
+
+/// One end of a channel, as an open file.
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+/// A new channel, as its two endpoints.
+pub fn new() -> (Endpoint, Endpoint) {
+    let side = || Mutex::new(Side { queue: VecDeque::new(), open: true });
+    let channel = Arc::new(Channel { sides: [side(), side()] });
+    (Endpoint { channel: channel.clone(), side: 0 }, Endpoint { channel, side: 1 })
+}
+
+impl Endpoint {
+    /// What receivers on this endpoint sleep on, see
+    /// `futex::wait_object`.
+    pub fn wait_key(&self) -> usize {
+        &self.channel.sides[self.side] as *const _ as usize
+    }
+
+    fn peer(&self) -> &Mutex<Side> {
+        &self.channel.sides[1 - self.side]
+    }
+
+    /// Queue `msg` for the other endpoint. If it can't be, it comes
+    /// back.
+    pub fn send(&self, msg: Message) -> Result<(), (ChannelError, Message)> {
+        {
+            let mut peer = self.peer().lock();
+            if !peer.open {
+                return Err((ChannelError::Closed, msg));
+            }
+            if peer.queue.len() == CHANNEL_DEPTH {
+                return Err((ChannelError::Full, msg));
+            }
+            peer.queue.push_back(msg);
+        }
+        futex::wake_object(self.peer() as *const _ as usize);
+        Ok(())
+    }
+
+    /// Take the oldest message sent to this endpoint. Once the other
+    /// endpoint is gone, what it sent can still be had, then Closed.
+    pub fn recv(&self) -> Result<Message, ChannelError> {
+        // one side locked at a time, or two endpoints doing this at
+        // once could deadlock
+        let own = &self.channel.sides[self.side];
+        if let Some(msg) = own.lock().queue.pop_front() {
+            return Ok(msg);
+        }
+        if self.peer().lock().open {
+            return Err(ChannelError::Empty);
+        }
+        // it may have sent something on its way out
+        own.lock().queue.pop_front().ok_or(ChannelError::Closed)
+    }
+
+    /// Put `msg`, just taken, back at the front, for a receive that
+    /// couldn't hand it over after all.
+    pub fn unrecv(&self, msg: Message) {
+        let own = &self.channel.sides[self.side];
+        own.lock().queue.push_front(msg);
+        // another thread may have gone to sleep on it in the meantime
+        futex::wake_object(self.wait_key());
+    }
+
+    /// Whether a receive would get something, a message or Closed.
+    pub fn ready(&self) -> bool {
+        let queued = !self.channel.sides[self.side].lock().queue.is_empty();
+        queued || !self.peer().lock().open
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let mut side = self.channel.sides[self.side].lock();
+        side.open = false;
+        // nobody will read these now
+        side.queue.clear();
+        drop(side);
+        // whoever waits on the other end finds it closed
+        futex::wake_object(self.peer() as *const _ as usize);
+    }
+}
+
+// Endpoints waiting to be connected to, by name.
+static PUBLISHED: Mutex<BTreeMap<String, Arc<File>>> = Mutex::new(BTreeMap::new());
+
+/// Leave `endpoint` under `name` for someone to connect to. Fails if
+/// the name is taken.
+pub fn publish(name: String, endpoint: Arc<File>) -> Result<(), Arc<File>> {
+    let mut published = PUBLISHED.lock();
+    if published.contains_key(&name) {
+        return Err(endpoint);
+    }
+    published.insert(name, endpoint);
+    Ok(())
+}
+
+/// Take the endpoint published under `name`, if there is one.
+pub fn connect(name: &str) -> Option<Arc<File>> {
+    PUBLISHED.lock().remove(name)
+}
@@ -0,0 +1,113 @@
+//! File descriptors. Each process has a table of open files, shared by
+//! its threads, and every fd is a reference to an open file that `dup`
+//! can hand out again under another number.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::file::vfs::Inode;
+use crate::lock::mutex::Mutex;
+use crate::process::channel::Endpoint;
+use crate::process::memfd::Memfd;
+use crate::process::pipe::PipeEnd;
+
+/// Something a file descriptor can refer to.
+pub enum File {
+    Console,      // the UART, as standard in, out and error
+    Pipe(PipeEnd),
+    Memfd(Memfd), // see memfd_create
+    Channel(Endpoint),
+    Vfs(VfsFile), // from openat
+}
+
+/// A file or directory opened by path.
+pub struct VfsFile {
+    pub inode: Arc<dyn Inode>,
+    pub path: String,
+    pub offset: Mutex<u64>, // bytes into a file, entries into a directory
+    pub read: bool,         // opened O_RDONLY or O_RDWR
+    pub write: bool,        // opened O_WRONLY or O_RDWR
+}
+
+#[derive(Clone)]
+struct Fd {
+    file: Arc<File>,
+    cloexec: bool,
+    nonblock: bool,
+}
+
+/// What an fd refers to, taken out of the table so the table isn't
+/// locked while we do anything with it.
+pub struct OpenFile {
+    pub file: Arc<File>,
+    pub nonblock: bool,
+}
+
+/// The open files of a process, by fd.
+#[derive(Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Fd>>,
+}
+
+impl FdTable {
+    /// A table with the console open as standard in, out and error.
+    pub fn new() -> Self {
+        let console = Arc::new(File::Console);
+        let fd = Fd { file: console, cloexec: false, nonblock: false };
+        Self {
+            fds: vec![Some(fd.clone()), Some(fd.clone()), Some(fd)],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<OpenFile> {
+        self.fds.get(fd)?.as_ref().map(|fd| OpenFile {
+            file: fd.file.clone(),
+            nonblock: fd.nonblock,
+        })
+    }
+
+    /// Every open fd and what it refers to, in order.
+    pub fn files(&self) -> Vec<(usize, Arc<File>)> {
+        self.fds.iter().enumerate()
+            .filter_map(|(n, fd)| fd.as_ref().map(|fd| (n, fd.file.clone())))
+            .collect()
+    }
+
+    /// Put `file` at the lowest free fd, if there is one below `limit`.
+    pub fn open(&mut self, file: Arc<File>, cloexec: bool, nonblock: bool,
+                limit: usize) -> Option<usize> {
+        let fd = self.fds.iter().position(Option::is_none).unwrap_or(self.fds.len());
+        if fd >= limit {
+            return None;
+        }
+        self.put(fd, file, cloexec, nonblock);
+        Some(fd)
+    }
+
+    /// Put `file` at `fd`, closing what was there.
+    pub fn put(&mut self, fd: usize, file: Arc<File>, cloexec: bool, nonblock: bool) {
+        if fd >= self.fds.len() {
+            self.fds.resize(fd + 1, None);
+        }
+        self.fds[fd] = Some(Fd { file, cloexec, nonblock });
+    }
+
+    /// Close `fd`. False if it wasn't open.
+    pub fn close(&mut self, fd: usize) -> bool {
+        match self.fds.get_mut(fd) {
+            Some(slot) => slot.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// execve closes the fds marked close on exec.
+    pub fn exec(&mut self) {
+        for slot in self.fds.iter_mut() {
+            if slot.as_ref().is_some_and(|fd| fd.cloexec) {
+                *slot = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Kernel threads: work the kernel does on its own schedule instead of
+//! on behalf of a process, like flushing the buffer cache every so
+//! often.
+//!
+//! A kthread is a closure and a period. Harts run the ones that are
+//! due from `schedule`, between processes, each on the kernel stack of
+//! whichever hart got to it first. There is no preemption, so the work
+//! should be short.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::device::clint;
+use crate::lock::mutex::Mutex;
+
+struct KThread {
+    name: &'static str,
+    period: u64,                        // in mtime ticks
+    next: u64,                          // mtime it's due at
+    work: Option<Box<dyn FnMut() + Send>>, // None while a hart runs it
+}
+
+static KTHREADS: Mutex<Vec<KThread>> = Mutex::new(Vec::new());
+
+/// Run `work` every `period_ms` milliseconds, starting one period from
+/// now.
+pub fn spawn(name: &'static str, period_ms: u64, work: impl FnMut() + Send + 'static) {
+    let period = period_ms * clint::mtime_frequency() / 1000;
+    KTHREADS.lock().push(KThread {
+        name,
+        period,
+        next: clint::read_mtime() + period,
+        work: Some(Box::new(work)),
+    });
+    log!(Debug, "Started kthread {}.", name);
+}
+
+/// Run every kthread that is due and not already running on another
+/// hart.
+pub fn run_due() {
+    loop {
+        let now = clint::read_mtime();
+        let due = {
+            let mut kthreads = KTHREADS.lock();
+            kthreads.iter_mut()
+                .enumerate()
+                .find(|(_, k)| k.work.is_some() && k.next <= now)
+                .map(|(i, k)| (i, k.work.take().unwrap()))
+        };
+        let Some((i, mut work)) = due else {
+            return;
+        };
+        // run it without the lock, so other harts can get at the rest
+        work();
+        let mut kthreads = KTHREADS.lock();
+        let kthread = &mut kthreads[i];
+        kthread.work = Some(work);
+        kthread.next = clint::read_mtime() + kthread.period;
+    }
+}
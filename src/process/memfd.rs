@@ -0,0 +1,61 @@
+//! Files that are just memory, from memfd_create. Their pages are
+//! refcounted, so a `MAP_SHARED` mapping keeps them for as long as it
+//! lasts and every process mapping one sees the same memory. They go
+//! back to the pool once the last fd and mapping are gone.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::hw::param::PAGE_SIZE;
+use crate::lock::mutex::Mutex;
+use crate::vm::{request_phys_page, PhysPageExtent, VmError};
+
+struct MemfdState {
+    size: usize,
+    pages: Vec<Arc<PhysPageExtent>>, // one a page, enough to hold size
+}
+
+pub struct Memfd {
+    name: String,
+    state: Mutex<MemfdState>,
+}
+
+impl Memfd {
+    /// An empty memfd called `name`.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            state: Mutex::new(MemfdState { size: 0, pages: Vec::new() }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().size
+    }
+
+    /// Grow or shrink to `size` bytes, as ftruncate does. New memory is
+    /// zeroed. Pages that are cut off stay in any mapping that has them.
+    pub fn truncate(&self, size: usize) -> Result<(), VmError> {
+        let mut state = self.state.lock();
+        let pages = size.div_ceil(PAGE_SIZE);
+        while state.pages.len() < pages {
+            let page = request_phys_page(1)?;
+            state.pages.push(Arc::new(page));
+        }
+        state.pages.truncate(pages);
+        state.size = size;
+        Ok(())
+    }
+
+    /// Up to `count` pages starting at page `first`, fewer if the file
+    /// ends sooner.
+    pub fn pages(&self, first: usize, count: usize) -> Vec<Arc<PhysPageExtent>> {
+        let state = self.state.lock();
+        state.pages.iter().skip(first).take(count).cloned().collect()
+    }
+}
@@ -0,0 +1,148 @@
+//! Anonymous pipes: a bounded ring buffer with a read end and a write
+//! end, each of which can be open under any number of fds.
+//!
+//! A read with nothing to read, or a write with no room, sleeps on the
+//! pipe with `futex::wait_object` until a read, a write or an end
+//! closing wakes it, then gets made again. Once every write end is
+//! closed reads get EOF, and once every read end is closed writes fail
+//! with EPIPE and a SIGPIPE.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+
+use crate::hw::param::PAGE_SIZE;
+use crate::lock::mutex::Mutex;
+use crate::process::syscall::futex;
+
+/// How much a pipe holds, the same as Linux's default.
+pub const PIPE_SIZE: usize = 16 * PAGE_SIZE;
+/// Writes up to this big go in all at once or not at all.
+pub const PIPE_BUF: usize = PAGE_SIZE;
+
+/// Why a pipe couldn't be read or written right now.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipeError {
+    WouldBlock, // empty, or full, with the other end still open
+    Broken,     // written with no read end open
+    NotTaken,   // the reader had nowhere to put what it read
+}
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+impl PipeState {
+    // Whether a read would have to wait.
+    fn empty(&self) -> bool {
+        self.buf.is_empty() && self.writers != 0
+    }
+
+    // Whether a write of `len` bytes would have to wait.
+    fn full(&self, len: usize) -> bool {
+        let room = PIPE_SIZE - self.buf.len();
+        self.readers != 0 && (room == 0 || (len <= PIPE_BUF && room < len))
+    }
+}
+
+pub struct Pipe {
+    state: Mutex<PipeState>,
+}
+
+/// One end of a pipe, as an open file. The pipe notices when the last
+/// of either end goes away.
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    write: bool,
+}
+
+/// A new pipe, as its read end and its write end.
+pub fn new() -> (PipeEnd, PipeEnd) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buf: VecDeque::new(),
+            readers: 1,
+            writers: 1,
+        }),
+    });
+    (PipeEnd { pipe: pipe.clone(), write: false }, PipeEnd { pipe, write: true })
+}
+
+impl PipeEnd {
+    pub fn is_write(&self) -> bool {
+        self.write
+    }
+
+    /// What a read or write that has to wait sleeps on, see
+    /// `futex::wait_object`. The same for both ends.
+    pub fn wait_key(&self) -> usize {
+        Arc::as_ptr(&self.pipe) as usize
+    }
+
+    /// Whether a read, or for the write end a write of `len` bytes,
+    /// could go on now, if only to fail.
+    pub fn ready(&self, len: usize) -> bool {
+        let state = self.pipe.state.lock();
+        match self.write {
+            true => !state.full(len),
+            false => !state.empty(),
+        }
+    }
+
+    fn wake(&self) {
+        futex::wake_object(self.wait_key());
+    }
+
+    /// Hand up to `count` bytes of the pipe to `take`, with the pipe
+    /// locked, and say how many. They only come out of the pipe if
+    /// `take` says it took them. Nothing at all is EOF.
+    pub fn read(&self, count: usize, take: impl FnOnce(&[u8]) -> bool) -> Result<usize, PipeError> {
+        let mut state = self.pipe.state.lock();
+        if state.empty() {
+            return Err(PipeError::WouldBlock);
+        }
+        let n = min(count, state.buf.len());
+        if !take(&state.buf.make_contiguous()[..n]) {
+            return Err(PipeError::NotTaken);
+        }
+        state.buf.drain(..n);
+        drop(state);
+        // writers waiting for room
+        self.wake();
+        Ok(n)
+    }
+
+    /// Put as much of `data` in the pipe as fits and say how much that
+    /// was. Small writes never go in by halves.
+    pub fn write(&self, data: &[u8]) -> Result<usize, PipeError> {
+        let mut state = self.pipe.state.lock();
+        if state.readers == 0 {
+            return Err(PipeError::Broken);
+        }
+        if state.full(data.len()) {
+            return Err(PipeError::WouldBlock);
+        }
+        let n = min(PIPE_SIZE - state.buf.len(), data.len());
+        state.buf.extend(&data[..n]);
+        drop(state);
+        // readers waiting for something to read
+        self.wake();
+        Ok(n)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        if self.write {
+            state.writers -= 1;
+        } else {
+            state.readers -= 1;
+        }
+        drop(state);
+        // whoever waits on the other end finds it closed
+        self.wake();
+    }
+}
@@ -0,0 +1,342 @@
+//! `/proc`: processes and kernel state, as files.
+//!
+//! The scheduler keeps a registry here of every thread it has seen,
+//! with its state as of its last switch, and handles on the memory,
+//! files and limits it shares with its process. The limits carry the
+//! process's CPU time too. Files are made up when they are looked up,
+//! so a read sees one snapshot from start to end, and a file opened a
+//! while ago keeps showing the past.
+//!
+//! The registry's lock is taken with the scheduler's locks held, so
+//! nothing else is locked while holding it: readers clone what they
+//! need out and let go first.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::Write;
+
+use crate::device::{clint, plic};
+use crate::file::vfs::*;
+use crate::hw::fdt;
+use crate::hw::param::{NHART, PAGE_SIZE, UART_IRQ, VIRTIO_IRQ};
+use crate::lock::mutex::Mutex;
+use crate::process::address_space::AddressSpace;
+use crate::process::fd::{File, FdTable};
+use crate::process::rlimit::Limits;
+use crate::process::{idle_ticks, Process, ProcessState};
+use crate::vm;
+use crate::vm::ptable::user_regions;
+
+#[derive(Clone)]
+struct Task {
+    tgid: usize,
+    name: String,
+    state: &'static str,
+    address_space: Arc<Mutex<AddressSpace>>,
+    files: Arc<Mutex<FdTable>>,
+    limits: Arc<Mutex<Limits>>,
+}
+
+// The address space's page table is a raw pointer, which its lock
+// looks after, the same as for the process that has it.
+unsafe impl Send for Task {}
+
+static TASKS: Mutex<BTreeMap<usize, Task>> = Mutex::new(BTreeMap::new());
+
+fn state_name(state: &ProcessState) -> &'static str {
+    match state {
+        ProcessState::Unstarted | ProcessState::Ready | ProcessState::Running => "R (running)",
+        ProcessState::Wait | ProcessState::Sleep => "S (sleeping)",
+        ProcessState::Stopped => "T (stopped)",
+        _ => "X (dead)",
+    }
+}
+
+/// Record `proc` as it is now, including what it shares with its
+/// process. For a new thread, and after execve swaps those out.
+pub fn register(proc: &Process) {
+    let task = Task {
+        tgid: proc.tgid,
+        name: proc.name.clone(),
+        state: state_name(&proc.state),
+        address_space: proc.address_space.clone(),
+        files: proc.files.clone(),
+        limits: proc.limits.clone(),
+    };
+    TASKS.lock().insert(proc.id, task);
+}
+
+/// Catch up on the state of `proc`, as it switches.
+pub fn update(proc: &Process) {
+    let mut tasks = TASKS.lock();
+    match tasks.get_mut(&proc.id) {
+        Some(task) => task.state = state_name(&proc.state),
+        None => {
+            drop(tasks);
+            register(proc);
+        },
+    }
+}
+
+/// Forget thread `pid`, which is gone.
+pub fn unregister(pid: usize) {
+    TASKS.lock().remove(&pid);
+}
+
+fn task(pid: usize) -> Option<Task> {
+    TASKS.lock().get(&pid).cloned()
+}
+
+// -------------------------------------------------------------------
+// What the files say
+
+fn status(pid: usize, task: &Task) -> String {
+    let threads = TASKS.lock().values().filter(|t| t.tgid == task.tgid).count();
+    let vm_size = task.address_space.lock().mapped / 1024;
+    let fds = task.files.lock().files().len();
+    let (user, system) = {
+        let limits = task.limits.lock();
+        (limits.user, limits.system)
+    };
+    let ms = |ticks: u64| ticks * 1000 / clint::mtime_frequency();
+    format!("Name:\t{}\nState:\t{}\nTgid:\t{}\nPid:\t{}\nThreads:\t{}\n\
+             VmSize:\t{} kB\nFDSize:\t{}\nUserTime:\t{} ms\nSystemTime:\t{} ms\n",
+            task.name, task.state, task.tgid, pid, threads, vm_size, fds,
+            ms(user), ms(system))
+}
+
+// One line per run of pages with the same permissions, like Linux's
+// maps without the offset and file columns.
+fn maps(task: &Task) -> String {
+    let regions = {
+        let space = task.address_space.lock();
+        user_regions(space.pgtbl)
+    };
+    let mut out = String::new();
+    for r in regions {
+        let flag = |set, c| if set { c } else { '-' };
+        let _ = writeln!(out, "{:08x}-{:08x} {}{}{}p", r.start, r.end,
+                         flag(r.read, 'r'), flag(r.write, 'w'), flag(r.exec, 'x'));
+    }
+    out
+}
+
+fn fds(task: &Task) -> String {
+    let files = task.files.lock().files();
+    let mut out = String::new();
+    for (fd, file) in files {
+        let what = match &*file {
+            File::Console => "console",
+            File::Pipe(_) => "pipe",
+            File::Memfd(_) => "memfd",
+            File::Channel(_) => "channel",
+            File::Vfs(f) => &f.path,
+        };
+        let _ = writeln!(out, "{} {}", fd, what);
+    }
+    out
+}
+
+fn meminfo() -> String {
+    let (pool, heap) = vm::stats();
+    let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+    format!("MemTotal:\t{} kB\nMemFree:\t{} kB\nHeapSmall:\t{} kB\nHeapPages:\t{} kB\n",
+            kb(pool.total), kb(pool.free), heap.small_bytes / 1024, kb(heap.pages))
+}
+
+fn interrupts() -> String {
+    let mut out = String::from("    ");
+    for hart in 0..NHART {
+        let _ = write!(out, " {:>10}", format!("CPU{}", hart));
+    }
+    out.push('\n');
+    for irq in 1..plic::NSOURCES {
+        let counts = plic::dispatched(irq);
+        let name = match irq {
+            UART_IRQ => "uart",
+            VIRTIO_IRQ => "virtio",
+            _ if counts.iter().any(|&n| n != 0) => "",
+            _ => continue,
+        };
+        let _ = write!(out, "{:>3}:", irq);
+        for n in counts {
+            let _ = write!(out, " {:>10}", n);
+        }
+        let _ = writeln!(out, "  PLIC {}", name);
+    }
+    out
+}
+
+// Seconds since boot and seconds every hart spent idle, between them.
+fn uptime() -> String {
+    let freq = clint::mtime_frequency();
+    let secs = |ticks: u64| format!("{}.{:02}", ticks / freq, ticks % freq * 100 / freq);
+    format!("{} {}\n", secs(clint::read_mtime()), secs(idle_ticks()))
+}
+
+fn cpuinfo() -> String {
+    let fdt = fdt::get();
+    let mut out = String::new();
+    for hart in 0..NHART {
+        let cpu = format!("/cpus/cpu@{}", hart);
+        let prop = |name| fdt.as_ref().and_then(|f| f.property_str(&cpu, name)).unwrap_or("unknown");
+        let _ = write!(out, "processor\t: {}\nhart\t\t: {}\nisa\t\t: {}\nmmu\t\t: {}\n\
+                             timebase\t: {}\n\n",
+                       hart, hart, prop("riscv,isa"), prop("mmu-type"),
+                       clint::mtime_frequency());
+    }
+    out
+}
+
+// -------------------------------------------------------------------
+// Inodes
+
+const ROOT_INO: u64 = 1;
+const GLOBALS: [&str; 4] = ["meminfo", "interrupts", "uptime", "cpuinfo"];
+const PER_PID: [&str; 4] = ["status", "state", "maps", "fds"];
+
+// Pids get a block of inode numbers each, well clear of the globals.
+fn pid_ino(pid: usize, n: usize) -> u64 {
+    ((pid as u64) << 8) | n as u64
+}
+
+/// A file, with what it says made up when it was looked up.
+struct ProcFile {
+    ino: u64,
+    text: String,
+}
+
+impl Inode for ProcFile {
+    fn stat(&self) -> Result<Stat, FsError> {
+        Ok(Stat { ino: self.ino, ftype: FileType::Regular, size: self.text.len() as u64, nlink: 1 })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let bytes = self.text.as_bytes();
+        let start = min(offset, bytes.len() as u64) as usize;
+        let len = min(buf.len(), bytes.len() - start);
+        buf[..len].copy_from_slice(&bytes[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+}
+
+fn dir_stat(ino: u64, entries: usize) -> Result<Stat, FsError> {
+    Ok(Stat { ino, ftype: FileType::Directory, size: entries as u64, nlink: 2 })
+}
+
+fn file(ino: u64, text: String) -> Arc<dyn Inode> {
+    Arc::new(ProcFile { ino, text })
+}
+
+/// `/proc/<pid>`, for any thread. Only processes are listed, though.
+struct PidDir {
+    pid: usize,
+}
+
+impl Inode for PidDir {
+    fn stat(&self) -> Result<Stat, FsError> {
+        dir_stat(pid_ino(self.pid, 0), PER_PID.len())
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let task = task(self.pid).ok_or(FsError::NotFound)?;
+        let n = PER_PID.iter().position(|&f| f == name).ok_or(FsError::NotFound)?;
+        let text = match name {
+            "status" => status(self.pid, &task),
+            "state" => format!("{}\n", task.state),
+            "maps" => maps(&task),
+            _ => fds(&task),
+        };
+        Ok(file(pid_ino(self.pid, n + 1), text))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        task(self.pid).ok_or(FsError::NotFound)?;
+        Ok(PER_PID.iter().enumerate().map(|(n, name)| DirEntry {
+            name: name.to_string(),
+            ino: pid_ino(self.pid, n + 1),
+            ftype: FileType::Regular,
+        }).collect())
+    }
+}
+
+struct Root;
+
+impl Inode for Root {
+    fn stat(&self) -> Result<Stat, FsError> {
+        dir_stat(ROOT_INO, GLOBALS.len() + TASKS.lock().len())
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if let Some(n) = GLOBALS.iter().position(|&f| f == name) {
+            let text = match name {
+                "meminfo" => meminfo(),
+                "interrupts" => interrupts(),
+                "uptime" => uptime(),
+                _ => cpuinfo(),
+            };
+            return Ok(file(ROOT_INO + 1 + n as u64, text));
+        }
+        match name.parse::<usize>() {
+            Ok(pid) if !name.starts_with('0') && task(pid).is_some() => {
+                Ok(Arc::new(PidDir { pid }))
+            },
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut out: Vec<DirEntry> = GLOBALS.iter().enumerate().map(|(n, name)| DirEntry {
+            name: name.to_string(),
+            ino: ROOT_INO + 1 + n as u64,
+            ftype: FileType::Regular,
+        }).collect();
+        let tasks = TASKS.lock();
+        out.extend(tasks.iter().filter(|(&pid, t)| t.tgid == pid).map(|(&pid, _)| DirEntry {
+            name: pid.to_string(),
+            ino: pid_ino(pid, 0),
+            ftype: FileType::Directory,
+        }));
+        Ok(out)
+    }
+}
+
+/// The filesystem to mount at `/proc`.
+#[derive(Default)]
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
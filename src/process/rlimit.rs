@@ -0,0 +1,117 @@
+//! Resource limits, as getrlimit, setrlimit and prlimit64 see them.
+//!
+//! Limits belong to a process as a whole, so its threads share them,
+//! along with the CPU time they've used between them. A process that
+//! forks hands its children a copy, with no CPU time used yet. Hitting
+//! a limit is an error from the syscall that would go over, or for CPU
+//! time, SIGXCPU once a second past the soft limit and SIGKILL at the
+//! hard one.
+
+use crate::device::clint;
+use crate::process::signal::{SIGKILL, SIGXCPU};
+
+pub const RLIMIT_CPU: usize = 0;        // seconds
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;       // bytes of heap
+pub const RLIMIT_STACK: usize = 3;      // bytes
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;      // threads, its own and its children's
+pub const RLIMIT_NOFILE: usize = 7;     // one more than the highest fd
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;         // bytes mapped
+pub const RLIM_NLIMITS: usize = 16;
+
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Highest RLIMIT_NOFILE can go, like Linux's nr_open.
+pub const NR_OPEN: u64 = 1 << 20;
+
+/// struct rlimit: the soft limit, which is the one that counts, and the
+/// hard limit the soft one can go up to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl Rlimit {
+    const INFINITY: Rlimit = Rlimit { cur: RLIM_INFINITY, max: RLIM_INFINITY };
+}
+
+#[derive(Clone)]
+pub struct Limits {
+    limits: [Rlimit; RLIM_NLIMITS],
+    pub user: u64,       // mtime ticks every thread spent in user mode
+    pub system: u64,     // and in the kernel, on their behalf
+    xcpu_at: u64,        // seconds of CPU time when SIGXCPU is next due
+}
+
+impl Limits {
+    /// What the first process starts with, as Linux has it.
+    pub fn new() -> Self {
+        let mut limits = [Rlimit::INFINITY; RLIM_NLIMITS];
+        limits[RLIMIT_STACK] = Rlimit { cur: 8 << 20, max: RLIM_INFINITY };
+        limits[RLIMIT_NOFILE] = Rlimit { cur: 1024, max: 4096 };
+        limits[RLIMIT_CORE] = Rlimit { cur: 0, max: RLIM_INFINITY };
+        limits[RLIMIT_MEMLOCK] = Rlimit { cur: 8 << 20, max: 8 << 20 };
+        Self { limits, user: 0, system: 0, xcpu_at: 0 }
+    }
+
+    /// What a forked child starts with: the same limits, and the CPU
+    /// time counting from nothing.
+    pub fn fork(&self) -> Self {
+        Self { limits: self.limits, user: 0, system: 0, xcpu_at: 0 }
+    }
+
+    pub fn get(&self, resource: usize) -> Rlimit {
+        self.limits[resource]
+    }
+
+    /// The soft limit on `resource`, in a form we can compare sizes to.
+    pub fn cur(&self, resource: usize) -> usize {
+        self.limits[resource].cur.try_into().unwrap_or(usize::MAX)
+    }
+
+    /// Set `resource` to `new`, which has to make sense: the soft limit
+    /// no higher than the hard one. Raising the hard limit is EPERM.
+    pub fn set(&mut self, resource: usize, new: Rlimit) -> Result<(), isize> {
+        use crate::process::syscall::{EINVAL, EPERM};
+        if new.cur > new.max || (resource == RLIMIT_NOFILE && new.max > NR_OPEN) {
+            return Err(EINVAL);
+        }
+        if new.max > self.limits[resource].max {
+            return Err(EPERM);
+        }
+        if resource == RLIMIT_CPU {
+            // warn again from the new soft limit
+            self.xcpu_at = 0;
+        }
+        self.limits[resource] = new;
+        Ok(())
+    }
+
+    /// Add CPU time a thread used, in user mode or not.
+    pub fn charge(&mut self, ticks: u64, user: bool) {
+        match user {
+            true => self.user += ticks,
+            false => self.system += ticks,
+        }
+    }
+
+    /// The signal the process has coming for its CPU time, if any:
+    /// SIGXCPU once a second past the soft limit, SIGKILL at the hard
+    /// one.
+    pub fn cpu_signal(&mut self) -> Option<usize> {
+        let secs = (self.user + self.system) / clint::mtime_frequency();
+        let limit = self.limits[RLIMIT_CPU];
+        if secs >= limit.max {
+            Some(SIGKILL)
+        } else if secs >= limit.cur && secs >= self.xcpu_at {
+            self.xcpu_at = secs + 1;
+            Some(SIGXCPU)
+        } else {
+            None
+        }
+    }
+}
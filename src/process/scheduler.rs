@@ -2,6 +2,7 @@
 //! other aspects of running user space processes
 
 use alloc::collections::VecDeque;
+use alloc::collections::btree_map::BTreeMap;
 
 use crate::process::*;
 
@@ -15,24 +16,38 @@ use crate::process::*;
 /// optional above this struct.
 pub struct ProcessQueue {
     proc_queue: VecDeque<Process>,
+    parked: BTreeMap<usize, Process>, // by pid, not runnable until unparked
 }
 
 impl ProcessQueue {
     pub fn new() -> Self {
         Self {
-            proc_queue: VecDeque::new()
+            proc_queue: VecDeque::new(),
+            parked: BTreeMap::new(),
         }
     }
 
     /// This is the acquiring half of the scheduler. This function
     /// internally enforces fairness and efficiency and everything else
     pub fn get_ready_process(&mut self) -> Process {
+        match self.try_get_ready_process() {
+            Some(proc) => proc,
+            // TODO This need to communicate with other harts to make sure
+            // it's not just that the other harts are running / own
+            // everything currently
+            None => panic!("Scheduling queue is empty! The root process died?"),
+        }
+    }
+
+    /// As `get_ready_process`, but `None` when there is nothing to run
+    /// right now, so an idle hart can wait for work.
+    pub fn try_get_ready_process(&mut self) -> Option<Process> {
         // iterate while the queue is non-empty
         while let Some(head) = self.proc_queue.pop_front() {
             match head.state {
                 // found something we can run
                 ProcessState::Ready | ProcessState::Unstarted => {
-                    return head
+                    return Some(head)
                 },
 
                 // found something we might be able to run, check. If
@@ -51,6 +66,9 @@ impl ProcessQueue {
                 ProcessState::Running => {
                     panic!("Running process in scheduling queue!")
                 },
+                ProcessState::Stopped => {
+                    panic!("Stopped process in scheduling queue!")
+                },
                 ProcessState::Dead => {
                     // TODO we need to decide what dead means, and how
                     // dead processes are desposed of
@@ -60,11 +78,7 @@ impl ProcessQueue {
             }
         }
         // The queue must be empty, there is nothing to run
-
-        // TODO This need to communicate with other harts to make sure
-        // it's not just that the other harts are running / own
-        // everything currently
-        panic!("Scheduling queue is empty! The root process died?");
+        None
     }
 
     /// This is for returning a process that has just stopped running but
@@ -72,14 +86,43 @@ impl ProcessQueue {
     /// blocked or slept or something. The caller has responsibility to
     /// alter the Process structure to match its state, and then moves it
     /// here to be restarted/started later
-    pub fn insert(&mut self, proc: Process) {
+    pub fn insert(&mut self, mut proc: Process) {
         match proc.state {
             ProcessState::Ready | ProcessState::Unstarted => {},
             _ => {
                 panic!("Unsuitable process state inserted into scheduling queue! {:?}", proc.state);
             }
         }
+        proc.off_cpu();
         self.proc_queue.push_back(proc);
     }
+
+    /// Hold on to a process that can't run until something else
+    /// happens, like a process stopped by a signal waiting for
+    /// SIGCONT. It stays out of the way until `unpark`.
+    pub fn park(&mut self, mut proc: Process) {
+        match proc.state {
+            ProcessState::Stopped => {},
+            _ => {
+                panic!("Unsuitable process state parked! {:?}", proc.state);
+            }
+        }
+        proc.off_cpu();
+        self.parked.insert(proc.id, proc);
+    }
+
+    /// Make parked process `pid` ready to run again. Returns whether
+    /// it was parked.
+    pub fn unpark(&mut self, pid: usize) -> bool {
+        match self.parked.remove(&pid) {
+            Some(mut proc) => {
+                proc.state = ProcessState::Ready;
+                procfs::update(&proc);
+                self.proc_queue.push_back(proc);
+                true
+            },
+            None => false,
+        }
+    }
 }
 
@@ -0,0 +1,570 @@
+//! POSIX signals.
+//!
+//! Each thread has its own blocked mask, and the threads of a process
+//! share its handlers. Signals waiting to be delivered live in a table
+//! keyed by thread id instead, so `kill` can reach a thread wherever it
+//! is: in the queue, parked, or running on another hart. A signal sent
+//! to a whole process goes to one of its threads, the first if it's
+//! still around.
+//!
+//! Delivery happens on the way back to user mode, in
+//! `Process::resume`. A signal with a handler gets a Linux style
+//! `rt_sigframe` (siginfo, then a ucontext holding the interrupted
+//! registers and mask) pushed on the process stack, and the handler
+//! returns through a trampoline page mapped at `SIGRETURN_VA` that
+//! calls rt_sigreturn to put everything back. Otherwise the default
+//! action terminates, stops, continues or ignores.
+//!
+//! Faults in user mode come here too, through `user_fault_rust`, as
+//! SIGSEGV, SIGILL and friends.
+
+use alloc::collections::btree_map::BTreeMap;
+use super::*;
+use super::syscall::{futex, EINVAL, EINTR, ESRCH};
+use crate::vm::uaccess::{copy_from_user, copy_to_user};
+
+pub const NSIG: usize = 64;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGSTKFLT: usize = 16;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGXCPU: usize = 24;
+pub const SIGXFSZ: usize = 25;
+pub const SIGVTALRM: usize = 26;
+pub const SIGPROF: usize = 27;
+pub const SIGWINCH: usize = 28;
+pub const SIGIO: usize = 29;
+pub const SIGPWR: usize = 30;
+pub const SIGSYS: usize = 31;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+pub const SA_SIGINFO: usize = 0x0000_0004;
+pub const SA_RESTART: usize = 0x1000_0000;
+pub const SA_NODEFER: usize = 0x4000_0000;
+pub const SA_RESETHAND: usize = 0x8000_0000;
+
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// si_code values
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const SI_TKILL: i32 = -6;
+pub const ILL_ILLOPC: i32 = 1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+pub const TRAP_BRKPT: i32 = 1;
+pub const SYS_SECCOMP: i32 = 1;
+
+/// Where every process finds the code that calls rt_sigreturn, which
+/// is where signal handlers return to. Just above the stacks.
+pub const SIGRETURN_VA: usize = 0x30_0000_0000;
+
+/// Registers as scall_asm and regular_strap save them, x0 to x31.
+pub const REG_FRAME: usize = 256;
+// struct rt_sigframe: siginfo, then the ucontext. Inside the ucontext
+// are the mask and, 16 byte aligned, the registers (pc, then x1 to
+// x31) followed by room for the FP state we don't keep.
+const SIGINFO_SIZE: usize = 128;
+const UC_STACK_FLAGS: usize = 24;
+const UC_SIGMASK: usize = 40;
+const UC_MCONTEXT: usize = 176;
+const SIGFRAME_SIZE: usize = SIGINFO_SIZE + 960;
+const SS_DISABLE: u32 = 2;
+
+/// A set of signals, bit n - 1 for signal n, as in the kernel's sigset_t.
+pub type SigSet = u64;
+
+fn bit(sig: usize) -> SigSet {
+    1 << (sig - 1)
+}
+
+// These can't be caught, blocked or ignored.
+const UNBLOCKABLE: SigSet = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+const STOP_SIGNALS: SigSet = (1 << (SIGSTOP - 1)) | (1 << (SIGTSTP - 1))
+    | (1 << (SIGTTIN - 1)) | (1 << (SIGTTOU - 1));
+
+/// struct sigaction as the riscv kernel ABI has it: no restorer.
+#[derive(Clone, Copy, Default)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub mask: SigSet,
+}
+
+/// What happens to a signal nobody handles.
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        _ if bit(sig) & STOP_SIGNALS != 0 => DefaultAction::Stop,
+        // real time signals too
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Who sent a signal, or what fault raised it, for the handler's
+/// siginfo.
+#[derive(Clone, Copy, Default)]
+pub struct SigInfo {
+    pub code: i32,
+    pub pid: usize,
+    pub addr: usize,
+}
+
+/// The signal state a thread carries around. Cloning it shares the
+/// handlers, the way threads do.
+#[derive(Clone)]
+pub struct Signals {
+    actions: Arc<Mutex<[SigAction; NSIG]>>,
+    pub mask: SigSet,
+    // set while the process sits on a syscall it will make again, so a
+    // handler without SA_RESTART can interrupt it with EINTR instead
+    pub restarting: bool,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self {
+            actions: Arc::new(Mutex::new([SigAction::default(); NSIG])),
+            mask: 0,
+            restarting: false,
+        }
+    }
+
+    pub fn action(&self, sig: usize) -> SigAction {
+        self.actions.lock()[sig - 1]
+    }
+
+    /// Install `action` for `sig`, which can't be SIGKILL or SIGSTOP.
+    /// Ignoring a signal throws away any of it already pending for
+    /// `pid`.
+    pub fn set_action(&mut self, pid: usize, sig: usize, mut action: SigAction) {
+        action.mask &= !UNBLOCKABLE;
+        self.actions.lock()[sig - 1] = action;
+        let ignored = match action.handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(default_action(sig), DefaultAction::Ignore),
+            _ => false,
+        };
+        if ignored {
+            if let Some(pending) = PENDING.lock().get_mut(&pid) {
+                pending.set &= !bit(sig);
+            }
+        }
+    }
+
+    pub fn set_mask(&mut self, mask: SigSet) {
+        self.mask = mask & !UNBLOCKABLE;
+    }
+
+    // Back to the default action for `sig`.
+    fn reset(&mut self, sig: usize) {
+        self.actions.lock()[sig - 1] = SigAction::default();
+    }
+
+    /// execve keeps the mask and ignored signals, but the handlers are
+    /// gone with the old program. The new program has its own copy.
+    pub fn exec(&mut self) {
+        let mut actions = *self.actions.lock();
+        for action in actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        self.actions = Arc::new(Mutex::new(actions));
+        self.restarting = false;
+    }
+}
+
+// The signals waiting for one thread.
+struct Pending {
+    tgid: usize,
+    parent: usize, // the process that forked its process, 0 for none
+    set: SigSet,
+    info: [SigInfo; NSIG],
+}
+
+// Pending signals of every live thread, by thread id. A thread is in
+// here from when it gets its id until it is dropped, which makes this
+// the list of processes `kill` can find.
+static PENDING: Mutex<BTreeMap<usize, Pending>> = Mutex::new(BTreeMap::new());
+
+/// Start keeping signals for a new thread `pid` of process `tgid`,
+/// which process `parent` forked.
+pub fn register(pid: usize, tgid: usize, parent: usize) {
+    PENDING.lock().insert(pid, Pending {
+        tgid,
+        parent,
+        set: 0,
+        info: [SigInfo::default(); NSIG],
+    });
+}
+
+/// Forget a process that is going away.
+pub fn unregister(pid: usize) {
+    PENDING.lock().remove(&pid);
+}
+
+/// Every process `kill` can find, by the pid getpid gives.
+pub fn pids() -> Vec<usize> {
+    let mut out: Vec<usize> = PENDING.lock().values().map(|p| p.tgid).collect();
+    out.sort_unstable();
+    out.dedup();
+    out
+}
+
+/// How many threads process `tgid` has, along with those of the
+/// processes it forked that are still around. RLIMIT_NPROC caps this.
+pub fn tasks(tgid: usize) -> usize {
+    PENDING.lock().values().filter(|p| p.tgid == tgid || p.parent == tgid).count()
+}
+
+/// The threads of process `tgid`.
+pub fn threads(tgid: usize) -> Vec<usize> {
+    PENDING.lock().iter()
+        .filter(|(_, p)| p.tgid == tgid)
+        .map(|(&pid, _)| pid)
+        .collect()
+}
+
+/// The thread of process `tgid` that takes signals sent to all of it:
+/// the first one, or any other if that one has exited.
+fn group_target(tgid: usize) -> Result<usize, isize> {
+    let table = PENDING.lock();
+    match table.get(&tgid) {
+        Some(_) => Ok(tgid),
+        None => table.iter()
+            .find(|(_, p)| p.tgid == tgid)
+            .map(|(&pid, _)| pid)
+            .ok_or(ESRCH),
+    }
+}
+
+/// Post `sig` to process `tgid` as a whole, for kill.
+pub fn send_group(tgid: usize, sig: usize, info: SigInfo) -> Result<(), isize> {
+    send(group_target(tgid)?, sig, info)
+}
+
+/// Post `sig` to thread `pid`, if it belongs to process `tgid`, for
+/// tgkill.
+pub fn send_thread(tgid: usize, pid: usize, sig: usize, info: SigInfo) -> Result<(), isize> {
+    if PENDING.lock().get(&pid).map(|p| p.tgid) != Some(tgid) {
+        return Err(ESRCH);
+    }
+    send(pid, sig, info)
+}
+
+/// SIGKILL every thread of `proc`'s process but `proc`, for exit_group
+/// and execve.
+pub fn kill_other_threads(proc: &Process) {
+    let info = SigInfo { code: SI_KERNEL, pid: 0, addr: 0 };
+    for pid in threads(proc.tgid) {
+        if pid != proc.id {
+            let _ = send(pid, SIGKILL, info);
+        }
+    }
+}
+
+/// Signals pending for `pid`.
+pub fn pending(pid: usize) -> SigSet {
+    PENDING.lock().get(&pid).map_or(0, |p| p.set)
+}
+
+/// Post signal `sig` to thread `pid`. Signal 0 only checks that there
+/// is such a thread.
+pub fn send(pid: usize, sig: usize, info: SigInfo) -> Result<(), isize> {
+    if sig > NSIG {
+        return Err(EINVAL);
+    }
+    let mut table = PENDING.lock();
+    let pending = table.get_mut(&pid).ok_or(ESRCH)?;
+    if sig == 0 {
+        return Ok(());
+    }
+    // a continue cancels pending stops, and a stop pending continues
+    if sig == SIGCONT {
+        pending.set &= !STOP_SIGNALS;
+    } else if bit(sig) & STOP_SIGNALS != 0 {
+        pending.set &= !bit(SIGCONT);
+    }
+    // standard signals don't queue, the first one's info stands
+    if pending.set & bit(sig) == 0 {
+        pending.set |= bit(sig);
+        pending.info[sig - 1] = info;
+    }
+    if sig == SIGCONT || sig == SIGKILL {
+        // still holding PENDING, so a process stopping right now
+        // either sees this or is already parked
+        unsafe {
+            (*addr_of!(QUEUE)).get().unwrap().lock().unpark(pid);
+        }
+    }
+    drop(table);
+    // and one waiting on a futex stops waiting
+    futex::interrupt(pid);
+    Ok(())
+}
+
+// Take the lowest numbered pending signal `mask` doesn't block.
+fn take_next(pid: usize, mask: SigSet) -> Option<(usize, SigInfo)> {
+    let mut table = PENDING.lock();
+    let pending = table.get_mut(&pid)?;
+    let ready = pending.set & !(mask & !UNBLOCKABLE);
+    if ready == 0 {
+        return None;
+    }
+    let sig = ready.trailing_zeros() as usize + 1;
+    pending.set &= !bit(sig);
+    Some((sig, pending.info[sig - 1]))
+}
+
+/// End `proc` because of `sig`.
+pub fn process_kill(proc: Process, sig: usize) -> ! {
+    log!(Info, "Process {} killed by signal {}.", proc.id, sig);
+    process_exit(proc, 128 + sig as isize);
+}
+
+/// Raise `sig` in `proc` because of something it did, like a fault.
+/// It gets through even if blocked or ignored, the way Linux forces
+/// these.
+pub fn force(proc: &mut Process, sig: usize, info: SigInfo) {
+    proc.signals.mask &= !bit(sig);
+    if proc.signals.action(sig).handler == SIG_IGN {
+        proc.signals.reset(sig);
+    }
+    let _ = send(proc.id, sig, info);
+}
+
+// Stop `proc` until someone sends SIGCONT or SIGKILL.
+fn stop(mut proc: Process) -> ! {
+    {
+        let table = PENDING.lock();
+        let go_on = table.get(&proc.id)
+            .is_some_and(|p| p.set & (bit(SIGCONT) | bit(SIGKILL)) != 0);
+        if go_on {
+            // already told to carry on, so don't bother
+            drop(table);
+            proc.resume();
+        }
+        proc.state = ProcessState::Stopped;
+        log!(Debug, "Process {} stopped.", proc.id);
+        unsafe {
+            (*addr_of!(QUEUE)).get().unwrap().lock().park(proc);
+        }
+    }
+    schedule();
+}
+
+/// Deliver the pending signals `proc` doesn't block, as it goes back
+/// to user mode. Signals with handlers set up a frame and come back
+/// with the handler about to run. Anything fatal doesn't come back at
+/// all.
+pub fn deliver(proc: Process) -> Process {
+    while let Some((sig, info)) = take_next(proc.id, proc.signals.mask) {
+        let action = proc.signals.action(sig);
+        match action.handler {
+            SIG_IGN => {},
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => {},
+                DefaultAction::Terminate => process_kill(proc, sig),
+                DefaultAction::Stop => stop(proc),
+            },
+            _ => return setup_frame(proc, sig, info, action),
+        }
+    }
+    proc
+}
+
+/// Read the register frame saved at `va` in process memory.
+pub fn read_regs(pt: PageTable, va: usize) -> Result<[usize; 32], VmError> {
+    let mut bytes = [0; REG_FRAME];
+    copy_from_user(pt, &mut bytes, va)?;
+    let mut regs = [0; 32];
+    for (reg, word) in regs.iter_mut().zip(bytes.chunks_exact(8)) {
+        *reg = usize::from_le_bytes(word.try_into().unwrap());
+    }
+    Ok(regs)
+}
+
+/// Write `regs` as a register frame at `va` in process memory.
+pub fn write_regs(pt: PageTable, va: usize, regs: &[usize; 32]) -> Result<(), VmError> {
+    let mut bytes = [0; REG_FRAME];
+    for (reg, word) in regs.iter().zip(bytes.chunks_exact_mut(8)) {
+        word.copy_from_slice(&reg.to_le_bytes());
+    }
+    copy_to_user(pt, va, &bytes)
+}
+
+// Make `proc` run the handler in `action` for `sig` next, with the
+// interrupted state saved on its stack for rt_sigreturn.
+fn setup_frame(mut proc: Process, sig: usize, info: SigInfo, action: SigAction) -> Process {
+    let pt = proc.pgtbl;
+    let mut regs = match read_regs(pt, proc.saved_sp) {
+        Ok(regs) => regs,
+        Err(_) => process_kill(proc, SIGSEGV),
+    };
+    let mut pc = proc.saved_pc;
+    if proc.signals.restarting {
+        proc.signals.restarting = false;
+        if action.flags & SA_RESTART == 0 {
+            // give up on the syscall instead of making it again
+            regs[10] = -EINTR as usize;
+            pc += 4;
+        }
+    }
+
+    let user_sp = proc.saved_sp + REG_FRAME;
+    let frame = (user_sp - SIGFRAME_SIZE) & !15;
+    let mut bytes = [0; SIGFRAME_SIZE];
+    bytes[0..4].copy_from_slice(&(sig as i32).to_le_bytes());
+    bytes[8..12].copy_from_slice(&info.code.to_le_bytes());
+    match sig {
+        SIGSEGV | SIGBUS | SIGILL | SIGFPE | SIGTRAP => {
+            bytes[16..24].copy_from_slice(&info.addr.to_le_bytes());
+        },
+        _ => bytes[16..20].copy_from_slice(&(info.pid as i32).to_le_bytes()),
+    }
+    let uc = SIGINFO_SIZE;
+    bytes[uc + UC_STACK_FLAGS..uc + UC_STACK_FLAGS + 4].copy_from_slice(&SS_DISABLE.to_le_bytes());
+    bytes[uc + UC_SIGMASK..uc + UC_SIGMASK + 8].copy_from_slice(&proc.signals.mask.to_le_bytes());
+    let gregs = uc + UC_MCONTEXT;
+    bytes[gregs..gregs + 8].copy_from_slice(&pc.to_le_bytes());
+    for n in 1..32 {
+        let val = if n == 2 { user_sp } else { regs[n] };
+        bytes[gregs + n * 8..gregs + n * 8 + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    // the handler runs with its frame on top, returning to the trampoline
+    let mut handler_regs = regs;
+    handler_regs[1] = SIGRETURN_VA;
+    handler_regs[2] = frame;
+    handler_regs[10] = sig;
+    handler_regs[11] = frame;
+    handler_regs[12] = frame + uc;
+    if copy_to_user(pt, frame, &bytes).is_err()
+        || write_regs(pt, frame - REG_FRAME, &handler_regs).is_err() {
+        // nowhere to put the frame, so it can't handle anything
+        process_kill(proc, SIGSEGV);
+    }
+
+    let mut mask = proc.signals.mask | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        mask |= bit(sig);
+    }
+    proc.signals.set_mask(mask);
+    if action.flags & SA_RESETHAND != 0 {
+        proc.signals.reset(sig);
+    }
+    proc.saved_sp = frame - REG_FRAME;
+    proc.saved_pc = action.handler;
+    proc
+}
+
+/// rt_sigreturn, made by the trampoline with the registers of the
+/// handler saved at `sp`. Puts back the state `setup_frame` saved.
+pub fn sigreturn(mut proc: Process, sp: usize) -> ! {
+    let frame = sp + REG_FRAME;
+    let uc = frame + SIGINFO_SIZE;
+    let mut bytes = [0; REG_FRAME];
+    let mut mask = [0; 8];
+    if copy_from_user(proc.pgtbl, &mut bytes, uc + UC_MCONTEXT).is_err()
+        || copy_from_user(proc.pgtbl, &mut mask, uc + UC_SIGMASK).is_err() {
+        process_kill(proc, SIGSEGV);
+    }
+    let mut regs = [0; 32];
+    for (reg, word) in regs.iter_mut().zip(bytes.chunks_exact(8)) {
+        *reg = usize::from_le_bytes(word.try_into().unwrap());
+    }
+    let pc = regs[0];
+    regs[0] = 0;
+    let saved_sp = regs[2].wrapping_sub(REG_FRAME);
+    if write_regs(proc.pgtbl, saved_sp, &regs).is_err() {
+        process_kill(proc, SIGSEGV);
+    }
+    proc.signals.set_mask(u64::from_le_bytes(mask));
+    proc.saved_pc = pc;
+    proc.saved_sp = saved_sp;
+    proc.state = ProcessState::Ready;
+    proc.resume();
+}
+
+// scause values for exceptions out of user mode
+const INST_MISALIGNED: usize = 0;
+const INST_ACCESS_FAULT: usize = 1;
+const ILLEGAL_INST: usize = 2;
+const BREAKPOINT: usize = 3;
+const LOAD_MISALIGNED: usize = 4;
+const LOAD_ACCESS_FAULT: usize = 5;
+const STORE_MISALIGNED: usize = 6;
+const STORE_ACCESS_FAULT: usize = 7;
+const INST_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+const STORE_PAGE_FAULT: usize = 15;
+
+/// The running process took exception `cause` at `pc`, with `stval`
+/// saying more. `regs` is the frame regular_strap saved on the
+/// interrupt stack, and `user_sp` the process's sp, see
+/// `user_fault_asm`. This turns the fault into a signal.
+#[no_mangle]
+pub extern "C" fn user_fault_rust(regs: *const [usize; 32], user_sp: usize,
+                                  cause: usize, stval: usize, pc: usize) -> ! {
+    // copy it off the interrupt stack before anything can trap
+    let mut regs = unsafe { *regs };
+    regs[2] = user_sp;
+    let mut proc = get_running_process();
+
+    let (sig, code) = match cause {
+        ILLEGAL_INST => (SIGILL, ILL_ILLOPC),
+        BREAKPOINT => (SIGTRAP, TRAP_BRKPT),
+        INST_MISALIGNED | LOAD_MISALIGNED | STORE_MISALIGNED => (SIGBUS, BUS_ADRALN),
+        INST_ACCESS_FAULT | LOAD_ACCESS_FAULT | STORE_ACCESS_FAULT => (SIGSEGV, SEGV_ACCERR),
+        INST_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT => (SIGSEGV, SEGV_MAPERR),
+        _ => (SIGSEGV, SI_KERNEL),
+    };
+    log!(Debug, "Process {} fault, scause {:#x} stval {:#x} sepc {:#x}.",
+         proc.id, cause, stval, pc);
+
+    // save it the way a syscall would have, so it resumes the same way
+    let saved_sp = user_sp.wrapping_sub(REG_FRAME);
+    if write_regs(proc.pgtbl, saved_sp, &regs).is_err() {
+        // the stack is gone, nothing to deliver a signal on
+        process_kill(proc, sig);
+    }
+    proc.saved_pc = pc;
+    proc.saved_sp = saved_sp;
+    force(&mut proc, sig, SigInfo { code, pid: 0, addr: stval });
+    proc.state = ProcessState::Ready;
+    proc.resume();
+}
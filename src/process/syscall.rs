@@ -1,77 +1,1491 @@
 /// This module isolates all the syscall stuff written in rust. See
 /// syscall.s for the asm half of this
 
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::arch::asm;
 use super::*;
+use crate::device::{clint, uart};
+use crate::vm::tlb;
+use crate::vm::uaccess::{copy_from_user, copy_to_user};
+use super::signal::{self, *};
+use super::fd::{File, OpenFile, VfsFile};
+use super::pipe::{self, PipeError, PIPE_SIZE};
+use super::memfd::Memfd;
+use super::channel::{self, ChannelError, Endpoint, Message, MSG_SIZE};
+use super::rlimit::*;
+use futex::{FUTEX_CMD_MASK, FUTEX_WAIT, FUTEX_WAKE};
 
-/// System call rust handler. This is called from scall_asm. See there
-/// for calling convention info.
+pub mod futex;
+pub mod seccomp;
+pub mod trace;
+
+// The process pc and sp `scall_asm` left in s2 and s3. Only valid at
+// the very start of `scall_rust`.
+macro_rules! process_pc_sp {
+    () => {{
+        let proc_pc: usize;
+        let proc_sp: usize;
+        unsafe {
+            asm!(
+                "mv {pc}, s2",
+                "mv {sp}, s3",
+                pc = out(reg) proc_pc,
+                sp = out(reg) proc_sp
+            );
+        }
+        (proc_pc, proc_sp)
+    }};
+}
+
+/// System call rust handler. This is called from scall_asm, on the
+/// kernel stack with the kernel page table, with the process
+/// registers in `trap_frame`. See there for calling convention info.
 ///
-/// This function is odd, because it runs EITHER in the kernel stack
-/// with the kernel page table, or with the process stack and page
-/// table. This choice is determined by calling `scall_direct` with
-/// the same arguments. With that in mind, this function is
-/// responsible for multiplexing into whatever call was actually
-/// issued.
+/// Every syscall comes through here, so this is where seccomp filters
+/// and the tracer see them. The process pc is in s2 and its sp in s3
+/// on the way in.
 ///
-/// This may change in the future.
+/// Calls that don't switch processes return through `syscall_return`,
+/// which hands the result back in a0. Anything we don't implement
+/// gets -ENOSYS.
 #[no_mangle]
 pub extern "C" fn scall_rust(a0: usize, a1: usize, a2: usize, a3: usize,
                              a4: usize, a5: usize, a6: usize, a7: usize) {
+    // see the comment on process_pc_sp for why we have these
+    let (proc_pc, proc_sp) = process_pc_sp!();
+    // copy the registers off the sscratch stack before anything can
+    // trap, and out to the process stack where the rest of the kernel
+    // expects them
+    let mut regs = unsafe { *trap_frame() };
+    let mut proc = get_running_process();
+    // scall_asm switched to the kernel page table and flushed
+    tlb::leave_user();
+    regs[2] = proc_sp.wrapping_add(REG_FRAME);
+    if write_regs(proc.pgtbl, proc_sp, &regs).is_err() {
+        // no stack to keep them on, so it can't go on
+        signal::process_kill(proc, SIGSEGV);
+    }
+    proc.signals.restarting = false;
+    let args = [a0, a1, a2, a3, a4, a5];
+    let traced = trace::enabled(&proc);
+
+    match proc.seccomp.check(a7, proc_pc, &args) {
+        seccomp::Action::Allow => {},
+        seccomp::Action::Errno(err) => {
+            if traced {
+                trace::syscall(&proc, a7, &args, Some(-err));
+            }
+            syscall_return(proc, proc_pc, proc_sp, -err);
+        },
+        seccomp::Action::Trap => {
+            let info = SigInfo { code: SYS_SECCOMP, pid: 0, addr: proc_pc };
+            signal::force(&mut proc, SIGSYS, info);
+            syscall_return(proc, proc_pc, proc_sp, -ENOSYS);
+        },
+        seccomp::Action::Kill => {
+            log!(Warning, "Process {} killed by seccomp for syscall {} ({}).",
+                 proc.id, a7, syscall_name(a7).unwrap_or("unknown"));
+            signal::process_kill(proc, SIGSYS);
+        },
+    }
+
+    // these don't come back here, so trace them on the way in
+    match a7 {
+        SCHED_YIELD | EXECVE | EXIT | EXIT_GROUP | RT_SIGRETURN if traced => {
+            trace::syscall(&proc, a7, &args, None);
+        }
+        CHANNEL_RECV if traced => {
+            trace::syscall(&proc, a7, &args, None);
+        }
+        FUTEX if traced && a1 & FUTEX_CMD_MASK == FUTEX_WAIT => {
+            trace::syscall(&proc, a7, &args, None);
+        }
+        _ => {}
+    }
     match a7 {
         SCHED_YIELD => {
-            // see the comment on scall_direct for why we have these
-            let proc_pc: usize;
-            let proc_sp: usize;
-            unsafe {
-                asm!(
-                    "mv {pc}, s2",
-                    "mv {sp}, s3",
-                    pc = out(reg) proc_pc,
-                    sp = out(reg) proc_sp
-                );
+            proc.write_saved_reg(proc_sp, 10, 0); // a0 is x10
+            process_pause(proc, proc_pc, proc_sp, 0); // cause 0, explicit yield
+        }
+        EXECVE => {
+            sys_execve(proc, proc_pc, proc_sp, a0, a1, a2);
+        }
+        EXIT => {
+            process_exit(proc, a0 as i32 as isize);
+        }
+        EXIT_GROUP => {
+            signal::kill_other_threads(&proc);
+            process_exit(proc, a0 as i32 as isize);
+        }
+        RT_SIGRETURN => {
+            signal::sigreturn(proc, proc_sp);
+        }
+        FUTEX if a1 & FUTEX_CMD_MASK == FUTEX_WAIT => {
+            futex::sys_futex_wait(proc, proc_pc, proc_sp, a0, a1, a2, a3);
+        }
+        CHANNEL_RECV => {
+            sys_channel_recv(proc, proc_pc, proc_sp, a0, a1, a2);
+        }
+        _ => {}
+    }
+
+    let ret = match a7 {
+        READ => sys_read(&proc, a0, a1, a2),
+        WRITE => sys_write(&proc, a0, a1, a2),
+        WRITEV => sys_writev(&proc, a0, a1, a2),
+        IOCTL => sys_ioctl(&proc, a0, a1, a2),
+        PIPE2 => sys_pipe2(&proc, a0, a1),
+        CLOSE => sys_close(&proc, a0),
+        DUP => sys_dup(&proc, a0),
+        DUP3 => sys_dup3(&proc, a0, a1, a2),
+        OPENAT => sys_openat(&proc, a0, a1, a2, a3),
+        GETDENTS64 => sys_getdents64(&proc, a0, a1, a2),
+        LSEEK => sys_lseek(&proc, a0, a1, a2),
+        CHANNEL_CREATE => sys_channel_create(&proc, a0, a1),
+        CHANNEL_SEND => sys_channel_send(&proc, a0, a1, a2),
+        CHANNEL_PUBLISH => sys_channel_publish(&proc, a0, a1),
+        CHANNEL_CONNECT => sys_channel_connect(&proc, a0, a1),
+        SET_TID_ADDRESS => {
+            proc.clear_child_tid = a0;
+            proc.id as isize
+        },
+        NEWUNAME => sys_uname(&proc, a0),
+        CLOCK_GETTIME => sys_clock_gettime(&mut proc, a0, a1),
+        TIMES => sys_times(&mut proc, a0),
+        GETRUSAGE => sys_getrusage(&mut proc, a0 as i32 as isize, a1),
+        BRK => sys_brk(&proc, a0),
+        MMAP => sys_mmap(&proc, a0, a1, a2, a3, a4, a5),
+        MEMFD_CREATE => sys_memfd_create(&proc, a0, a1),
+        FTRUNCATE64 => sys_ftruncate(&proc, a0, a1),
+        PRCTL => sys_prctl(&mut proc, a0, a1, a2),
+        SECCOMP => seccomp::sys_seccomp(&mut proc, a0, a1, a2),
+        GETPID => proc.tgid as isize,
+        GETTID => proc.id as isize,
+        FUTEX => match a1 & FUTEX_CMD_MASK {
+            FUTEX_WAKE => futex::sys_futex_wake(&proc, a0, a1, a2),
+            _ => -ENOSYS,
+        },
+        CLONE => {
+            let args = CloneArgs { flags: a0, stack: a1, parent_tid: a2, tls: a3, child_tid: a4 };
+            sys_clone(&proc, proc_pc, proc_sp, args)
+        },
+        KILL => sys_kill(&proc, a0 as i32 as isize, a1),
+        TKILL => sys_tgkill(&proc, None, a0 as i32 as isize, a1),
+        TGKILL => sys_tgkill(&proc, Some(a0 as i32 as isize), a1 as i32 as isize, a2),
+        RT_SIGACTION => sys_rt_sigaction(&mut proc, a0, a1, a2, a3),
+        RT_SIGPROCMASK => sys_rt_sigprocmask(&mut proc, a0, a1, a2, a3),
+        RT_SIGPENDING => sys_rt_sigpending(&proc, a0, a1),
+        GETRLIMIT => sys_prlimit64(&proc, 0, a0, 0, a1),
+        SETRLIMIT => sys_prlimit64(&proc, 0, a0, a1, 0),
+        PRLIMIT64 => sys_prlimit64(&proc, a0, a1, a2, a3),
+        _ => -ENOSYS,
+    };
+    if ret == -ERESTARTSYS {
+        wait_file(proc, proc_pc, proc_sp, a7, &args);
+    }
+    if traced {
+        trace::syscall(&proc, a7, &args, Some(ret));
+    }
+    syscall_return(proc, proc_pc, proc_sp, ret);
+}
+
+/// Finish a syscall for `proc`, which made it at `pc` with its
+/// registers saved at `sp`, handing back `ret` in a0.
+fn syscall_return(mut proc: Process, pc: usize, sp: usize, ret: isize) -> ! {
+    proc.write_saved_reg(sp, 10, ret as usize); // a0 is x10
+    proc.saved_pc = pc + 4;
+    proc.saved_sp = sp;
+    proc.state = ProcessState::Ready;
+    proc.resume();
+}
+
+// Every way a user pointer can be bad is EFAULT to the process.
+fn efault(_: VmError) -> isize {
+    EFAULT
+}
+
+/// Read a NUL terminated string out of process memory. Fails with
+/// EFAULT if any of it isn't mapped readable for the process.
+fn user_str(pt: PageTable, va: usize) -> Result<String, isize> {
+    let mut out = Vec::new();
+    while out.len() < PATH_MAX {
+        // a page at a time, so we don't read past the end of the
+        // string into memory that might not be there
+        let at = va.checked_add(out.len()).ok_or(EFAULT)?;
+        let mut chunk = vec![0; min(PAGE_SIZE - at % PAGE_SIZE, PATH_MAX - out.len())];
+        copy_from_user(pt, &mut chunk, at).map_err(efault)?;
+        match chunk.iter().position(|&c| c == 0) {
+            Some(len) => {
+                out.extend_from_slice(&chunk[..len]);
+                return String::from_utf8(out).map_err(|_| EINVAL);
+            },
+            None => out.extend_from_slice(&chunk),
+        }
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// Read a pointer sized word out of process memory.
+fn user_word(pt: PageTable, va: usize) -> Result<usize, isize> {
+    let mut bytes = [0; size_of::<usize>()];
+    copy_from_user(pt, &mut bytes, va).map_err(efault)?;
+    Ok(usize::from_le_bytes(bytes))
+}
+
+/// Read a NULL terminated array of strings, like execve's argv, out of
+/// process memory. A NULL array is empty.
+fn user_str_array(pt: PageTable, va: usize) -> Result<Vec<String>, isize> {
+    let mut out = Vec::new();
+    if va == 0 {
+        return Ok(out);
+    }
+    loop {
+        if out.len() == ARG_COUNT_MAX {
+            return Err(E2BIG);
+        }
+        let ptr = user_word(pt, va.wrapping_add(out.len() * size_of::<usize>()))?;
+        if ptr == 0 {
+            return Ok(out);
+        }
+        out.push(user_str(pt, ptr)?);
+    }
+}
+
+/// The errno for a failed filesystem operation.
+fn fs_errno(e: FsError) -> isize {
+    match e {
+        FsError::NotFound | FsError::InvalidPath => ENOENT,
+        FsError::NotDirectory => ENOTDIR,
+        FsError::IsDirectory => EISDIR,
+        FsError::Exists => EEXIST,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::NoSpace => ENOSPC,
+        FsError::ReadOnly => EROFS,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::Busy => EBUSY,
+        FsError::Unsupported => EINVAL,
+        FsError::Corrupt | FsError::Io => EIO,
+    }
+}
+
+fn exec_errno(e: ExecError) -> isize {
+    match e {
+        // execve says EACCES for anything that isn't a regular file
+        ExecError::File(FsError::IsDirectory) => EACCES,
+        ExecError::File(e) => fs_errno(e),
+        ExecError::Elf(ELFError::FailedAlloc) => ENOMEM,
+        ExecError::Elf(ELFError::ArgumentsTooLong) => E2BIG,
+        ExecError::Elf(_) => ENOEXEC,
+    }
+}
+
+/// execve(path, argv, envp). Only returns to the caller, with -errno
+/// in a0, on failure.
+fn sys_execve(mut proc: Process, pc: usize, sp: usize, path: usize, argv: usize, envp: usize) -> ! {
+    let image = (|| {
+        let path = user_str(proc.pgtbl, path)?;
+        let argv = user_str_array(proc.pgtbl, argv)?;
+        let envp = user_str_array(proc.pgtbl, envp)?;
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+        if STACK_PAGES * PAGE_SIZE > limit(&proc, RLIMIT_STACK) {
+            return Err(ENOMEM);
+        }
+        let image = Process::load(&path, &argv, &envp).map_err(exec_errno)?;
+        if image.address_space.lock().mapped > limit(&proc, RLIMIT_AS) {
+            return Err(ENOMEM);
+        }
+        Ok(image)
+    })();
+    match image {
+        Ok(image) => {
+            // the new program starts out with just this thread
+            signal::kill_other_threads(&proc);
+            proc.replace_image(image);
+            log!(Debug, "Process {} exec'd a new program.", proc.id);
+            proc.start();
+        },
+        Err(errno) => syscall_return(proc, pc, sp, -errno),
+    }
+}
+
+/// Write `len` bytes of process memory at `va` to the console, turning
+/// \n into \r\n like a terminal would.
+fn console_write(pt: PageTable, va: usize, len: usize) -> Result<usize, isize> {
+    let mut buf = [0; 256];
+    let mut done = 0;
+    while done < len {
+        let n = min(len - done, buf.len());
+        // what we already wrote counts, even if the rest is bad
+        match copy_from_user(pt, &mut buf[..n], va.wrapping_add(done)) {
+            Ok(()) => {},
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(efault(e)),
+        }
+        let mut dev = unsafe { (*addr_of!(uart::WRITER)).lock() };
+        for &c in &buf[..n] {
+            if c == b'\n' {
+                dev.put(b'\r');
             }
-            process_pause(proc_pc, proc_sp, 0); // cause 0, explicit yield
+            dev.put(c);
         }
-        _ => {
-            panic!("Uncaught system call: {}", a7);
+        done += n;
+    }
+    Ok(done)
+}
+
+// The open file `fd` refers to in `proc`.
+fn open_file(proc: &Process, fd: usize) -> Result<OpenFile, isize> {
+    proc.files.lock().get(fd).ok_or(EBADF)
+}
+
+// What a pipe that can't go on right now means to the caller: wait,
+// see `wait_file`, or fail straight away if it doesn't want to.
+fn pipe_errno(proc: &Process, file: &OpenFile, e: PipeError) -> isize {
+    match e {
+        PipeError::WouldBlock if file.nonblock => EAGAIN,
+        PipeError::WouldBlock => ERESTARTSYS,
+        PipeError::NotTaken => EFAULT,
+        PipeError::Broken => {
+            let info = SigInfo { code: SI_USER, pid: proc.tgid, addr: 0 };
+            let _ = signal::send(proc.id, SIGPIPE, info);
+            EPIPE
+        },
+    }
+}
+
+/// `proc` made a read or write at `pc`, with its registers at `sp`, that
+/// has to wait. It sleeps on the pipe, or the console's input, until
+/// that changes, then makes the call again. Anything else just gets
+/// made again later.
+fn wait_file(proc: Process, pc: usize, sp: usize, call: usize, args: &[usize; 6]) -> ! {
+    let file = match open_file(&proc, args[0]) {
+        Ok(file) => file.file,
+        Err(_) => process_retry(proc, pc, sp),
+    };
+    let len = match call {
+        WRITE => min(args[2], PIPE_SIZE),
+        // everything before the first iovec with something in it
+        // went in, as nothing at all
+        WRITEV => (0..min(args[2], IOV_MAX))
+            .map(|i| user_word(proc.pgtbl, args[1].wrapping_add((2 * i + 1) * size_of::<usize>())))
+            .find(|len| *len != Ok(0))
+            .and_then(Result::ok)
+            .map_or(0, |len| min(len, PIPE_SIZE)),
+        _ => 0,
+    };
+    let key = match &*file {
+        File::Pipe(end) => end.wait_key(),
+        File::Console => uart::input_key(),
+        _ => process_retry(proc, pc, sp),
+    };
+    futex::wait_object(proc, pc, sp, key, None, move || match &*file {
+        File::Pipe(end) => end.ready(len),
+        File::Console => uart::has_input(),
+        _ => true,
+    })
+}
+
+/// Read up to `count` bytes from the console to process memory at
+/// `buf`. Whatever has been typed comes back, echoed, and we wait for
+/// the UART interrupt if nothing has. If `buf` is bad it stays typed,
+/// for the next read.
+fn console_read(pt: PageTable, buf: usize, count: usize) -> Result<usize, isize> {
+    let mut got = Vec::new();
+    while got.len() < count {
+        let c = match uart::read_input() {
+            Some(b'\r') => b'\n',
+            Some(c) => c,
+            None => break,
+        };
+        got.push(c);
+        if c == b'\n' {
+            break;
         }
     }
+    if got.is_empty() {
+        return Err(ERESTARTSYS);
+    }
+    if let Err(e) = copy_to_user(pt, buf, &got) {
+        uart::unread_input(&got);
+        return Err(efault(e));
+    }
+    let mut dev = unsafe { (*addr_of!(uart::WRITER)).lock() };
+    for &c in &got {
+        if c == b'\n' {
+            dev.put(b'\r');
+        }
+        dev.put(c);
+    }
+    Ok(got.len())
 }
 
-/// This call runs in the sscratch stack, and serves only to direct
-/// traffic, deciding whether further scall processing happens on the
-/// kernel stack with the kernel page table or the process stack with
-/// the process page table.
-///
-/// This function returns 0 if the syscall should execute on the
-/// program stack/page table, and any non-zero value to execute on the kernel
-/// stack/page table.
-///
-/// The return value here is in a0. This function must also have a net
-/// stack impact of zero. This function cannot overwrite its
-/// arguments. These conditions are expected by the asm.
-///
-/// In addition, if this function returns non-zero, then this scall is
-/// considered to have left the process, at least partially. This
-/// means that as of entering `scall_rust`, the process registers have
-/// been saved to the process stack, we are now in kernel space, the
-/// process pc is in s2, and the process sp is in s3
-#[no_mangle]
-pub extern "C" fn scall_direct(a0: usize, a1: usize, a2: usize, a3: usize,
-                               a4: usize, a5: usize, a6: usize, a7: usize)
-                               -> usize {
-    match a7 {
-        SCHED_YIELD => {
-            1
+// Read up to `count` bytes of `file` from where it is up to, to
+// process memory at `buf`, and move it on.
+fn vfs_read(pt: PageTable, file: &VfsFile, buf: usize, count: usize) -> Result<usize, isize> {
+    if !file.read {
+        return Err(EBADF);
+    }
+    let mut offset = file.offset.lock();
+    let mut data = vec![0; min(count, PAGE_SIZE)];
+    let n = file.inode.read_at(*offset, &mut data).map_err(fs_errno)?;
+    copy_to_user(pt, buf, &data[..n]).map_err(efault)?;
+    *offset += n as u64;
+    Ok(n)
+}
+
+// Write up to `len` bytes of process memory at `va` to `file` where it
+// is up to, and move it on. Filesystems that can't be written say EROFS.
+fn vfs_write(pt: PageTable, file: &VfsFile, va: usize, len: usize) -> Result<usize, isize> {
+    if !file.write {
+        return Err(EBADF);
+    }
+    let mut offset = file.offset.lock();
+    let mut data = vec![0; min(len, PAGE_SIZE)];
+    copy_from_user(pt, &mut data, va).map_err(efault)?;
+    let n = file.inode.write_at(*offset, &data).map_err(fs_errno)?;
+    *offset += n as u64;
+    Ok(n)
+}
+
+/// read(fd, buf, count)
+fn sys_read(proc: &Process, fd: usize, buf: usize, count: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    if count == 0 {
+        return 0;
+    }
+    let read = match &*file.file {
+        File::Console => console_read(proc.pgtbl, buf, count),
+        File::Pipe(end) if end.is_write() => Err(EBADF),
+        File::Pipe(end) => end
+            .read(min(count, PIPE_SIZE), |data| copy_to_user(proc.pgtbl, buf, data).is_ok())
+            .map_err(|e| pipe_errno(proc, &file, e)),
+        // no file offsets yet, so a memfd is only for mapping, and
+        // channels have calls of their own
+        File::Memfd(_) | File::Channel(_) => Err(EINVAL),
+        File::Vfs(file) => vfs_read(proc.pgtbl, file, buf, count),
+    };
+    match read {
+        Ok(n) => n as isize,
+        Err(errno) => -errno,
+    }
+}
+
+// Write `len` bytes of process memory at `va` to `file`.
+fn file_write(proc: &Process, file: &OpenFile, va: usize, len: usize) -> Result<usize, isize> {
+    match &*file.file {
+        File::Console => console_write(proc.pgtbl, va, len),
+        File::Pipe(end) if !end.is_write() => Err(EBADF),
+        File::Pipe(end) => {
+            let mut data = vec![0; min(len, PIPE_SIZE)];
+            copy_from_user(proc.pgtbl, &mut data, va).map_err(efault)?;
+            end.write(&data).map_err(|e| pipe_errno(proc, file, e))
+        },
+        File::Memfd(_) | File::Channel(_) => Err(EINVAL),
+        File::Vfs(file) => vfs_write(proc.pgtbl, file, va, len),
+    }
+}
+
+/// write(fd, buf, count)
+fn sys_write(proc: &Process, fd: usize, buf: usize, count: usize) -> isize {
+    match open_file(proc, fd).and_then(|file| file_write(proc, &file, buf, count)) {
+        Ok(n) => n as isize,
+        Err(errno) => -errno,
+    }
+}
+
+/// writev(fd, iov, iovcnt)
+fn sys_writev(proc: &Process, fd: usize, iov: usize, iovcnt: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    if iovcnt > IOV_MAX {
+        return -EINVAL;
+    }
+    let mut total = 0;
+    for i in 0..iovcnt {
+        // struct iovec { void *iov_base; size_t iov_len; }
+        let entry = iov.wrapping_add(i * 2 * size_of::<usize>());
+        let written = user_word(proc.pgtbl, entry)
+            .and_then(|base| Ok((base, user_word(proc.pgtbl, entry + size_of::<usize>())?)))
+            .and_then(|(base, len)| Ok((len, file_write(proc, &file, base, len)?)));
+        match written {
+            Ok((len, n)) => {
+                total += n;
+                if n < len {
+                    break;
+                }
+            },
+            // partial writes still count
+            Err(_) if total > 0 => break,
+            Err(errno) => return -errno,
+        }
+    }
+    total as isize
+}
+
+/// ioctl(fd, request, arg). The console only answers TIOCGWINSZ, which
+/// is how isatty() finds out it is a terminal.
+fn sys_ioctl(proc: &Process, fd: usize, request: usize, arg: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    match (&*file.file, request) {
+        (File::Console, TIOCGWINSZ) => {
+            // struct winsize { rows, cols, xpixel, ypixel }, all u16
+            let mut winsize = [0; 8];
+            winsize[0..2].copy_from_slice(&CONSOLE_ROWS.to_le_bytes());
+            winsize[2..4].copy_from_slice(&CONSOLE_COLS.to_le_bytes());
+            match copy_to_user(proc.pgtbl, arg, &winsize) {
+                Ok(()) => 0,
+                Err(e) => -efault(e),
+            }
         },
+        _ => -ENOTTY,
+    }
+}
+
+// Open `first` and `second` at the lowest free fds of `proc`, and put
+// the two fds in the int[2] at `fds`.
+fn open_pair(proc: &Process, fds: usize, first: File, second: File,
+             cloexec: bool, nonblock: bool) -> isize {
+    let max = limit(proc, RLIMIT_NOFILE);
+    let mut files = proc.files.lock();
+    let Some(first_fd) = files.open(Arc::new(first), cloexec, nonblock, max) else {
+        return -EMFILE;
+    };
+    let Some(second_fd) = files.open(Arc::new(second), cloexec, nonblock, max) else {
+        files.close(first_fd);
+        return -EMFILE;
+    };
+    let mut out = [0; 8];
+    out[0..4].copy_from_slice(&(first_fd as i32).to_le_bytes());
+    out[4..8].copy_from_slice(&(second_fd as i32).to_le_bytes());
+    if let Err(e) = copy_to_user(proc.pgtbl, fds, &out) {
+        files.close(first_fd);
+        files.close(second_fd);
+        return -efault(e);
+    }
+    0
+}
+
+/// pipe2(fds, flags). The read end goes in fds[0] and the write end in
+/// fds[1].
+fn sys_pipe2(proc: &Process, fds: usize, flags: usize) -> isize {
+    if flags & !(O_CLOEXEC | O_NONBLOCK) != 0 {
+        return -EINVAL;
+    }
+    let (read_end, write_end) = pipe::new();
+    open_pair(proc, fds, File::Pipe(read_end), File::Pipe(write_end),
+              flags & O_CLOEXEC != 0, flags & O_NONBLOCK != 0)
+}
+
+/// channel_create(fds, flags). A new channel, with its endpoints in
+/// fds[0] and fds[1].
+fn sys_channel_create(proc: &Process, fds: usize, flags: usize) -> isize {
+    if flags & !O_CLOEXEC != 0 {
+        return -EINVAL;
+    }
+    let (first, second) = channel::new();
+    open_pair(proc, fds, File::Channel(first), File::Channel(second),
+              flags & O_CLOEXEC != 0, false)
+}
+
+// The channel endpoint `file` is, if it is one.
+fn endpoint(file: &File) -> Result<&Endpoint, isize> {
+    match file {
+        File::Channel(endpoint) => Ok(endpoint),
+        _ => Err(EBADF),
+    }
+}
+
+/// channel_send(fd, msg, page) sends the MSG_SIZE bytes at `msg`, and
+/// the page at `page` unless that is 0. The page moves rather than
+/// being copied: it is unmapped from the sender, and has to be one that
+/// mmap or brk gave it, not part of the stack or shared. If the send
+/// fails it is mapped back where it was. A full channel is EAGAIN,
+/// never a wait.
+fn sys_channel_send(proc: &Process, fd: usize, msg: usize, page: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    let endpoint = match endpoint(&file.file) {
+        Ok(endpoint) => endpoint,
+        Err(errno) => return -errno,
+    };
+    let mut data = [0; MSG_SIZE];
+    if let Err(e) = copy_from_user(proc.pgtbl, &mut data, msg) {
+        return -efault(e);
+    }
+    let (moved, had) = match page {
+        0 => (None, 0),
+        _ if !page.is_multiple_of(PAGE_SIZE) => return -EINVAL,
         _ => {
+            let flags = user_process_flags(true, false, false);
+            if unsafe { translate(proc.pgtbl, page as *mut usize, flags) }.is_none() {
+                return -EFAULT;
+            }
+            match proc.address_space.lock().take_page(page, flags) {
+                Some((taken, had)) => (Some(taken), had),
+                None => return -EINVAL,
+            }
+        },
+    };
+    match endpoint.send(Message { data, page: moved }) {
+        Ok(()) => 0,
+        Err((e, message)) => {
+            if let Some(taken) = message.page {
+                // the address is still free, nothing maps over it
+                let _ = proc.address_space.lock().put_page(page, taken, had);
+            }
+            match e {
+                ChannelError::Closed => -EPIPE,
+                _ => -EAGAIN,
+            }
+        },
+    }
+}
+
+// Receive from the endpoint `file` for channel_recv. Err has what to
+// wait on, and until when, if there's nothing yet.
+fn channel_recv(proc: &Process, file: &File, msg: usize,
+                deadline: usize) -> Result<isize, (usize, Option<u64>)> {
+    let endpoint = match endpoint(file) {
+        Ok(endpoint) => endpoint,
+        Err(errno) => return Ok(-errno),
+    };
+    let mut message = match endpoint.recv() {
+        Ok(message) => message,
+        Err(ChannelError::Closed) => return Ok(-EPIPE),
+        Err(_) if deadline == 0 => return Ok(-EAGAIN),
+        Err(_) if deadline == usize::MAX => return Err((endpoint.wait_key(), None)),
+        Err(_) => {
+            let freq = clint::mtime_frequency() as u128;
+            let at = (deadline as u128 * freq / 1_000_000_000) as u64;
+            if at <= clint::read_mtime() {
+                return Ok(-ETIMEDOUT);
+            }
+            return Err((endpoint.wait_key(), Some(at)));
+        },
+    };
+    // from here on anything that goes wrong puts the message back
+    let flags = user_process_flags(true, true, false);
+    let va = match message.page.take() {
+        Some(page) => {
+            let max = limit(proc, RLIMIT_AS);
+            let mut space = proc.address_space.lock();
+            let mapped = match space.mapped + PAGE_SIZE > max {
+                true => Err(page),
+                false => space.map_page(page, flags).map_err(|(_, page)| page),
+            };
+            match mapped {
+                Ok(va) => va,
+                Err(page) => {
+                    drop(space);
+                    message.page = Some(page);
+                    endpoint.unrecv(message);
+                    return Ok(-ENOMEM);
+                },
+            }
+        },
+        None => 0,
+    };
+    match copy_to_user(proc.pgtbl, msg, &message.data) {
+        Ok(()) => Ok(va as isize),
+        Err(e) => {
+            if va != 0 {
+                message.page = proc.address_space.lock().take_page(va, flags).map(|(page, _)| page);
+            }
+            endpoint.unrecv(message);
+            Ok(-efault(e))
+        },
+    }
+}
+
+/// channel_recv(fd, msg, deadline) takes the next message into `msg`.
+/// A page that came with it is mapped somewhere new and the call
+/// returns where, 0 if there wasn't one. With nothing to take yet it
+/// waits until `deadline`, in nanoseconds of CLOCK_MONOTONIC, where 0
+/// doesn't wait and -1 waits for ever. A message that can't be handed
+/// over, for want of memory or a bad `msg`, stays first in line. Only
+/// returns here through `syscall_return`.
+fn sys_channel_recv(proc: Process, pc: usize, sp: usize, fd: usize, msg: usize,
+                    deadline: usize) -> ! {
+    let file = match open_file(&proc, fd) {
+        Ok(file) => file.file,
+        Err(errno) => syscall_return(proc, pc, sp, -errno),
+    };
+    match channel_recv(&proc, &file, msg, deadline) {
+        Ok(ret) => {
+            // nothing gets dropped once we're off
+            drop(file);
+            syscall_return(proc, pc, sp, ret);
+        },
+        Err((key, at)) => futex::wait_object(proc, pc, sp, key, at, move || {
+            endpoint(&file).map_or(true, Endpoint::ready)
+        }),
+    }
+}
+
+/// channel_publish(fd, name) gives up the endpoint at `fd` for someone
+/// else to connect to by `name`.
+fn sys_channel_publish(proc: &Process, fd: usize, name: usize) -> isize {
+    let name = match user_str(proc.pgtbl, name) {
+        Ok(name) => name,
+        Err(errno) => return -errno,
+    };
+    let mut files = proc.files.lock();
+    let Some(file) = files.get(fd) else {
+        return -EBADF;
+    };
+    if let Err(errno) = endpoint(&file.file) {
+        return -errno;
+    }
+    match channel::publish(name, file.file) {
+        Ok(()) => {
+            files.close(fd);
             0
+        },
+        Err(_) => -EEXIST,
+    }
+}
+
+/// channel_connect(name, flags) takes the endpoint published as `name`
+/// and returns it as a new fd.
+fn sys_channel_connect(proc: &Process, name: usize, flags: usize) -> isize {
+    if flags & !O_CLOEXEC != 0 {
+        return -EINVAL;
+    }
+    let name = match user_str(proc.pgtbl, name) {
+        Ok(name) => name,
+        Err(errno) => return -errno,
+    };
+    let Some(file) = channel::connect(&name) else {
+        return -ENOENT;
+    };
+    let max = limit(proc, RLIMIT_NOFILE);
+    let opened = proc.files.lock().open(file.clone(), flags & O_CLOEXEC != 0, false, max);
+    match opened {
+        Some(fd) => fd as isize,
+        None => {
+            // leave it for someone with room
+            let _ = channel::publish(name, file);
+            -EMFILE
+        },
+    }
+}
+
+/// memfd_create(name, flags). A new empty memfd, see `memfd`. Sealing
+/// is allowed for, but there are no seals to add.
+fn sys_memfd_create(proc: &Process, name: usize, flags: usize) -> isize {
+    if flags & !(MFD_CLOEXEC | MFD_ALLOW_SEALING) != 0 {
+        return -EINVAL;
+    }
+    let name = match user_str(proc.pgtbl, name) {
+        Ok(name) if name.len() <= MFD_NAME_MAX => name,
+        Ok(_) => return -EINVAL,
+        Err(errno) => return -errno,
+    };
+    let file = Arc::new(File::Memfd(Memfd::new(name)));
+    let max = limit(proc, RLIMIT_NOFILE);
+    match proc.files.lock().open(file, flags & MFD_CLOEXEC != 0, false, max) {
+        Some(fd) => fd as isize,
+        None => -EMFILE,
+    }
+}
+
+/// ftruncate(fd, length). Only memfds can change size.
+fn sys_ftruncate(proc: &Process, fd: usize, length: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    if (length as isize) < 0 {
+        return -EINVAL;
+    }
+    match &*file.file {
+        File::Memfd(memfd) => match memfd.truncate(length) {
+            Ok(()) => 0,
+            Err(_) => -ENOMEM,
+        },
+        _ => -EINVAL,
+    }
+}
+
+/// close(fd)
+fn sys_close(proc: &Process, fd: usize) -> isize {
+    match proc.files.lock().close(fd) {
+        true => 0,
+        false => -EBADF,
+    }
+}
+
+/// openat(dirfd, path, flags, mode). Only files that are already
+/// there, with no O_CREAT or O_TRUNC yet, and relative paths start from
+/// `/` for AT_FDCWD, as there's no working directory yet.
+fn sys_openat(proc: &Process, dirfd: usize, path: usize, flags: usize, _mode: usize) -> isize {
+    if flags & !(O_ACCMODE | O_LARGEFILE | O_DIRECTORY | O_CLOEXEC | O_NONBLOCK) != 0 {
+        return -EINVAL;
+    }
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return -EINVAL,
+    };
+    let path = match user_str(proc.pgtbl, path) {
+        Ok(path) => path,
+        Err(errno) => return -errno,
+    };
+    let path = if path.starts_with('/') {
+        path
+    } else if dirfd as i32 as isize == AT_FDCWD {
+        format!("/{}", path)
+    } else {
+        let dir = match open_file(proc, dirfd) {
+            Ok(dir) => dir,
+            Err(errno) => return -errno,
+        };
+        match &*dir.file {
+            File::Vfs(dir) => format!("{}/{}", dir.path, path),
+            _ => return -ENOTDIR,
         }
+    };
+    let inode = match vfs::lookup(&path) {
+        Ok(inode) => inode,
+        Err(e) => return -fs_errno(e),
+    };
+    match inode.stat() {
+        Ok(stat) if stat.ftype != vfs::FileType::Directory && flags & O_DIRECTORY != 0 => {
+            return -ENOTDIR;
+        },
+        Ok(stat) if stat.ftype == vfs::FileType::Directory && write => return -EISDIR,
+        Ok(_) => {},
+        Err(e) => return -fs_errno(e),
+    }
+    let file = VfsFile { inode, path, offset: Mutex::new(0), read, write };
+    let file = Arc::new(File::Vfs(file));
+    let max = limit(proc, RLIMIT_NOFILE);
+    match proc.files.lock().open(file, flags & O_CLOEXEC != 0, flags & O_NONBLOCK != 0, max) {
+        Some(fd) => fd as isize,
+        None => -EMFILE,
+    }
+}
+
+/// getdents64(fd, dirp, count). As many entries of the directory as
+/// fit in `count` bytes, carrying on from the last call.
+fn sys_getdents64(proc: &Process, fd: usize, dirp: usize, count: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    let File::Vfs(dir) = &*file.file else {
+        return -ENOTDIR;
+    };
+    let entries = match dir.inode.readdir() {
+        Ok(entries) => entries,
+        Err(e) => return -fs_errno(e),
+    };
+    // the offset of a directory counts entries
+    let mut next = dir.offset.lock();
+    let mut out = Vec::new();
+    for (n, entry) in entries.iter().enumerate().skip(*next as usize) {
+        // struct linux_dirent64 { u64 d_ino; i64 d_off; u16 d_reclen;
+        // u8 d_type; char d_name[]; }, padded to 8 bytes
+        let reclen = (DIRENT64_NAME + entry.name.len() + 1).next_multiple_of(8);
+        if out.len() + reclen > count {
+            break;
+        }
+        let start = out.len();
+        out.extend_from_slice(&entry.ino.to_le_bytes());
+        out.extend_from_slice(&(n as u64 + 1).to_le_bytes());
+        out.extend_from_slice(&(reclen as u16).to_le_bytes());
+        out.push(match entry.ftype {
+            vfs::FileType::Directory => DT_DIR,
+            vfs::FileType::Regular => DT_REG,
+        });
+        out.extend_from_slice(entry.name.as_bytes());
+        out.resize(start + reclen, 0);
+        *next = n as u64 + 1;
+    }
+    if out.is_empty() && (*next as usize) < entries.len() {
+        // not even one entry fits
+        return -EINVAL;
+    }
+    match copy_to_user(proc.pgtbl, dirp, &out) {
+        Ok(()) => out.len() as isize,
+        Err(e) => -efault(e),
+    }
+}
+
+/// lseek(fd, offset, whence). Directories only go back to where they
+/// were, or to the start.
+fn sys_lseek(proc: &Process, fd: usize, offset: usize, whence: usize) -> isize {
+    let file = match open_file(proc, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    let File::Vfs(file) = &*file.file else {
+        return -ESPIPE;
+    };
+    let stat = match file.inode.stat() {
+        Ok(stat) => stat,
+        Err(e) => return -fs_errno(e),
+    };
+    let mut pos = file.offset.lock();
+    let base = match (whence, stat.ftype) {
+        (SEEK_SET, _) => 0,
+        (SEEK_CUR, vfs::FileType::Regular) => *pos,
+        (SEEK_END, vfs::FileType::Regular) => stat.size,
+        _ => return -EINVAL,
+    };
+    match base.checked_add_signed(offset as i64) {
+        Some(new) if new <= isize::MAX as u64 => {
+            *pos = new;
+            new as isize
+        },
+        _ => -EINVAL,
+    }
+}
+
+/// dup(fd), the lowest free fd for the same open file.
+fn sys_dup(proc: &Process, fd: usize) -> isize {
+    let max = limit(proc, RLIMIT_NOFILE);
+    let mut files = proc.files.lock();
+    let Some(file) = files.get(fd) else {
+        return -EBADF;
+    };
+    match files.open(file.file, false, file.nonblock, max) {
+        Some(new) => new as isize,
+        None => -EMFILE,
+    }
+}
+
+/// dup3(fd, new, flags) puts the open file at `fd` at `new` too,
+/// closing whatever `new` was.
+fn sys_dup3(proc: &Process, fd: usize, new: usize, flags: usize) -> isize {
+    if flags & !O_CLOEXEC != 0 || fd == new {
+        return -EINVAL;
+    }
+    if new >= limit(proc, RLIMIT_NOFILE) {
+        return -EBADF;
+    }
+    let mut files = proc.files.lock();
+    let Some(file) = files.get(fd) else {
+        return -EBADF;
+    };
+    files.put(new, file.file, flags & O_CLOEXEC != 0, file.nonblock);
+    new as isize
+}
+
+/// uname(buf)
+fn sys_uname(proc: &Process, buf: usize) -> isize {
+    // struct utsname is six NUL padded strings
+    let fields = [
+        "reedos",                       // sysname
+        "reedos",                       // nodename
+        env!("CARGO_PKG_VERSION"),      // release
+        env!("CARGO_PKG_VERSION"),      // version
+        "riscv64",                      // machine
+        "(none)",                       // domainname
+    ];
+    let mut utsname = [0; 6 * UTSNAME_LENGTH];
+    for (i, field) in fields.iter().enumerate() {
+        let len = min(field.len(), UTSNAME_LENGTH - 1);
+        utsname[i * UTSNAME_LENGTH..][..len].copy_from_slice(&field.as_bytes()[..len]);
+    }
+    match copy_to_user(proc.pgtbl, buf, &utsname) {
+        Ok(()) => 0,
+        Err(e) => -efault(e),
     }
 }
 
-/// Default handler for syscalls that aren't yet implemented
+// mtime `ticks` in nanoseconds.
+fn nanos(ticks: u64) -> u128 {
+    ticks as u128 * 1_000_000_000 / clint::mtime_frequency() as u128
+}
+
+// The CPU time `proc` and the rest of its process have used, as user
+// time and system time, in mtime ticks. The call being made counts.
+fn cpu_times(proc: &mut Process) -> (u64, u64) {
+    proc.account(false);
+    let limits = proc.limits.lock();
+    (limits.user, limits.system)
+}
+
+/// clock_gettime(clockid, tp). Every clock but the CPU time ones counts
+/// from boot, as there is no real time clock to set the wall clock
+/// from.
+fn sys_clock_gettime(proc: &mut Process, clock: usize, tp: usize) -> isize {
+    let ticks = match clock {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW
+            | CLOCK_REALTIME_COARSE | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            clint::read_mtime()
+        },
+        CLOCK_PROCESS_CPUTIME_ID => {
+            let (user, system) = cpu_times(proc);
+            user + system
+        },
+        CLOCK_THREAD_CPUTIME_ID => {
+            proc.account(false);
+            proc.user_time + proc.system_time
+        },
+        _ => return -EINVAL,
+    };
+    let nanos = nanos(ticks);
+    // struct timespec { time_t tv_sec; long tv_nsec; }
+    let mut timespec = [0; 16];
+    timespec[0..8].copy_from_slice(&((nanos / 1_000_000_000) as u64).to_le_bytes());
+    timespec[8..16].copy_from_slice(&((nanos % 1_000_000_000) as u64).to_le_bytes());
+    match copy_to_user(proc.pgtbl, tp, &timespec) {
+        Ok(()) => 0,
+        Err(e) => -efault(e),
+    }
+}
+
+/// times(buf) fills in the struct tms at `buf`, unless it's null,
+/// with the CPU time of this process in clock ticks. Its children have
+/// none, as it can't have any. Returns the ticks since boot.
+fn sys_times(proc: &mut Process, buf: usize) -> isize {
+    let clock_ticks = |ticks| (nanos(ticks) / (1_000_000_000 / USER_HZ)) as u64;
+    if buf != 0 {
+        let (user, system) = cpu_times(proc);
+        // struct tms { clock_t tms_utime, tms_stime, tms_cutime, tms_cstime; }
+        let mut tms = [0; 32];
+        tms[0..8].copy_from_slice(&clock_ticks(user).to_le_bytes());
+        tms[8..16].copy_from_slice(&clock_ticks(system).to_le_bytes());
+        if let Err(e) = copy_to_user(proc.pgtbl, buf, &tms) {
+            return -efault(e);
+        }
+    }
+    clock_ticks(clint::read_mtime()) as isize
+}
+
+/// getrusage(who, usage). Only the CPU times are counted, for this
+/// process or just this thread, and children, there being none, have
+/// used nothing.
+fn sys_getrusage(proc: &mut Process, who: isize, usage: usize) -> isize {
+    let (user, system) = match who {
+        RUSAGE_SELF => cpu_times(proc),
+        RUSAGE_THREAD => {
+            proc.account(false);
+            (proc.user_time, proc.system_time)
+        },
+        RUSAGE_CHILDREN => (0, 0),
+        _ => return -EINVAL,
+    };
+    // struct rusage starts with two struct timevals, ru_utime and
+    // ru_stime, then fourteen longs we leave 0
+    let mut rusage = [0; 144];
+    for (i, ticks) in [user, system].into_iter().enumerate() {
+        let micros = nanos(ticks) / 1000;
+        rusage[i * 16..][..8].copy_from_slice(&((micros / 1_000_000) as u64).to_le_bytes());
+        rusage[i * 16 + 8..][..8].copy_from_slice(&((micros % 1_000_000) as u64).to_le_bytes());
+    }
+    match copy_to_user(proc.pgtbl, usage, &rusage) {
+        Ok(()) => 0,
+        Err(e) => -efault(e),
+    }
+}
+
+/// brk(addr). Moves the end of the heap to `addr` if it can, and
+/// returns where the end is now. Zero just asks. Pages are kept when
+/// the heap shrinks. RLIMIT_DATA limits the heap, and RLIMIT_AS
+/// everything mapped.
+fn sys_brk(proc: &Process, addr: usize) -> isize {
+    let max_data = limit(proc, RLIMIT_DATA);
+    let max_mapped = limit(proc, RLIMIT_AS);
+    let mut space = proc.address_space.lock();
+    // leave a page between the heap and mmap'd memory
+    if addr < space.heap_start || addr > space.mmap_base - PAGE_SIZE {
+        return space.brk as isize;
+    }
+    let end = (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    if end > space.heap_end {
+        let heap_end = space.heap_end;
+        if end - space.heap_start > max_data || space.mapped + (end - heap_end) > max_mapped {
+            return space.brk as isize;
+        }
+        let pages = (end - heap_end) / PAGE_SIZE;
+        match space.map_anonymous(heap_end, pages, user_process_flags(true, true, false)) {
+            Ok(()) => space.heap_end = end,
+            Err(_) => return space.brk as isize,
+        }
+    }
+    space.brk = addr;
+    addr as isize
+}
+
+/// prctl(option, arg2, arg3, ...). The seccomp options, and our own.
+fn sys_prctl(proc: &mut Process, option: usize, arg2: usize, arg3: usize) -> isize {
+    match option {
+        PR_GET_SECCOMP => proc.seccomp.mode() as isize,
+        PR_SET_SECCOMP => seccomp::prctl_set_seccomp(proc, arg2, arg3),
+        PR_GET_NO_NEW_PRIVS => proc.seccomp.no_new_privs as isize,
+        PR_SET_NO_NEW_PRIVS => {
+            if arg2 != 1 {
+                return -EINVAL;
+            }
+            proc.seccomp.no_new_privs = true;
+            0
+        },
+        PR_SET_SYSCALL_TRACE => {
+            proc.traced = arg2 != 0;
+            0
+        },
+        _ => -EINVAL,
+    }
+}
+
+/// The arguments to clone, like Linux's struct clone_args for clone3.
+struct CloneArgs {
+    flags: usize,
+    stack: usize,
+    parent_tid: usize,
+    tls: usize,
+    child_tid: usize,
+}
+
+/// clone(flags, stack, parent_tid, tls, child_tid), the order riscv
+/// has them. With CLONE_VM, CLONE_SIGHAND and CLONE_THREAD it makes a
+/// thread, sharing our memory and signal handlers. With none of them it
+/// forks a process of its own, with a copy of our memory, limits and
+/// signal handlers and the same seccomp filters. Either way the open
+/// files are copied too unless CLONE_FILES. The new one starts on
+/// `stack`, or where we are if a fork has 0 there, as if it had made
+/// the call itself, and gets 0 back. Anything in between, like vfork's
+/// CLONE_VM alone, is ENOSYS.
+fn sys_clone(proc: &Process, pc: usize, sp: usize, args: CloneArgs) -> isize {
+    let CloneArgs { flags, stack, parent_tid, tls, child_tid } = args;
+    const THREAD: usize = CLONE_VM | CLONE_SIGHAND | CLONE_THREAD;
+    const KNOWN: usize = THREAD | CLONE_FS | CLONE_FILES | CLONE_SYSVSEM | CLONE_SETTLS
+        | CLONE_PARENT_SETTID | CLONE_CHILD_SETTID | CLONE_CHILD_CLEARTID;
+    let thread = match flags & THREAD {
+        THREAD => true,
+        0 => false,
+        _ => return -ENOSYS,
+    };
+    // the low byte is the signal for a child's exit, which nothing
+    // waits for yet
+    if flags & !KNOWN & !0xff != 0 || (thread && stack == 0) {
+        return -EINVAL;
+    }
+    if signal::tasks(proc.tgid) >= limit(proc, RLIMIT_NPROC) {
+        return -EAGAIN;
+    }
+
+    let mut regs = match read_regs(proc.pgtbl, sp) {
+        Ok(regs) => regs,
+        Err(e) => return -efault(e),
+    };
+    regs[10] = 0; // a0 is x10
+    if flags & CLONE_SETTLS != 0 {
+        regs[4] = tls; // tp is x4
+    }
+    // a stack too low to hold the frame fails the write below
+    let child_sp = match stack {
+        0 => sp,
+        _ => {
+            regs[2] = stack & !15; // sp is x2
+            (stack & !15).wrapping_sub(REG_FRAME)
+        },
+    };
+    let mut child = match thread {
+        true => proc.new_thread(),
+        false => match proc.fork() {
+            Ok(child) => child,
+            Err(_) => return -ENOMEM,
+        },
+    };
+    let tid = (child.id as u32).to_le_bytes();
+    // a fork's memory is its own from here, the same addresses as ours
+    if let Err(e) = write_regs(child.pgtbl, child_sp, &regs) {
+        return -efault(e);
+    }
+    if flags & CLONE_PARENT_SETTID != 0 {
+        if let Err(e) = copy_to_user(proc.pgtbl, parent_tid, &tid) {
+            return -efault(e);
+        }
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        if let Err(e) = copy_to_user(child.pgtbl, child_tid, &tid) {
+            return -efault(e);
+        }
+    }
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        child.clear_child_tid = child_tid;
+    }
+    if flags & CLONE_FILES == 0 {
+        let files = proc.files.lock().clone();
+        child.files = Arc::new(Mutex::new(files));
+    }
+    child.saved_pc = pc + 4;
+    child.saved_sp = child_sp;
+    child.state = ProcessState::Ready;
+    let id = child.id;
+    match thread {
+        true => log!(Debug, "Process {} started thread {}.", proc.tgid, id),
+        false => log!(Debug, "Process {} forked process {}.", proc.tgid, id),
+    }
+    unsafe {
+        (*addr_of!(QUEUE)).get().unwrap().lock().insert(child);
+    }
+    id as isize
+}
+
+/// kill(pid, sig). Every process is its own process group, so pid 0
+/// and -pid are just the caller, and -1 is everyone else.
+fn sys_kill(proc: &Process, pid: isize, sig: usize) -> isize {
+    let info = SigInfo { code: SI_USER, pid: proc.tgid, addr: 0 };
+    let target = if pid == 0 || pid == -(proc.tgid as isize) {
+        proc.tgid
+    } else if pid > 0 {
+        pid as usize
+    } else if pid == -1 {
+        let mut sent = false;
+        for other in signal::pids() {
+            if other != proc.tgid && signal::send_group(other, sig, info).is_ok() {
+                sent = true;
+            }
+        }
+        return if sent { 0 } else { -ESRCH };
+    } else {
+        return -ESRCH;
+    };
+    match signal::send_group(target, sig, info) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+/// tgkill(tgid, tid, sig) signals thread `tid` if it's in process
+/// `tgid`. tkill(tid, sig) comes here without the tgid and signals the
+/// thread whatever process it's in.
+fn sys_tgkill(proc: &Process, tgid: Option<isize>, tid: isize, sig: usize) -> isize {
+    if tgid.is_some_and(|tgid| tgid <= 0) || tid <= 0 {
+        return -EINVAL;
+    }
+    let info = SigInfo { code: SI_TKILL, pid: proc.tgid, addr: 0 };
+    let sent = match tgid {
+        Some(tgid) => signal::send_thread(tgid as usize, tid as usize, sig, info),
+        None => signal::send(tid as usize, sig, info),
+    };
+    match sent {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+/// rt_sigaction(sig, act, oldact, sigsetsize).
+fn sys_rt_sigaction(proc: &mut Process, sig: usize, act: usize, oldact: usize,
+                    sigsetsize: usize) -> isize {
+    if sigsetsize != size_of::<SigSet>() || sig == 0 || sig > NSIG {
+        return -EINVAL;
+    }
+    // read the new one first, they may be the same memory
+    let new = if act != 0 {
+        if sig == SIGKILL || sig == SIGSTOP {
+            return -EINVAL;
+        }
+        let mut bytes = [0; SIGACTION_SIZE];
+        if let Err(e) = copy_from_user(proc.pgtbl, &mut bytes, act) {
+            return -efault(e);
+        }
+        let word = |i: usize| usize::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        Some(SigAction { handler: word(0), flags: word(1), mask: word(2) as SigSet })
+    } else {
+        None
+    };
+    if oldact != 0 {
+        let old = proc.signals.action(sig);
+        let mut bytes = [0; SIGACTION_SIZE];
+        bytes[0..8].copy_from_slice(&old.handler.to_le_bytes());
+        bytes[8..16].copy_from_slice(&old.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&old.mask.to_le_bytes());
+        if let Err(e) = copy_to_user(proc.pgtbl, oldact, &bytes) {
+            return -efault(e);
+        }
+    }
+    if let Some(new) = new {
+        proc.signals.set_action(proc.id, sig, new);
+    }
+    0
+}
+
+/// rt_sigprocmask(how, set, oldset, sigsetsize).
+fn sys_rt_sigprocmask(proc: &mut Process, how: usize, set: usize, oldset: usize,
+                      sigsetsize: usize) -> isize {
+    if sigsetsize != size_of::<SigSet>() {
+        return -EINVAL;
+    }
+    let new = if set != 0 {
+        let mut bytes = [0; size_of::<SigSet>()];
+        if let Err(e) = copy_from_user(proc.pgtbl, &mut bytes, set) {
+            return -efault(e);
+        }
+        let set = SigSet::from_le_bytes(bytes);
+        match how {
+            SIG_BLOCK => Some(proc.signals.mask | set),
+            SIG_UNBLOCK => Some(proc.signals.mask & !set),
+            SIG_SETMASK => Some(set),
+            _ => return -EINVAL,
+        }
+    } else {
+        None
+    };
+    if oldset != 0 {
+        if let Err(e) = copy_to_user(proc.pgtbl, oldset, &proc.signals.mask.to_le_bytes()) {
+            return -efault(e);
+        }
+    }
+    if let Some(new) = new {
+        proc.signals.set_mask(new);
+    }
+    0
+}
+
+/// rt_sigpending(set, sigsetsize): the pending signals we block.
+fn sys_rt_sigpending(proc: &Process, set: usize, sigsetsize: usize) -> isize {
+    if sigsetsize != size_of::<SigSet>() {
+        return -EINVAL;
+    }
+    let pending = signal::pending(proc.id) & proc.signals.mask;
+    match copy_to_user(proc.pgtbl, set, &pending.to_le_bytes()) {
+        Ok(()) => 0,
+        Err(e) => -efault(e),
+    }
+}
+
+/// mmap(addr, length, prot, flags, fd, offset), placed wherever we
+/// like. Anonymous memory is fresh zeroed pages, which a fork shares if
+/// they are mapped shared and copies if not. A memfd can be mapped
+/// shared, or private as a copy of what it holds now. Only the pages
+/// the file has get mapped, and touching past them faults.
+fn sys_mmap(proc: &Process, addr: usize, len: usize, prot: usize, flags: usize,
+            fd: usize, offset: usize) -> isize {
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return -EINVAL;
+    }
+    let shared = match flags & MAP_TYPE {
+        MAP_PRIVATE => false,
+        MAP_SHARED => true,
+        _ => return -EINVAL,
+    };
+    if flags & MAP_FIXED != 0 {
+        return -EINVAL;
+    }
+    let pages = match len.checked_add(PAGE_SIZE - 1) {
+        Some(n) => n / PAGE_SIZE,
+        None => return -ENOMEM,
+    };
+    // the file's pages, or None for anonymous memory
+    let source = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return -EINVAL;
+        }
+        let file = match open_file(proc, fd) {
+            Ok(file) => file,
+            Err(errno) => return -errno,
+        };
+        match &*file.file {
+            File::Memfd(memfd) => Some(memfd.pages(offset / PAGE_SIZE, pages)),
+            _ => return -ENODEV,
+        }
+    };
+    let max = limit(proc, RLIMIT_AS);
+    let mut space = proc.address_space.lock();
+    if pages * PAGE_SIZE > max.saturating_sub(space.mapped) {
+        return -ENOMEM;
+    }
+    let Some(va) = space.reserve(pages) else {
+        return -ENOMEM;
+    };
+    // PROT_NONE just reserves the addresses
+    if prot != PROT_NONE {
+        // RISC-V has no write only pages
+        let flags = user_process_flags(
+            prot & (PROT_READ | PROT_WRITE) != 0,
+            prot & PROT_WRITE != 0,
+            prot & PROT_EXEC != 0,
+        );
+        let mapped = match &source {
+            None if shared => (0..pages)
+                .map(|_| request_phys_page(1).map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|fresh| space.map_shared(va, &fresh, flags)),
+            None => space.map_anonymous(va, pages, flags),
+            Some(from) if shared => space.map_shared(va, from, flags),
+            Some(from) => space.map_copy(va, from, flags),
+        };
+        if mapped.is_err() {
+            return -ENOMEM;
+        }
+    }
+    va as isize
+}
+
+// The soft limit `proc` has on `resource`.
+fn limit(proc: &Process, resource: usize) -> usize {
+    proc.limits.lock().cur(resource)
+}
+
+/// prlimit64(pid, resource, new, old), and getrlimit and setrlimit,
+/// which are it for this process. Puts the limit on `resource` in
+/// `old` and sets it to `new`, either of which can be null. Any thread
+/// of this process will do as `pid`, and other processes are EPERM:
+/// their limits are with them, wherever they are waiting.
+fn sys_prlimit64(proc: &Process, pid: usize, resource: usize, new: usize, old: usize) -> isize {
+    let pid = pid as i32 as isize;
+    if pid != 0 && !signal::threads(proc.tgid).contains(&(pid as usize)) {
+        return match pid > 0 && signal::send(pid as usize, 0, SigInfo::default()).is_ok() {
+            true => -EPERM,
+            false => -ESRCH,
+        };
+    }
+    if resource >= RLIM_NLIMITS {
+        return -EINVAL;
+    }
+    // struct rlimit { rlim_t rlim_cur; rlim_t rlim_max; }
+    let mut rlimit = [0; 16];
+    let new = match new {
+        0 => None,
+        _ => match copy_from_user(proc.pgtbl, &mut rlimit, new) {
+            Ok(()) => Some(Rlimit {
+                cur: u64::from_le_bytes(rlimit[0..8].try_into().unwrap()),
+                max: u64::from_le_bytes(rlimit[8..16].try_into().unwrap()),
+            }),
+            Err(e) => return -efault(e),
+        },
+    };
+    let was = {
+        let mut limits = proc.limits.lock();
+        let was = limits.get(resource);
+        if let Some(new) = new {
+            if let Err(errno) = limits.set(resource, new) {
+                return -errno;
+            }
+        }
+        was
+    };
+    if old != 0 {
+        rlimit[0..8].copy_from_slice(&was.cur.to_le_bytes());
+        rlimit[8..16].copy_from_slice(&was.max.to_le_bytes());
+        if let Err(e) = copy_to_user(proc.pgtbl, old, &rlimit) {
+            return -efault(e);
+        }
+    }
+    0
+}
 
 
 // -------------------------------------------------------------------
@@ -79,6 +1493,125 @@ pub extern "C" fn scall_direct(a0: usize, a1: usize, a2: usize, a3: usize,
 // Just a lot of constants down here.
 //
 
+/// Longest path, including its NUL, a syscall will read.
+pub const PATH_MAX: usize = 4096;
+/// Most strings execve will take in each of argv and envp.
+pub const ARG_COUNT_MAX: usize = 1024;
+
+// Linux errno values. Syscalls hand back -errno in a0 on failure.
+
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const ESRCH: isize = 3;
+pub const EINTR: isize = 4;
+pub const EIO: isize = 5;
+pub const E2BIG: isize = 7;
+pub const ENOEXEC: isize = 8;
+pub const EBADF: isize = 9;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EACCES: isize = 13;
+pub const EFAULT: isize = 14;
+pub const EBUSY: isize = 16;
+pub const EEXIST: isize = 17;
+pub const ENODEV: isize = 19;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EMFILE: isize = 24;
+pub const ENOTTY: isize = 25;
+pub const ENOSPC: isize = 28;
+pub const ESPIPE: isize = 29;
+pub const EPIPE: isize = 32;
+pub const EROFS: isize = 30;
+pub const ENAMETOOLONG: isize = 36;
+pub const ENOSYS: isize = 38;
+pub const ENOTEMPTY: isize = 39;
+pub const EOPNOTSUPP: isize = 95;
+pub const ETIMEDOUT: isize = 110;
+// Never seen by the process: the call is made again once it can finish
+pub const ERESTARTSYS: isize = 512;
+
+// Flags and structures the calls above share with user space.
+
+pub const IOV_MAX: usize = 1024;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const CONSOLE_ROWS: u16 = 24;
+pub const CONSOLE_COLS: u16 = 80;
+pub const UTSNAME_LENGTH: usize = 65;
+// struct sigaction: handler, flags, mask
+pub const SIGACTION_SIZE: usize = 24;
+
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_NONBLOCK: usize = 0o4000;
+pub const O_LARGEFILE: usize = 0o100000;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_CLOEXEC: usize = 0o2000000;
+pub const AT_FDCWD: isize = -100;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+// where d_name starts in struct linux_dirent64
+pub const DIRENT64_NAME: usize = 19;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_TYPE: usize = 0x0f;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+pub const MFD_CLOEXEC: usize = 1;
+pub const MFD_ALLOW_SEALING: usize = 2;
+pub const MFD_NAME_MAX: usize = 249;
+
+pub const CLONE_VM: usize = 0x0000_0100;
+pub const CLONE_FS: usize = 0x0000_0200;
+pub const CLONE_FILES: usize = 0x0000_0400;
+pub const CLONE_SIGHAND: usize = 0x0000_0800;
+pub const CLONE_THREAD: usize = 0x0001_0000;
+pub const CLONE_SYSVSEM: usize = 0x0004_0000;
+pub const CLONE_SETTLS: usize = 0x0008_0000;
+pub const CLONE_PARENT_SETTID: usize = 0x0010_0000;
+pub const CLONE_CHILD_CLEARTID: usize = 0x0020_0000;
+pub const CLONE_CHILD_SETTID: usize = 0x0100_0000;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+pub const CLOCK_MONOTONIC_RAW: usize = 4;
+pub const CLOCK_REALTIME_COARSE: usize = 5;
+pub const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+/// Clock ticks a second, as times counts them.
+pub const USER_HZ: u128 = 100;
+
+pub const RUSAGE_SELF: isize = 0;
+pub const RUSAGE_CHILDREN: isize = -1;
+pub const RUSAGE_THREAD: isize = 1;
+
+pub const PR_GET_SECCOMP: usize = 21;
+pub const PR_SET_SECCOMP: usize = 22;
+pub const PR_SET_NO_NEW_PRIVS: usize = 38;
+pub const PR_GET_NO_NEW_PRIVS: usize = 39;
+
+// prctl options of our own, well clear of Linux's
+pub const PR_SET_SYSCALL_TRACE: usize = 0x5245_4501; // arg2 non-zero to trace
+
+/// Where our own syscalls start, "RE" and then the call.
+pub const REEDOS_SYSCALL_BASE: usize = 0x5245_0000;
+
 // These are the RISC-V Linux syscall numbers
 //
 // I'd love for them to be an enum, but those aren't transparent over
@@ -91,300 +1624,326 @@ pub extern "C" fn scall_direct(a0: usize, a1: usize, a2: usize, a3: usize,
 // abstract them elsewhere, but also there is literally no way to
 // write this kind of stuff in a way that is not hardware
 // specific. Also these should reasonably only be used here.
+//
+// The macro also gives us `syscall_name`, for tracing.
 
-pub const IO_SETUP: usize = 0;
-pub const IO_DESTROY: usize = 1;
-pub const IO_SUBMIT: usize = 2;
-pub const IO_CANCEL: usize = 3;
-pub const IO_GETEVENTS: usize = 4;
-pub const SETXATTR: usize = 5;
-pub const LSETXATTR: usize = 6;
-pub const FSETXATTR: usize = 7;
-pub const GETXATTR: usize = 8;
-pub const LGETXATTR: usize = 9;
-pub const FGETXATTR: usize = 10;
-pub const LISTXATTR: usize = 11;
-pub const LLISTXATTR: usize = 12;
-pub const FLISTXATTR: usize = 13;
-pub const REMOVEXATTR: usize = 14;
-pub const LREMOVEXATTR: usize = 15;
-pub const FREMOVEXATTR: usize = 16;
-pub const GETCWD: usize = 17;
-pub const LOOKUP_DCOOKIE: usize = 18;
-pub const EVENTFD2: usize = 19;
-pub const EPOLL_CREATE1: usize = 20;
-pub const EPOLL_CTL: usize = 21;
-pub const EPOLL_PWAIT: usize = 22;
-pub const DUP: usize = 23;
-pub const DUP3: usize = 24;
-pub const FCNTL64: usize = 25;
-pub const INOTIFY_INIT1: usize = 26;
-pub const INOTIFY_ADD_WATCH: usize = 27;
-pub const INOTIFY_RM_WATCH: usize = 28;
-pub const IOCTL: usize = 29;
-pub const IOPRIO_SET: usize = 30;
-pub const IOPRIO_GET: usize = 31;
-pub const FLOCK: usize = 32;
-pub const MKNODAT: usize = 33;
-pub const MKDIRAT: usize = 34;
-pub const UNLINKAT: usize = 35;
-pub const SYMLINKAT: usize = 36;
-pub const LINKAT: usize = 37;
-pub const RENAMEAT: usize = 38;
-pub const UMOUNT: usize = 39;
-pub const MOUNT: usize = 40;
-pub const PIVOT_ROOT: usize = 41;
-pub const NI_SYSCALL: usize = 42;
-pub const STATFS64: usize = 43;
-pub const FSTATFS64: usize = 44;
-pub const TRUNCATE64: usize = 45;
-pub const FTRUNCATE64: usize = 46;
-pub const FALLOCATE: usize = 47;
-pub const FACCESSAT: usize = 48;
-pub const CHDIR: usize = 49;
-pub const FCHDIR: usize = 50;
-pub const CHROOT: usize = 51;
-pub const FCHMOD: usize = 52;
-pub const FCHMODAT: usize = 53;
-pub const FCHOWNAT: usize = 54;
-pub const FCHOWN: usize = 55;
-pub const OPENAT: usize = 56;
-pub const CLOSE: usize = 57;
-pub const VHANGUP: usize = 58;
-pub const PIPE2: usize = 59;
-pub const QUOTACTL: usize = 60;
-pub const GETDENTS64: usize = 61;
-pub const LSEEK: usize = 62;
-pub const READ: usize = 63;
-pub const WRITE: usize = 64;
-pub const READV: usize = 65;
-pub const WRITEV: usize = 66;
-pub const PREAD64: usize = 67;
-pub const PWRITE64: usize = 68;
-pub const PREADV: usize = 69;
-pub const PWRITEV: usize = 70;
-pub const SENDFILE64: usize = 71;
-pub const PSELECT6_TIME32: usize = 72;
-pub const PPOLL_TIME32: usize = 73;
-pub const SIGNALFD4: usize = 74;
-pub const VMSPLICE: usize = 75;
-pub const SPLICE: usize = 76;
-pub const TEE: usize = 77;
-pub const READLINKAT: usize = 78;
-pub const NEWFSTATAT: usize = 79;
-pub const NEWFSTAT: usize = 80;
-pub const SYNC: usize = 81;
-pub const FSYNC: usize = 82;
-pub const FDATASYNC: usize = 83;
-// pub const sync_file_range2: usize = 84,  // Not clear why this was included twice, leaving for posterity
-pub const SYNC_FILE_RANGE: usize = 84;
-pub const TIMERFD_CREATE: usize = 85;
-pub const TIMERFD_SETTIME: usize = 411;
-pub const TIMERFD_GETTIME: usize = 410;
-pub const UTIMENSAT: usize = 412;
-pub const ACCT: usize = 89;
-pub const CAPGET: usize = 90;
-pub const CAPSET: usize = 91;
-pub const PERSONALITY: usize = 92;
-pub const EXIT: usize = 93;
-pub const EXIT_GROUP: usize = 94;
-pub const WAITID: usize = 95;
-pub const SET_TID_ADDRESS: usize = 96;
-pub const UNSHARE: usize = 97;
-pub const FUTEX: usize = 422;
-pub const SET_ROBUST_LIST: usize = 99;
-pub const GET_ROBUST_LIST: usize = 100;
-pub const NANOSLEEP: usize = 101;
-pub const GETITIMER: usize = 102;
-pub const SETITIMER: usize = 103;
-pub const KEXEC_LOAD: usize = 104;
-pub const INIT_MODULE: usize = 105;
-pub const DELETE_MODULE: usize = 106;
-pub const TIMER_CREATE: usize = 107;
-pub const TIMER_GETTIME: usize = 408;
-pub const TIMER_GETOVERRUN: usize = 109;
-pub const TIMER_SETTIME: usize = 409;
-pub const TIMER_DELETE: usize = 111;
-pub const CLOCK_SETTIME: usize = 404;
-pub const CLOCK_GETTIME: usize = 403;
-pub const CLOCK_GETRES: usize = 406;
-pub const CLOCK_NANOSLEEP: usize = 407;
-pub const SYSLOG: usize = 116;
-pub const PTRACE: usize = 117;
-pub const SCHED_SETPARAM: usize = 118;
-pub const SCHED_SETSCHEDULER: usize = 119;
-pub const SCHED_GETSCHEDULER: usize = 120;
-pub const SCHED_GETPARAM: usize = 121;
-pub const SCHED_SETAFFINITY: usize = 122;
-pub const SCHED_GETAFFINITY: usize = 123;
-pub const SCHED_YIELD: usize = 124;
-pub const SCHED_GET_PRIORITY_MAX: usize = 125;
-pub const SCHED_GET_PRIORITY_MIN: usize = 126;
-pub const SCHED_RR_GET_INTERVAL: usize = 423;
-pub const RESTART_SYSCALL: usize = 128;
-pub const KILL: usize = 129;
-pub const TKILL: usize = 130;
-pub const TGKILL: usize = 131;
-pub const SIGALTSTACK: usize = 132;
-pub const RT_SIGSUSPEND: usize = 133;
-pub const RT_SIGACTION: usize = 134;
-pub const RT_SIGPROCMASK: usize = 135;
-pub const RT_SIGPENDING: usize = 136;
-pub const RT_SIGTIMEDWAIT_TIME32: usize = 137;
-pub const RT_SIGQUEUEINFO: usize = 138;
-pub const SETPRIORITY: usize = 140;
-pub const GETPRIORITY: usize = 141;
-pub const REBOOT: usize = 142;
-pub const SETREGID: usize = 143;
-pub const SETGID: usize = 144;
-pub const SETREUID: usize = 145;
-pub const SETUID: usize = 146;
-pub const SETRESUID: usize = 147;
-pub const GETRESUID: usize = 148;
-pub const SETRESGID: usize = 149;
-pub const GETRESGID: usize = 150;
-pub const SETFSUID: usize = 151;
-pub const SETFSGID: usize = 152;
-pub const TIMES: usize = 153;
-pub const SETPGID: usize = 154;
-pub const GETPGID: usize = 155;
-pub const GETSID: usize = 156;
-pub const SETSID: usize = 157;
-pub const GETGROUPS: usize = 158;
-pub const SETGROUPS: usize = 159;
-pub const NEWUNAME: usize = 160;
-pub const SETHOSTNAME: usize = 161;
-pub const SETDOMAINNAME: usize = 162;
-pub const GETRLIMIT: usize = 163;
-pub const SETRLIMIT: usize = 164;
-pub const GETRUSAGE: usize = 165;
-pub const UMASK: usize = 166;
-pub const PRCTL: usize = 167;
-pub const GETCPU: usize = 168;
-pub const GETTIMEOFDAY: usize = 169;
-pub const SETTIMEOFDAY: usize = 170;
-pub const ADJTIMEX: usize = 171;
-pub const GETPID: usize = 172;
-pub const GETPPID: usize = 173;
-pub const GETUID: usize = 174;
-pub const GETEUID: usize = 175;
-pub const GETGID: usize = 176;
-pub const GETEGID: usize = 177;
-pub const GETTID: usize = 178;
-pub const SYSINFO: usize = 179;
-pub const MQ_OPEN: usize = 180;
-pub const MQ_UNLINK: usize = 181;
-pub const MQ_TIMEDSEND: usize = 418;
-pub const MQ_TIMEDRECEIVE: usize = 419;
-pub const MQ_NOTIFY: usize = 184;
-pub const MQ_GETSETATTR: usize = 185;
-pub const MSGGET: usize = 186;
-pub const MSGCTL: usize = 187;
-pub const MSGRCV: usize = 188;
-pub const MSGSND: usize = 189;
-pub const SEMGET: usize = 190;
-pub const SEMCTL: usize = 191;
-pub const SEMTIMEDOP: usize = 420;
-pub const SEMOP: usize = 193;
-pub const SHMGET: usize = 194;
-pub const SHMCTL: usize = 195;
-pub const SHMAT: usize = 196;
-pub const SHMDT: usize = 197;
-pub const SOCKET: usize = 198;
-pub const SOCKETPAIR: usize = 199;
-pub const BIND: usize = 200;
-pub const LISTEN: usize = 201;
-pub const ACCEPT: usize = 202;
-pub const CONNECT: usize = 203;
-pub const GETSOCKNAME: usize = 204;
-pub const GETPEERNAME: usize = 205;
-pub const SENDTO: usize = 206;
-pub const RECVFROM: usize = 207;
-pub const SETSOCKOPT: usize = 208;
-pub const GETSOCKOPT: usize = 209;
-pub const SHUTDOWN: usize = 210;
-pub const SENDMSG: usize = 211;
-pub const RECVMSG: usize = 212;
-pub const READAHEAD: usize = 213;
-pub const BRK: usize = 214;
-pub const MUNMAP: usize = 215;
-pub const MREMAP: usize = 216;
-pub const ADD_KEY: usize = 217;
-pub const REQUEST_KEY: usize = 218;
-pub const KEYCTL: usize = 219;
-pub const CLONE: usize = 220;
-pub const EXECVE: usize = 221;
-pub const MMAP: usize = 222;
-pub const FADVISE64_64: usize = 223;
-pub const SWAPON: usize = 224;
-pub const SWAPOFF: usize = 225;
-pub const MPROTECT: usize = 226;
-pub const MSYNC: usize = 227;
-pub const MLOCK: usize = 228;
-pub const MUNLOCK: usize = 229;
-pub const MLOCKALL: usize = 230;
-pub const MUNLOCKALL: usize = 231;
-pub const MINCORE: usize = 232;
-pub const MADVISE: usize = 233;
-pub const REMAP_FILE_PAGES: usize = 234;
-pub const MBIND: usize = 235;
-pub const GET_MEMPOLICY: usize = 236;
-pub const SET_MEMPOLICY: usize = 237;
-pub const MIGRATE_PAGES: usize = 238;
-pub const MOVE_PAGES: usize = 239;
-pub const RT_TGSIGQUEUEINFO: usize = 240;
-pub const PERF_EVENT_OPEN: usize = 241;
-pub const ACCEPT4: usize = 242;
-pub const RECVMMSG_TIME32: usize = 243;
-pub const WAIT4: usize = 260;
-pub const PRLIMIT64: usize = 261;
-pub const FANOTIFY_INIT: usize = 262;
-pub const FANOTIFY_MARK: usize = 263;
-pub const NAME_TO_HANDLE_AT: usize = 264;
-pub const OPEN_BY_HANDLE_AT: usize = 265;
-pub const CLOCK_ADJTIME: usize = 405;
-pub const SYNCFS: usize = 267;
-pub const SETNS: usize = 268;
-pub const SENDMMSG: usize = 269;
-pub const PROCESS_VM_READV: usize = 270;
-pub const PROCESS_VM_WRITEV: usize = 271;
-pub const KCMP: usize = 272;
-pub const FINIT_MODULE: usize = 273;
-pub const SCHED_SETATTR: usize = 274;
-pub const SCHED_GETATTR: usize = 275;
-pub const RENAMEAT2: usize = 276;
-pub const SECCOMP: usize = 277;
-pub const GETRANDOM: usize = 278;
-pub const MEMFD_CREATE: usize = 279;
-pub const BPF: usize = 280;
-pub const EXECVEAT: usize = 281;
-pub const USERFAULTFD: usize = 282;
-pub const MEMBARRIER: usize = 283;
-pub const MLOCK2: usize = 284;
-pub const COPY_FILE_RANGE: usize = 285;
-pub const PREADV2: usize = 286;
-pub const PWRITEV2: usize = 287;
-pub const PKEY_MPROTECT: usize = 288;
-pub const PKEY_ALLOC: usize = 289;
-pub const PKEY_FREE: usize = 290;
-pub const STATX: usize = 291;
-pub const IO_PGETEVENTS: usize = 416;
-pub const RSEQ: usize = 293;
-pub const KEXEC_FILE_LOAD: usize = 294;
-pub const PIDFD_SEND_SIGNAL: usize = 424;
-pub const IO_URING_SETUP: usize = 425;
-pub const IO_URING_ENTER: usize = 426;
-pub const IO_URING_REGISTER: usize = 427;
-pub const OPEN_TREE: usize = 428;
-pub const MOVE_MOUNT: usize = 429;
-pub const FSOPEN: usize = 430;
-pub const FSCONFIG: usize = 431;
-pub const FSMOUNT: usize = 432;
-pub const FSPICK: usize = 433;
-pub const PIDFD_OPEN: usize = 434;
-pub const CLONE3: usize = 435;
-pub const CLOSE_RANGE: usize = 436;
-pub const OPENAT2: usize = 437;
-pub const PIDFD_GETFD: usize = 438;
-pub const FACCESSAT2: usize = 439;
-pub const PROCESS_MADVISE: usize = 440;
+macro_rules! syscall_numbers {
+    ($($name:ident = $num:expr;)*) => {
+        $(pub const $name: usize = $num;)*
+
+        /// The name of syscall `num` as we spell the constant, like
+        /// "WRITE", if we know it.
+        pub fn syscall_name(num: usize) -> Option<&'static str> {
+            match num {
+                $($name => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+syscall_numbers! {
+    IO_SETUP = 0;
+    IO_DESTROY = 1;
+    IO_SUBMIT = 2;
+    IO_CANCEL = 3;
+    IO_GETEVENTS = 4;
+    SETXATTR = 5;
+    LSETXATTR = 6;
+    FSETXATTR = 7;
+    GETXATTR = 8;
+    LGETXATTR = 9;
+    FGETXATTR = 10;
+    LISTXATTR = 11;
+    LLISTXATTR = 12;
+    FLISTXATTR = 13;
+    REMOVEXATTR = 14;
+    LREMOVEXATTR = 15;
+    FREMOVEXATTR = 16;
+    GETCWD = 17;
+    LOOKUP_DCOOKIE = 18;
+    EVENTFD2 = 19;
+    EPOLL_CREATE1 = 20;
+    EPOLL_CTL = 21;
+    EPOLL_PWAIT = 22;
+    DUP = 23;
+    DUP3 = 24;
+    FCNTL64 = 25;
+    INOTIFY_INIT1 = 26;
+    INOTIFY_ADD_WATCH = 27;
+    INOTIFY_RM_WATCH = 28;
+    IOCTL = 29;
+    IOPRIO_SET = 30;
+    IOPRIO_GET = 31;
+    FLOCK = 32;
+    MKNODAT = 33;
+    MKDIRAT = 34;
+    UNLINKAT = 35;
+    SYMLINKAT = 36;
+    LINKAT = 37;
+    RENAMEAT = 38;
+    UMOUNT = 39;
+    MOUNT = 40;
+    PIVOT_ROOT = 41;
+    NI_SYSCALL = 42;
+    STATFS64 = 43;
+    FSTATFS64 = 44;
+    TRUNCATE64 = 45;
+    FTRUNCATE64 = 46;
+    FALLOCATE = 47;
+    FACCESSAT = 48;
+    CHDIR = 49;
+    FCHDIR = 50;
+    CHROOT = 51;
+    FCHMOD = 52;
+    FCHMODAT = 53;
+    FCHOWNAT = 54;
+    FCHOWN = 55;
+    OPENAT = 56;
+    CLOSE = 57;
+    VHANGUP = 58;
+    PIPE2 = 59;
+    QUOTACTL = 60;
+    GETDENTS64 = 61;
+    LSEEK = 62;
+    READ = 63;
+    WRITE = 64;
+    READV = 65;
+    WRITEV = 66;
+    PREAD64 = 67;
+    PWRITE64 = 68;
+    PREADV = 69;
+    PWRITEV = 70;
+    SENDFILE64 = 71;
+    PSELECT6 = 72;
+    PPOLL = 73;
+    SIGNALFD4 = 74;
+    VMSPLICE = 75;
+    SPLICE = 76;
+    TEE = 77;
+    READLINKAT = 78;
+    NEWFSTATAT = 79;
+    NEWFSTAT = 80;
+    SYNC = 81;
+    FSYNC = 82;
+    FDATASYNC = 83;
+    // pub const sync_file_range2: usize = 84,  // Not clear why this was included twice, leaving for posterity
+    SYNC_FILE_RANGE = 84;
+    TIMERFD_CREATE = 85;
+    TIMERFD_SETTIME = 86;
+    TIMERFD_GETTIME = 87;
+    UTIMENSAT = 88;
+    ACCT = 89;
+    CAPGET = 90;
+    CAPSET = 91;
+    PERSONALITY = 92;
+    EXIT = 93;
+    EXIT_GROUP = 94;
+    WAITID = 95;
+    SET_TID_ADDRESS = 96;
+    UNSHARE = 97;
+    FUTEX = 98;
+    SET_ROBUST_LIST = 99;
+    GET_ROBUST_LIST = 100;
+    NANOSLEEP = 101;
+    GETITIMER = 102;
+    SETITIMER = 103;
+    KEXEC_LOAD = 104;
+    INIT_MODULE = 105;
+    DELETE_MODULE = 106;
+    TIMER_CREATE = 107;
+    TIMER_GETTIME = 108;
+    TIMER_GETOVERRUN = 109;
+    TIMER_SETTIME = 110;
+    TIMER_DELETE = 111;
+    CLOCK_SETTIME = 112;
+    CLOCK_GETTIME = 113;
+    CLOCK_GETRES = 114;
+    CLOCK_NANOSLEEP = 115;
+    SYSLOG = 116;
+    PTRACE = 117;
+    SCHED_SETPARAM = 118;
+    SCHED_SETSCHEDULER = 119;
+    SCHED_GETSCHEDULER = 120;
+    SCHED_GETPARAM = 121;
+    SCHED_SETAFFINITY = 122;
+    SCHED_GETAFFINITY = 123;
+    SCHED_YIELD = 124;
+    SCHED_GET_PRIORITY_MAX = 125;
+    SCHED_GET_PRIORITY_MIN = 126;
+    SCHED_RR_GET_INTERVAL = 127;
+    RESTART_SYSCALL = 128;
+    KILL = 129;
+    TKILL = 130;
+    TGKILL = 131;
+    SIGALTSTACK = 132;
+    RT_SIGSUSPEND = 133;
+    RT_SIGACTION = 134;
+    RT_SIGPROCMASK = 135;
+    RT_SIGPENDING = 136;
+    RT_SIGTIMEDWAIT = 137;
+    RT_SIGQUEUEINFO = 138;
+    RT_SIGRETURN = 139;
+    SETPRIORITY = 140;
+    GETPRIORITY = 141;
+    REBOOT = 142;
+    SETREGID = 143;
+    SETGID = 144;
+    SETREUID = 145;
+    SETUID = 146;
+    SETRESUID = 147;
+    GETRESUID = 148;
+    SETRESGID = 149;
+    GETRESGID = 150;
+    SETFSUID = 151;
+    SETFSGID = 152;
+    TIMES = 153;
+    SETPGID = 154;
+    GETPGID = 155;
+    GETSID = 156;
+    SETSID = 157;
+    GETGROUPS = 158;
+    SETGROUPS = 159;
+    NEWUNAME = 160;
+    SETHOSTNAME = 161;
+    SETDOMAINNAME = 162;
+    GETRLIMIT = 163;
+    SETRLIMIT = 164;
+    GETRUSAGE = 165;
+    UMASK = 166;
+    PRCTL = 167;
+    GETCPU = 168;
+    GETTIMEOFDAY = 169;
+    SETTIMEOFDAY = 170;
+    ADJTIMEX = 171;
+    GETPID = 172;
+    GETPPID = 173;
+    GETUID = 174;
+    GETEUID = 175;
+    GETGID = 176;
+    GETEGID = 177;
+    GETTID = 178;
+    SYSINFO = 179;
+    MQ_OPEN = 180;
+    MQ_UNLINK = 181;
+    MQ_TIMEDSEND = 182;
+    MQ_TIMEDRECEIVE = 183;
+    MQ_NOTIFY = 184;
+    MQ_GETSETATTR = 185;
+    MSGGET = 186;
+    MSGCTL = 187;
+    MSGRCV = 188;
+    MSGSND = 189;
+    SEMGET = 190;
+    SEMCTL = 191;
+    SEMTIMEDOP = 192;
+    SEMOP = 193;
+    SHMGET = 194;
+    SHMCTL = 195;
+    SHMAT = 196;
+    SHMDT = 197;
+    SOCKET = 198;
+    SOCKETPAIR = 199;
+    BIND = 200;
+    LISTEN = 201;
+    ACCEPT = 202;
+    CONNECT = 203;
+    GETSOCKNAME = 204;
+    GETPEERNAME = 205;
+    SENDTO = 206;
+    RECVFROM = 207;
+    SETSOCKOPT = 208;
+    GETSOCKOPT = 209;
+    SHUTDOWN = 210;
+    SENDMSG = 211;
+    RECVMSG = 212;
+    READAHEAD = 213;
+    BRK = 214;
+    MUNMAP = 215;
+    MREMAP = 216;
+    ADD_KEY = 217;
+    REQUEST_KEY = 218;
+    KEYCTL = 219;
+    CLONE = 220;
+    EXECVE = 221;
+    MMAP = 222;
+    FADVISE64_64 = 223;
+    SWAPON = 224;
+    SWAPOFF = 225;
+    MPROTECT = 226;
+    MSYNC = 227;
+    MLOCK = 228;
+    MUNLOCK = 229;
+    MLOCKALL = 230;
+    MUNLOCKALL = 231;
+    MINCORE = 232;
+    MADVISE = 233;
+    REMAP_FILE_PAGES = 234;
+    MBIND = 235;
+    GET_MEMPOLICY = 236;
+    SET_MEMPOLICY = 237;
+    MIGRATE_PAGES = 238;
+    MOVE_PAGES = 239;
+    RT_TGSIGQUEUEINFO = 240;
+    PERF_EVENT_OPEN = 241;
+    ACCEPT4 = 242;
+    RECVMMSG = 243;
+    WAIT4 = 260;
+    PRLIMIT64 = 261;
+    FANOTIFY_INIT = 262;
+    FANOTIFY_MARK = 263;
+    NAME_TO_HANDLE_AT = 264;
+    OPEN_BY_HANDLE_AT = 265;
+    CLOCK_ADJTIME = 266;
+    SYNCFS = 267;
+    SETNS = 268;
+    SENDMMSG = 269;
+    PROCESS_VM_READV = 270;
+    PROCESS_VM_WRITEV = 271;
+    KCMP = 272;
+    FINIT_MODULE = 273;
+    SCHED_SETATTR = 274;
+    SCHED_GETATTR = 275;
+    RENAMEAT2 = 276;
+    SECCOMP = 277;
+    GETRANDOM = 278;
+    MEMFD_CREATE = 279;
+    BPF = 280;
+    EXECVEAT = 281;
+    USERFAULTFD = 282;
+    MEMBARRIER = 283;
+    MLOCK2 = 284;
+    COPY_FILE_RANGE = 285;
+    PREADV2 = 286;
+    PWRITEV2 = 287;
+    PKEY_MPROTECT = 288;
+    PKEY_ALLOC = 289;
+    PKEY_FREE = 290;
+    STATX = 291;
+    IO_PGETEVENTS = 292;
+    RSEQ = 293;
+    KEXEC_FILE_LOAD = 294;
+    PIDFD_SEND_SIGNAL = 424;
+    IO_URING_SETUP = 425;
+    IO_URING_ENTER = 426;
+    IO_URING_REGISTER = 427;
+    OPEN_TREE = 428;
+    MOVE_MOUNT = 429;
+    FSOPEN = 430;
+    FSCONFIG = 431;
+    FSMOUNT = 432;
+    FSPICK = 433;
+    PIDFD_OPEN = 434;
+    CLONE3 = 435;
+    CLOSE_RANGE = 436;
+    OPENAT2 = 437;
+    PIDFD_GETFD = 438;
+    FACCESSAT2 = 439;
+    PROCESS_MADVISE = 440;
+    // our own, well clear of Linux's
+    CHANNEL_CREATE = REEDOS_SYSCALL_BASE;
+    CHANNEL_SEND = REEDOS_SYSCALL_BASE + 1;
+    CHANNEL_RECV = REEDOS_SYSCALL_BASE + 2;
+    CHANNEL_PUBLISH = REEDOS_SYSCALL_BASE + 3;
+    CHANNEL_CONNECT = REEDOS_SYSCALL_BASE + 4;
+}
@@ -0,0 +1,292 @@
+//! futex(2), so user space locks can sleep instead of spinning through
+//! sched_yield.
+//!
+//! A waiting thread is parked on one of a fixed number of wait queues,
+//! picked by hashing the physical address of the futex word. Keying by
+//! physical address means two processes sharing a page find each other.
+//! `FUTEX_PRIVATE_FLAG` additionally ties the key to the address space,
+//! so a private wait is only woken from within the process, but both
+//! kinds are found by a wake that isn't private.
+//!
+//! Timeouts are checked by `expire`, which the scheduler calls on every
+//! pass. A signal wakes a waiter too, see `interrupt`.
+//!
+//! The same queues let a syscall sleep on a kernel object until
+//! something happens to it, see `wait_object`. Objects are keyed by
+//! their own address, which no user page can share.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use super::*;
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+pub const FUTEX_CLOCK_REALTIME: usize = 256;
+pub const FUTEX_CMD_MASK: usize = !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+
+// Number of wait queues. A power of two, see `bucket`.
+const BUCKETS: usize = 64;
+
+// Who a waiter is waiting for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Key {
+    pa: usize,
+    space: Option<usize>, // address space, for private futexes
+}
+
+impl Key {
+    // Whether a wake for `other` is one for us too.
+    fn matches(&self, other: &Key) -> bool {
+        self.pa == other.pa
+            && (self.space.is_none() || other.space.is_none() || self.space == other.space)
+    }
+}
+
+struct Waiter {
+    key: Key,
+    deadline: Option<u64>, // mtime to give up at
+    restart: bool,         // woken to make its syscall again, see wait_object
+    proc: Process,
+}
+
+// Processes only move in and out of here with the queue locked, the
+// same as they do with the scheduler's queue.
+unsafe impl Send for Waiter {}
+
+static QUEUES: [Mutex<Vec<Waiter>>; BUCKETS] = [const { Mutex::new(Vec::new()) }; BUCKETS];
+
+// Earliest deadline of any waiter, so `expire` usually has nothing to
+// look at.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn bucket(key: &Key) -> &'static Mutex<Vec<Waiter>> {
+    let hash = (key.pa >> 2).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    &QUEUES[hash >> (usize::BITS - BUCKETS.trailing_zeros())]
+}
+
+// The key for the futex word at `uaddr` in `proc`'s memory, and the
+// word itself.
+fn key(proc: &Process, uaddr: usize, private: bool) -> Result<(Key, &'static AtomicU32), isize> {
+    if !uaddr.is_multiple_of(size_of::<u32>()) {
+        return Err(EINVAL);
+    }
+    let va = uaddr as *mut usize;
+    let flags = user_process_flags(true, false, false);
+    let pa = unsafe { translate(proc.pgtbl, va, flags) }.ok_or(EFAULT)?;
+    let space = private.then_some(Arc::as_ptr(&proc.address_space) as usize);
+    let word = unsafe { &*(pa as *const AtomicU32) };
+    Ok((Key { pa: pa as usize, space }, word))
+}
+
+// Put a waiter back to work. With `ret` its futex call returns that,
+// otherwise it makes the call again.
+fn resume_waiter(mut proc: Process, ret: Option<isize>) {
+    match ret {
+        Some(ret) => {
+            let sp = proc.saved_sp;
+            proc.write_saved_reg(sp, 10, ret as usize); // a0 is x10
+            proc.saved_pc += 4;
+        },
+        None => proc.signals.restarting = true,
+    }
+    proc.state = ProcessState::Ready;
+    unsafe {
+        (*addr_of!(QUEUE)).get().unwrap().lock().insert(proc);
+    }
+}
+
+// Read a relative struct timespec and turn it into an mtime deadline.
+fn deadline(pt: PageTable, timeout: usize) -> Result<u64, isize> {
+    let mut timespec = [0; 16];
+    copy_from_user(pt, &mut timespec, timeout).map_err(efault)?;
+    let sec = i64::from_le_bytes(timespec[0..8].try_into().unwrap());
+    let nsec = i64::from_le_bytes(timespec[8..16].try_into().unwrap());
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Err(EINVAL);
+    }
+    let nanos = sec as u128 * 1_000_000_000 + nsec as u128;
+    let ticks = nanos * clint::mtime_frequency() as u128 / 1_000_000_000;
+    Ok(clint::read_mtime().saturating_add(min(ticks, u64::MAX as u128) as u64))
+}
+
+/// futex(uaddr, FUTEX_WAIT, val, timeout), made by `proc` at `pc` with
+/// its registers at `sp`. Sleeps as long as the word at `uaddr` holds
+/// `val`, until woken, `timeout` runs out or a signal comes. Only
+/// returns here through `syscall_return`.
+pub fn sys_futex_wait(mut proc: Process, pc: usize, sp: usize, uaddr: usize, op: usize,
+                      val: usize, timeout: usize) -> ! {
+    if op & FUTEX_CLOCK_REALTIME != 0 {
+        // only FUTEX_WAIT_BITSET can have it
+        syscall_return(proc, pc, sp, -ENOSYS);
+    }
+    let (key, word) = match key(&proc, uaddr, op & FUTEX_PRIVATE_FLAG != 0) {
+        Ok(found) => found,
+        Err(errno) => syscall_return(proc, pc, sp, -errno),
+    };
+    let deadline = match timeout {
+        0 => None,
+        _ => match deadline(proc.pgtbl, timeout) {
+            Ok(at) => Some(at),
+            Err(errno) => syscall_return(proc, pc, sp, -errno),
+        },
+    };
+
+    let id = proc.id;
+    let mask = proc.signals.mask;
+    {
+        // a waker changes the word before it takes this lock, so
+        // checking under it can't miss a wake
+        let mut queue = bucket(&key).lock();
+        if word.load(Ordering::SeqCst) != val as u32 {
+            drop(queue);
+            syscall_return(proc, pc, sp, -EAGAIN);
+        }
+        proc.saved_pc = pc;
+        proc.saved_sp = sp;
+        proc.state = ProcessState::Wait;
+        proc.off_cpu();
+        queue.push(Waiter { key, deadline, restart: false, proc });
+    }
+    if let Some(at) = deadline {
+        NEXT_DEADLINE.fetch_min(at, Ordering::SeqCst);
+    }
+    // a signal sent before we were on the queue didn't find us there
+    if signal::pending(id) & !mask != 0 {
+        interrupt(id);
+    }
+    schedule();
+}
+
+/// futex(uaddr, FUTEX_WAKE, count). Wakes up to `count` threads waiting
+/// on `uaddr` and says how many it woke.
+pub fn sys_futex_wake(proc: &Process, uaddr: usize, op: usize, count: usize) -> isize {
+    match key(proc, uaddr, op & FUTEX_PRIVATE_FLAG != 0) {
+        Ok((key, _)) => wake(key, count) as isize,
+        Err(errno) => -errno,
+    }
+}
+
+// Wake up to `count` waiters for `key`.
+fn wake(key: Key, count: usize) -> usize {
+    let mut woken = Vec::new();
+    {
+        let mut queue = bucket(&key).lock();
+        let mut i = 0;
+        while i < queue.len() && woken.len() < count {
+            if queue[i].key.matches(&key) {
+                woken.push(queue.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+    }
+    let n = woken.len();
+    for waiter in woken {
+        let ret = match waiter.restart {
+            true => None,
+            false => Some(0),
+        };
+        resume_waiter(waiter.proc, ret);
+    }
+    n
+}
+
+/// Wake one waiter on the word at `uaddr`, as a thread exits and clears
+/// its `clear_child_tid` for whoever is joining it.
+pub fn wake_one(proc: &Process, uaddr: usize) {
+    if let Ok((key, _)) = key(proc, uaddr, false) {
+        wake(key, 1);
+    }
+}
+
+/// Sleep `proc`, which is making a syscall at `pc` with its registers
+/// at `sp`, on the kernel object at `obj` until `wake_object(obj)`, the
+/// mtime `deadline` or a signal. Unless `ready` says something already
+/// happened, checked in a way that can't miss a wake: whoever changes
+/// the object does so before waking it. Either way the syscall gets
+/// made again, except for a timeout, which fails it with ETIMEDOUT.
+pub fn wait_object(mut proc: Process, pc: usize, sp: usize, obj: usize,
+                   deadline: Option<u64>, ready: impl FnOnce() -> bool) -> ! {
+    let key = Key { pa: obj, space: None };
+    let id = proc.id;
+    let mask = proc.signals.mask;
+    proc.saved_pc = pc;
+    proc.saved_sp = sp;
+    {
+        let mut queue = bucket(&key).lock();
+        if ready() {
+            drop(queue);
+            proc.state = ProcessState::Ready;
+            proc.resume();
+        }
+        proc.state = ProcessState::Wait;
+        proc.off_cpu();
+        queue.push(Waiter { key, deadline, restart: true, proc });
+    }
+    if let Some(at) = deadline {
+        NEXT_DEADLINE.fetch_min(at, Ordering::SeqCst);
+    }
+    if signal::pending(id) & !mask != 0 {
+        interrupt(id);
+    }
+    schedule();
+}
+
+/// Wake everything waiting on the kernel object at `obj`.
+pub fn wake_object(obj: usize) {
+    wake(Key { pa: obj, space: None }, usize::MAX);
+}
+
+/// A signal came for thread `pid`. If it's waiting on a futex and
+/// doesn't block every pending signal, it stops waiting. Timed waits
+/// fail with EINTR, others are made again after the signal, or fail
+/// with EINTR if a handler says not to restart.
+pub fn interrupt(pid: usize) {
+    let pending = signal::pending(pid);
+    for queue in QUEUES.iter() {
+        let found = {
+            let mut queue = queue.lock();
+            queue.iter()
+                .position(|w| w.proc.id == pid && pending & !w.proc.signals.mask != 0)
+                .map(|i| queue.remove(i))
+        };
+        if let Some(waiter) = found {
+            let ret = match waiter.restart {
+                true => None,
+                false => waiter.deadline.map(|_| -EINTR),
+            };
+            resume_waiter(waiter.proc, ret);
+            return;
+        }
+    }
+}
+
+/// Fail the waits whose timeouts have run out with ETIMEDOUT.
+pub fn expire() {
+    let now = clint::read_mtime();
+    if now < NEXT_DEADLINE.load(Ordering::SeqCst) {
+        return;
+    }
+    // waiters added while we look lower it again themselves
+    NEXT_DEADLINE.store(u64::MAX, Ordering::SeqCst);
+    for queue in QUEUES.iter() {
+        let mut expired = Vec::new();
+        {
+            let mut queue = queue.lock();
+            let mut i = 0;
+            while i < queue.len() {
+                match queue[i].deadline {
+                    Some(at) if at <= now => expired.push(queue.remove(i).proc),
+                    Some(at) => {
+                        NEXT_DEADLINE.fetch_min(at, Ordering::SeqCst);
+                        i += 1;
+                    },
+                    None => i += 1,
+                }
+            }
+        }
+        for proc in expired {
+            resume_waiter(proc, Some(-ETIMEDOUT));
+        }
+    }
+}
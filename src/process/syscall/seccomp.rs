@@ -0,0 +1,368 @@
+//! Seccomp, a la Linux: per process syscall filters for sandboxing.
+//!
+//! A process restricts itself with `seccomp(2)` or
+//! `prctl(PR_SET_SECCOMP)`. Strict mode allows read, write, exit and
+//! rt_sigreturn and kills on anything else. Filter mode runs classic
+//! BPF programs over a `struct seccomp_data` describing the call, and
+//! the program's return value picks what happens: allow it, fail it
+//! with an errno, raise SIGSYS, or kill the process.
+//!
+//! Filters only ever get added. Each new one links to the ones before
+//! it, every filter runs on every call, and the most restrictive
+//! answer wins. The chain is shared by reference count, so a child
+//! takes its parent's filters just by cloning `Seccomp`, and execve
+//! leaves them alone.
+
+use alloc::sync::Arc;
+use super::*;
+
+// seccomp(2) operations
+pub const SECCOMP_SET_MODE_STRICT: usize = 0;
+pub const SECCOMP_SET_MODE_FILTER: usize = 1;
+pub const SECCOMP_GET_ACTION_AVAIL: usize = 2;
+
+// what prctl(PR_GET_SECCOMP) reports, and PR_SET_SECCOMP takes
+pub const SECCOMP_MODE_DISABLED: usize = 0;
+pub const SECCOMP_MODE_STRICT: usize = 1;
+pub const SECCOMP_MODE_FILTER: usize = 2;
+
+// SECCOMP_SET_MODE_FILTER flags. Every process has one thread, so
+// syncing threads is a no-op.
+pub const SECCOMP_FILTER_FLAG_TSYNC: usize = 1;
+
+// Filter return values. The low 16 bits are data, like the errno.
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+pub const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+/// `seccomp_data.arch` for us.
+pub const AUDIT_ARCH_RISCV64: u32 = 0xc000_00f3;
+
+// Size of struct seccomp_data: nr, arch, instruction_pointer, args[6].
+const SECCOMP_DATA_SIZE: usize = 64;
+// Longest filter program, and deepest chain of filters, we take.
+const BPF_MAXINSNS: usize = 4096;
+const MAX_FILTERS: usize = 64;
+// struct sock_fprog is a u16 length, padding, and a pointer.
+const SOCK_FPROG_SIZE: usize = 16;
+const SOCK_FILTER_SIZE: usize = 8;
+const BPF_MEMWORDS: usize = 16;
+
+// Classic BPF instruction classes and the parts of the opcode we use.
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+const BPF_W: u16 = 0x00;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// What to do with a syscall, once the filters have had their say.
+pub enum Action {
+    Allow,
+    Errno(isize),
+    Trap,   // skip the call and raise SIGSYS
+    Kill,
+}
+
+// One classic BPF instruction, struct sock_filter.
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+// One installed filter program, and the ones installed before it.
+struct Filter {
+    prog: Vec<Insn>,
+    prev: Option<Arc<Filter>>,
+    depth: usize,
+}
+
+/// The seccomp state of a process. Cloning it shares the filters.
+#[derive(Clone, Default)]
+pub struct Seccomp {
+    strict: bool,
+    filters: Option<Arc<Filter>>,
+    // PR_SET_NO_NEW_PRIVS. We have no setuid to stop, but sandboxing
+    // libraries set it before installing filters and may check it.
+    pub no_new_privs: bool,
+}
+
+impl Seccomp {
+    /// Which SECCOMP_MODE_* we are in.
+    pub fn mode(&self) -> usize {
+        if self.strict {
+            SECCOMP_MODE_STRICT
+        } else if self.filters.is_some() {
+            SECCOMP_MODE_FILTER
+        } else {
+            SECCOMP_MODE_DISABLED
+        }
+    }
+
+    /// Decide what happens to syscall `num` with `args`, made at `pc`.
+    pub fn check(&self, num: usize, pc: usize, args: &[usize; 6]) -> Action {
+        if self.strict {
+            return match num {
+                READ | WRITE | EXIT | RT_SIGRETURN => Action::Allow,
+                _ => Action::Kill,
+            };
+        }
+        let mut filter = match &self.filters {
+            Some(filter) => filter,
+            None => return Action::Allow,
+        };
+
+        let mut data = [0; SECCOMP_DATA_SIZE];
+        data[0..4].copy_from_slice(&(num as u32).to_le_bytes());
+        data[4..8].copy_from_slice(&AUDIT_ARCH_RISCV64.to_le_bytes());
+        data[8..16].copy_from_slice(&(pc as u64).to_le_bytes());
+        for (i, arg) in args.iter().enumerate() {
+            data[16 + i * 8..24 + i * 8].copy_from_slice(&(*arg as u64).to_le_bytes());
+        }
+
+        // the lowest action, as a signed number, is the most restrictive
+        let mut ret = run(&filter.prog, &data);
+        while let Some(prev) = &filter.prev {
+            let other = run(&prev.prog, &data);
+            if ((other & SECCOMP_RET_ACTION_FULL) as i32) < ((ret & SECCOMP_RET_ACTION_FULL) as i32) {
+                ret = other;
+            }
+            filter = prev;
+        }
+
+        match ret & SECCOMP_RET_ACTION_FULL {
+            SECCOMP_RET_ALLOW => Action::Allow,
+            SECCOMP_RET_LOG => {
+                log!(Info, "seccomp: allowed {} at {:#x}", syscall_name(num).unwrap_or("?"), pc);
+                Action::Allow
+            },
+            SECCOMP_RET_ERRNO => Action::Errno(min(ret & SECCOMP_RET_DATA, 4095) as isize),
+            // nobody is tracing or listening, so these are ENOSYS, as on Linux
+            SECCOMP_RET_TRACE | SECCOMP_RET_USER_NOTIF => Action::Errno(ENOSYS),
+            SECCOMP_RET_TRAP => Action::Trap,
+            _ => Action::Kill,
+        }
+    }
+}
+
+// Run a checked filter program over `data`.
+fn run(prog: &[Insn], data: &[u8; SECCOMP_DATA_SIZE]) -> u32 {
+    let mut a: u32 = 0;
+    let mut x: u32 = 0;
+    let mut mem = [0u32; BPF_MEMWORDS];
+    let mut pc = 0;
+    loop {
+        // `check_prog` makes sure we stay in the program and end on a
+        // ret, and that loads stay in `data` and `mem`
+        let insn = prog[pc];
+        let k = insn.k;
+        pc += 1;
+        match insn.code {
+            c if c == BPF_LD | BPF_W | BPF_ABS => {
+                let at = k as usize;
+                a = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            },
+            c if c == BPF_LD | BPF_IMM => a = k,
+            c if c == BPF_LD | BPF_MEM => a = mem[k as usize],
+            c if c == BPF_LD | BPF_W | BPF_LEN => a = SECCOMP_DATA_SIZE as u32,
+            c if c == BPF_LDX | BPF_IMM => x = k,
+            c if c == BPF_LDX | BPF_MEM => x = mem[k as usize],
+            c if c == BPF_LDX | BPF_W | BPF_LEN => x = SECCOMP_DATA_SIZE as u32,
+            BPF_ST => mem[k as usize] = a,
+            BPF_STX => mem[k as usize] = x,
+            c if c & 0x07 == BPF_ALU => {
+                let src = if c & BPF_X != 0 { x } else { k };
+                a = match c & 0xf0 {
+                    BPF_ADD => a.wrapping_add(src),
+                    BPF_SUB => a.wrapping_sub(src),
+                    BPF_MUL => a.wrapping_mul(src),
+                    // like Linux, dividing by zero ends the program with 0
+                    BPF_DIV | BPF_MOD if src == 0 => return 0,
+                    BPF_DIV => a / src,
+                    BPF_MOD => a % src,
+                    BPF_OR => a | src,
+                    BPF_AND => a & src,
+                    BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    _ => a ^ src, // BPF_XOR
+                };
+            },
+            c if c == BPF_JMP | BPF_JA => pc += k as usize,
+            c if c & 0x07 == BPF_JMP => {
+                let src = if c & BPF_X != 0 { x } else { k };
+                let taken = match c & 0xf0 {
+                    BPF_JEQ => a == src,
+                    BPF_JGT => a > src,
+                    BPF_JGE => a >= src,
+                    _ => a & src != 0, // BPF_JSET
+                };
+                pc += if taken { insn.jt } else { insn.jf } as usize;
+            },
+            c if c == BPF_RET | BPF_K => return k,
+            c if c == BPF_RET | BPF_A => return a,
+            c if c == BPF_MISC | BPF_TAX => x = a,
+            _ => a = x, // BPF_MISC | BPF_TXA
+        }
+    }
+}
+
+// Make sure `prog` is one `run` can run: only instructions it knows,
+// jumps that land in the program, loads that stay in bounds, and a
+// ret at the end so it can't run off.
+fn check_prog(prog: &[Insn]) -> Result<(), isize> {
+    if prog.is_empty() || prog.len() > BPF_MAXINSNS {
+        return Err(EINVAL);
+    }
+    for (pc, insn) in prog.iter().enumerate() {
+        let left = prog.len() - pc - 1;
+        let k = insn.k as usize;
+        let ok = match insn.code {
+            c if c == BPF_LD | BPF_W | BPF_ABS => k.is_multiple_of(4) && k < SECCOMP_DATA_SIZE,
+            c if c == BPF_LD | BPF_MEM || c == BPF_LDX | BPF_MEM => k < BPF_MEMWORDS,
+            BPF_ST | BPF_STX => k < BPF_MEMWORDS,
+            c if c == BPF_LD | BPF_IMM || c == BPF_LDX | BPF_IMM => true,
+            c if c == BPF_LD | BPF_W | BPF_LEN || c == BPF_LDX | BPF_W | BPF_LEN => true,
+            c if c & !0xf8 == BPF_ALU => match c & 0xf0 {
+                BPF_NEG => c & BPF_X == 0,
+                BPF_DIV | BPF_MOD => c & BPF_X != 0 || k != 0,
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND
+                    | BPF_LSH | BPF_RSH | BPF_XOR => true,
+                _ => false,
+            },
+            c if c == BPF_JMP | BPF_JA => k < left,
+            c if c & !0xf8 == BPF_JMP => matches!(c & 0xf0, BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET)
+                && (insn.jt as usize) < left
+                && (insn.jf as usize) < left,
+            c if c == BPF_RET | BPF_K || c == BPF_RET | BPF_A => true,
+            c if c == BPF_MISC | BPF_TAX || c == BPF_MISC | BPF_TXA => true,
+            _ => false,
+        };
+        if !ok {
+            return Err(EINVAL);
+        }
+    }
+    match prog[prog.len() - 1].code {
+        c if c == BPF_RET | BPF_K || c == BPF_RET | BPF_A => Ok(()),
+        _ => Err(EINVAL),
+    }
+}
+
+// Read a struct sock_fprog, and the program it points to, out of
+// process memory.
+fn user_prog(pt: PageTable, va: usize) -> Result<Vec<Insn>, isize> {
+    let mut fprog = [0; SOCK_FPROG_SIZE];
+    copy_from_user(pt, &mut fprog, va).map_err(efault)?;
+    let len = u16::from_le_bytes([fprog[0], fprog[1]]) as usize;
+    let filter = usize::from_le_bytes(fprog[8..16].try_into().unwrap());
+    if len == 0 || len > BPF_MAXINSNS {
+        return Err(EINVAL);
+    }
+    let mut raw = vec![0; len * SOCK_FILTER_SIZE];
+    copy_from_user(pt, &mut raw, filter).map_err(efault)?;
+    Ok(raw.chunks_exact(SOCK_FILTER_SIZE).map(|b| Insn {
+        code: u16::from_le_bytes([b[0], b[1]]),
+        jt: b[2],
+        jf: b[3],
+        k: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+    }).collect())
+}
+
+/// seccomp(operation, flags, args).
+pub fn sys_seccomp(proc: &mut Process, op: usize, flags: usize, args: usize) -> isize {
+    match op {
+        SECCOMP_SET_MODE_STRICT => {
+            if flags != 0 || args != 0 {
+                return -EINVAL;
+            }
+            proc.seccomp.strict = true;
+            0
+        },
+        SECCOMP_SET_MODE_FILTER => {
+            if flags & !SECCOMP_FILTER_FLAG_TSYNC != 0 {
+                return -EINVAL;
+            }
+            if proc.seccomp.strict {
+                return -EINVAL;
+            }
+            let prog = match user_prog(proc.pgtbl, args) {
+                Ok(prog) => prog,
+                Err(err) => return -err,
+            };
+            if let Err(err) = check_prog(&prog) {
+                return -err;
+            }
+            let prev = proc.seccomp.filters.take();
+            let depth = prev.as_ref().map_or(0, |f| f.depth) + 1;
+            if depth > MAX_FILTERS {
+                proc.seccomp.filters = prev;
+                return -ENOMEM;
+            }
+            proc.seccomp.filters = Some(Arc::new(Filter { prog, prev, depth }));
+            0
+        },
+        SECCOMP_GET_ACTION_AVAIL => {
+            if flags != 0 {
+                return -EINVAL;
+            }
+            let mut action = [0; 4];
+            if let Err(e) = copy_from_user(proc.pgtbl, &mut action, args) {
+                return -efault(e);
+            }
+            match u32::from_le_bytes(action) {
+                SECCOMP_RET_KILL_PROCESS | SECCOMP_RET_KILL_THREAD | SECCOMP_RET_TRAP
+                    | SECCOMP_RET_ERRNO | SECCOMP_RET_USER_NOTIF | SECCOMP_RET_TRACE
+                    | SECCOMP_RET_LOG | SECCOMP_RET_ALLOW => 0,
+                _ => -EOPNOTSUPP,
+            }
+        },
+        _ => -EINVAL,
+    }
+}
+
+/// prctl(PR_SET_SECCOMP, mode, prog), the old way in.
+pub fn prctl_set_seccomp(proc: &mut Process, mode: usize, prog: usize) -> isize {
+    match mode {
+        SECCOMP_MODE_STRICT => sys_seccomp(proc, SECCOMP_SET_MODE_STRICT, 0, 0),
+        SECCOMP_MODE_FILTER => sys_seccomp(proc, SECCOMP_SET_MODE_FILTER, 0, prog),
+        _ => -EINVAL,
+    }
+}
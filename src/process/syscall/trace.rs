@@ -0,0 +1,190 @@
+//! Syscall tracing, a little like strace. Each traced call prints a
+//! line like
+//!
+//! ```text
+//! [pid 2 hart 0] write(1, "hello\n", 6) = 6
+//! ```
+//!
+//! Every process is traced when the kernel is booted with `strace` on
+//! its command line, and any one process can turn tracing on for
+//! itself with `prctl(PR_SET_SYSCALL_TRACE, 1)`.
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::*;
+use crate::hw::fdt;
+
+// Trace every process, from the `strace` boot argument.
+static TRACE_ALL: AtomicBool = AtomicBool::new(false);
+
+// Bytes of a buffer we show before giving up with "...".
+const SHOW_BYTES: usize = 32;
+
+/// Read the kernel command line. Called once at boot.
+pub fn init() {
+    TRACE_ALL.store(fdt::bootarg("strace").is_some(), Ordering::Relaxed);
+}
+
+/// Whether syscalls made by `proc` get traced.
+pub fn enabled(proc: &Process) -> bool {
+    proc.traced || TRACE_ALL.load(Ordering::Relaxed)
+}
+
+// How to show an argument.
+#[derive(Clone, Copy)]
+enum Arg {
+    Int,        // signed decimal
+    Hex,        // pointers and flags
+    Str,        // NUL terminated string
+    In(usize),  // buffer the call reads, as long as argument n says
+    Out,        // buffer the call fills, as long as the return value says
+}
+
+// How to show the arguments of syscall `num`. Calls we don't know get
+// all six registers in hex.
+fn signature(num: usize) -> &'static [Arg] {
+    use Arg::*;
+    match num {
+        READ => &[Int, Out, Int],
+        WRITE => &[Int, In(2), Int],
+        WRITEV => &[Int, Hex, Int],
+        IOCTL => &[Int, Hex, Hex],
+        EXECVE => &[Str, Hex, Hex],
+        EXIT | EXIT_GROUP => &[Int],
+        SCHED_YIELD => &[],
+        SET_TID_ADDRESS | BRK | NEWUNAME => &[Hex],
+        CLOCK_GETTIME => &[Int, Hex],
+        TIMES => &[Hex],
+        GETRUSAGE => &[Int, Hex],
+        MMAP => &[Hex, Int, Hex, Hex, Int, Int],
+        PRCTL => &[Hex, Int, Hex],
+        SECCOMP => &[Int, Hex, Hex],
+        GETPID | GETTID | RT_SIGRETURN => &[],
+        CLONE => &[Hex, Hex, Hex, Hex, Hex],
+        CLOSE | DUP => &[Int],
+        DUP3 => &[Int, Int, Hex],
+        PIPE2 => &[Hex, Hex],
+        MEMFD_CREATE => &[Str, Hex],
+        FTRUNCATE64 => &[Int, Int],
+        OPENAT => &[Int, Str, Hex, Hex],
+        GETDENTS64 => &[Int, Hex, Int],
+        LSEEK => &[Int, Int, Int],
+        CHANNEL_CREATE => &[Hex, Hex],
+        CHANNEL_SEND => &[Int, Hex, Hex],
+        CHANNEL_RECV => &[Int, Hex, Int],
+        CHANNEL_PUBLISH => &[Int, Str],
+        CHANNEL_CONNECT => &[Str, Hex],
+        FUTEX => &[Hex, Int, Int, Hex],
+        KILL | TKILL => &[Int, Int],
+        TGKILL => &[Int, Int, Int],
+        RT_SIGACTION | RT_SIGPROCMASK => &[Int, Hex, Hex, Int],
+        RT_SIGPENDING => &[Hex, Int],
+        GETRLIMIT | SETRLIMIT => &[Int, Hex],
+        PRLIMIT64 => &[Int, Int, Hex, Hex],
+        _ => &[Hex, Hex, Hex, Hex, Hex, Hex],
+    }
+}
+
+// The spelling of the errno `err`, if we know it.
+fn errno_name(err: isize) -> Option<&'static str> {
+    Some(match err {
+        EPERM => "EPERM",
+        ENOENT => "ENOENT",
+        ESRCH => "ESRCH",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        E2BIG => "E2BIG",
+        ENOEXEC => "ENOEXEC",
+        EBADF => "EBADF",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EACCES => "EACCES",
+        EFAULT => "EFAULT",
+        EBUSY => "EBUSY",
+        EEXIST => "EEXIST",
+        ENODEV => "ENODEV",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        EMFILE => "EMFILE",
+        ENOTTY => "ENOTTY",
+        ENOSPC => "ENOSPC",
+        ESPIPE => "ESPIPE",
+        EPIPE => "EPIPE",
+        EROFS => "EROFS",
+        ENAMETOOLONG => "ENAMETOOLONG",
+        ENOSYS => "ENOSYS",
+        ENOTEMPTY => "ENOTEMPTY",
+        EOPNOTSUPP => "EOPNOTSUPP",
+        ETIMEDOUT => "ETIMEDOUT",
+        _ => return None,
+    })
+}
+
+// Append `len` bytes of process memory at `va` as a quoted, escaped
+// string, or the bare pointer if we can't read it.
+fn show_bytes(out: &mut String, pt: PageTable, va: usize, len: usize) {
+    let mut bytes = vec![0; min(len, SHOW_BYTES)];
+    if copy_from_user(pt, &mut bytes, va).is_err() {
+        let _ = write!(out, "{:#x}", va);
+        return;
+    }
+    out.push('"');
+    for &b in &bytes {
+        let _ = match b {
+            b'\n' => write!(out, "\\n"),
+            b'\r' => write!(out, "\\r"),
+            b'\t' => write!(out, "\\t"),
+            b'"' | b'\\' => write!(out, "\\{}", b as char),
+            0x20..=0x7e => write!(out, "{}", b as char),
+            _ => write!(out, "\\x{:02x}", b),
+        };
+    }
+    out.push('"');
+    if len > SHOW_BYTES {
+        out.push_str("...");
+    }
+}
+
+/// Print the trace line for syscall `num` with arguments `args`, made
+/// by `proc`. `ret` is what it returned, or `None` for calls that
+/// don't return here, like exit.
+pub fn syscall(proc: &Process, num: usize, args: &[usize; 6], ret: Option<isize>) {
+    let mut line = String::new();
+    match syscall_name(num) {
+        Some(name) => line.push_str(&name.to_ascii_lowercase()),
+        None => { let _ = write!(line, "syscall_{}", num); },
+    }
+    line.push('(');
+    for (i, kind) in signature(num).iter().enumerate() {
+        if i > 0 {
+            line.push_str(", ");
+        }
+        let arg = args[i];
+        match *kind {
+            Arg::Int => { let _ = write!(line, "{}", arg as isize); },
+            Arg::Hex => { let _ = write!(line, "{:#x}", arg); },
+            Arg::Str => match user_str(proc.pgtbl, arg) {
+                Ok(s) => { let _ = write!(line, "{:?}", s); },
+                Err(_) => { let _ = write!(line, "{:#x}", arg); },
+            },
+            Arg::In(n) => show_bytes(&mut line, proc.pgtbl, arg, args[n]),
+            Arg::Out => match ret {
+                Some(got) if got >= 0 => show_bytes(&mut line, proc.pgtbl, arg, got as usize),
+                _ => { let _ = write!(line, "{:#x}", arg); },
+            },
+        }
+    }
+    line.push_str(") = ");
+    let _ = match ret {
+        None => write!(line, "?"),
+        Some(err) if (-4095..0).contains(&err) => match errno_name(-err) {
+            Some(name) => write!(line, "-1 {}", name),
+            None => write!(line, "-1 errno {}", -err),
+        },
+        Some(addr) if num == BRK || num == MMAP => write!(line, "{:#x}", addr),
+        Some(val) => write!(line, "{}", val),
+    };
+    println!("[pid {} hart {}] {}", proc.id, read_tp(), line);
+}
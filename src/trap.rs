@@ -1,12 +1,16 @@
 //! Kernel trap handlers.
 use crate::device::{clint, plic, uart, virtio};
 use crate::hw::{riscv, param};
+use crate::vm::{tlb, uaccess};
+use crate::process;
 
 use crate::log;
+use core::ptr::addr_of;
 
 extern "C" {
     pub fn __mtrapvec();
     pub fn __strapvec();
+    fn user_fault_asm(frame: *const usize) -> !;
 }
 
 
@@ -24,7 +28,10 @@ extern "C" {
 /// see any reason they need to be public.
 ///
 /// TODO how can we make these generic over 32/64 bit width?
+const S_SOFT_IRQ: u64 = 0x1 | ( 1 << 63);
 const S_EXTERN_IRQ: u64 = 0x9 | ( 1 << 63);
+const S_LOAD_PAGE_FAULT: u64 = 13;
+const S_STORE_PAGE_FAULT: u64 = 15;
 
 /// Write the supervisor trap vector to stvec register on each hart.
 pub fn init() {
@@ -41,6 +48,12 @@ pub extern "C" fn m_handler() {
             // log::log!(Debug, "Machine timer interupt, hart: {}", riscv::read_mhartid());
             clint::set_mtimecmp(10_000_000);
         }
+        riscv::MSTATUS_SOFTWARE => {
+            // another hart wants us out of user mode (see vm::tlb),
+            // which takes a supervisor interrupt
+            clint::clear_ipi();
+            riscv::write_mip(riscv::read_mip() | riscv::SIP_SSIP);
+        }
         _ => {
             log::log!(
                 Warning,
@@ -52,14 +65,59 @@ pub extern "C" fn m_handler() {
     }
 }
 
-/// Supervisor mode trap handler.
+/// Supervisor mode trap handler. `frame` is the registers
+/// regular_strap saved.
+///
+/// A process's registers only ever move through `copy_to_user` and
+/// `copy_from_user`, so the only faults on user memory we can take in
+/// supervisor mode are the ones `fixup_user_fault` knows about. A
+/// bad process sp is a signal for it, from `scall_rust` or `resume`.
+///
+/// # Safety
+///
+/// Only for regular_strap, with `frame` the registers it saved on
+/// the sscratch stack.
 #[no_mangle]
-pub extern "C" fn s_handler() {
+pub unsafe extern "C" fn s_handler(frame: *const usize) {
     let cause = riscv::read_scause();
+    let from_user = riscv::read_sstatus() & riscv::SSTATUS_SPP == 0;
+    // regular_strap switched to the kernel page table and flushed
+    if from_user {
+        tlb::leave_user();
+    }
 
     match cause {
+        S_SOFT_IRQ => {
+            // trapping was all vm::tlb::shootdown wanted
+            riscv::write_sip(riscv::read_sip() & !riscv::SIP_SSIP);
+        },
         S_EXTERN_IRQ => {
-            s_extern()
+            // the process is still there, and handling this is kernel
+            // time on its account
+            if from_user {
+                process::interrupted(true);
+            }
+            s_extern();
+            if from_user {
+                process::interrupted(false);
+            }
+        },
+        // an exception in a process is a signal for it, not our problem
+        _ if from_user && cause & (1 << 63) == 0 => user_fault_asm(frame),
+        S_LOAD_PAGE_FAULT | S_STORE_PAGE_FAULT => {
+            match uaccess::fixup_user_fault(riscv::read_sepc()) {
+                // a bad user pointer in copy_from_user / copy_to_user
+                Some(pc) => riscv::write_sepc(pc),
+                None => {
+                    log::log!(
+                        Warning,
+                        "Uncaught page fault. scause: 0x{:x} sepc: 0x{:x}",
+                        cause,
+                        riscv::read_sepc()
+                    );
+                    panic!()
+                }
+            }
         },
         _ => {
             log::log!(
@@ -70,6 +128,10 @@ pub extern "C" fn s_handler() {
             panic!()
         }
     }
+    // regular_strap goes back to the process page table
+    if from_user {
+        tlb::enter_user();
+    }
 }
 
 /// Called when we get a S mode external interupt. Probably UART input
@@ -92,7 +154,7 @@ fn s_extern() {
             // allow printing. Normally we shouldn't print
             // here
             let input = unsafe {
-                match uart::WRITER.lock().get() {
+                match (*addr_of!(uart::WRITER)).lock().get() {
                     Some(i) => i,
                     None => {
                         // spurious irq? just exit early
@@ -101,10 +163,9 @@ fn s_extern() {
                     }
                 }
             };
-            log!(Info, "Got UART input: {}",
-                 char::from_u32(input as u32).expect(
-                     "Illformed UART input character!"
-                 ));
+            // keep it for whoever reads the console next
+            uart::push_input(input);
+            process::console_input();
             unsafe {
                 plic::PLIC.get().unwrap().complete(irq)
             };
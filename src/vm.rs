@@ -2,6 +2,8 @@
 pub mod global;
 mod palloc;
 pub mod ptable;
+pub mod tlb;
+pub mod uaccess;
 pub mod vmalloc;
 
 use crate::lock::mutex::Mutex;
@@ -9,10 +11,13 @@ use crate::hw::param::*;
 use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::OnceCell;
+use core::ptr::{addr_of, addr_of_mut};
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use global::Galloc;
+use global::{Galloc, GallocStats};
 use palloc::*;
+pub use palloc::PoolStats;
 use ptable::{PageTable, kpage_init};
 
 // For saftey reasons, no part of the page allocation process all the
@@ -24,6 +29,9 @@ use ptable::{PageTable, kpage_init};
 
 /// Global physical page pool allocated by the kernel physical allocator.
 static mut PAGEPOOL: OnceCell<PagePool> = OnceCell::new();
+/// Start of memory the bootloader left things in (device tree,
+/// initrd), held back from the page pool until `release_boot_memory`.
+static BOOT_RESERVED: AtomicUsize = AtomicUsize::new(0);
 #[global_allocator]
 static mut GLOBAL: GlobalWrapper = GlobalWrapper {
     inner: OnceCell::new(),
@@ -60,19 +68,52 @@ pub enum VmError {
     PfreeFail,
     GNoSpace,
     Koom,
+    UserFault,                  // bad user pointer, EFAULT to a process
 }
 
 
+// Lowest page holding something the bootloader handed us. Everything
+// from there up stays out of the pool for now.
+fn boot_reserved_start() -> usize {
+    let mut top = memory_end().addr();
+    let fdt = crate::hw::fdt::get();
+    let ranges = [crate::hw::fdt::boot_range(), fdt.and_then(|f| f.initrd())];
+    for (start, _) in ranges.into_iter().flatten() {
+        if start >= bss_end().addr() && start < top {
+            top = start & !(PAGE_SIZE - 1);
+        }
+    }
+    top
+}
+
+/// Hand the memory held back at boot to the page pool. Call once
+/// the device tree is preserved and the initramfs unpacked.
+pub fn release_boot_memory() {
+    let start = BOOT_RESERVED.swap(0, Ordering::Relaxed);
+    if start == 0 || start == memory_end().addr() {
+        return;
+    }
+    let num = (memory_end().addr() - start) / PAGE_SIZE;
+    log!(Debug, "Releasing {} pages of boot memory...", num);
+    unsafe {
+        let _ = (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap()
+            .grow(memory_end().with_addr(start), num);
+    }
+}
+
 /// Initialize the kernel VM system.
 /// First, setup the kernel physical page pool.
-/// We start the pool at the end of the .bss section, and stop at the end of physical memory.
+/// We start the pool at the end of the .bss section, and stop at the end of physical memory,
+/// or below anything the bootloader left in memory for us.
 /// Next, we map physical memory into the kernel's physical memory 1:1.
 /// Next, initialize the kernel virtual memory allocator pool.
 ///
 /// TODO better error type
 pub fn global_init() -> Result<PageTable, ()> {
+    let top = boot_reserved_start();
+    BOOT_RESERVED.store(top, Ordering::Relaxed);
     unsafe {
-        match PAGEPOOL.set(PagePool::new(bss_end(), memory_end())) {
+        match (*addr_of!(PAGEPOOL)).set(PagePool::new(bss_end(), memory_end().with_addr(top))) {
             Ok(_) => {}
             Err(_) => {
                 panic!("vm double init.")
@@ -82,7 +123,7 @@ pub fn global_init() -> Result<PageTable, ()> {
     log!(Debug, "Successfully initialized kernel page pool...");
 
     unsafe {
-        match GLOBAL.inner.set(Mutex::new(Galloc::new(PAGEPOOL.get_mut().unwrap()))) {
+        match (*addr_of!(GLOBAL)).inner.set(Mutex::new(Galloc::new((*addr_of_mut!(PAGEPOOL)).get_mut().unwrap()))) {
             Ok(_) => {}
             Err(_) => {
                 panic!("vm double init.")
@@ -123,14 +164,14 @@ fn pagetable_interrupt_stack_setup(pt: &ptable::PageTable) {
 /// A test designed to be used with GDB.
 /// Allocate A, then B. Free A, then B.
 pub unsafe fn test_palloc() {
-    let one = PAGEPOOL.get_mut().unwrap().palloc().unwrap();
+    let one = (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().palloc().unwrap();
     one.addr.write(0xdeadbeaf);
 
-    let many = PAGEPOOL.get_mut().unwrap().palloc_plural(5).unwrap();
+    let many = (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().palloc_plural(5).unwrap();
     many.write_bytes(5, 512 * 2);
 
-    let _ = PAGEPOOL.get_mut().unwrap().pfree(one);
-    let _ = PAGEPOOL.get_mut().unwrap().pfree_plural(many, 5);
+    let _ = (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().pfree(one);
+    let _ = (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().pfree_plural(many, 5);
 
     log!(Debug, "Successful test of page allocation and freeing...");
 }
@@ -168,13 +209,24 @@ pub unsafe fn test_galloc() {
 //     unsafe { VMALLOC.get_mut().unwrap().free(ptr) }
 // }
 
+/// Where physical memory has gone: the page pool, and how much of it
+/// the kernel heap has.
+pub fn stats() -> (PoolStats, GallocStats) {
+    unsafe {
+        let pool = (*addr_of!(PAGEPOOL)).get().unwrap().stats();
+        // the heap's lock, so nothing here may allocate
+        let heap = (*addr_of!(GLOBAL)).inner.get().unwrap().lock().stats();
+        (pool, heap)
+    }
+}
+
 // for internal vm use only.
 fn palloc() -> Result<Page, VmError> {
-    unsafe { PAGEPOOL.get_mut().unwrap().palloc() }
+    unsafe { (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().palloc() }
 }
 
 fn pfree(page: Page) -> Result<(), VmError> {
-    unsafe { PAGEPOOL.get_mut().unwrap().pfree(page) }
+    unsafe { (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().pfree(page) }
 }
 
 
@@ -202,7 +254,7 @@ impl PhysPageExtent {
 impl Drop for PhysPageExtent {
     fn drop(&mut self) {
         unsafe {
-            match PAGEPOOL.get_mut().unwrap()
+            match (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap()
                 .pfree_plural(self.head.addr, self.num) {
                     Ok(_) => {},
                     Err(e) => {panic!("Double palloc free! {:?}", e)}
@@ -212,13 +264,15 @@ impl Drop for PhysPageExtent {
 }
 
 unsafe impl Send for PhysPageExtent {}
+// Nothing about the pages changes through a shared reference.
+unsafe impl Sync for PhysPageExtent {}
 
 
 // VERY IMPORTANT: see top of module comment about deadlock safety
 /// Should be one and only way to get physical pages outside of vm module/subsystem.
 pub fn request_phys_page(num: usize) -> Result<PhysPageExtent, VmError>{
     let addr = unsafe {
-        PAGEPOOL.get_mut().unwrap().palloc_plural(num)?
+        (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().palloc_plural(num)?
     };
     Ok(PhysPageExtent {
         head: Page::from(addr),
@@ -4,10 +4,20 @@ use crate::vm::vmalloc::{Kalloc, MAX_CHUNK_SIZE};
 /// Global allocator on top of vmalloc and palloc
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Galloc {
     pool: *mut PagePool,
     small_pool: UnsafeCell<Kalloc>,
+    small_bytes: AtomicUsize,   // asked for in live small allocations
+    pages: AtomicUsize,         // taken by live page sized ones
+}
+
+/// What the kernel heap holds right now.
+#[derive(Copy, Clone)]
+pub struct GallocStats {
+    pub small_bytes: usize,
+    pub pages: usize,
 }
 
 impl Galloc {
@@ -18,6 +28,15 @@ impl Galloc {
         Galloc {
             pool,
             small_pool: UnsafeCell::new(Kalloc::new(small_pool_start)),
+            small_bytes: AtomicUsize::new(0),
+            pages: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> GallocStats {
+        GallocStats {
+            small_bytes: self.small_bytes.load(Ordering::Relaxed),
+            pages: self.pages.load(Ordering::Relaxed),
         }
     }
 }
@@ -59,6 +78,7 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
+            self.small_bytes.fetch_add(layout.size(), Ordering::Relaxed);
             match (*self.small_pool.get()).alloc(layout.size()) {
                 Ok(ptr) => ptr as *mut u8,
                 Err(e) => {
@@ -66,6 +86,7 @@ unsafe impl GlobalAlloc for Galloc {
                 }
             }
         } else {
+            self.pages.fetch_add(num_pages, Ordering::Relaxed);
             match (*self.pool).palloc_plural(num_pages) {
                 Ok(ptr) => ptr as *mut u8,
                 Err(e) => {
@@ -83,8 +104,10 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
+            self.small_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
             (*self.small_pool.get()).free(ptr as *mut usize)
         } else {
+            self.pages.fetch_sub(num_pages, Ordering::Relaxed);
             match (*self.pool).pfree_plural(ptr as *mut usize, num_pages) {
                 Ok(_) => {}
                 Err(e) => {
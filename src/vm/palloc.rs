@@ -24,6 +24,15 @@ struct Pool {
     free: Option<Page>, // Head of free page list (stored in the free pages).
     bottom: *mut usize, // Min addr of this page allocation pool.
     top: *mut usize,    // Max addr of this page allocation pool.
+    pages: usize,       // Pages the pool has been given, in all.
+    free_pages: usize,  // Of those, how many are on the free list.
+}
+
+/// How full a page pool is, in pages.
+#[derive(Copy, Clone)]
+pub struct PoolStats {
+    pub total: usize,
+    pub free: usize,
 }
 
 /// Convenience struct to read a free page like a doubly linked list.
@@ -95,6 +104,29 @@ impl PagePool {
         pool.free_pages(Page::from(page), num_pages);
         Ok(())
     }
+
+    /// Hand the pool `num_pages` pages at `page` it didn't start out
+    /// with, like memory held back at boot. The pool's bounds grow to
+    /// take them in.
+    pub fn grow(&mut self, page: *mut usize, num_pages: usize) -> Result<(), VmError> {
+        assert!(num_pages != 0, "tried to grow by zero pages");
+        if !is_multiple(page.addr(), PAGE_SIZE) {
+            panic!("Grown page addr not page aligned.")
+        }
+
+        let mut pool = self.pool.lock();
+        let end = page.map_addr(|addr| addr + num_pages * PAGE_SIZE);
+        pool.bottom = core::cmp::min(pool.bottom, page);
+        pool.top = core::cmp::max(pool.top, end);
+        pool.pages += num_pages;
+        pool.free_pages(Page::from(page), num_pages);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let pool = self.pool.lock();
+        PoolStats { total: pool.pages, free: pool.free_pages }
+    }
 }
 
 /// Create a new page from a physical address.
@@ -192,10 +224,13 @@ impl Pool {
             pa = pa.map_addr(|addr| addr + chunk_size); // Don't use next_pa. End of loop will fail.
         }
 
+        let pages = (top.addr() - bottom.addr()) / chunk_size;
         Pool {
             free: Some(free),
             bottom,
             top,
+            pages,
+            free_pages: pages,
         }
     }
 
@@ -256,6 +291,7 @@ impl Pool {
         }
 
         // we found it
+        self.free_pages -= num_pages;
         // zero them all out
         let mut cur = start_region;
         while cur.addr as usize <= page.addr as usize {
@@ -269,6 +305,7 @@ impl Pool {
     fn free_pages(&mut self, mut page: Page, num_pages: usize) {
         assert!(num_pages != 0, "Tried to free zero pages");
         let example_null = core::ptr::null_mut::<usize>();
+        self.free_pages += num_pages;
 
         let mut region_end = Page::from(page.addr.map_addr(|addr| addr + (num_pages - 1) * 0x1000));
         let stop = region_end.addr.map_addr(|addr| addr + 0x1000);
@@ -4,9 +4,13 @@
 use crate::hw::param::*;
 use crate::hw::riscv::*;
 use crate::vm::*;
+use alloc::vec::Vec;
 use core::assert;
 
 const VA_TOP: usize = 1 << (27 + 12); // 2^27 VPN + 12 Offset
+/// User addresses are below this. Sv39 sign extends bit 38, so
+/// anything above is the upper (kernel) half or not an address.
+pub const USER_VA_TOP: usize = 1 << 38;
 const PTE_TOP: usize = 512; // 4Kb / 8 byte PTEs = 512 PTEs / page!
 const PTE_VALID: usize = 1 << 0;
 const PTE_READ: usize = 1 << 1;
@@ -111,6 +115,11 @@ impl PageTable {
         write_satp(phy_to_satp(self.base));
         flush_tlb();
     }
+
+    /// The satp value that selects this table.
+    pub fn satp(&self) -> usize {
+        phy_to_satp(self.base)
+    }
 }
 
 // Get the address of the PTE for va given the page table pt.
@@ -126,7 +135,7 @@ unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut P
             true => PageTable::from(*next),
             false => {
                 if alloc_new {
-                    match PAGEPOOL.get_mut().unwrap().palloc() {
+                    match (*addr_of_mut!(PAGEPOOL)).get_mut().unwrap().palloc() {
                         Ok(pg) => {
                             *next = PteSetFlag!(phy_to_pte(pg.addr), PTE_VALID);
                             PageTable::from(phy_to_pte(pg.addr))
@@ -145,6 +154,84 @@ unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut P
     Ok(table.index_mut(idx))
 }
 
+/// Find the physical address `va` maps to in `pt`, if it is mapped
+/// with at least the permissions in `flags` (see `user_process_flags`).
+///
+/// # Safety
+/// `pt` has to be a live page table, not one that has been freed.
+pub unsafe fn translate(pt: PageTable, va: VirtAddress, flags: usize) -> Option<PhysAddress> {
+    if va.addr() >= VA_TOP {
+        return None;
+    }
+    let pte = read_pte(walk(pt, va, false).ok()?);
+    if !PteGetFlag!(pte, PTE_VALID) || pte & flags != flags {
+        return None;
+    }
+    Some(pte_to_phy(pte).map_addr(|addr| addr | (va.addr() & (PAGE_SIZE - 1))))
+}
+
+/// A run of user pages, mapped one after the other with the same
+/// permissions.
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+/// Every page mapped for user mode in `pt`, in address order: where,
+/// what to, and with which of the flags `page_map` takes.
+pub fn user_pages(pt: PageTable) -> Vec<(usize, PhysAddress, usize)> {
+    const PERMS: usize = PTE_READ | PTE_WRITE | PTE_EXEC;
+    let mut out = Vec::new();
+    // top level entries are a GiB each
+    for i in 0..USER_VA_TOP >> 30 {
+        let top = read_pte(pt.index_mut(i));
+        if !PteGetFlag!(top, PTE_VALID) || top & PERMS != 0 {
+            continue;
+        }
+        let mid_table = PageTable::from(top);
+        for j in 0..PTE_TOP {
+            let mid = read_pte(mid_table.index_mut(j));
+            if !PteGetFlag!(mid, PTE_VALID) || mid & PERMS != 0 {
+                continue;
+            }
+            let leaf_table = PageTable::from(mid);
+            for k in 0..PTE_TOP {
+                let leaf = read_pte(leaf_table.index_mut(k));
+                if PteGetFlag!(leaf, PTE_VALID) && PteGetFlag!(leaf, PTE_USER) {
+                    let va = (i << 30) | (j << 21) | (k << 12);
+                    out.push((va, pte_to_phy(leaf), leaf & (PTE_USER | PERMS)));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Everything mapped for user mode in `pt`, in address order, as
+/// regions as big as they go.
+pub fn user_regions(pt: PageTable) -> Vec<Region> {
+    let mut out: Vec<Region> = Vec::new();
+    for (va, _, flags) in user_pages(pt) {
+        let region = Region {
+            start: va,
+            end: va + PAGE_SIZE,
+            read: flags & PTE_READ != 0,
+            write: flags & PTE_WRITE != 0,
+            exec: flags & PTE_EXEC != 0,
+        };
+        match out.last_mut() {
+            Some(last) if last.end == va && (last.read, last.write, last.exec)
+                == (region.read, region.write, region.exec) => last.end = region.end,
+            _ => out.push(region),
+        }
+    }
+    out
+}
+
 /// Helper for making flags for page_map for unpriviledged processes
 pub fn user_process_flags(r: bool, w: bool, e: bool) -> usize {
     PTE_USER |
@@ -195,6 +282,26 @@ pub fn page_map(
     Ok(())
 }
 
+/// Unmap the page at `va` in `pt`, and say what it was mapped to and
+/// with which of the flags `page_map` takes. The page table pages stay.
+/// Other harts can still have the page in their TLB, so it isn't free
+/// until `tlb::shootdown`.
+///
+/// # Safety
+/// `pt` has to be a live page table, not one that has been freed.
+pub unsafe fn page_unmap(pt: PageTable, va: VirtAddress) -> Option<(PhysAddress, usize)> {
+    if va.addr() >= VA_TOP {
+        return None;
+    }
+    let pte_addr = walk(pt, va, false).ok()?;
+    let pte = read_pte(pte_addr);
+    if !PteGetFlag!(pte, PTE_VALID) {
+        return None;
+    }
+    set_pte(pte_addr, 0);
+    Some((pte_to_phy(pte), pte & (PTE_USER | PTE_READ | PTE_WRITE | PTE_EXEC)))
+}
+
 /// Create the kernel page table with 1:1 mappings to physical memory.
 /// First allocate a new page for the kernel page table.
 /// Next, map memory mapped I/O devices to the kernel page table.
@@ -224,6 +331,25 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
     )?;
     log!(Debug, "Successfully mapped UART into kernel pgtable...");
 
+    // The first page is msip, for poking other harts (see
+    // vm::tlb). The rest is read only, for mtime. Only machine mode
+    // sets timers.
+    page_map(
+        kpage_table,
+        CLINT_BASE as *mut usize,
+        CLINT_BASE as *mut usize,
+        PAGE_SIZE,
+        PTE_READ | PTE_WRITE,
+    )?;
+    page_map(
+        kpage_table,
+        (CLINT_BASE + PAGE_SIZE) as *mut usize,
+        (CLINT_BASE + PAGE_SIZE) as *mut usize,
+        CLINT_SIZE - PAGE_SIZE,
+        PTE_READ,
+    )?;
+    log!(Debug, "Successfully mapped CLINT into kernel pgtable...");
+
     page_map(
         kpage_table,
         PLIC_BASE as *mut usize,
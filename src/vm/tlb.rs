@@ -0,0 +1,49 @@
+//! Other harts' TLBs.
+//!
+//! `sfence.vma` only flushes the hart that runs it. We flush on every
+//! switch between the kernel and a process page table, so a hart only
+//! has process translations cached while it is in user mode or in
+//! `copy_user_asm`. Each hart counts those switches, odd while it is
+//! out in a process page table and even while it is back in the
+//! kernel's. After unmapping a page, `shootdown` waits for every other
+//! hart that is out to come back at least once, poking the ones in
+//! user mode with a software interrupt so they trap. Only then is the
+//! page ours to free or hand to someone else.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::device::clint;
+use crate::hw::param::NHART;
+use crate::hw::riscv::read_tp;
+
+static SWITCHES: [AtomicUsize; NHART] = [const { AtomicUsize::new(0) }; NHART];
+
+/// This hart is about to switch to a process page table.
+pub fn enter_user() {
+    let old = SWITCHES[read_tp() as usize].fetch_add(1, Ordering::SeqCst);
+    assert!(old.is_multiple_of(2), "entering user mode twice");
+}
+
+/// This hart is back on the kernel page table, with a flushed TLB.
+pub fn leave_user() {
+    let old = SWITCHES[read_tp() as usize].fetch_add(1, Ordering::SeqCst);
+    assert!(!old.is_multiple_of(2), "leaving user mode twice");
+}
+
+/// Wait until no other hart can still have a translation from before
+/// now. Call it after changing a process page table, before the old
+/// page is reused.
+pub fn shootdown() {
+    let me = read_tp() as usize;
+    for (hart, switches) in SWITCHES.iter().enumerate() {
+        let seen = switches.load(Ordering::SeqCst);
+        if hart == me || seen.is_multiple_of(2) {
+            continue;
+        }
+        // in copy_user_asm it comes back on its own, in user mode this
+        // makes it trap
+        clint::send_ipi(hart);
+        while switches.load(Ordering::SeqCst) == seen {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Access to process memory from the kernel.
+//!
+//! The kernel runs with SUM clear, so a stray user pointer faults
+//! instead of being followed. Syscalls copy to and from process memory
+//! with `copy_from_user` and `copy_to_user`, which check the whole
+//! range against the process page table first, then copy with the
+//! process page table loaded and SUM set for just as long as it takes.
+//!
+//! The check should catch everything, but if the copy faults anyway
+//! the trap handler hands control back here through
+//! `fixup_user_fault`, and the copy fails with `VmError::UserFault`.
+
+use crate::hw::param::PAGE_SIZE;
+use crate::vm::ptable::*;
+use crate::vm::tlb;
+use crate::vm::VmError;
+
+extern "C" {
+    fn copy_user_asm(dst: *mut u8, src: *const u8, len: usize, satp: usize) -> usize;
+    fn copy_user_load();
+    fn copy_user_store();
+    fn copy_user_fixup();
+}
+
+// Check that every page of `va..va + len` is user memory mapped with
+// at least `flags`, from `user_process_flags`.
+fn check_range(pt: PageTable, va: usize, len: usize, flags: usize) -> Result<(), VmError> {
+    let end = va.checked_add(len).ok_or(VmError::UserFault)?;
+    if end > USER_VA_TOP {
+        return Err(VmError::UserFault);
+    }
+    let mut page = va & !(PAGE_SIZE - 1);
+    while page < end {
+        unsafe { translate(pt, page as *mut usize, flags) }.ok_or(VmError::UserFault)?;
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes out of the process memory at `src`.
+pub fn copy_from_user(pt: PageTable, dst: &mut [u8], src: usize) -> Result<(), VmError> {
+    check_range(pt, src, dst.len(), user_process_flags(true, false, false))?;
+    tlb::enter_user();
+    let left = unsafe { copy_user_asm(dst.as_mut_ptr(), src as *const u8, dst.len(), pt.satp()) };
+    tlb::leave_user();
+    if left != 0 {
+        return Err(VmError::UserFault);
+    }
+    Ok(())
+}
+
+/// Copy `src` into the process memory at `dst`.
+pub fn copy_to_user(pt: PageTable, dst: usize, src: &[u8]) -> Result<(), VmError> {
+    check_range(pt, dst, src.len(), user_process_flags(false, true, false))?;
+    tlb::enter_user();
+    let left = unsafe { copy_user_asm(dst as *mut u8, src.as_ptr(), src.len(), pt.satp()) };
+    tlb::leave_user();
+    if left != 0 {
+        return Err(VmError::UserFault);
+    }
+    Ok(())
+}
+
+/// Called by the trap handler on a page fault in supervisor mode at
+/// `pc`. If it is one of ours, returns where to resume instead.
+pub fn fixup_user_fault(pc: usize) -> Option<usize> {
+    if pc == copy_user_load as *const () as usize || pc == copy_user_store as *const () as usize {
+        Some(copy_user_fixup as *const () as usize)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,224 @@
+//! Check a native filesystem image for consistency.
+//!
+//! Replays the log in memory first, exactly as the kernel would at
+//! mount, so an image from a machine that crashed mid-write should
+//! always come out clean. Never modifies the image.
+//!
+//! Usage: fsck <image>
+//!
+//! Exits non-zero if anything is wrong.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::exit;
+
+use rfs_tools::layout::*;
+use rfs_tools::Image;
+
+struct Fsck {
+    img: Image,
+    errors: usize,
+    used: BTreeSet<u32>,            // blocks owned by some inode
+    links: BTreeMap<u32, u32>,      // directory entries naming each inode
+    subdirs: BTreeMap<u32, u32>,    // child directories of each directory
+    seen: BTreeSet<u32>,
+}
+
+impl Fsck {
+    fn error(&mut self, msg: String) {
+        println!("fsck: {}", msg);
+        self.errors += 1;
+    }
+
+    fn claim(&mut self, inum: u32, b: u32) {
+        if b < self.img.sb.datastart() || b >= self.img.sb.size {
+            self.error(format!("inode {}: block {} out of range", inum, b));
+        } else if !self.used.insert(b) {
+            self.error(format!("inode {}: block {} used twice", inum, b));
+        }
+    }
+
+    /// Claim the blocks under an indirect block mapping
+    /// `NINDIRECT^depth` file blocks starting at file block `base`.
+    fn claim_table(&mut self, inum: u32, table: u32, depth: u32, base: usize, nblocks: usize) {
+        self.claim(inum, table);
+        if table < self.img.sb.datastart() || table >= self.img.sb.size {
+            return;
+        }
+        let span = NINDIRECT.pow(depth - 1);
+        for i in 0..NINDIRECT {
+            let b = indirect_get(self.img.block(table), i);
+            if b == 0 {
+                continue;
+            }
+            let start = base + i * span;
+            if start >= nblocks {
+                self.error(format!("inode {}: block {} past end of file", inum, b));
+            }
+            if depth > 1 {
+                self.claim_table(inum, b, depth - 1, start, nblocks);
+            } else {
+                self.claim(inum, b);
+            }
+        }
+    }
+
+    fn claim_inode(&mut self, inum: u32, ip: &DiskInode) {
+        if ip.size as usize > MAXFILE * BSIZE {
+            self.error(format!("inode {}: size {} too large", inum, ip.size));
+        }
+        let nblocks = (ip.size as usize).div_ceil(BSIZE);
+        for (i, &b) in ip.addrs[..NDIRECT].iter().enumerate() {
+            if b != 0 {
+                if i >= nblocks {
+                    self.error(format!("inode {}: block {} past end of file", inum, b));
+                }
+                self.claim(inum, b);
+            }
+        }
+        if ip.addrs[NDIRECT] != 0 {
+            self.claim_table(inum, ip.addrs[NDIRECT], 1, NDIRECT, nblocks);
+        }
+        if ip.addrs[NDIRECT + 1] != 0 {
+            self.claim_table(inum, ip.addrs[NDIRECT + 1], 2, NDIRECT + NINDIRECT, nblocks);
+        }
+    }
+
+    fn walk(&mut self, inum: u32, parent: u32) {
+        if !self.seen.insert(inum) {
+            self.error(format!("directory {} reachable twice", inum));
+            return;
+        }
+        let dp = self.img.inode(inum);
+        self.claim_inode(inum, &dp);
+        if !(dp.size as usize).is_multiple_of(DIRENT_SIZE) {
+            self.error(format!("directory {}: odd size {}", inum, dp.size));
+        }
+        let mut dot = false;
+        let mut dotdot = false;
+        for ent in self.img.dirents(&dp) {
+            if ent.inum == 0 {
+                continue;
+            }
+            let name = String::from_utf8_lossy(ent.name()).into_owned();
+            if ent.inum >= self.img.sb.ninodes {
+                self.error(format!("directory {}: {} has bad inode {}", inum, name, ent.inum));
+                continue;
+            }
+            match name.as_str() {
+                "." => {
+                    dot = true;
+                    if ent.inum != inum {
+                        self.error(format!("directory {}: . points to {}", inum, ent.inum));
+                    }
+                    continue;
+                },
+                ".." => {
+                    dotdot = true;
+                    if ent.inum != parent {
+                        self.error(format!("directory {}: .. points to {}", inum, ent.inum));
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+            *self.links.entry(ent.inum).or_insert(0) += 1;
+            let ip = self.img.inode(ent.inum);
+            match ip.ftype {
+                T_DIR => {
+                    *self.subdirs.entry(inum).or_insert(0) += 1;
+                    self.walk(ent.inum, inum);
+                },
+                T_FILE => {
+                    if self.seen.insert(ent.inum) {
+                        self.claim_inode(ent.inum, &ip);
+                    }
+                },
+                t => self.error(format!("directory {}: {} is inode {} of type {}",
+                                        inum, name, ent.inum, t)),
+            }
+        }
+        if !dot || !dotdot {
+            self.error(format!("directory {}: missing . or ..", inum));
+        }
+    }
+
+    fn check_inodes(&mut self) {
+        for inum in 1..self.img.sb.ninodes {
+            let ip = self.img.inode(inum);
+            if ip.ftype == T_FREE {
+                continue;
+            }
+            if !self.seen.contains(&inum) {
+                if ip.nlink == 0 {
+                    // unlinked while open when the machine went down
+                    println!("fsck: warning: orphaned inode {} ({} bytes)", inum, ip.size);
+                    self.claim_inode(inum, &ip);
+                } else {
+                    self.error(format!("inode {} allocated but unreachable", inum));
+                }
+                continue;
+            }
+            let mut expect = self.links.get(&inum).copied().unwrap_or(0);
+            if ip.ftype == T_DIR {
+                expect += self.subdirs.get(&inum).copied().unwrap_or(0);
+                if inum == ROOTINO {
+                    expect += 1;
+                }
+            }
+            if ip.nlink as u32 != expect {
+                self.error(format!("inode {}: nlink {} but {} links", inum, ip.nlink, expect));
+            }
+        }
+    }
+
+    fn check_bitmap(&mut self) {
+        let sb = self.img.sb;
+        for b in 0..sb.size {
+            let bit = b as usize % BPB;
+            let set = self.img.block(sb.bblock(b))[bit / 8] & (1 << (bit % 8)) != 0;
+            let used = b < sb.datastart() || self.used.contains(&b);
+            if set && !used {
+                self.error(format!("block {} marked used but unowned", b));
+            } else if used && !set {
+                self.error(format!("block {} in use but marked free", b));
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: fsck <image>");
+        exit(2);
+    }
+    let mut img = Image::open(&args[1]).unwrap_or_else(|e| {
+        eprintln!("fsck: {}: {}", args[1], e);
+        exit(2)
+    });
+    let replayed = img.replay_log();
+    if replayed > 0 {
+        println!("fsck: replayed {} committed log blocks", replayed);
+    }
+
+    let mut fsck = Fsck {
+        img,
+        errors: 0,
+        used: BTreeSet::new(),
+        links: BTreeMap::new(),
+        subdirs: BTreeMap::new(),
+        seen: BTreeSet::new(),
+    };
+    if fsck.img.inode(ROOTINO).ftype != T_DIR {
+        fsck.error(String::from("root is not a directory"));
+    } else {
+        fsck.walk(ROOTINO, ROOTINO);
+    }
+    fsck.check_inodes();
+    fsck.check_bitmap();
+
+    let sb = fsck.img.sb;
+    println!("fsck: {} inodes, {}/{} data blocks in use, {} errors",
+             fsck.seen.len(), fsck.used.len(), sb.nblocks, fsck.errors);
+    exit(if fsck.errors == 0 { 0 } else { 1 });
+}
@@ -0,0 +1,187 @@
+//! Make a native filesystem image, optionally filled from a host
+//! directory.
+//!
+//! Usage: mkfs <image> <size in blocks> [<ninodes>] [<directory>]
+
+use std::cmp::min;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+use rfs_tools::layout::*;
+use rfs_tools::Image;
+
+struct Mkfs {
+    img: Image,
+    next_inode: u32,
+    next_block: u32,
+}
+
+impl Mkfs {
+    fn ialloc(&mut self, ftype: u16) -> u32 {
+        let inum = self.next_inode;
+        if inum >= self.img.sb.ninodes {
+            fail("out of inodes");
+        }
+        self.next_inode += 1;
+        let mut ip = DiskInode::empty(ftype);
+        ip.nlink = 1;
+        self.img.set_inode(inum, &ip);
+        inum
+    }
+
+    fn balloc(&mut self) -> u32 {
+        let b = self.next_block;
+        if b >= self.img.sb.size {
+            fail("out of data blocks");
+        }
+        self.next_block += 1;
+        b
+    }
+
+    fn slot(&mut self, table: u32, i: usize) -> u32 {
+        let mut addr = indirect_get(self.img.block(table), i);
+        if addr == 0 {
+            addr = self.balloc();
+            indirect_set(self.img.block_mut(table), i, addr);
+        }
+        addr
+    }
+
+    fn bmap(&mut self, ip: &mut DiskInode, bn: usize) -> u32 {
+        let mut direct = |mk: &mut Self, i: usize| {
+            if ip.addrs[i] == 0 {
+                ip.addrs[i] = mk.balloc();
+            }
+            ip.addrs[i]
+        };
+        if bn < NDIRECT {
+            return direct(self, bn);
+        }
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT {
+            let ind = direct(self, NDIRECT);
+            return self.slot(ind, bn);
+        }
+        let bn = bn - NINDIRECT;
+        if bn >= NINDIRECT * NINDIRECT {
+            fail("file too large");
+        }
+        let dind = direct(self, NDIRECT + 1);
+        let ind = self.slot(dind, bn / NINDIRECT);
+        self.slot(ind, bn % NINDIRECT)
+    }
+
+    fn append(&mut self, inum: u32, data: &[u8]) {
+        let mut ip = self.img.inode(inum);
+        let mut off = ip.size as usize;
+        if off + data.len() > MAXFILE * BSIZE {
+            fail("file too large");
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let n = min(data.len() - done, BSIZE - off % BSIZE);
+            let b = self.bmap(&mut ip, off / BSIZE);
+            self.img.block_mut(b)[off % BSIZE..off % BSIZE + n].copy_from_slice(&data[done..done + n]);
+            off += n;
+            done += n;
+        }
+        ip.size = off as u32;
+        self.img.set_inode(inum, &ip);
+    }
+
+    fn link(&mut self, dir: u32, name: &str, inum: u32) {
+        if name.len() > DIRSIZ {
+            fail(&format!("name too long: {}", name));
+        }
+        let mut ent = [0; DIRENT_SIZE];
+        DiskDirent::new(inum, name.as_bytes()).encode(&mut ent);
+        self.append(dir, &ent);
+    }
+
+    fn mkdir(&mut self, parent: u32) -> u32 {
+        let inum = self.ialloc(T_DIR);
+        self.link(inum, ".", inum);
+        self.link(inum, "..", parent);
+        if inum != parent {
+            let mut pp = self.img.inode(parent);
+            pp.nlink += 1;
+            self.img.set_inode(parent, &pp);
+        }
+        inum
+    }
+
+    /// Copy a host directory's contents into directory `dir`.
+    fn fill(&mut self, dir: u32, host: &Path) {
+        let mut entries: Vec<_> = fs::read_dir(host)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", host.display(), e)))
+            .map(|e| e.unwrap())
+            .collect();
+        entries.sort_by_key(|e| e.file_name());
+        for ent in entries {
+            let name = ent.file_name().into_string()
+                .unwrap_or_else(|n| fail(&format!("non UTF-8 name {:?}", n)));
+            let path = ent.path();
+            let ftype = ent.file_type().unwrap();
+            if ftype.is_dir() {
+                let inum = self.mkdir(dir);
+                self.link(dir, &name, inum);
+                self.fill(inum, &path);
+            } else if ftype.is_file() {
+                let inum = self.ialloc(T_FILE);
+                self.link(dir, &name, inum);
+                let data = fs::read(&path).unwrap();
+                self.append(inum, &data);
+            } else {
+                eprintln!("mkfs: skipping {}", path.display());
+            }
+        }
+    }
+
+    /// Mark every block handed out so far, and all metadata, as used.
+    fn write_bitmap(&mut self) {
+        for b in 0..self.next_block {
+            let bno = self.img.sb.bblock(b);
+            let bit = b as usize % BPB;
+            self.img.block_mut(bno)[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("mkfs: {}", msg);
+    exit(1)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        fail("usage: mkfs <image> <size in blocks> [<ninodes>] [<directory>]");
+    }
+    let size: u32 = args[2].parse().unwrap_or_else(|_| fail("bad size"));
+    let (ninodes, dir) = match args.get(3).map(|a| a.parse::<u32>()) {
+        Some(Ok(n)) => (n, args.get(4)),
+        Some(Err(_)) => (1024, args.get(3)),
+        None => (1024, None),
+    };
+    let sb = SuperBlock::new(size, ninodes);
+    if sb.nblocks < 16 || ninodes <= ROOTINO {
+        fail("image too small");
+    }
+
+    let mut mk = Mkfs {
+        img: Image::new(size, ninodes),
+        next_inode: ROOTINO,
+        next_block: sb.datastart(),
+    };
+    let root = mk.mkdir(ROOTINO);
+    assert_eq!(root, ROOTINO);
+    if let Some(dir) = dir {
+        mk.fill(root, Path::new(dir));
+    }
+    mk.write_bitmap();
+    println!("mkfs: {} blocks ({} meta, {} data, {} used), {} inodes ({} used)",
+             sb.size, sb.datastart(), sb.nblocks, mk.next_block - sb.datastart(),
+             sb.ninodes, mk.next_inode - 1);
+    mk.img.save(&args[1]).unwrap_or_else(|e| fail(&format!("{}: {}", args[1], e)));
+}
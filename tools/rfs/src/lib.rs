@@ -0,0 +1,116 @@
+//! Shared pieces of the host side native filesystem tools.
+//!
+//! Images are small, so the tools just work on a whole image held in
+//! memory.
+
+#[path = "../../../src/file/rfs/layout.rs"]
+pub mod layout;
+
+use std::fs;
+use std::io;
+
+use layout::*;
+
+pub struct Image {
+    pub sb: SuperBlock,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// A blank image with a fresh superblock and nothing else.
+    pub fn new(size: u32, ninodes: u32) -> Self {
+        let sb = SuperBlock::new(size, ninodes);
+        let mut img = Self {
+            sb,
+            data: vec![0; size as usize * BSIZE],
+        };
+        sb.encode(img.block_mut(SUPER_BLOCK));
+        img
+    }
+
+    pub fn open(path: &str) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.len() < 2 * BSIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "image too small"));
+        }
+        let sb = SuperBlock::decode(&data[SUPER_BLOCK as usize * BSIZE..]);
+        if !sb.valid() || sb.size as usize * BSIZE > data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad superblock"));
+        }
+        Ok(Self { sb, data })
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, &self.data)
+    }
+
+    pub fn block(&self, b: u32) -> &[u8] {
+        &self.data[b as usize * BSIZE..(b as usize + 1) * BSIZE]
+    }
+
+    pub fn block_mut(&mut self, b: u32) -> &mut [u8] {
+        &mut self.data[b as usize * BSIZE..(b as usize + 1) * BSIZE]
+    }
+
+    pub fn inode(&self, inum: u32) -> DiskInode {
+        DiskInode::decode(self.block(self.sb.iblock(inum)), inum)
+    }
+
+    pub fn set_inode(&mut self, inum: u32, ip: &DiskInode) {
+        let b = self.sb.iblock(inum);
+        ip.encode(self.block_mut(b), inum);
+    }
+
+    /// Install anything committed in the log, like the kernel does at
+    /// mount. Returns how many blocks were replayed.
+    pub fn replay_log(&mut self) -> usize {
+        let hdr = LogHeader::decode(self.block(self.sb.logstart));
+        let n = hdr.n as usize;
+        for i in 0..n.min(LOGSIZE) {
+            let src = self.block(self.sb.logstart + 1 + i as u32).to_vec();
+            if hdr.blocks[i] < self.sb.size {
+                self.block_mut(hdr.blocks[i]).copy_from_slice(&src);
+            }
+        }
+        self.block_mut(self.sb.logstart)[..4].fill(0);
+        n
+    }
+
+    /// Disk block holding block `bn` of a file, zero for a hole.
+    pub fn bmap(&self, ip: &DiskInode, bn: usize) -> u32 {
+        let entry = |table: u32, i: usize| {
+            if table == 0 || table >= self.sb.size {
+                0
+            } else {
+                indirect_get(self.block(table), i)
+            }
+        };
+        if bn < NDIRECT {
+            return ip.addrs[bn];
+        }
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT {
+            return entry(ip.addrs[NDIRECT], bn);
+        }
+        let bn = bn - NINDIRECT;
+        entry(entry(ip.addrs[NDIRECT + 1], bn / NINDIRECT), bn % NINDIRECT)
+    }
+
+    pub fn read_file(&self, ip: &DiskInode) -> Vec<u8> {
+        let mut out = vec![0; ip.size as usize];
+        for (bn, chunk) in out.chunks_mut(BSIZE).enumerate() {
+            let b = self.bmap(ip, bn);
+            if b != 0 && b < self.sb.size {
+                chunk.copy_from_slice(&self.block(b)[..chunk.len()]);
+            }
+        }
+        out
+    }
+
+    pub fn dirents(&self, ip: &DiskInode) -> Vec<DiskDirent> {
+        self.read_file(ip)
+            .chunks_exact(DIRENT_SIZE)
+            .map(DiskDirent::decode)
+            .collect()
+    }
+}
@@ -0,0 +1,187 @@
+//! mkfs and fsck end to end, on images in a scratch directory, and log
+//! replay after a machine goes down in the middle of a commit.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rfs_tools::layout::*;
+use rfs_tools::Image;
+
+const SIZE: u32 = 4096;
+
+// An empty directory of its own for each test.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rfs-tools-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn mkfs(image: &Path, from: &Path) {
+    let out = Command::new(env!("CARGO_BIN_EXE_mkfs"))
+        .arg(image)
+        .arg(SIZE.to_string())
+        .arg(from)
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "mkfs: {}", String::from_utf8_lossy(&out.stderr));
+}
+
+// Whether fsck found the image clean, and what it said.
+fn fsck(image: &Path) -> (bool, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_fsck")).arg(image).output().unwrap();
+    (out.status.success(), String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+fn lookup(img: &Image, path: &str) -> DiskInode {
+    let mut ip = img.inode(ROOTINO);
+    for name in path.split('/') {
+        let ent = img.dirents(&ip).into_iter()
+            .find(|e| e.inum != 0 && e.name() == name.as_bytes())
+            .unwrap_or_else(|| panic!("{} not found", path));
+        ip = img.inode(ent.inum);
+    }
+    ip
+}
+
+// Something to tell the blocks of a file apart by.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / BSIZE + i) as u8).collect()
+}
+
+#[test]
+fn mkfs_then_fsck_is_clean() {
+    let dir = scratch("round-trip");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub/deep")).unwrap();
+    fs::write(tree.join("hello"), b"hello, world\n").unwrap();
+    fs::write(tree.join("empty"), b"").unwrap();
+    fs::write(tree.join("sub/deep/file"), b"nested").unwrap();
+    // far enough to need the double indirect block
+    let big = pattern((NDIRECT + NINDIRECT + 3) * BSIZE + 100);
+    fs::write(tree.join("big"), &big).unwrap();
+    let image = dir.join("fs.img");
+    mkfs(&image, &tree);
+
+    let (clean, out) = fsck(&image);
+    assert!(clean, "{}", out);
+    assert!(out.contains(" 0 errors"), "{}", out);
+
+    let img = Image::open(image.to_str().unwrap()).unwrap();
+    assert_eq!(img.read_file(&lookup(&img, "hello")), b"hello, world\n");
+    assert_eq!(img.read_file(&lookup(&img, "empty")), b"");
+    assert_eq!(img.read_file(&lookup(&img, "sub/deep/file")), b"nested");
+    assert_eq!(img.read_file(&lookup(&img, "big")), big);
+    assert_eq!(lookup(&img, "sub").ftype, T_DIR);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn fsck_notices_damage() {
+    let dir = scratch("damage");
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("file"), pattern(3 * BSIZE)).unwrap();
+    let image = dir.join("fs.img");
+    mkfs(&image, &tree);
+
+    // two files can't have the same block
+    let mut img = Image::open(image.to_str().unwrap()).unwrap();
+    let file = lookup(&img, "file");
+    let mut root = img.inode(ROOTINO);
+    root.addrs[1] = file.addrs[0];
+    img.set_inode(ROOTINO, &root);
+    img.save(image.to_str().unwrap()).unwrap();
+
+    let (clean, out) = fsck(&image);
+    assert!(!clean, "{}", out);
+    assert!(out.contains("used twice"), "{}", out);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// Grow `file` by a block the way the kernel would, on a copy of `img`:
+// allocate the block, fill it, and point the inode at it.
+fn grow(img: &Image, data: &[u8]) -> Image {
+    let mut new = Image { sb: img.sb, data: img.data.clone() };
+    let sb = new.sb;
+    let b = (sb.datastart()..sb.size)
+        .find(|&b| {
+            let bit = b as usize % BPB;
+            new.block(sb.bblock(b))[bit / 8] & (1 << (bit % 8)) == 0
+        })
+        .unwrap();
+    let bit = b as usize % BPB;
+    new.block_mut(sb.bblock(b))[bit / 8] |= 1 << (bit % 8);
+    new.block_mut(b)[..data.len()].copy_from_slice(data);
+    let root = img.dirents(&img.inode(ROOTINO));
+    let inum = root.iter().find(|e| e.name() == b"file").unwrap().inum;
+    let mut ip = new.inode(inum);
+    assert_eq!(ip.size as usize, BSIZE);
+    ip.addrs[1] = b;
+    ip.size += data.len() as u32;
+    new.set_inode(inum, &ip);
+    new
+}
+
+// Write the blocks `new` changed from `img` to `img`'s log, and the
+// header saying they are committed only if `commit`, which is the last
+// write of a commit. Then the machine goes down, before any of them is
+// installed.
+fn crash(img: &mut Image, new: &Image, commit: bool) -> usize {
+    let changed: Vec<u32> = (0..img.sb.size).filter(|&b| img.block(b) != new.block(b)).collect();
+    assert!(!changed.is_empty() && changed.len() <= LOGSIZE);
+    let mut hdr = LogHeader { n: 0, blocks: [0; LOGSIZE] };
+    for (i, &b) in changed.iter().enumerate() {
+        let to = img.sb.logstart + 1 + i as u32;
+        img.block_mut(to).copy_from_slice(new.block(b));
+        hdr.blocks[i] = b;
+    }
+    if commit {
+        hdr.n = changed.len() as u32;
+    }
+    let start = img.sb.logstart;
+    hdr.encode(img.block_mut(start));
+    changed.len()
+}
+
+fn replay_test(name: &str, commit: bool) {
+    let dir = scratch(name);
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    let old = pattern(BSIZE);
+    fs::write(tree.join("file"), &old).unwrap();
+    let image = dir.join("fs.img");
+    mkfs(&image, &tree);
+
+    let mut img = Image::open(image.to_str().unwrap()).unwrap();
+    let more = vec![0x5a; 200];
+    let new = grow(&img, &more);
+    let n = crash(&mut img, &new, commit);
+    // the bitmap, the inode and the data
+    assert_eq!(n, 3);
+    img.save(image.to_str().unwrap()).unwrap();
+
+    let (clean, out) = fsck(&image);
+    assert!(clean, "{}", out);
+    assert_eq!(out.contains(&format!("replayed {} committed", n)), commit, "{}", out);
+
+    let mut img = Image::open(image.to_str().unwrap()).unwrap();
+    assert_eq!(img.replay_log(), if commit { n } else { 0 });
+    let expect = match commit {
+        true => [old, more].concat(),
+        false => old,
+    };
+    assert_eq!(img.read_file(&lookup(&img, "file")), expect);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn committed_transaction_is_replayed() {
+    replay_test("committed", true);
+}
+
+#[test]
+fn uncommitted_transaction_is_dropped() {
+    replay_test("uncommitted", false);
+}